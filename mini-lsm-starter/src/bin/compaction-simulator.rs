@@ -85,6 +85,8 @@ impl MockStorage {
             l0_sstables: Vec::new(),
             levels: Vec::new(),
             sstables: Default::default(),
+            l0_sorted_runs: None,
+            range_tombstones: Vec::new(),
         };
         Self {
             snapshot,
@@ -284,8 +286,12 @@ fn main() {
                     );
                     println!("-> {:?}", sst_ids);
                     max_space = max_space.max(storage.file_list.len());
-                    let (snapshot, del) =
-                        controller.apply_compaction_result(&storage.snapshot, &task, &sst_ids);
+                    let (snapshot, del) = controller.apply_compaction_result(
+                        &storage.snapshot,
+                        &task,
+                        &sst_ids,
+                        false,
+                    );
                     storage.snapshot = snapshot;
                     storage.remove(&del);
                     println!("--- After Compaction ---");
@@ -374,8 +380,12 @@ fn main() {
                     }
                     println!("-> {:?}", sst_ids);
                     max_space = max_space.max(storage.file_list.len());
-                    let (snapshot, del) =
-                        controller.apply_compaction_result(&storage.snapshot, &task, &sst_ids);
+                    let (snapshot, del) = controller.apply_compaction_result(
+                        &storage.snapshot,
+                        &task,
+                        &sst_ids,
+                        false,
+                    );
                     storage.snapshot = snapshot;
                     storage.remove(&del);
                     println!("--- After Compaction ---");
@@ -435,6 +445,13 @@ fn main() {
                 level_size_multiplier,
                 max_levels,
                 base_level_size_mb,
+                per_level_block_size: None,
+                abort_overlap_ratio: None,
+                min_sst_age_before_compaction: None,
+                prioritize_by_age: false,
+                max_compaction_bytes: None,
+                pick_strategy: Default::default(),
+                level_entry_count_trigger: None,
             });
 
             let mut storage = MockStorage::new();
@@ -340,12 +340,21 @@ fn main() -> Result<()> {
                         level0_file_num_compaction_trigger: 2,
                         max_levels: 4,
                         base_level_size_mb: 128,
+                        per_level_block_size: None,
+                        abort_overlap_ratio: None,
+                        min_sst_age_before_compaction: None,
+                        prioritize_by_age: false,
+                        max_compaction_bytes: None,
+                        pick_strategy: Default::default(),
+                        level_entry_count_trigger: None,
                         level_size_multiplier: 2,
                     })
                 }
             },
             enable_wal: args.enable_wal,
             serializable: args.serializable,
+            group_l0_sorted_runs: false,
+            reverse_block_order_compaction_output: false,
         },
     )?;
 
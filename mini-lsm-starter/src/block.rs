@@ -2,47 +2,363 @@
 #![allow(dead_code)] // TODO(you): remove this lint after implementing this mod
 
 mod builder;
+mod compression;
 mod iterator;
+mod streaming;
+mod validate;
+mod value_prefix;
+mod xxhash;
 
-pub use builder::BlockBuilder;
+use std::sync::Arc;
+
+use builder::compute_overlap;
+pub use builder::{
+    AddResult, BlockBufferAllocator, BlockBuilder, BlockSizePolicy, GlobalBlockAllocator,
+};
 use bytes::{Buf, BufMut, Bytes};
-pub use iterator::BlockIterator;
+pub use compression::BlockCompression;
+pub use iterator::{BlockIterator, SeekStrategy};
+pub use streaming::StreamingBlockReader;
+pub use validate::BlockError;
+pub(crate) use xxhash::XxHash64;
+
+use crate::key::KeyVec;
 pub(crate) const SIZEOF_U16: usize = std::mem::size_of::<u16>();
+/// Set in the encoded number of entries for columnar blocks.
+const COLUMNAR_FLAG: u16 = 1 << 15;
+/// Set in the encoded number of entries for blocks with an extension trailer.
+const EXTENSION_FLAG: u16 = 1 << 14;
+/// Set in the encoded number of entries for blocks with a checksum.
+const CHECKSUM_FLAG: u16 = 1 << 13;
+/// Set in the encoded number of entries for dictionary-encoded blocks.
+const DICTIONARY_FLAG: u16 = 1 << 12;
+/// Set in the encoded number of entries for blocks with prefix-compressed values.
+const VALUE_PREFIX_FLAG: u16 = 1 << 11;
+/// The encoded number of entries without the flags.
+const ENTRY_COUNT_MASK: u16 =
+    !(COLUMNAR_FLAG | EXTENSION_FLAG | CHECKSUM_FLAG | DICTIONARY_FLAG | VALUE_PREFIX_FLAG);
+
+/// Encode `value` as a LEB128 varint, 7 bits per byte with the high bit marking continuation.
+pub(crate) fn put_varint(buf: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Decode a varint written by `put_varint` and advance `buf`, returns `None` if `buf` ends in the
+/// middle of the varint or it does not fit in `usize`.
+pub(crate) fn try_get_varint(buf: &mut &[u8]) -> Option<usize> {
+    let mut value = 0usize;
+    for (idx, byte) in buf.iter().enumerate() {
+        let shift = idx * 7;
+        if shift >= usize::BITS as usize {
+            return None;
+        }
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            buf.advance(idx + 1);
+            return Some(value);
+        }
+    }
+    None
+}
+
+pub(crate) fn get_varint(buf: &mut &[u8]) -> usize {
+    try_get_varint(buf).expect("truncated varint")
+}
+
+/// Number of bytes `put_varint` uses for `value`.
+pub(crate) fn varint_len(value: usize) -> usize {
+    (usize::BITS - value.leading_zeros()).max(1).div_ceil(7) as usize
+}
+
+/// How the entries are laid out in a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlockLayout {
+    /// Each key is followed by its value.
+    #[default]
+    RowWise,
+    /// All keys are stored contiguously, followed by all values, so that seeking and key-only
+    /// scans never touch the value bytes.
+    Columnar,
+    /// Each key is followed by the index of its value in a dictionary of the distinct values of
+    /// the block, stored after all entries. Saves space when values repeat, e.g. enum-like data.
+    Dictionary,
+    /// Like the row-wise layout, but each value is stored as
+    /// `value_overlap (u16) | value_suffix_len (u16) | value_suffix`, where the overlap is shared
+    /// with the value of the previous entry. Saves space when consecutive values share long
+    /// prefixes, e.g. JSON documents or time-series payloads. The values stay compressed in
+    /// memory, reading a value rebuilds it from the previous ones.
+    PrefixCompressedValues,
+}
+
+impl BlockLayout {
+    /// The layout marked in the encoded number of entries.
+    fn from_flags(raw_offsets_len: u16) -> Self {
+        if raw_offsets_len & VALUE_PREFIX_FLAG != 0 {
+            Self::PrefixCompressedValues
+        } else if raw_offsets_len & COLUMNAR_FLAG != 0 {
+            Self::Columnar
+        } else if raw_offsets_len & DICTIONARY_FLAG != 0 {
+            Self::Dictionary
+        } else {
+            Self::RowWise
+        }
+    }
+}
+
+/// Key length distribution of a block, see `Block::key_length_stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyLenStats {
+    /// Length of the shortest user key.
+    pub min: usize,
+    /// Length of the longest user key.
+    pub max: usize,
+    /// Mean length of the user keys.
+    pub mean: f64,
+    /// Bytes saved by prefix compression, i.e., the sum of the overlap lengths.
+    pub total_overlap_bytes: usize,
+}
 
 /// A block is the smallest unit of read and caching in LSM tree. It is a collection of sorted key-value pairs.
 pub struct Block {
     //data中包含 Data Section             |              Offset Section             |      Extra      |
-    pub(crate) data: Vec<u8>,
+    /// The entries of the block, may be a slice of a larger shared buffer, see
+    /// `Block::decode_shared`.
+    pub(crate) data: Bytes,
     pub(crate) offsets: Vec<u16>,
+    pub(crate) layout: BlockLayout,
+    /// Offset of each value in `data` for the columnar layout, or of each dictionary value for the
+    /// dictionary layout, a value ends where the next one begins. Empty for the row-wise layout.
+    pub(crate) value_offsets: Vec<u16>,
+    /// Opaque bytes stored after the offsets, reserved for future fields of the block.
+    pub(crate) extension: Vec<u8>,
+    /// xxHash64 of `data`, computed by the builder while the entries are added. Only stored in the
+    /// encoded block by `encode_with_checksum`.
+    pub(crate) checksum: Option<u64>,
 }
 
 impl Block {
     /// Encode the internal data to the data layout illustrated in the tutorial
     /// Note: You may want to recheck if any of the expected field is missing from your output
+    /// The columnar layout stores the value offsets after the key offsets, and sets
+    /// `COLUMNAR_FLAG` in the number of elements.
+    /// A non-empty extension trailer follows the offsets as `extension | extension_len (u16)` and
+    /// is marked by `EXTENSION_FLAG`, so readers that know nothing about its content can skip it.
+    /// The dictionary layout stores the offsets of the dictionary values after the key offsets,
+    /// followed by the number of dictionary values (u16), and sets `DICTIONARY_FLAG`.
+    /// Blocks with prefix-compressed values set `VALUE_PREFIX_FLAG`.
+    /// The checksum is only written by `encode_with_checksum`, so a row-wise block without
+    /// extension keeps the plain layout.
     pub fn encode(&self) -> Bytes {
-        let mut buf = self.data.clone();
+        self.encode_with(None).into()
+    }
+
+    /// Encode the block, with `checksum` as the last field before the number of elements, marked
+    /// by `CHECKSUM_FLAG`.
+    fn encode_with(&self, checksum: Option<u64>) -> Vec<u8> {
+        let mut buf = self.data.to_vec();
         let offsets_len = self.offsets.len();
-        for offset in &self.offsets {
+        for offset in self.offsets.iter().chain(self.value_offsets.iter()) {
             buf.put_u16(*offset);
         }
+        let mut flags = 0;
+        match self.layout {
+            BlockLayout::RowWise => {}
+            BlockLayout::Columnar => flags |= COLUMNAR_FLAG,
+            BlockLayout::Dictionary => {
+                buf.put_u16(self.value_offsets.len() as u16);
+                flags |= DICTIONARY_FLAG;
+            }
+            BlockLayout::PrefixCompressedValues => flags |= VALUE_PREFIX_FLAG,
+        }
+        if !self.extension.is_empty() {
+            buf.put(&self.extension[..]);
+            buf.put_u16(self.extension.len() as u16);
+            flags |= EXTENSION_FLAG;
+        }
+        if let Some(checksum) = checksum {
+            buf.put_u64(checksum);
+            flags |= CHECKSUM_FLAG;
+        }
         // Adds number of elements at the end of the block
-        buf.put_u16(offsets_len as u16);
-        buf.into()
+        buf.put_u16(offsets_len as u16 | flags);
+        buf
     }
 
     /// Decode from the data layout, transform the input `data` to a single `Block`
     pub fn decode(data: &[u8]) -> Self {
+        Self::decode_encoded_entries(data)
+    }
+
+    /// Decode a block without copying its entries, `data` of the block is a slice of `buf`. The
+    /// offsets are still parsed into a `Vec`, which is 2 bytes per entry. This saves copying the
+    /// entries when blocks come from a shared buffer, e.g. an mmap or a cached file region. The
+    /// block keeps the whole of `buf` alive.
+    pub fn decode_shared(buf: Bytes) -> Arc<Self> {
+        Arc::new(Self::decode_encoded_entries_with(&buf, |data| {
+            buf.slice_ref(data)
+        }))
+    }
+
+    /// Decode the block, copying its entries out of `data`.
+    fn decode_encoded_entries(data: &[u8]) -> Self {
+        Self::decode_encoded_entries_with(data, Bytes::copy_from_slice)
+    }
+
+    /// Decode the block as is, `entries` turns the data section of `data` into the data of the
+    /// block.
+    fn decode_encoded_entries_with(data: &[u8], entries: impl FnOnce(&[u8]) -> Bytes) -> Self {
         // get number of elements in the block
-        let entry_offsets_len = (&data[data.len() - SIZEOF_U16..]).get_u16() as usize;
-        let data_end = data.len() - SIZEOF_U16 - entry_offsets_len * SIZEOF_U16;
-        let offsets_raw = &data[data_end..data.len() - SIZEOF_U16];
+        let raw_offsets_len = (&data[data.len() - SIZEOF_U16..]).get_u16();
+        let layout = BlockLayout::from_flags(raw_offsets_len);
+        let entry_offsets_len = (raw_offsets_len & ENTRY_COUNT_MASK) as usize;
+        let mut offsets_end = data.len() - SIZEOF_U16;
+        let mut checksum = None;
+        if raw_offsets_len & CHECKSUM_FLAG != 0 {
+            offsets_end -= std::mem::size_of::<u64>();
+            checksum = Some((&data[offsets_end..]).get_u64());
+        }
+        // skip the extension trailer, older blocks without EXTENSION_FLAG have none
+        let mut extension = Vec::new();
+        if raw_offsets_len & EXTENSION_FLAG != 0 {
+            let extension_len = (&data[offsets_end - SIZEOF_U16..]).get_u16() as usize;
+            offsets_end -= SIZEOF_U16 + extension_len;
+            extension = data[offsets_end..offsets_end + extension_len].to_vec();
+        }
+        let value_offsets_len = match layout {
+            BlockLayout::RowWise | BlockLayout::PrefixCompressedValues => 0,
+            BlockLayout::Columnar => entry_offsets_len,
+            BlockLayout::Dictionary => {
+                offsets_end -= SIZEOF_U16;
+                (&data[offsets_end..]).get_u16() as usize
+            }
+        };
+        let data_end = offsets_end - (entry_offsets_len + value_offsets_len) * SIZEOF_U16;
+        let offsets_raw = &data[data_end..offsets_end];
         // get offset array
-        let offsets = offsets_raw
+        let mut offsets: Vec<u16> = offsets_raw
             .chunks(SIZEOF_U16)
             .map(|mut x| x.get_u16())
             .collect();
+        let value_offsets = offsets.split_off(entry_offsets_len);
         // retrieve data
-        let data = data[0..data_end].to_vec();
-        Self { data, offsets }
+        let data = entries(&data[0..data_end]);
+        Self {
+            data,
+            offsets,
+            layout,
+            value_offsets,
+            extension,
+            checksum,
+        }
+    }
+
+    /// Creates a row-wise block from its entries and their offsets, without extension or
+    /// checksum. Used with `BlockBuilder::build_with` to finalize blocks outside of the builder.
+    pub fn from_raw_parts(data: Vec<u8>, offsets: Vec<u16>) -> Self {
+        Self {
+            data: data.into(),
+            offsets,
+            layout: BlockLayout::RowWise,
+            value_offsets: Vec::new(),
+            extension: Vec::new(),
+            checksum: None,
+        }
+    }
+
+    /// Returns the entries and their offsets of a row-wise block, the reverse of
+    /// `from_raw_parts`.
+    pub fn into_raw_parts(self) -> (Vec<u8>, Vec<u16>) {
+        (self.data.into(), self.offsets)
+    }
+
+    /// Check the data against the checksum of the block, blocks without a checksum always pass.
+    pub fn verify_checksum(&self) -> bool {
+        self.checksum
+            .is_none_or(|checksum| XxHash64::hash(&self.data) == checksum)
+    }
+
+    /// Returns the extension trailer of the block, empty unless set with
+    /// `BlockBuilder::set_extension`.
+    pub fn extension(&self) -> &[u8] {
+        &self.extension
+    }
+
+    /// Returns the number of entries in the block.
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Returns true if the block has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// Returns the index of every entry whose user key is not valid UTF-8. This is only used by
+    /// text-mode tooling to flag binary keys and does not affect how the block is stored.
+    pub fn non_utf8_key_indices(&self) -> Vec<usize> {
+        let mut indices = Vec::new();
+        self.for_each_entry(|idx, key, _| {
+            if std::str::from_utf8(key.key_ref()).is_err() {
+                indices.push(idx);
+            }
+        });
+        indices
+    }
+
+    /// Returns the entries whose value satisfies `pred`, decoded in a single pass over the block.
+    /// Used to filter on the server side so that rejected values are never copied out.
+    pub fn filter_values(&self, pred: impl Fn(&[u8]) -> bool) -> Vec<(KeyVec, Vec<u8>)> {
+        let mut entries = Vec::new();
+        self.for_each_entry(|_, key, value| {
+            if pred(value) {
+                entries.push((key.clone(), value.to_vec()));
+            }
+        });
+        entries
+    }
+
+    /// Returns the longest prefix shared by the user keys of every entry, empty for an empty block.
+    pub fn common_prefix(&self) -> Vec<u8> {
+        if self.offsets.is_empty() {
+            return Vec::new();
+        }
+        let first_key = self.get_first_key();
+        let mut prefix_len = first_key.key_len();
+        self.for_each_entry(|_, key, _| {
+            prefix_len = prefix_len.min(compute_overlap(
+                first_key.as_key_slice(),
+                key.as_key_slice(),
+            ));
+        });
+        first_key.key_ref()[..prefix_len].to_vec()
+    }
+
+    /// Returns the user key length distribution and how many bytes prefix compression saved.
+    pub fn key_length_stats(&self) -> KeyLenStats {
+        let mut stats = KeyLenStats {
+            min: usize::MAX,
+            max: 0,
+            mean: 0.0,
+            total_overlap_bytes: 0,
+        };
+        let mut total_key_len = 0;
+        self.for_each_entry(|idx, key, _| {
+            stats.min = stats.min.min(key.key_len());
+            stats.max = stats.max.max(key.key_len());
+            total_key_len += key.key_len();
+            // the overlap is the first field of an entry
+            let offset = self.offsets[idx] as usize;
+            stats.total_overlap_bytes += (&self.data[offset..]).get_u16() as usize;
+        });
+        if self.offsets.is_empty() {
+            stats.min = 0;
+        } else {
+            stats.mean = total_key_len as f64 / self.offsets.len() as f64;
+        }
+        stats
     }
 }
@@ -1,10 +1,81 @@
 #![allow(unused_variables)] // TODO(you): remove this lint after implementing this mod
 #![allow(dead_code)] // TODO(you): remove this lint after implementing this mod
 
+use std::collections::HashMap;
+
 use crate::key::{KeySlice, KeyVec};
-use bytes::BufMut;
+use bytes::{Buf, BufMut};
+
+use super::{
+    get_varint, put_varint, value_prefix, varint_len, Block, BlockLayout, XxHash64,
+    ENTRY_COUNT_MASK, SIZEOF_U16,
+};
+
+/// Maximum number of distinct values in the dictionary of a block, a block with more distinct
+/// values falls back to the row-wise layout.
+const MAX_DICTIONARY_VALUES: usize = 256;
+
+/// Number of entries per block `BlockBuilder::recommend_block_size` aims for. Fewer entries make
+/// the block index larger, more entries make a point read decode more of a block.
+const RECOMMENDED_ENTRIES_PER_BLOCK: usize = 64;
+
+/// Bounds of `BlockBuilder::recommend_block_size`, the upper one is the largest power of two the
+/// `u16` offsets can address.
+const MIN_RECOMMENDED_BLOCK_SIZE: usize = 1024;
+const MAX_RECOMMENDED_BLOCK_SIZE: usize = 32 * 1024;
+
+/// The outcome of `BlockBuilder::try_add` and `BlockBuilder::add_raw`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddResult {
+    /// The entry is added to the block.
+    Added,
+    /// The block is full, the entry should go to a new block.
+    Full,
+    /// The key is empty and is never stored, as an empty key marks an invalid iterator.
+    EmptyKeyRejected,
+    /// The entry alone exceeds the hard limit of `BlockSizePolicy::Hard`, it does not fit in any
+    /// block.
+    ExceedsHardLimit,
+}
+
+/// How strictly a `BlockBuilder` keeps to its size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlockSizePolicy {
+    /// The block size is a target: an entry is refused once the block would grow past it, except
+    /// for the first entry, which is always added so that a key-value pair larger than the block
+    /// gets a block of its own.
+    #[default]
+    Soft,
+    /// No block grows past `max` bytes, not even for its first entry. An entry that does not fit
+    /// in an empty block is refused with `AddResult::ExceedsHardLimit`, and the caller decides
+    /// where to store it, e.g. in a separate area for large values. Blocks are still split at the
+    /// block size when it is smaller than `max`.
+    Hard { max: usize },
+}
+
+/// Produces the buffers of a `BlockBuilder`, so that embedders control where block memory comes
+/// from, e.g. a pool of recycled buffers or NUMA-local memory. The buffers may still grow through
+/// the global allocator if an entry does not fit in the given capacity.
+pub trait BlockBufferAllocator {
+    /// Returns an empty buffer for the encoded entries.
+    fn alloc_data(&self, capacity: usize) -> Vec<u8>;
+
+    /// Returns an empty buffer for the entry offsets.
+    fn alloc_offsets(&self, capacity: usize) -> Vec<u16>;
+}
+
+/// Allocates the buffers from the global allocator.
+pub struct GlobalBlockAllocator;
 
-use super::{Block, SIZEOF_U16};
+impl BlockBufferAllocator for GlobalBlockAllocator {
+    fn alloc_data(&self, capacity: usize) -> Vec<u8> {
+        Vec::with_capacity(capacity)
+    }
+
+    fn alloc_offsets(&self, capacity: usize) -> Vec<u16> {
+        Vec::with_capacity(capacity)
+    }
+}
 
 /// Builds a block.
 pub struct BlockBuilder {
@@ -14,12 +85,29 @@ pub struct BlockBuilder {
     data: Vec<u8>,
     /// The expected block size.
     block_size: usize,
+    policy: BlockSizePolicy,
     /// The first key in the block
     first_key: KeyVec,
+    layout: BlockLayout,
+    /// Values of the columnar layout, or the dictionary of the dictionary layout, appended after
+    /// `data` when the block is built.
+    values: Vec<u8>,
+    /// Offset of each value in `values` for the columnar and dictionary layouts.
+    value_offsets: Vec<u16>,
+    /// Index of each dictionary value for the dictionary layout.
+    dictionary: HashMap<Vec<u8>, usize>,
+    /// Length of `data` if the values were stored in the entries as in the row-wise layout.
+    inline_data_len: usize,
+    /// The last value added, for the prefix-compressed values layout.
+    prev_value: Vec<u8>,
+    /// The extension trailer of the block.
+    extension: Vec<u8>,
+    /// Running hash of `data`, the values of the columnar layout are hashed in `build`.
+    hasher: XxHash64,
 }
 
 //返回它们相同的前缀的字节数
-fn compute_overlap(first_key: KeySlice, key: KeySlice) -> usize {
+pub(super) fn compute_overlap(first_key: KeySlice, key: KeySlice) -> usize {
     let mut i = 0;
     loop {
         if i >= first_key.key_len() || i >= key.key_len() {
@@ -36,49 +124,374 @@ fn compute_overlap(first_key: KeySlice, key: KeySlice) -> usize {
 impl BlockBuilder {
     /// Creates a new block builder.
     pub fn new(block_size: usize) -> Self {
+        Self::new_with_layout(block_size, BlockLayout::RowWise)
+    }
+
+    /// Creates a new block builder with the given entry layout.
+    pub fn new_with_layout(block_size: usize, layout: BlockLayout) -> Self {
         Self {
             offsets: Vec::new(),
             data: Vec::new(),
             block_size,
+            policy: BlockSizePolicy::Soft,
             first_key: KeyVec::new(),
+            layout,
+            values: Vec::new(),
+            value_offsets: Vec::new(),
+            dictionary: HashMap::new(),
+            inline_data_len: 0,
+            prev_value: Vec::new(),
+            extension: Vec::new(),
+            hasher: XxHash64::new(),
+        }
+    }
+
+    /// Creates a new block builder that keeps to `block_size` as told by `policy`.
+    pub fn new_with_policy(block_size: usize, policy: BlockSizePolicy) -> Self {
+        let mut builder = Self::new(block_size);
+        builder.policy = policy;
+        builder
+    }
+
+    /// Creates a new block builder storing each value as its overlap with the previous value,
+    /// see `BlockLayout::PrefixCompressedValues`.
+    pub fn new_with_value_prefix_compression(block_size: usize) -> Self {
+        Self::new_with_layout(block_size, BlockLayout::PrefixCompressedValues)
+    }
+
+    /// Recommend a `block_size` for entries like the `(key_len, value_len)` samples: the average
+    /// row-wise entry size times `RECOMMENDED_ENTRIES_PER_BLOCK`, rounded up to a power of two, so
+    /// that a block holds 64 to 128 such entries. Very small or very large entries are clamped to
+    /// 1KB..=32KB. Without samples, returns the usual 4KB.
+    pub fn recommend_block_size(samples: &[(usize, usize)]) -> usize {
+        if samples.is_empty() {
+            return 4096;
+        }
+        let total_entry_len = samples
+            .iter()
+            .map(|(key_len, value_len)| {
+                SIZEOF_U16 /* overlap */ + varint_len(*key_len) + key_len
+                    + std::mem::size_of::<u64>() + SIZEOF_U16 /* value_len */ + value_len
+                    + SIZEOF_U16 /* offset */
+            })
+            .sum::<usize>();
+        let avg_entry_len = total_entry_len.div_ceil(samples.len());
+        (avg_entry_len * RECOMMENDED_ENTRIES_PER_BLOCK)
+            .next_power_of_two()
+            .clamp(MIN_RECOMMENDED_BLOCK_SIZE, MAX_RECOMMENDED_BLOCK_SIZE)
+    }
+
+    /// Creates a new block builder whose buffers come from `allocator`, sized for a full block.
+    pub fn new_in(block_size: usize, allocator: &impl BlockBufferAllocator) -> Self {
+        let mut builder = Self::new(block_size);
+        builder.data = allocator.alloc_data(block_size);
+        // an entry plus offset is at least 16 bytes: overlap, key len, 1-byte key, ts, value len
+        builder.offsets = allocator.alloc_offsets(block_size / 16);
+        builder
+    }
+
+    /// Sets the extension trailer of the block, which is empty by default. Fields added to the
+    /// block format later (e.g., ts range or stats) go here, readers that do not know them skip
+    /// the trailer.
+    pub fn set_extension(&mut self, extension: Vec<u8>) {
+        assert!(
+            extension.len() <= u16::MAX as usize,
+            "block extension is too large"
+        );
+        self.extension = extension;
+    }
+
+    /// Size the block must not grow past once it has an entry.
+    fn size_limit(&self) -> usize {
+        match self.policy {
+            BlockSizePolicy::Soft => self.block_size,
+            BlockSizePolicy::Hard { max } => self.block_size.min(max),
+        }
+    }
+
+    /// Whether an entry is too large for even an empty block under `BlockSizePolicy::Hard`.
+    fn exceeds_hard_limit(&self, suffix_len: usize, value: &[u8]) -> bool {
+        let BlockSizePolicy::Hard { max } = self.policy else {
+            return false;
+        };
+        self.is_empty() && self.estimated_size() + self.entry_len(suffix_len, value) > max
+    }
+
+    /// Number of bytes an entry with a key suffix of `suffix_len` bytes and `value` adds to the
+    /// encoded block, including its offset.
+    fn entry_len(&self, suffix_len: usize, value: &[u8]) -> usize {
+        SIZEOF_U16 /* overlap */ + varint_len(suffix_len) + suffix_len
+            + std::mem::size_of::<u64>() + self.value_cost(value) + SIZEOF_U16 /* offset */
+    }
+
+    /// Whether an entry with a key suffix of `suffix_len` bytes and `value` must go to a new block.
+    /// A key larger than the block is stored in a block of its own, unless the policy is
+    /// `BlockSizePolicy::Hard`, see `exceeds_hard_limit`. Entries are also never placed past what
+    /// the `u16` offsets can address.
+    fn is_full_for(&self, suffix_len: usize, value: &[u8]) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        let entry_len = self.entry_len(suffix_len, value);
+        self.estimated_size() + entry_len > self.size_limit()
+            || self.data.len() + self.values.len() + entry_len > u16::MAX as usize
+            || self.offsets.len() >= ENTRY_COUNT_MASK as usize
+    }
+
+    /// Number of bytes `value` adds to the block in the current layout.
+    fn value_cost(&self, value: &[u8]) -> usize {
+        match self.layout {
+            BlockLayout::RowWise => SIZEOF_U16 /* value_len */ + value.len(),
+            BlockLayout::Columnar => SIZEOF_U16 /* value offset */ + value.len(),
+            BlockLayout::Dictionary => match self.dictionary.get(value) {
+                Some(value_idx) => varint_len(*value_idx),
+                None => {
+                    varint_len(self.dictionary.len()) + SIZEOF_U16 /* value offset */ + value.len()
+                }
+            },
+            BlockLayout::PrefixCompressedValues => {
+                value_prefix::compressed_value_len(&self.prev_value, value)
+            }
         }
     }
 
     fn estimated_size(&self) -> usize {
+        let dictionary_len = if self.layout == BlockLayout::Dictionary {
+            SIZEOF_U16
+        } else {
+            0
+        };
+        let extension_len = if self.extension.is_empty() {
+            0
+        } else {
+            SIZEOF_U16 /* extension length */ + self.extension.len()
+        };
         SIZEOF_U16 /* number of key-value pairs in the block */ +  self.offsets.len() * SIZEOF_U16 /* offsets */ + self.data.len()
         // key-value pairs
+        + self.value_offsets.len() * SIZEOF_U16 + self.values.len() + dictionary_len
+        + extension_len
+    }
+
+    /// For the dictionary layout, switch to the row-wise layout when `value` would grow the
+    /// dictionary past `MAX_DICTIONARY_VALUES`. Returns false if the entries no longer fit in the
+    /// block once their values are inlined, the block is then finished with the dictionary.
+    fn fall_back_if_dictionary_full(&mut self, suffix_len: usize, value: &[u8]) -> bool {
+        if !self.needs_dictionary_fallback(value) {
+            return true;
+        }
+        if !self.fits_inline(suffix_len, value) {
+            return false;
+        }
+        self.fall_back_to_inline();
+        true
     }
 
-    /// Adds a key-value pair to the block. Returns false when the block is full.
+    /// Whether adding `value` to the dictionary layout would grow the dictionary past
+    /// `MAX_DICTIONARY_VALUES`.
+    fn needs_dictionary_fallback(&self, value: &[u8]) -> bool {
+        self.layout == BlockLayout::Dictionary
+            && self.dictionary.len() >= MAX_DICTIONARY_VALUES
+            && !self.dictionary.contains_key(value)
+    }
+
+    /// Whether the entries of the dictionary layout and the new entry fit in the block once their
+    /// values are inlined.
+    fn fits_inline(&self, suffix_len: usize, value: &[u8]) -> bool {
+        let inline_entry_len = SIZEOF_U16 /* overlap */ + varint_len(suffix_len) + suffix_len
+            + std::mem::size_of::<u64>() + SIZEOF_U16 /* value_len */ + value.len();
+        let inline_size = self.estimated_size() - self.data.len()
+            - self.value_offsets.len() * SIZEOF_U16
+            - self.values.len()
+            - SIZEOF_U16 /* dictionary length */
+            + self.inline_data_len;
+        inline_size + inline_entry_len + SIZEOF_U16 <= self.size_limit()
+            && self.inline_data_len + inline_entry_len <= u16::MAX as usize
+    }
+
+    /// Re-encode the entries of the dictionary layout in the row-wise layout.
+    fn fall_back_to_inline(&mut self) {
+        let mut data = Vec::with_capacity(self.inline_data_len);
+        for idx in 0..self.offsets.len() {
+            let begin = self.offsets[idx] as usize;
+            let end = self
+                .offsets
+                .get(idx + 1)
+                .map_or(self.data.len(), |x| *x as usize);
+            let entry = &self.data[begin..end];
+            let mut rest = &entry[SIZEOF_U16..];
+            let key_len = get_varint(&mut rest);
+            rest.advance(key_len + std::mem::size_of::<u64>());
+            // overlap, key and ts stay as they are, only the value index becomes the value
+            let key_part = &entry[..entry.len() - rest.len()];
+            let value_idx = get_varint(&mut rest);
+            let value_begin = self.value_offsets[value_idx] as usize;
+            let value_end = self
+                .value_offsets
+                .get(value_idx + 1)
+                .map_or(self.values.len(), |x| *x as usize);
+            self.offsets[idx] = data.len() as u16;
+            data.put(key_part);
+            data.put_u16((value_end - value_begin) as u16);
+            data.put(&self.values[value_begin..value_end]);
+        }
+        debug_assert_eq!(data.len(), self.inline_data_len);
+        self.hasher = XxHash64::new();
+        self.hasher.update(&data);
+        self.data = data;
+        self.values.clear();
+        self.value_offsets.clear();
+        self.dictionary.clear();
+        self.layout = BlockLayout::RowWise;
+    }
+
+    /// Number of bytes left before the block reaches its target size.
+    pub fn estimated_remaining(&self) -> usize {
+        self.block_size.saturating_sub(self.estimated_size())
+    }
+
+    /// Whether `add` would accept the key-value pair, without adding it. This lets the caller
+    /// decide where to split blocks up front instead of retrying a rejected entry.
+    pub fn would_fit(&self, key: KeySlice, value: &[u8]) -> bool {
+        if key.is_empty() {
+            return false;
+        }
+        let overlap = compute_overlap(self.first_key.as_key_slice(), key).min(u16::MAX as usize);
+        let suffix_len = key.key_len() - overlap;
+        if self.exceeds_hard_limit(suffix_len, value) {
+            return false;
+        }
+        if self.needs_dictionary_fallback(value) {
+            // `add` falls back to the row-wise layout first
+            return self.fits_inline(suffix_len, value)
+                && self.offsets.len() < ENTRY_COUNT_MASK as usize;
+        }
+        !self.is_full_for(suffix_len, value)
+    }
+
+    /// Adds a key-value pair to the block. Returns false when the block is full, the key is
+    /// empty or the entry exceeds the hard limit of the policy, use `try_add` to tell them apart.
     #[must_use]
     pub fn add(&mut self, key: KeySlice, value: &[u8]) -> bool {
-        assert!(!key.is_empty(), "key must not be empty");
-        if self.estimated_size() + key.raw_len() + value.len() + SIZEOF_U16 * 3 /* key_len, value_len and offset */ > self.block_size
-            && !self.is_empty()
+        self.try_add(key, value) == AddResult::Added
+    }
+
+    /// Adds a key-value pair to the block, reporting why the entry is not added.
+    #[must_use]
+    pub fn try_add(&mut self, key: KeySlice, value: &[u8]) -> AddResult {
+        if key.is_empty() {
+            return AddResult::EmptyKeyRejected;
+        }
+        // the overlap is stored as `u16`, a shorter overlap than the real one is still valid
+        let overlap = compute_overlap(self.first_key.as_key_slice(), key).min(u16::MAX as usize);
+        let suffix_len = key.key_len() - overlap;
+        if self.exceeds_hard_limit(suffix_len, value) {
+            return AddResult::ExceedsHardLimit;
+        }
+        if !self.fall_back_if_dictionary_full(suffix_len, value)
+            || self.is_full_for(suffix_len, value)
         {
-            return false;
+            return AddResult::Full;
+        }
+        self.put_entry(overlap, &key.key_ref()[overlap..], key.ts(), value);
+
+        if self.first_key.is_empty() {
+            self.first_key = key.to_key_vec();
         }
+
+        AddResult::Added
+    }
+
+    /// Appends an entry whose key overlap with the first key of the block is computed by the
+    /// caller, skipping `compute_overlap`. This is a fast path to copy trusted sorted input, e.g.
+    /// entries decoded from another block. Reports why the entry is not added as `try_add` does.
+    #[must_use]
+    pub fn add_raw(&mut self, overlap: u16, key_suffix: &[u8], ts: u64, value: &[u8]) -> AddResult {
+        let overlap = overlap as usize;
+        if overlap + key_suffix.len() == 0 {
+            return AddResult::EmptyKeyRejected;
+        }
+        if self.exceeds_hard_limit(key_suffix.len(), value) {
+            return AddResult::ExceedsHardLimit;
+        }
+        if !self.fall_back_if_dictionary_full(key_suffix.len(), value)
+            || self.is_full_for(key_suffix.len(), value)
+        {
+            return AddResult::Full;
+        }
+        debug_assert!(
+            overlap <= self.first_key.key_len(),
+            "overlap is longer than the first key"
+        );
+        if cfg!(debug_assertions) && !self.first_key.is_empty() {
+            let mut key = self.first_key.key_ref()[..overlap].to_vec();
+            key.extend_from_slice(key_suffix);
+            debug_assert_eq!(
+                compute_overlap(
+                    self.first_key.as_key_slice(),
+                    KeySlice::from_slice(&key, ts)
+                ),
+                overlap,
+                "overlap does not match the first key"
+            );
+        }
+        self.put_entry(overlap, key_suffix, ts, value);
+
+        if self.first_key.is_empty() {
+            self.first_key = KeyVec::from_vec_with_ts(key_suffix.to_vec(), ts);
+        }
+
+        AddResult::Added
+    }
+
+    fn put_entry(&mut self, overlap: usize, key_suffix: &[u8], ts: u64, value: &[u8]) {
+        let entry_begin = self.data.len();
         // Add the offset of the data into the offset array. 以 u16 类型的形式压入
         self.offsets.push(self.data.len() as u16);
-        let overlap = compute_overlap(self.first_key.as_key_slice(), key);
         // Encode key overlap.
         self.data.put_u16(overlap as u16);
         // Encode key length.
-        self.data.put_u16((key.key_len() - overlap) as u16);
+        put_varint(&mut self.data, key_suffix.len());
         // Encode key content.
-        self.data.put(&key.key_ref()[overlap..]);
+        self.data.put(key_suffix);
         // Encode key ts
-        self.data.put_u64(key.ts());
-        // Encode value length.
-        self.data.put_u16(value.len() as u16);
-        // Encode value content.
-        self.data.put(value);
-
-        if self.first_key.is_empty() {
-            self.first_key = key.to_key_vec();
+        self.data.put_u64(ts);
+        self.inline_data_len += self.data.len() - entry_begin + SIZEOF_U16 + value.len();
+        match self.layout {
+            BlockLayout::RowWise => {
+                // Encode value length.
+                self.data.put_u16(value.len() as u16);
+                // Encode value content.
+                self.data.put(value);
+            }
+            BlockLayout::PrefixCompressedValues => {
+                // Encode the value as its overlap with the previous value and the suffix.
+                let overlap = value_prefix::common_prefix_len(&self.prev_value, value);
+                self.data.put_u16(overlap as u16);
+                self.data.put_u16((value.len() - overlap) as u16);
+                self.data.put(&value[overlap..]);
+                self.prev_value.clear();
+                self.prev_value.extend_from_slice(value);
+            }
+            BlockLayout::Columnar => {
+                // values are stored separately, their length comes from the adjacent value offsets
+                self.value_offsets.push(self.values.len() as u16);
+                self.values.put(value);
+            }
+            BlockLayout::Dictionary => {
+                let value_idx = match self.dictionary.get(value) {
+                    Some(value_idx) => *value_idx,
+                    None => {
+                        let value_idx = self.value_offsets.len();
+                        self.value_offsets.push(self.values.len() as u16);
+                        self.values.put(value);
+                        self.dictionary.insert(value.to_vec(), value_idx);
+                        value_idx
+                    }
+                };
+                put_varint(&mut self.data, value_idx);
+            }
         }
-
-        true
+        self.hasher.update(&self.data[entry_begin..]);
     }
 
     /// Check if there is no key-value pair in the block.
@@ -86,14 +499,48 @@ impl BlockBuilder {
         self.offsets.is_empty()
     }
 
+    /// Finalize the block through `finalize`, which receives the encoded entries and their offsets
+    /// and produces the block, e.g. after encrypting the entries or recording the block in an
+    /// external index. The checksum and the extension are not passed to `finalize`, as the entries
+    /// may no longer match the checksum, see `Block::from_raw_parts`. Panics for the layouts other
+    /// than row-wise, as `Block::from_raw_parts` only creates row-wise blocks.
+    pub fn build_with(self, finalize: impl FnOnce(Vec<u8>, Vec<u16>) -> Block) -> Block {
+        if self.is_empty() {
+            panic!("block should not be empty");
+        }
+        assert!(
+            self.layout == BlockLayout::RowWise,
+            "build_with only supports the row-wise layout"
+        );
+        finalize(self.data, self.offsets)
+    }
+
     /// Finalize the block.
     pub fn build(self) -> Block {
         if self.is_empty() {
             panic!("block should not be empty");
         }
+        let mut data = self.data;
+        assert!(
+            self.value_offsets.is_empty() || data.len() + self.values.len() <= u16::MAX as usize,
+            "block too large for u16 value offsets"
+        );
+        let values_begin = data.len() as u16;
+        let mut hasher = self.hasher;
+        // in the columnar layout the values follow the keys, keep hashing to cover the whole data
+        hasher.update(&self.values);
+        data.extend(self.values);
         Block {
-            data: self.data,
+            data: data.into(),
             offsets: self.offsets,
+            layout: self.layout,
+            value_offsets: self
+                .value_offsets
+                .iter()
+                .map(|offset| values_begin + offset)
+                .collect(),
+            extension: self.extension,
+            checksum: Some(hasher.digest()),
         }
     }
 }
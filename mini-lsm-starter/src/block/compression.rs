@@ -0,0 +1,214 @@
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut};
+
+use super::Block;
+
+const MIN_MATCH: usize = 4;
+/// The last bytes of the input are always literals.
+const LAST_LITERALS: usize = 5;
+/// The last match must start at least this many bytes before the end of the input.
+const MF_LIMIT: usize = 12;
+const HASH_LOG: u32 = 12;
+/// `codec (u8) | uncompressed_len (u32) | payload_len (u32)`
+const WIRE_HEADER_LEN: usize = 1 + 4 + 4;
+
+/// How a block is compressed by `Block::to_wire`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockCompression {
+    /// Stored as is.
+    None,
+    /// The LZ4 block format, cheap to compress and decompress.
+    Lz4,
+}
+
+impl BlockCompression {
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Lz4 => 1,
+        }
+    }
+
+    fn from_u8(codec: u8) -> Option<Self> {
+        match codec {
+            0 => Some(Self::None),
+            1 => Some(Self::Lz4),
+            _ => None,
+        }
+    }
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+fn hash(seq: u32) -> usize {
+    (seq.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+/// Write the part of a length that does not fit in the 4 bits of the token.
+fn put_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn get_length(input: &mut &[u8]) -> Option<usize> {
+    let mut len = 0;
+    loop {
+        let byte = *input.first()?;
+        input.advance(1);
+        len += byte as usize;
+        if byte != 255 {
+            return Some(len);
+        }
+    }
+}
+
+/// Write a sequence of `literals` followed by a match of `match_len` bytes at `offset` bytes back,
+/// the last sequence has no match.
+fn put_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(u16, usize)>) {
+    let match_len = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push(((literals.len().min(15) << 4) | match_len.min(15)) as u8);
+    if literals.len() >= 15 {
+        put_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        out.extend_from_slice(&offset.to_le_bytes());
+        if match_len >= 15 {
+            put_length(out, match_len - 15);
+        }
+    }
+}
+
+pub(crate) fn lz4_compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 16);
+    // 1 + the last position this 4-byte sequence was seen at, 0 means never
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+    while pos + MF_LIMIT < input.len() {
+        let seq = read_u32(input, pos);
+        let slot = &mut table[hash(seq)];
+        let candidate = slot.checked_sub(1);
+        *slot = pos + 1;
+        let Some(candidate) =
+            candidate.filter(|c| pos - c <= u16::MAX as usize && read_u32(input, *c) == seq)
+        else {
+            pos += 1;
+            continue;
+        };
+        let max_len = input.len() - LAST_LITERALS - pos;
+        let mut len = MIN_MATCH;
+        while len < max_len && input[candidate + len] == input[pos + len] {
+            len += 1;
+        }
+        put_sequence(
+            &mut out,
+            &input[anchor..pos],
+            Some(((pos - candidate) as u16, len)),
+        );
+        pos += len;
+        anchor = pos;
+    }
+    put_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+/// Decompress `input` into exactly `uncompressed_len` bytes, returns `None` if `input` is
+/// malformed.
+pub(crate) fn lz4_decompress(mut input: &[u8], uncompressed_len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(uncompressed_len);
+    loop {
+        let token = *input.first()?;
+        input.advance(1);
+        let mut literal_len = (token >> 4) as usize;
+        if literal_len == 15 {
+            literal_len += get_length(&mut input)?;
+        }
+        if input.len() < literal_len || out.len() + literal_len > uncompressed_len {
+            return None;
+        }
+        out.extend_from_slice(&input[..literal_len]);
+        input.advance(literal_len);
+        if input.is_empty() {
+            break;
+        }
+        if input.len() < 2 {
+            return None;
+        }
+        let offset = input.get_u16_le() as usize;
+        let mut match_len = (token & 0xf) as usize;
+        if match_len == 15 {
+            match_len += get_length(&mut input)?;
+        }
+        match_len += MIN_MATCH;
+        if offset == 0 || offset > out.len() || out.len() + match_len > uncompressed_len {
+            return None;
+        }
+        // the match may overlap itself, so copy byte by byte
+        let start = out.len() - offset;
+        for idx in start..start + match_len {
+            out.push(out[idx]);
+        }
+    }
+    (out.len() == uncompressed_len).then_some(out)
+}
+
+impl Block {
+    /// Serialize the block for network transfer as
+    /// `codec (u8) | uncompressed_len (u32) | payload_len (u32) | payload`, where the payload is
+    /// the encoded block compressed with `codec`. The framing is independent of the on-disk format
+    /// of the SST, so that the two can evolve separately.
+    pub fn to_wire(&self, codec: BlockCompression) -> Vec<u8> {
+        let encoded = self.encode();
+        let payload = match codec {
+            BlockCompression::None => encoded.to_vec(),
+            BlockCompression::Lz4 => lz4_compress(&encoded),
+        };
+        let mut buf = Vec::with_capacity(WIRE_HEADER_LEN + payload.len());
+        buf.put_u8(codec.to_u8());
+        buf.put_u32(encoded.len() as u32);
+        buf.put_u32(payload.len() as u32);
+        buf.put(&payload[..]);
+        buf
+    }
+
+    /// Deserialize a block from `to_wire`, the block is checked with `Block::decode_checked`.
+    pub fn from_wire(mut data: &[u8]) -> Result<Self> {
+        if data.len() < WIRE_HEADER_LEN {
+            bail!("wire block of {} bytes is too short", data.len());
+        }
+        let raw_codec = data.get_u8();
+        let Some(codec) = BlockCompression::from_u8(raw_codec) else {
+            bail!("unknown block codec {}", raw_codec);
+        };
+        let uncompressed_len = data.get_u32() as usize;
+        let payload_len = data.get_u32() as usize;
+        if data.len() != payload_len {
+            bail!(
+                "wire block payload is {} bytes, expected {}",
+                data.len(),
+                payload_len
+            );
+        }
+        let encoded = match codec {
+            BlockCompression::None => data.to_vec(),
+            BlockCompression::Lz4 => match lz4_decompress(data, uncompressed_len) {
+                Some(encoded) => encoded,
+                None => bail!("malformed lz4 block"),
+            },
+        };
+        if encoded.len() != uncompressed_len {
+            bail!(
+                "wire block is {} bytes, expected {}",
+                encoded.len(),
+                uncompressed_len
+            );
+        }
+        Ok(Self::decode_checked(&encoded)?)
+    }
+}
@@ -1,17 +1,28 @@
 #![allow(unused_variables)] // TODO(you): remove this lint after implementing this mod
 #![allow(dead_code)] // TODO(you): remove this lint after implementing this mod
 
+use std::cell::OnceCell;
 use std::sync::Arc;
 
 use bytes::Buf;
 
 use crate::{
-    block::SIZEOF_U16,
+    block::{try_get_varint, BlockLayout, SIZEOF_U16},
     key::{KeySlice, KeyVec},
 };
 
 use super::Block;
 
+/// How `BlockIterator::seek_to_key_with` looks for the target key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekStrategy {
+    /// Binary search over the whole block.
+    Binary,
+    /// Search from the current position with doubling steps, then binary search the narrowed
+    /// window. Faster when the target is close to the cursor, e.g. for sequential-ish access.
+    ExponentialFromCurrent,
+}
+
 /// Iterates on a block.
 pub struct BlockIterator {
     /// The internal `Block`, wrapped by an `Arc`
@@ -25,32 +36,184 @@ pub struct BlockIterator {
     idx: usize,
     /// The first key in the block
     first_key: KeyVec,
+    /// The value of the `value_idx`-th entry for the prefix-compressed values layout, rebuilt
+    /// from the previous values when it is first read, or from the previous entry when moving
+    /// to the next one.
+    value: OnceCell<Vec<u8>>,
+    value_idx: usize,
 }
 
 impl Block {
-    fn get_first_key(&self) -> KeyVec {
-        let mut buf = &self.data[..];
-        //buf.get_u16() 是跳过了 overlap 字段，因为第一个键的 overlap 始终为 0
-        //它会修改 buf 的指针，推进切片的起始位置。这个操作不会改变 buf 的生命周期，只是改变了 buf 的视图
-        buf.get_u16();
-        // let key_len = buf.get_u16();
-        // let key = &buf[..key_len as usize];
-        // KeyVec::from_vec(key.to_vec())
-        let key_len = buf.get_u16() as usize;
-        let key = &buf[..key_len];
+    pub(crate) fn get_first_key(&self) -> KeyVec {
+        self.try_get_first_key().expect("malformed first key")
+    }
+
+    /// Like `get_first_key`, returns `None` if the first entry is malformed.
+    fn try_get_first_key(&self) -> Option<KeyVec> {
+        // skip the overlap field, the first key always has an overlap of 0
+        let mut buf = self.data.get(SIZEOF_U16..)?;
+        let key_len = try_get_varint(&mut buf)?;
+        let key = buf.get(..key_len)?;
         buf.advance(key_len);
-        KeyVec::from_vec_with_ts(key.to_vec(), buf.get_u64())
+        if buf.remaining() < std::mem::size_of::<u64>() {
+            return None;
+        }
+        Some(KeyVec::from_vec_with_ts(key.to_vec(), buf.get_u64()))
+    }
+
+    /// Decode the key of the `idx`-th entry into `key`, returning the value range in `data`.
+    /// `first_key` is needed to rebuild the prefix-compressed key. For the columnar and dictionary
+    /// layouts, the value bytes are not touched. For prefix-compressed values, the range is the
+    /// value suffix, see `try_expand_value` for the whole value.
+    pub(crate) fn decode_entry(
+        &self,
+        first_key: &[u8],
+        idx: usize,
+        key: &mut KeyVec,
+    ) -> (usize, usize) {
+        self.try_decode_entry(first_key, idx, key)
+            .expect("malformed block entry")
+    }
+
+    /// Like `decode_entry`, but checks every length against the block and returns `None` if the
+    /// entry is malformed, e.g. its overlap is longer than `first_key`. `key` is then unspecified.
+    pub(crate) fn try_decode_entry(
+        &self,
+        first_key: &[u8],
+        idx: usize,
+        key: &mut KeyVec,
+    ) -> Option<(usize, usize)> {
+        let offset = *self.offsets.get(idx)? as usize;
+        let mut entry = self.data.get(offset..)?;
+        if entry.remaining() < SIZEOF_U16 {
+            return None;
+        }
+        // Since `get_u16()` will automatically move the ptr 2 bytes ahead here,
+        // we don't need to manually advance it
+        let overlap_len = entry.get_u16() as usize;
+        // the key suffix length is a varint so that keys may be longer than `u16::MAX`
+        let key_len = try_get_varint(&mut entry)?;
+        key.clear();
+        key.append(first_key.get(..overlap_len)?);
+        key.append(entry.get(..key_len)?);
+        entry.advance(key_len);
+        if entry.remaining() < std::mem::size_of::<u64>() {
+            return None;
+        }
+        let ts = entry.get_u64();
+        key.set_ts(ts);
+        let value_idx = match self.layout {
+            BlockLayout::RowWise | BlockLayout::PrefixCompressedValues => None,
+            BlockLayout::Columnar => Some(idx),
+            BlockLayout::Dictionary => Some(try_get_varint(&mut entry)?),
+        };
+        let (value_offset_begin, value_offset_end) = match value_idx {
+            Some(value_idx) => {
+                let value_offset_begin = *self.value_offsets.get(value_idx)? as usize;
+                let value_offset_end = self
+                    .value_offsets
+                    .get(value_idx + 1)
+                    .map_or(self.data.len(), |x| *x as usize);
+                (value_offset_begin, value_offset_end)
+            }
+            None => {
+                if self.layout == BlockLayout::PrefixCompressedValues {
+                    if entry.remaining() < SIZEOF_U16 {
+                        return None;
+                    }
+                    // the value overlap is only needed to expand the value
+                    entry.advance(SIZEOF_U16);
+                }
+                if entry.remaining() < SIZEOF_U16 {
+                    return None;
+                }
+                let value_len = entry.get_u16() as usize;
+                // the value follows the value length, which is where `entry` stops
+                let value_offset_begin = self.data.len() - entry.len();
+                (value_offset_begin, value_offset_begin + value_len)
+            }
+        };
+        if value_offset_begin > value_offset_end || value_offset_end > self.data.len() {
+            return None;
+        }
+        Some((value_offset_begin, value_offset_end))
+    }
+
+    /// Returns the value of a handle from `BlockIterator::current_value_handle`. For
+    /// prefix-compressed values, this is only the suffix of the value.
+    pub fn value_at(&self, handle: (usize, usize)) -> &[u8] {
+        &self.data[handle.0..handle.1]
+    }
+
+    /// Returns the value of the newest version of `user_key` visible at `read_ts`, or `None` if
+    /// there is no such version in this block or it is a tombstone.
+    pub fn get(&self, user_key: &[u8], read_ts: u64) -> Option<Vec<u8>> {
+        if self.offsets.is_empty() {
+            return None;
+        }
+        let first_key = self.get_first_key();
+        let target = KeySlice::from_slice(user_key, read_ts);
+        let mut key = KeyVec::new();
+        // binary search for the first entry >= (user_key, read_ts)
+        let (mut low, mut high) = (0, self.offsets.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            self.decode_entry(first_key.key_ref(), mid, &mut key);
+            if key.as_key_slice() < target {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        if low == self.offsets.len() {
+            return None;
+        }
+        let (begin, end) = self.decode_entry(first_key.key_ref(), low, &mut key);
+        if key.key_ref() != user_key {
+            return None;
+        }
+        if self.layout == BlockLayout::PrefixCompressedValues {
+            let mut value = Vec::new();
+            self.try_expand_value(low, &mut value)
+                .expect("malformed prefix-compressed values");
+            return (!value.is_empty()).then_some(value);
+        }
+        (begin != end).then(|| self.data[begin..end].to_vec())
+    }
+
+    /// Decode every entry of the block in order, calling `f` with the entry index, the full key
+    /// and the value.
+    pub(crate) fn for_each_entry(&self, mut f: impl FnMut(usize, &KeyVec, &[u8])) {
+        if self.offsets.is_empty() {
+            return;
+        }
+        let first_key = self.get_first_key();
+        let mut key = KeyVec::new();
+        let mut value = Vec::new();
+        for idx in 0..self.offsets.len() {
+            let (begin, end) = self.decode_entry(first_key.key_ref(), idx, &mut key);
+            if self.layout == BlockLayout::PrefixCompressedValues {
+                self.try_advance_value(idx, &mut value)
+                    .expect("malformed prefix-compressed values");
+                f(idx, &key, &value);
+            } else {
+                f(idx, &key, &self.data[begin..end]);
+            }
+        }
     }
 }
 
 impl BlockIterator {
     fn new(block: Arc<Block>) -> Self {
         Self {
-            first_key: block.get_first_key(),
+            // if the first entry is corrupted, every entry with an overlap is treated as invalid
+            first_key: block.try_get_first_key().unwrap_or_default(),
             block,
             key: KeyVec::new(),
             value_range: (0, 0),
             idx: 0,
+            value: OnceCell::new(),
+            value_idx: 0,
         }
     }
 
@@ -70,6 +233,14 @@ impl BlockIterator {
         iter
     }
 
+    /// Creates a block iterator and seek to the last entry, to scan the block in descending order
+    /// with `prev`.
+    pub fn create_and_seek_to_last(block: Arc<Block>) -> Self {
+        let mut iter = Self::new(block);
+        iter.seek_to_last();
+        iter
+    }
+
     /// Returns the key of the current entry.
     pub fn key(&self) -> KeySlice {
         debug_assert!(!self.key.is_empty(), "invalid iterator");
@@ -79,9 +250,34 @@ impl BlockIterator {
     /// Returns the value of the current entry.
     pub fn value(&self) -> &[u8] {
         debug_assert!(!self.key.is_empty(), "invalid iterator");
+        if self.block.layout == BlockLayout::PrefixCompressedValues {
+            return self.value.get_or_init(|| self.expand_value(self.value_idx));
+        }
         &self.block.data[self.value_range.0..self.value_range.1]
     }
 
+    /// Rebuild the value of the `idx`-th entry from the first entry on.
+    fn expand_value(&self, idx: usize) -> Vec<u8> {
+        let mut value = Vec::new();
+        self.block
+            .try_expand_value(idx, &mut value)
+            .expect("malformed prefix-compressed values");
+        value
+    }
+
+    /// Returns the range of the current value in the block data, so that reading the value can be
+    /// deferred until the key is accepted. Resolve it with `Block::value_at`. For
+    /// prefix-compressed values, the range only covers the suffix of the value, use `value`.
+    pub fn current_value_handle(&self) -> (usize, usize) {
+        debug_assert!(!self.key.is_empty(), "invalid iterator");
+        self.value_range
+    }
+
+    /// Returns the number of entries in the underlying block.
+    pub fn num_entries(&self) -> usize {
+        self.block.len()
+    }
+
     /// Returns true if the iterator is valid.
     pub fn is_valid(&self) -> bool {
         !self.key.is_empty()
@@ -92,16 +288,67 @@ impl BlockIterator {
         self.seek_to(0);
     }
 
-    /// Seeks to the idx-th key in the block.
-    fn seek_to(&mut self, idx: usize) {
-        if idx >= self.block.offsets.len() {
+    /// Seeks to the last key in the block.
+    pub fn seek_to_last(&mut self) {
+        self.seek_to(self.block.offsets.len().saturating_sub(1));
+    }
+
+    /// Move to the previous key in the block, the iterator becomes invalid before the first key.
+    pub fn prev(&mut self) {
+        if self.idx == 0 {
             self.key.clear();
             self.value_range = (0, 0);
             return;
         }
-        let offset = self.block.offsets[idx] as usize;
-        self.seek_to_offset(offset);
-        self.idx = idx;
+        self.idx -= 1;
+        self.seek_to(self.idx);
+    }
+
+    /// Seeks to the `n`-th entry of the block in O(1) through the offsets, e.g. to sample entries
+    /// for a sparse index. The iterator becomes invalid if `n` is out of range.
+    pub fn seek_to_nth(&mut self, n: usize) {
+        self.seek_to(n);
+    }
+
+    /// Seeks to the idx-th key in the block. The iterator becomes invalid if the entry is
+    /// malformed, instead of panicking on a corrupted block.
+    fn seek_to(&mut self, idx: usize) {
+        match self
+            .block
+            .try_decode_entry(self.first_key.key_ref(), idx, &mut self.key)
+        {
+            Some(value_range) => {
+                self.value_range = value_range;
+                self.idx = idx;
+                if self.block.layout == BlockLayout::PrefixCompressedValues {
+                    self.seek_value(idx);
+                }
+            }
+            None => {
+                self.key.clear();
+                self.value_range = (0, 0);
+            }
+        }
+    }
+
+    /// Keep the value of the prefix-compressed values layout for the `idx`-th entry. Moving to the
+    /// next entry only applies its suffix, so that a scan never rebuilds a value from the first
+    /// entry more than once. Other moves rebuild the value when it is read, as the binary search
+    /// seeks to entries whose value is never read.
+    fn seek_value(&mut self, idx: usize) {
+        let value = match self.value.take() {
+            value if idx == self.value_idx => value,
+            Some(mut value) if idx == self.value_idx + 1 => {
+                self.block.try_advance_value(idx, &mut value).map(|_| value)
+            }
+            None if idx == self.value_idx + 1 => {
+                let mut value = Vec::new();
+                self.block.try_expand_value(idx, &mut value).map(|_| value)
+            }
+            _ => None,
+        };
+        self.value = value.map_or_else(OnceCell::new, OnceCell::from);
+        self.value_idx = idx;
     }
 
     /// Move to the next key in the block.
@@ -110,43 +357,89 @@ impl BlockIterator {
         self.seek_to(self.idx);
     }
 
-    /// Seek to the specified position and update the current `key` and `value`
-    /// Index update will be handled by caller
-    /// 通过给定的偏移量 offset 来定位到 Block 中的某个数据条目，并更新当前对象中的 key 和 value
-    fn seek_to_offset(&mut self, offset: usize) {
-        let mut entry = &self.block.data[offset..];
-        // Since `get_u16()` will automatically move the ptr 2 bytes ahead here,
-        // we don't need to manually advance it
-        let overlap_len = entry.get_u16() as usize;
-        let key_len = entry.get_u16() as usize;
-        let key = &entry[..key_len];
-        self.key.clear();
-        self.key.append(&self.first_key.key_ref()[..overlap_len]);
-        self.key.append(key);
-        entry.advance(key_len);
-        let ts = entry.get_u64();
-        self.key.set_ts(ts);
-        let value_len = entry.get_u16() as usize;
-        // REMEMBER TO CHANGE THIS every time you change the encoding!
-        let value_offset_begin =
-            offset + SIZEOF_U16 + SIZEOF_U16 + std::mem::size_of::<u64>() + key_len + SIZEOF_U16;
-        // offset + overlap + remaining_key_len + ts + key_context_len + value_len
-        let value_offset_end = value_offset_begin + value_len;
-        self.value_range = (value_offset_begin, value_offset_end);
-        entry.advance(value_len);
-    }
-
     /// Seek to the first key that >= `key`.
     /// Note: You should assume the key-value pairs in the block are sorted when being added by
     /// callers.
     /// 进行二分搜索并返回第一个大于目标键的索引来实现的
     pub fn seek_to_key(&mut self, key: KeySlice) {
-        let mut low = 0;
-        let mut high = self.block.offsets.len();
+        self.seek_within(0, self.block.offsets.len(), key);
+    }
+
+    /// Seek to the first entry whose user key >= `user_key`, ignoring the ts. As the versions of a
+    /// key are ordered by ts descending, this lands on the newest version of `user_key` if it is
+    /// in the block, the caller decides which version to read.
+    pub fn seek_to_user_key(&mut self, user_key: &[u8]) {
+        let (mut low, mut high) = (0, self.block.offsets.len());
         while low < high {
             let mid = low + (high - low) / 2;
             self.seek_to(mid);
-            assert!(self.is_valid());
+            if !self.is_valid() {
+                return;
+            }
+            // keep going left on equality to land on the first version of the key
+            if self.key().key_ref() < user_key {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        self.seek_to(low);
+    }
+
+    /// Seek to the first key that >= `key` with the given strategy. All strategies land on the
+    /// same entry.
+    pub fn seek_to_key_with(&mut self, key: KeySlice, strategy: SeekStrategy) {
+        let len = self.block.offsets.len();
+        if strategy == SeekStrategy::Binary || !self.is_valid() {
+            self.seek_within(0, len, key);
+            return;
+        }
+        let idx = self.idx;
+        let mut step = 1;
+        if self.key() < key {
+            // gallop forward until past the target key
+            while idx + step < len {
+                self.seek_to(idx + step);
+                if !self.is_valid() || self.key() >= key {
+                    break;
+                }
+                step *= 2;
+            }
+            self.seek_within(idx + step / 2 + 1, (idx + step).min(len), key);
+        } else {
+            // gallop backward until a position below the target key
+            while step <= idx {
+                self.seek_to(idx - step);
+                if !self.is_valid() || self.key() < key {
+                    break;
+                }
+                step *= 2;
+            }
+            let low = if step <= idx { idx - step + 1 } else { 0 };
+            self.seek_within(low, idx - step / 2, key);
+        }
+    }
+
+    /// Seek to the last key that <= `key`, the iterator becomes invalid if every key is larger.
+    pub fn seek_to_key_le(&mut self, key: KeySlice) {
+        self.seek_to_key(key);
+        if !self.is_valid() {
+            // every key is below the target key
+            self.seek_to_last();
+        } else if self.key() > key {
+            self.prev();
+        }
+    }
+
+    /// Binary search the first key that >= `key` in `[low, high]`, the caller guarantees the
+    /// target index is in this range (`high` may be the number of entries).
+    fn seek_within(&mut self, mut low: usize, mut high: usize, key: KeySlice) {
+        while low < high {
+            let mid = low + (high - low) / 2;
+            self.seek_to(mid);
+            if !self.is_valid() {
+                return;
+            }
             match self.key().cmp(&key) {
                 std::cmp::Ordering::Less => low = mid + 1,
                 std::cmp::Ordering::Greater => high = mid,
@@ -0,0 +1,217 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use anyhow::{bail, Result};
+
+use super::{
+    put_varint, BlockLayout, XxHash64, CHECKSUM_FLAG, ENTRY_COUNT_MASK, EXTENSION_FLAG, SIZEOF_U16,
+};
+use crate::iterators::StorageIterator;
+use crate::key::{KeySlice, KeyVec};
+
+/// Reads the entries of an encoded block one at a time from a `Read` source, so that only the
+/// current entry is held in memory instead of the whole block. Only the row-wise and the
+/// prefix-compressed value layouts are supported, as the other layouts store the values apart
+/// from the keys. The checksum, if any, is verified once the last entry is read.
+pub struct StreamingBlockReader<R> {
+    /// The data section of the block, the offsets are not needed to read the entries in order.
+    reader: std::io::Take<R>,
+    layout: BlockLayout,
+    /// Number of entries declared by the block that are not read yet.
+    entries_left: usize,
+    first_key: Vec<u8>,
+    key: KeyVec,
+    value: Vec<u8>,
+    /// The expected checksum, and the hash of the entries read so far.
+    checksum: Option<(u64, XxHash64)>,
+}
+
+impl<R: Read + Seek> StreamingBlockReader<R> {
+    /// Create a reader over the block of `block_len` bytes starting at the current position of
+    /// `reader`, and read the first entry. The trailer at the end of the block is read first to
+    /// find where the data section ends.
+    pub fn create_and_seek_to_first(mut reader: R, block_len: usize) -> Result<Self> {
+        let block_begin = reader.stream_position()?;
+        if block_len < SIZEOF_U16 {
+            bail!("block of {} bytes is too short", block_len);
+        }
+        let mut trailer_end = block_len - SIZEOF_U16;
+        reader.seek(SeekFrom::Start(block_begin + trailer_end as u64))?;
+        let raw_offsets_len = read_u16(&mut reader)?;
+        let layout = BlockLayout::from_flags(raw_offsets_len);
+        if !matches!(
+            layout,
+            BlockLayout::RowWise | BlockLayout::PrefixCompressedValues
+        ) {
+            bail!("{:?} blocks cannot be streamed", layout);
+        }
+        let mut checksum = None;
+        if raw_offsets_len & CHECKSUM_FLAG != 0 {
+            let Some(end) = trailer_end.checked_sub(std::mem::size_of::<u64>()) else {
+                bail!("block of {} bytes is too short", block_len);
+            };
+            trailer_end = end;
+            reader.seek(SeekFrom::Start(block_begin + trailer_end as u64))?;
+            checksum = Some((read_u64(&mut reader)?, XxHash64::new()));
+        }
+        if raw_offsets_len & EXTENSION_FLAG != 0 {
+            if trailer_end < SIZEOF_U16 {
+                bail!("block of {} bytes is too short", block_len);
+            }
+            trailer_end -= SIZEOF_U16;
+            reader.seek(SeekFrom::Start(block_begin + trailer_end as u64))?;
+            let extension_len = read_u16(&mut reader)? as usize;
+            let Some(end) = trailer_end.checked_sub(extension_len) else {
+                bail!("extension of {} bytes overruns the block", extension_len);
+            };
+            trailer_end = end;
+        }
+        let entries = (raw_offsets_len & ENTRY_COUNT_MASK) as usize;
+        let Some(data_len) = trailer_end.checked_sub(entries * SIZEOF_U16) else {
+            bail!("block declares {} entries but is too short", entries);
+        };
+        reader.seek(SeekFrom::Start(block_begin))?;
+        let mut iter = Self {
+            reader: reader.take(data_len as u64),
+            layout,
+            entries_left: entries,
+            first_key: Vec::new(),
+            key: KeyVec::new(),
+            value: Vec::new(),
+            checksum,
+        };
+        iter.read_entry()?;
+        Ok(iter)
+    }
+}
+
+impl<R: Read> StreamingBlockReader<R> {
+    /// Read the next entry into `key` and `value`, or clear `key` at the end of the block.
+    fn read_entry(&mut self) -> Result<()> {
+        if self.entries_left == 0 {
+            if self.reader.limit() != 0 {
+                bail!("{} bytes left after the last entry", self.reader.limit());
+            }
+            self.key.clear();
+            self.value.clear();
+            if let Some((expected, hasher)) = &self.checksum {
+                let actual = hasher.digest();
+                if actual != *expected {
+                    bail!(
+                        "block checksum mismatched, expected {:#x}, got {:#x}",
+                        expected,
+                        actual
+                    );
+                }
+            }
+            return Ok(());
+        }
+        self.entries_left -= 1;
+        let overlap = read_u16(&mut self.reader)? as usize;
+        let key_len = read_varint(&mut self.reader)?;
+        if overlap > self.first_key.len() {
+            bail!("overlap {} is longer than the first key", overlap);
+        }
+        if key_len > self.reader.limit() as usize {
+            bail!("key of {} bytes overruns the block", key_len);
+        }
+        // rebuild the full key from the prefix of the first key
+        let mut key = Vec::with_capacity(overlap + key_len);
+        key.extend_from_slice(&self.first_key[..overlap]);
+        key.resize(overlap + key_len, 0);
+        self.reader.read_exact(&mut key[overlap..])?;
+        let ts = read_u64(&mut self.reader)?;
+        if let Some((_, hasher)) = &mut self.checksum {
+            let mut key_part = Vec::with_capacity(SIZEOF_U16 + 10);
+            key_part.extend_from_slice(&(overlap as u16).to_be_bytes());
+            put_varint(&mut key_part, key_len);
+            hasher.update(&key_part);
+            hasher.update(&key[overlap..]);
+            hasher.update(&ts.to_be_bytes());
+        }
+        match self.layout {
+            BlockLayout::PrefixCompressedValues => {
+                let value_overlap = read_u16(&mut self.reader)? as usize;
+                let suffix_len = read_u16(&mut self.reader)? as usize;
+                if value_overlap > self.value.len() {
+                    bail!(
+                        "value overlap {} is longer than the previous value",
+                        value_overlap
+                    );
+                }
+                self.value.truncate(value_overlap);
+                self.value.resize(value_overlap + suffix_len, 0);
+                self.reader.read_exact(&mut self.value[value_overlap..])?;
+                if let Some((_, hasher)) = &mut self.checksum {
+                    hasher.update(&(value_overlap as u16).to_be_bytes());
+                    hasher.update(&(suffix_len as u16).to_be_bytes());
+                    hasher.update(&self.value[value_overlap..]);
+                }
+            }
+            _ => {
+                let value_len = read_u16(&mut self.reader)? as usize;
+                self.value.resize(value_len, 0);
+                self.reader.read_exact(&mut self.value)?;
+                if let Some((_, hasher)) = &mut self.checksum {
+                    hasher.update(&(value_len as u16).to_be_bytes());
+                    hasher.update(&self.value);
+                }
+            }
+        }
+        if self.first_key.is_empty() {
+            self.first_key = key.clone();
+        }
+        self.key = KeyVec::from_vec_with_ts(key, ts);
+        Ok(())
+    }
+}
+
+impl<R: Read> StorageIterator for StreamingBlockReader<R> {
+    type KeyType<'a>
+        = KeySlice<'a>
+    where
+        Self: 'a;
+
+    fn value(&self) -> &[u8] {
+        debug_assert!(self.is_valid(), "invalid iterator");
+        &self.value
+    }
+
+    fn key(&self) -> Self::KeyType<'_> {
+        debug_assert!(self.is_valid(), "invalid iterator");
+        self.key.as_key_slice()
+    }
+
+    fn is_valid(&self) -> bool {
+        !self.key.is_empty()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.read_entry()
+    }
+}
+
+fn read_u16(reader: &mut impl Read) -> Result<u16> {
+    let mut buf = [0; SIZEOF_U16];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut buf = [0; std::mem::size_of::<u64>()];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// Read a varint written by `put_varint` one byte at a time.
+fn read_varint(reader: &mut impl Read) -> Result<usize> {
+    let mut value = 0usize;
+    for shift in (0..usize::BITS).step_by(7) {
+        let mut byte = [0; 1];
+        reader.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("varint does not fit in usize")
+}
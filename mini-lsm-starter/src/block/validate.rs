@@ -0,0 +1,321 @@
+use std::fmt;
+use std::sync::Arc;
+
+use bytes::{Buf, BufMut, Bytes};
+
+use super::{
+    try_get_varint, Block, BlockLayout, XxHash64, CHECKSUM_FLAG, ENTRY_COUNT_MASK, EXTENSION_FLAG,
+    SIZEOF_U16,
+};
+
+/// Why a block fails `Block::validate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    /// The encoded block cannot even hold the number of entries.
+    TooShort { len: usize },
+    /// The extension trailer is longer than the block.
+    ExtensionOverrun { len: usize },
+    /// The number of entries in the block header needs more offsets than the block holds.
+    EntryCountMismatch { declared: usize, available: usize },
+    /// The block has no entry.
+    Empty,
+    /// The offset of an entry points outside of the data section.
+    OffsetOutOfBounds { idx: usize, offset: usize },
+    /// The offset of an entry is not larger than the offset of the previous one.
+    OffsetsNotIncreasing { idx: usize },
+    /// The key overlap of an entry is longer than the first key.
+    InvalidOverlap { idx: usize, overlap: usize },
+    /// An entry runs past the beginning of the next entry or the end of the data section.
+    EntryOverrun { idx: usize },
+    /// The value overlap of an entry is longer than the value of the previous entry.
+    InvalidValuePrefix { idx: usize, overlap: usize },
+    /// An entry refers to a value that is not in the dictionary.
+    InvalidValueIndex { idx: usize, value_idx: usize },
+    /// The data does not match the checksum of the block.
+    ChecksumMismatch { expected: u64, actual: u64 },
+    /// The encoded block does not match the crc32 appended by `Block::encode_with_checksum`.
+    Crc32Mismatch { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooShort { len } => write!(f, "block of {} bytes is too short", len),
+            Self::ExtensionOverrun { len } => {
+                write!(f, "extension of {} bytes overruns the block", len)
+            }
+            Self::EntryCountMismatch {
+                declared,
+                available,
+            } => write!(
+                f,
+                "block declares {} entries but only has room for {}",
+                declared, available
+            ),
+            Self::Empty => write!(f, "block has no entry"),
+            Self::OffsetOutOfBounds { idx, offset } => {
+                write!(f, "offset {} of entry {} is out of bounds", offset, idx)
+            }
+            Self::OffsetsNotIncreasing { idx } => {
+                write!(f, "offset of entry {} is not increasing", idx)
+            }
+            Self::InvalidOverlap { idx, overlap } => write!(
+                f,
+                "overlap {} of entry {} is longer than the first key",
+                overlap, idx
+            ),
+            Self::EntryOverrun { idx } => write!(f, "entry {} overruns its space", idx),
+            Self::InvalidValuePrefix { idx, overlap } => write!(
+                f,
+                "value overlap {} of entry {} is longer than the previous value",
+                overlap, idx
+            ),
+            Self::InvalidValueIndex { idx, value_idx } => write!(
+                f,
+                "entry {} refers to value {} which is not in the dictionary",
+                idx, value_idx
+            ),
+            Self::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "block checksum mismatched, expected {:#x}, got {:#x}",
+                expected, actual
+            ),
+            Self::Crc32Mismatch { expected, actual } => write!(
+                f,
+                "block crc32 mismatched, expected {:#x}, got {:#x}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+/// Check that `offsets` are increasing and within `[begin, end)`.
+fn validate_offsets(offsets: &[u16], begin: usize, end: usize) -> Result<(), BlockError> {
+    for (idx, offset) in offsets.iter().enumerate() {
+        let offset = *offset as usize;
+        if offset < begin || offset >= end {
+            return Err(BlockError::OffsetOutOfBounds { idx, offset });
+        }
+        if idx > 0 && offset <= offsets[idx - 1] as usize {
+            return Err(BlockError::OffsetsNotIncreasing { idx });
+        }
+    }
+    Ok(())
+}
+
+/// Size of the crc32 appended by `Block::encode_with_checksum`.
+const SIZEOF_CRC32: usize = std::mem::size_of::<u32>();
+
+/// Check the crc32 appended to an encoded block, returns the length of the encoded block.
+fn check_crc32(data: &[u8]) -> Result<usize, BlockError> {
+    let Some(block_len) = data.len().checked_sub(SIZEOF_CRC32) else {
+        return Err(BlockError::TooShort { len: data.len() });
+    };
+    let expected = (&data[block_len..]).get_u32();
+    let actual = crc32fast::hash(&data[..block_len]);
+    if expected != actual {
+        return Err(BlockError::Crc32Mismatch { expected, actual });
+    }
+    Ok(block_len)
+}
+
+/// Check that the trailer and the offsets declared by the header of an encoded block fit in it.
+fn check_header(data: &[u8]) -> Result<(), BlockError> {
+    if data.len() < SIZEOF_U16 {
+        return Err(BlockError::TooShort { len: data.len() });
+    }
+    let raw_offsets_len = (&data[data.len() - SIZEOF_U16..]).get_u16();
+    let layout = BlockLayout::from_flags(raw_offsets_len);
+    let num_offset_arrays = if layout == BlockLayout::Columnar {
+        2
+    } else {
+        1
+    };
+    let mut offsets_end = data.len() - SIZEOF_U16;
+    if raw_offsets_len & CHECKSUM_FLAG != 0 {
+        offsets_end = offsets_end
+            .checked_sub(std::mem::size_of::<u64>())
+            .ok_or(BlockError::TooShort { len: data.len() })?;
+    }
+    if raw_offsets_len & EXTENSION_FLAG != 0 {
+        if offsets_end < SIZEOF_U16 {
+            return Err(BlockError::TooShort { len: data.len() });
+        }
+        let len = (&data[offsets_end - SIZEOF_U16..]).get_u16() as usize;
+        offsets_end = (offsets_end - SIZEOF_U16)
+            .checked_sub(len)
+            .ok_or(BlockError::ExtensionOverrun { len })?;
+    }
+    if layout == BlockLayout::Dictionary {
+        if offsets_end < SIZEOF_U16 {
+            return Err(BlockError::TooShort { len: data.len() });
+        }
+        let dictionary_len = (&data[offsets_end - SIZEOF_U16..]).get_u16() as usize;
+        offsets_end = (offsets_end - SIZEOF_U16)
+            .checked_sub(dictionary_len * SIZEOF_U16)
+            .ok_or(BlockError::TooShort { len: data.len() })?;
+    }
+    let declared = (raw_offsets_len & ENTRY_COUNT_MASK) as usize;
+    let available = offsets_end / (SIZEOF_U16 * num_offset_arrays);
+    if declared > available {
+        return Err(BlockError::EntryCountMismatch {
+            declared,
+            available,
+        });
+    }
+    Ok(())
+}
+
+impl Block {
+    /// Encode the block with the xxHash64 of its data, followed by the crc32 of the encoded bytes
+    /// (u32), so that corruption of any byte is caught by `decode_with_checksum` before the block
+    /// is decoded. This is an opt-in layout, `encode` stays the plain one.
+    pub fn encode_with_checksum(&self) -> Bytes {
+        let checksum = self.checksum.unwrap_or_else(|| XxHash64::hash(&self.data));
+        let mut buf = self.encode_with(Some(checksum));
+        let crc32 = crc32fast::hash(&buf);
+        buf.put_u32(crc32);
+        buf.into()
+    }
+
+    /// Check the crc32 appended by `encode_with_checksum`, then decode the block with
+    /// `decode_checked`. Also accepts blocks encoded by `encode` followed by their crc32, as in an
+    /// SST.
+    pub fn decode_with_checksum(data: &[u8]) -> Result<Self, BlockError> {
+        let block_len = check_crc32(data)?;
+        Self::decode_checked(&data[..block_len])
+    }
+
+    /// `decode_with_checksum` without copying the entries, see `decode_shared`.
+    pub fn decode_shared_with_checksum(buf: Bytes) -> Result<Arc<Self>, BlockError> {
+        let block_len = check_crc32(&buf)?;
+        let buf = buf.slice(..block_len);
+        check_header(&buf)?;
+        let block = Self::decode_encoded_entries_with(&buf, |data| buf.slice_ref(data));
+        block.validate()?;
+        Ok(Arc::new(block))
+    }
+
+    /// Check the block header of an encoded block, then decode and `validate` it. Unlike
+    /// `decode`, this never panics on corrupted input, and a block whose data does not match its
+    /// checksum is an error.
+    pub fn decode_checked(data: &[u8]) -> Result<Self, BlockError> {
+        check_header(data)?;
+        let block = Self::decode_encoded_entries(data);
+        block.validate()?;
+        Ok(block)
+    }
+
+    /// Decode a block with `decode_checked`, for callers that report errors with `anyhow`, e.g.
+    /// when loading SSTs written by an older or buggy version of the format.
+    pub fn try_decode(data: &[u8]) -> anyhow::Result<Self> {
+        Ok(Self::decode_checked(data)?)
+    }
+
+    /// Check that the offsets are increasing and in bounds, that every entry decodes without
+    /// running into the next one, and that the data matches the checksum if the block has one.
+    /// This is the integrity check for recovery and fsck tools.
+    pub fn validate(&self) -> Result<(), BlockError> {
+        if self.offsets.is_empty() {
+            return Err(BlockError::Empty);
+        }
+        let keys_end = match self.layout {
+            BlockLayout::RowWise | BlockLayout::PrefixCompressedValues => self.data.len(),
+            BlockLayout::Columnar => {
+                if self.value_offsets.len() != self.offsets.len() {
+                    return Err(BlockError::EntryCountMismatch {
+                        declared: self.offsets.len(),
+                        available: self.value_offsets.len(),
+                    });
+                }
+                let values_begin = self.value_offsets[0] as usize;
+                // an empty value may point at the end of data
+                validate_offsets(&self.value_offsets, values_begin, self.data.len() + 1)?;
+                values_begin
+            }
+            BlockLayout::Dictionary => match self.value_offsets.first() {
+                Some(values_begin) => {
+                    let values_begin = *values_begin as usize;
+                    validate_offsets(&self.value_offsets, values_begin, self.data.len() + 1)?;
+                    values_begin
+                }
+                // empty dictionary: every value index is invalid and reported below
+                None => self.data.len(),
+            },
+        };
+        validate_offsets(&self.offsets, 0, keys_end)?;
+        if self.offsets[0] != 0 {
+            return Err(BlockError::OffsetOutOfBounds {
+                idx: 0,
+                offset: self.offsets[0] as usize,
+            });
+        }
+        let mut first_key_len = 0;
+        let mut prev_value_len = 0;
+        for idx in 0..self.offsets.len() {
+            let begin = self.offsets[idx] as usize;
+            let end = self
+                .offsets
+                .get(idx + 1)
+                .map_or(keys_end, |offset| *offset as usize);
+            let mut entry = &self.data[begin..end];
+            if entry.remaining() < SIZEOF_U16 {
+                return Err(BlockError::EntryOverrun { idx });
+            }
+            let overlap = entry.get_u16() as usize;
+            let key_len = try_get_varint(&mut entry).ok_or(BlockError::EntryOverrun { idx })?;
+            if overlap > first_key_len {
+                return Err(BlockError::InvalidOverlap { idx, overlap });
+            }
+            if idx == 0 {
+                first_key_len = key_len;
+            }
+            if entry.remaining() < key_len + std::mem::size_of::<u64>() {
+                return Err(BlockError::EntryOverrun { idx });
+            }
+            entry.advance(key_len + std::mem::size_of::<u64>());
+            match self.layout {
+                BlockLayout::RowWise => {
+                    if entry.remaining() < SIZEOF_U16 {
+                        return Err(BlockError::EntryOverrun { idx });
+                    }
+                    let value_len = entry.get_u16() as usize;
+                    if entry.remaining() < value_len {
+                        return Err(BlockError::EntryOverrun { idx });
+                    }
+                }
+                BlockLayout::PrefixCompressedValues => {
+                    if entry.remaining() < SIZEOF_U16 * 2 {
+                        return Err(BlockError::EntryOverrun { idx });
+                    }
+                    let overlap = entry.get_u16() as usize;
+                    let suffix_len = entry.get_u16() as usize;
+                    if overlap > prev_value_len {
+                        return Err(BlockError::InvalidValuePrefix { idx, overlap });
+                    }
+                    if entry.remaining() < suffix_len {
+                        return Err(BlockError::EntryOverrun { idx });
+                    }
+                    prev_value_len = overlap + suffix_len;
+                }
+                BlockLayout::Columnar => {}
+                BlockLayout::Dictionary => {
+                    let value_idx =
+                        try_get_varint(&mut entry).ok_or(BlockError::EntryOverrun { idx })?;
+                    if value_idx >= self.value_offsets.len() {
+                        return Err(BlockError::InvalidValueIndex { idx, value_idx });
+                    }
+                }
+            }
+        }
+        if let Some(expected) = self.checksum {
+            let actual = XxHash64::hash(&self.data);
+            if actual != expected {
+                return Err(BlockError::ChecksumMismatch { expected, actual });
+            }
+        }
+        Ok(())
+    }
+}
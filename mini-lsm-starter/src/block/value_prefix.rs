@@ -0,0 +1,53 @@
+use bytes::Buf;
+
+use super::{try_get_varint, Block, SIZEOF_U16};
+
+/// Number of leading bytes `a` and `b` have in common, capped to what a `u16` can store.
+pub(super) fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter()
+        .zip(b.iter())
+        .take_while(|(a, b)| a == b)
+        .count()
+        .min(u16::MAX as usize)
+}
+
+/// Number of bytes a value takes in a prefix-compressed entry after `prev_value`.
+pub(super) fn compressed_value_len(prev_value: &[u8], value: &[u8]) -> usize {
+    SIZEOF_U16 * 2 + value.len() - common_prefix_len(prev_value, value)
+}
+
+impl Block {
+    /// Returns the value overlap and the value suffix of the `idx`-th entry of a block with
+    /// prefix-compressed values, or `None` if the entry is malformed.
+    fn value_suffix(&self, idx: usize) -> Option<(usize, &[u8])> {
+        let offset = *self.offsets.get(idx)? as usize;
+        let mut entry = self.data.get(offset..)?.get(SIZEOF_U16..)?;
+        let key_len = try_get_varint(&mut entry)?;
+        let mut entry = entry.get(key_len.checked_add(std::mem::size_of::<u64>())?..)?;
+        if entry.remaining() < SIZEOF_U16 * 2 {
+            return None;
+        }
+        let overlap = entry.get_u16() as usize;
+        let suffix_len = entry.get_u16() as usize;
+        Some((overlap, entry.get(..suffix_len)?))
+    }
+
+    /// Turn `value`, the value of the entry before the `idx`-th one, into the value of the
+    /// `idx`-th entry. Returns `None` if the entry is malformed, `value` is then unspecified.
+    pub(super) fn try_advance_value(&self, idx: usize, value: &mut Vec<u8>) -> Option<()> {
+        let (overlap, suffix) = self.value_suffix(idx)?;
+        if overlap > value.len() {
+            return None;
+        }
+        value.truncate(overlap);
+        value.extend_from_slice(suffix);
+        Some(())
+    }
+
+    /// Rebuild the value of the `idx`-th entry into `value` from the first entry on, returns
+    /// `None` if an entry up to `idx` is malformed.
+    pub(super) fn try_expand_value(&self, idx: usize, value: &mut Vec<u8>) -> Option<()> {
+        value.clear();
+        (0..=idx).try_for_each(|idx| self.try_advance_value(idx, value))
+    }
+}
@@ -0,0 +1,130 @@
+const PRIME64_1: u64 = 0x9E3779B185EBCA87;
+const PRIME64_2: u64 = 0xC2B2AE3D27D4EB4F;
+const PRIME64_3: u64 = 0x165667B19E3779F9;
+const PRIME64_4: u64 = 0x85EBCA77C2B2AE63;
+const PRIME64_5: u64 = 0x27D4EB2F165667C5;
+const STRIPE_LEN: usize = 32;
+
+/// Incremental xxHash64 (seed 0), so that the block checksum is computed while the entries are
+/// added instead of in a second pass over the block.
+#[derive(Clone)]
+pub(crate) struct XxHash64 {
+    acc: [u64; 4],
+    total_len: u64,
+    /// Input not yet consumed as a full stripe.
+    buf: [u8; STRIPE_LEN],
+    buf_len: usize,
+}
+
+fn round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME64_2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME64_1)
+}
+
+fn merge_round(acc: u64, val: u64) -> u64 {
+    (acc ^ round(0, val))
+        .wrapping_mul(PRIME64_1)
+        .wrapping_add(PRIME64_4)
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    u64::from_le_bytes(data[..8].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes(data[..4].try_into().unwrap())
+}
+
+impl XxHash64 {
+    pub(crate) fn new() -> Self {
+        Self {
+            acc: [
+                PRIME64_1.wrapping_add(PRIME64_2),
+                PRIME64_2,
+                0,
+                0u64.wrapping_sub(PRIME64_1),
+            ],
+            total_len: 0,
+            buf: [0; STRIPE_LEN],
+            buf_len: 0,
+        }
+    }
+
+    /// One-shot hash of `data`.
+    pub(crate) fn hash(data: &[u8]) -> u64 {
+        let mut hasher = Self::new();
+        hasher.update(data);
+        hasher.digest()
+    }
+
+    fn consume_stripe(acc: &mut [u64; 4], stripe: &[u8]) {
+        for (i, acc) in acc.iter_mut().enumerate() {
+            *acc = round(*acc, read_u64(&stripe[i * 8..]));
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+        if self.buf_len > 0 {
+            let fill = (STRIPE_LEN - self.buf_len).min(data.len());
+            self.buf[self.buf_len..self.buf_len + fill].copy_from_slice(&data[..fill]);
+            self.buf_len += fill;
+            data = &data[fill..];
+            if self.buf_len < STRIPE_LEN {
+                return;
+            }
+            Self::consume_stripe(&mut self.acc, &self.buf);
+            self.buf_len = 0;
+        }
+        while data.len() >= STRIPE_LEN {
+            Self::consume_stripe(&mut self.acc, &data[..STRIPE_LEN]);
+            data = &data[STRIPE_LEN..];
+        }
+        self.buf[..data.len()].copy_from_slice(data);
+        self.buf_len = data.len();
+    }
+
+    /// Returns the hash of everything passed to `update` so far.
+    pub(crate) fn digest(&self) -> u64 {
+        let [v1, v2, v3, v4] = self.acc;
+        let mut hash = if self.total_len >= STRIPE_LEN as u64 {
+            let hash = v1
+                .rotate_left(1)
+                .wrapping_add(v2.rotate_left(7))
+                .wrapping_add(v3.rotate_left(12))
+                .wrapping_add(v4.rotate_left(18));
+            self.acc.iter().fold(hash, |hash, v| merge_round(hash, *v))
+        } else {
+            PRIME64_5
+        };
+        hash = hash.wrapping_add(self.total_len);
+
+        let mut tail = &self.buf[..self.buf_len];
+        while tail.len() >= 8 {
+            hash = (hash ^ round(0, read_u64(tail)))
+                .rotate_left(27)
+                .wrapping_mul(PRIME64_1)
+                .wrapping_add(PRIME64_4);
+            tail = &tail[8..];
+        }
+        if tail.len() >= 4 {
+            hash = (hash ^ (read_u32(tail) as u64).wrapping_mul(PRIME64_1))
+                .rotate_left(23)
+                .wrapping_mul(PRIME64_2)
+                .wrapping_add(PRIME64_3);
+            tail = &tail[4..];
+        }
+        for byte in tail {
+            hash = (hash ^ (*byte as u64).wrapping_mul(PRIME64_5))
+                .rotate_left(11)
+                .wrapping_mul(PRIME64_1);
+        }
+
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(PRIME64_2);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(PRIME64_3);
+        hash ^ (hash >> 32)
+    }
+}
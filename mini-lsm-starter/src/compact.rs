@@ -5,11 +5,17 @@ mod simple_leveled;
 mod tiered;
 
 use std::collections::HashSet;
+use std::fs::File;
+use std::io::Write;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Result;
-pub use leveled::{LeveledCompactionController, LeveledCompactionOptions, LeveledCompactionTask};
+pub use leveled::{
+    tasks_conflict, CompactionPickStrategy, CompactionPreview, CompactionStats,
+    LeveledCompactionController, LeveledCompactionOptions, LeveledCompactionTask, OptionsError,
+    SkippedReason, SstRankMetric, TaskPoll,
+};
 use serde::{Deserialize, Serialize};
 pub use simple_leveled::{
     SimpleLeveledCompactionController, SimpleLeveledCompactionOptions, SimpleLeveledCompactionTask,
@@ -48,7 +54,7 @@ impl CompactionTask {
 }
 
 pub(crate) enum CompactionController {
-    Leveled(LeveledCompactionController),
+    Leveled(Box<LeveledCompactionController>),
     Tiered(TieredCompactionController),
     Simple(SimpleLeveledCompactionController),
     NoCompaction,
@@ -82,10 +88,10 @@ impl CompactionController {
                 ctrl.apply_compaction_result(&snapshot, task, output, in_recovery)
             }
             (CompactionController::Simple(ctrl), CompactionTask::Simple(task)) => {
-                ctrl.apply_compaction_result(&snapshot, task, output)
+                ctrl.apply_compaction_result(&snapshot, task, output, in_recovery)
             }
             (CompactionController::Tiered(ctrl), CompactionTask::Tiered(task)) => {
-                ctrl.apply_compaction_result(&snapshot, task, output)
+                ctrl.apply_compaction_result(&snapshot, task, output, in_recovery)
             }
             _ => unreachable!(),
         }
@@ -93,6 +99,16 @@ impl CompactionController {
 }
 
 impl CompactionController {
+    /// The block size configured for the output of `task`, if any.
+    pub fn output_block_size(&self, task: &CompactionTask) -> Option<usize> {
+        match (self, task) {
+            (CompactionController::Leveled(ctrl), CompactionTask::Leveled(task)) => {
+                ctrl.output_block_size(task.lower_level)
+            }
+            _ => None,
+        }
+    }
+
     pub fn flush_to_l0(&self) -> bool {
         if let Self::Leveled(_) | Self::Simple(_) | Self::NoCompaction = self {
             true
@@ -102,7 +118,7 @@ impl CompactionController {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum CompactionOptions {
     /// Leveled compaction with partial compaction + dynamic level support (= RocksDB's Leveled
     /// Compaction)
@@ -115,58 +131,138 @@ pub enum CompactionOptions {
     NoCompaction,
 }
 
-impl LsmStorageInner {
-    fn compact_generate_sst_from_iter(
-        &self,
-        mut iter: impl for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>,
-        _compact_to_bottom_level: bool,
-    ) -> Result<Vec<Arc<SsTable>>> {
-        let mut builder = None;
-        let mut new_sst = Vec::new();
-        // last_key 是用来跟踪压缩过程中处理的上一条记录的键（不包含时间戳的主键）。它的主要作用是帮助判断当前正在处理的键是否和上一条记录的键相同
-        let mut last_key = Vec::<u8>::new();
-        while iter.is_valid() {
-            if builder.is_none() {
-                builder = Some(SsTableBuilder::new(self.options.block_size));
+/// Writes the compaction output into SSTs of `target_sst_size`. Each SST id is allocated when the
+/// SST starts, and when streaming, every finished block goes to the SST file right away, so that
+/// only one block is kept in memory instead of the whole output. The SST files written so far are
+/// removed if the writer is dropped without `finish`, e.g. when the compaction fails.
+pub(crate) struct OutputWriter<'a> {
+    storage: &'a LsmStorageInner,
+    block_size: usize,
+    streaming: bool,
+    /// The id, the builder and (when streaming) the file of the SST being written.
+    current: Option<(usize, SsTableBuilder, Option<File>)>,
+    // last_key 是用来跟踪压缩过程中处理的上一条记录的键（不包含时间戳的主键）。它的主要作用是帮助判断当前正在处理的键是否和上一条记录的键相同
+    last_key: Vec<u8>,
+    ssts: Vec<Arc<SsTable>>,
+}
+
+impl<'a> OutputWriter<'a> {
+    /// Streams the output unless the output SSTs are written in reverse block order, which needs
+    /// all the blocks of an SST in memory.
+    pub(crate) fn new(storage: &'a LsmStorageInner, block_size: usize) -> Self {
+        Self {
+            storage,
+            block_size,
+            streaming: !storage.options.reverse_block_order_compaction_output,
+            current: None,
+            last_key: Vec::new(),
+            ssts: Vec::new(),
+        }
+    }
+
+    /// Whether to stream the blocks to disk. Ignored when the output is in reverse block order.
+    pub(crate) fn with_streaming(mut self, streaming: bool) -> Self {
+        self.streaming = streaming && !self.storage.options.reverse_block_order_compaction_output;
+        self
+    }
+
+    /// Appends an entry, the entries must be pushed in key order.
+    pub(crate) fn push_entry(&mut self, key: KeySlice, value: &[u8]) -> Result<()> {
+        let same_as_last_key = key.key_ref() == self.last_key;
+        // 由于 MVCC 的压缩规则要求同一主键的不同版本必须存储在同一个 SST 文件中，因此在实际运行中，生成的 SST 文件大小可能会有所不同
+        if let Some((_, builder, _)) = &self.current {
+            if builder.estimated_size() >= self.storage.options.target_sst_size && !same_as_last_key
+            {
+                self.finish_sst()?;
             }
+        }
+        if self.current.is_none() {
+            let sst_id = self.storage.next_sst_id();
+            let file = if self.streaming {
+                Some(File::create(self.storage.path_of_sst(sst_id))?)
+            } else {
+                None
+            };
+            let builder = self.storage.new_compaction_output_builder(self.block_size);
+            self.current = Some((sst_id, builder, file));
+        }
 
-            let same_as_last_key = iter.key().key_ref() == last_key;
-
-            let builder_inner = builder.as_mut().unwrap();
-
-            // 由于 MVCC 的压缩规则要求同一主键的不同版本必须存储在同一个 SST 文件中，因此在实际运行中，生成的 SST 文件大小可能会有所不同
-            if builder_inner.estimated_size() >= self.options.target_sst_size && !same_as_last_key {
-                let sst_id = self.next_sst_id();
-                let old_builder = builder.take().unwrap();
-                let sst = Arc::new(old_builder.build(
-                    sst_id,
-                    Some(self.block_cache.clone()),
-                    self.path_of_sst(sst_id),
-                )?);
-                new_sst.push(sst);
-                builder = Some(SsTableBuilder::new(self.options.block_size));
+        let (_, builder, file) = self.current.as_mut().unwrap();
+        builder.add(key, value);
+        if let Some(file) = file {
+            let data = builder.take_data();
+            if !data.is_empty() {
+                file.write_all(&data)?;
             }
+        }
 
-            let builder_inner = builder.as_mut().unwrap();
-            builder_inner.add(iter.key(), iter.value());
+        if !same_as_last_key {
+            self.last_key.clear();
+            self.last_key.extend(key.key_ref());
+        }
+        Ok(())
+    }
 
-            if !same_as_last_key {
-                last_key.clear();
-                last_key.extend(iter.key().key_ref());
+    fn finish_sst(&mut self) -> Result<()> {
+        let (sst_id, builder, file) = self.current.take().unwrap();
+        let block_cache = Some(self.storage.block_cache.clone());
+        let path = self.storage.path_of_sst(sst_id);
+        let sst = match file {
+            Some(file) => builder.build_streamed(sst_id, block_cache, &path, file),
+            None => builder.build(sst_id, block_cache, &path),
+        };
+        match sst {
+            Ok(sst) => {
+                self.ssts.push(Arc::new(sst));
+                Ok(())
             }
+            Err(e) => {
+                std::fs::remove_file(&path).ok();
+                Err(e)
+            }
+        }
+    }
 
-            iter.next()?;
+    /// Builds the last SST and returns all the output SSTs in key order.
+    pub(crate) fn finish(mut self) -> Result<Vec<Arc<SsTable>>> {
+        if self.current.is_some() {
+            self.finish_sst()?;
+        }
+        Ok(std::mem::take(&mut self.ssts))
+    }
+}
+
+impl Drop for OutputWriter<'_> {
+    fn drop(&mut self) {
+        // unfinished outputs never reach the manifest, remove the SST files already written to disk
+        if let Some((sst_id, _, Some(_))) = self.current.take() {
+            std::fs::remove_file(self.storage.path_of_sst(sst_id)).ok();
         }
-        if let Some(builder) = builder {
-            let sst_id = self.next_sst_id(); // lock dropped here
-            let sst = Arc::new(builder.build(
-                sst_id,
-                Some(self.block_cache.clone()),
-                self.path_of_sst(sst_id),
-            )?);
-            new_sst.push(sst);
+        for sst in self.ssts.drain(..) {
+            std::fs::remove_file(self.storage.path_of_sst(sst.sst_id())).ok();
         }
-        Ok(new_sst)
+    }
+}
+
+impl LsmStorageInner {
+    fn new_compaction_output_builder(&self, block_size: usize) -> SsTableBuilder {
+        let mut builder = SsTableBuilder::new(block_size);
+        builder.set_reverse_block_order(self.options.reverse_block_order_compaction_output);
+        builder
+    }
+
+    fn compact_generate_sst_from_iter(
+        &self,
+        mut iter: impl for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>,
+        _compact_to_bottom_level: bool,
+        block_size: usize,
+    ) -> Result<Vec<Arc<SsTable>>> {
+        let mut writer = OutputWriter::new(self, block_size);
+        while iter.is_valid() {
+            writer.push_entry(iter.key(), iter.value())?;
+            iter.next()?;
+        }
+        writer.finish()
     }
 
     fn compact(&self, task: &CompactionTask) -> Result<Vec<Arc<SsTable>>> {
@@ -174,6 +270,10 @@ impl LsmStorageInner {
             let state = self.state.read();
             state.clone()
         };
+        let block_size = self
+            .compaction_controller
+            .output_block_size(task)
+            .unwrap_or(self.options.block_size);
         match task {
             CompactionTask::ForceFullCompaction {
                 l0_sstables,
@@ -193,7 +293,11 @@ impl LsmStorageInner {
                     MergeIterator::create(l0_iters),
                     SstConcatIterator::create_and_seek_to_first(l1_iters)?,
                 )?;
-                self.compact_generate_sst_from_iter(iter, task.compact_to_bottom_level())
+                self.compact_generate_sst_from_iter(
+                    iter,
+                    task.compact_to_bottom_level(),
+                    block_size,
+                )
             }
             CompactionTask::Simple(SimpleLeveledCompactionTask {
                 upper_level,
@@ -223,6 +327,7 @@ impl LsmStorageInner {
                     self.compact_generate_sst_from_iter(
                         TwoMergeIterator::create(upper_iter, lower_iter)?,
                         task.compact_to_bottom_level(),
+                        block_size,
                     )
                 }
                 None => {
@@ -241,6 +346,7 @@ impl LsmStorageInner {
                     self.compact_generate_sst_from_iter(
                         TwoMergeIterator::create(upper_iter, lower_iter)?,
                         task.compact_to_bottom_level(),
+                        block_size,
                     )
                 }
             },
@@ -256,6 +362,7 @@ impl LsmStorageInner {
                 self.compact_generate_sst_from_iter(
                     MergeIterator::create(iters),
                     task.compact_to_bottom_level(),
+                    block_size,
                 )
             }
             _ => unimplemented!(),
@@ -302,6 +409,7 @@ impl LsmStorageInner {
                 .copied()
                 .collect::<Vec<_>>();
             assert!(l0_sstables_map.is_empty());
+            state.sync_l0_sorted_runs();
             *self.state.write() = Arc::new(state);
         }
         for sst in l0_sstables.iter().chain(l1_sstables.iter()) {
@@ -402,16 +510,21 @@ impl LsmStorageInner {
         Ok(None)
     }
 
+    /// Flush the immutable memtables until fewer than `num_memtable_limit` are queued. Bursty
+    /// writes can freeze memtables faster than one flush per tick, so the queue is drained here
+    /// instead of flushing a single memtable. Writers waiting in `wait_for_flush` are woken
+    /// after each flush.
     fn trigger_flush(&self) -> Result<()> {
-        if {
-            let state = self.state.read();
-            state.imm_memtables.len() >= self.options.num_memtable_limit
-        } {
-            println!(
-                "execution force_flush_next_imm_memtable {:?}",
+        let limit = self.options.num_memtable_limit.max(1);
+        while self.state.read().imm_memtables.len() >= limit {
+            log::debug!(
+                "flush the next immutable memtable, {} L0 SSTs",
                 self.state.read().l0_sstables.len()
             );
-            self.force_flush_next_imm_memtable()?;
+            let result = self.force_flush_next_imm_memtable();
+            let _guard = self.flush_lock.lock();
+            self.flush_cvar.notify_all();
+            result?;
         }
         Ok(())
     }
@@ -421,16 +534,26 @@ impl LsmStorageInner {
         rx: crossbeam_channel::Receiver<()>,
     ) -> Result<Option<std::thread::JoinHandle<()>>> {
         let this = self.clone();
+        self.flush_thread_running
+            .store(true, std::sync::atomic::Ordering::SeqCst);
         let handle = std::thread::spawn(move || {
             let ticker = crossbeam_channel::tick(Duration::from_millis(50));
+            let wakeup = this.flush_wakeup.1.clone();
             loop {
                 crossbeam_channel::select! {
                     recv(ticker) -> _ => if let Err(e) = this.trigger_flush() {
                         eprintln!("flush failed: {}", e);
                     },
-                    recv(rx) -> _ => return
+                    recv(wakeup) -> _ => if let Err(e) = this.trigger_flush() {
+                        eprintln!("flush failed: {}", e);
+                    },
+                    recv(rx) -> _ => break
                 }
             }
+            this.flush_thread_running
+                .store(false, std::sync::atomic::Ordering::SeqCst);
+            let _guard = this.flush_lock.lock();
+            this.flush_cvar.notify_all();
         });
         return Ok(Some(handle));
     }
@@ -1,7 +1,18 @@
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, Bytes};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
 
+use parking_lot::Mutex;
+
+use crate::iterators::merge_iterator::MergeIterator;
+use crate::iterators::StorageIterator;
+use crate::key::{KeyBytes, KeyVec};
 use crate::lsm_storage::LsmStorageState;
+use crate::table::hll::HyperLogLog;
+use crate::table::{SsTable, SsTableIterator, SstMeta};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LeveledCompactionTask {
@@ -13,58 +24,880 @@ pub struct LeveledCompactionTask {
     pub is_lower_level_bottom_level: bool,
 }
 
-#[derive(Debug, Clone)]
+impl LeveledCompactionTask {
+    /// Encode the task in a stable binary form, independent of the serde representation:
+    /// `upper_level (u32, 0 for L0) | upper_count (u32) | upper ids (u64) | lower_level (u32) |
+    /// lower_count (u32) | lower ids (u64) | is_lower_level_bottom_level (u8)`.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.serialized_size());
+        buf.put_u32(self.upper_level.unwrap_or(0) as u32);
+        buf.put_u32(self.upper_level_sst_ids.len() as u32);
+        for id in &self.upper_level_sst_ids {
+            buf.put_u64(*id as u64);
+        }
+        buf.put_u32(self.lower_level as u32);
+        buf.put_u32(self.lower_level_sst_ids.len() as u32);
+        for id in &self.lower_level_sst_ids {
+            buf.put_u64(*id as u64);
+        }
+        buf.put_u8(self.is_lower_level_bottom_level as u8);
+        buf
+    }
+
+    /// Decode a task from `encode`.
+    pub fn decode(mut buf: &[u8]) -> Result<Self> {
+        fn get_ids(buf: &mut &[u8]) -> Result<Vec<usize>> {
+            if buf.remaining() < 4 {
+                bail!("compaction task is truncated");
+            }
+            let count = buf.get_u32() as usize;
+            if buf.remaining() / 8 < count {
+                bail!("compaction task is truncated");
+            }
+            Ok((0..count).map(|_| buf.get_u64() as usize).collect())
+        }
+        if buf.remaining() < 4 {
+            bail!("compaction task is truncated");
+        }
+        let upper_level = match buf.get_u32() {
+            0 => None,
+            level => Some(level as usize),
+        };
+        let upper_level_sst_ids = get_ids(&mut buf)?;
+        if buf.remaining() < 4 {
+            bail!("compaction task is truncated");
+        }
+        let lower_level = buf.get_u32() as usize;
+        let lower_level_sst_ids = get_ids(&mut buf)?;
+        if buf.remaining() != 1 {
+            bail!("compaction task has {} trailing bytes", buf.remaining());
+        }
+        Ok(Self {
+            upper_level,
+            upper_level_sst_ids,
+            lower_level,
+            lower_level_sst_ids,
+            is_lower_level_bottom_level: buf.get_u8() != 0,
+        })
+    }
+
+    /// The exact length of `encode`, so that the manifest can reserve space before encoding.
+    pub fn serialized_size(&self) -> usize {
+        // 4 bytes each for the two levels and two counts, 8 bytes per id, and a trailing 1-byte
+        // bottom level flag
+        4 * 4 + 8 * (self.upper_level_sst_ids.len() + self.lower_level_sst_ids.len()) + 1
+    }
+}
+
+/// Why `generate_compaction_batch` did not schedule a candidate. `level` is the upper level of the
+/// candidate, 0 for L0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkippedReason {
+    /// The candidate conflicts with an earlier task of the batch (see `tasks_conflict`), or shares
+    /// these SSTs with a running compaction.
+    Conflict { level: usize, sst_ids: Vec<usize> },
+    /// The candidate refers to an SST that is not in `snapshot.sstables`.
+    MissingSst { level: usize, sst_id: usize },
+}
+
+/// The outcome of `LeveledCompactionController::poll_compaction_task`.
+#[derive(Debug)]
+pub enum TaskPoll {
+    /// A task to run.
+    Task(LeveledCompactionTask),
+    /// Nothing to compact.
+    NoTask,
+    /// The level sizes have not changed since the last poll, so the task was not recomputed.
+    NoChange,
+}
+
+/// Number of SSTs and their total size of L0 followed by each level, to tell whether a snapshot
+/// changed since the last poll.
+type SnapshotFingerprint = Vec<(usize, u64)>;
+
+/// The maximum number of tasks `simulate_flush_cascade` generates for a single flush.
+const MAX_CASCADE_TASKS: usize = 64;
+
+/// Why `LeveledCompactionController::update_options` rejects new options.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OptionsError {
+    /// Levels would not grow, so the tree could never be deeper than L1.
+    InvalidMultiplier { multiplier: usize },
+    /// The storage state has fewer levels than `max_levels`.
+    TooManyLevels {
+        max_levels: usize,
+        state_levels: usize,
+    },
+    /// `level` holds SSTs but is below the new `max_levels`.
+    OrphanedLevel { level: usize, max_levels: usize },
+    /// `per_level_block_size` does not have one entry per level.
+    BlockSizeCountMismatch { expected: usize, actual: usize },
+}
+
+impl fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidMultiplier { multiplier } => write!(
+                f,
+                "level size multiplier must be larger than 1, got {}",
+                multiplier
+            ),
+            Self::TooManyLevels {
+                max_levels,
+                state_levels,
+            } => write!(
+                f,
+                "max_levels {} exceeds the {} levels of the storage",
+                max_levels, state_levels
+            ),
+            Self::OrphanedLevel { level, max_levels } => write!(
+                f,
+                "L{} still holds SSTs, cannot reduce max_levels to {}",
+                level, max_levels
+            ),
+            Self::BlockSizeCountMismatch { expected, actual } => write!(
+                f,
+                "per_level_block_size has {} entries, expected {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for OptionsError {}
+
+/// The metric `LeveledCompactionController::rank_ssts` sorts SSTs by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SstRankMetric {
+    /// `table_size()` in bytes.
+    Size,
+    /// Number of delete tombstones, read from the SST blocks.
+    TombstoneCount,
+    /// Number of SST generations created after this one.
+    Age,
+}
+
+/// How `generate_compaction_task` picks the SST to compact out of a level over its target size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompactionPickStrategy {
+    /// The SST with the smallest id. Ids are allocated in order, so this is roughly the oldest.
+    #[default]
+    LowestId,
+    /// The SST with the smallest first key, then the smallest id.
+    OldestByFirstKey,
+    /// The SST overlapping the fewest SSTs in the next level, then the smallest id, to minimize
+    /// write amplification.
+    FewestOverlaps,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LeveledCompactionOptions {
     pub level_size_multiplier: usize,
     pub level0_file_num_compaction_trigger: usize,
     pub max_levels: usize,
     pub base_level_size_mb: usize,
+    /// Block size of the compaction output for L1..=L_max, e.g. larger blocks for deeper and
+    /// colder levels to reduce index overhead. Uses `LsmStorageOptions::block_size` if not set.
+    pub per_level_block_size: Option<Vec<usize>>,
+    /// Skip an upper SST if the lower level SSTs it overlaps are larger than this ratio of its own
+    /// size, and pick another SST or level instead, so that one bad file cannot stall compaction.
+    pub abort_overlap_ratio: Option<f64>,
+    /// Do not compact an SST out of L1..=L_max until at least this many SSTs have been written
+    /// after it (its age in generations, as SST ids are allocated in order), so that hot data
+    /// settles before it is rewritten. The L0 compaction is never deferred.
+    pub min_sst_age_before_compaction: Option<u64>,
+    /// Among the levels over their target size, pick the one whose oldest SST is the oldest
+    /// instead of the one most over its target, so that overwritten versions in a slightly
+    /// oversized level do not linger behind a busier one. SSTs are aged by their `max_ts`, not by
+    /// their id, which is new for compaction outputs. Levels under their target are never
+    /// compacted for their age alone. The L0 compaction is still driven by its trigger.
+    pub prioritize_by_age: bool,
+    /// Cap on the input bytes (the upper SST and the lower level SSTs it overlaps) of a compaction
+    /// triggered by priority. The overlapping lower SSTs are taken all or none: one left out would
+    /// straddle the key range of the output and break the sorted order of the lower level. So an
+    /// upper SST over the cap is passed over for the next one within it, and if there is none,
+    /// the one with the smallest input is compacted anyway so that the level does not stall. The
+    /// output is split into multiple SSTs as usual.
+    pub max_compaction_bytes: Option<u64>,
+    /// The order in which the SSTs of a level are considered, see `CompactionPickStrategy`.
+    pub pick_strategy: CompactionPickStrategy,
+    /// Also compact a level out of L1..L_max-1 when it holds more entries than this, even if it is
+    /// under its target size, as many small entries are slow to iterate. Entry counts are read
+    /// from the SST blocks once per SST.
+    pub level_entry_count_trigger: Option<usize>,
+}
+
+/// Statistics of an applied compaction task, passed to the `on_task_end` callback.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionTaskStats {
+    pub input_sst_count: usize,
+    pub output_sst_count: usize,
+    pub input_bytes: u64,
+    pub output_bytes: u64,
 }
 
+/// What `apply_compaction_result` would change, see `LeveledCompactionController::preview`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionPreview {
+    /// `(level, SST ids)` taken out of each level by the task, 0 for L0.
+    pub moved_out: Vec<(usize, Vec<usize>)>,
+    /// The SSTs to remove, as returned by `apply_compaction_result`.
+    pub removed: Vec<usize>,
+    /// The SSTs of the lower level that are kept, in their order after the task is applied.
+    pub kept_lower_level_ssts: Vec<usize>,
+    /// Where the output SSTs go in `kept_lower_level_ssts`.
+    pub output_position: usize,
+}
+
+/// What the controller computed for the last polled snapshot, see `with_stats_collection`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompactionStats {
+    /// Target size in bytes of L1..=L_max.
+    pub target_level_sizes: Vec<usize>,
+    /// Size in bytes of L1..=L_max.
+    pub real_level_sizes: Vec<usize>,
+    pub base_level: usize,
+    /// `(priority, level)` of the level compacted by priority, `None` if L0 is compacted or there
+    /// is no task.
+    pub winning_priority: Option<(f64, usize)>,
+}
+
+type TaskBeginCallback = Box<dyn Fn(&LeveledCompactionTask) + Send + Sync>;
+type TaskEndCallback =
+    Box<dyn Fn(&LeveledCompactionTask, &[usize], &CompactionTaskStats) + Send + Sync>;
+
 pub struct LeveledCompactionController {
     options: LeveledCompactionOptions,
+    /// Called with every task returned by `generate_compaction_task`.
+    on_task_begin: Option<TaskBeginCallback>,
+    /// Called with every task applied by `apply_compaction_result` outside of recovery, along with
+    /// its output SSTs.
+    on_task_end: Option<TaskEndCallback>,
+    /// (first_key, last_key) of the SSTs looked up by `find_overlapping_ssts`, kept up to date
+    /// by `apply_compaction_result`.
+    key_range_cache: Mutex<HashMap<usize, (KeyBytes, KeyBytes)>>,
+    /// Entry count of the SSTs looked up for `level_entry_count_trigger`, kept up to date by
+    /// `apply_compaction_result`.
+    entry_count_cache: Mutex<HashMap<usize, usize>>,
+    /// Size of the SSTs written by compaction, i.e., `target_sst_size` of the storage.
+    target_output_sst_size: usize,
+    /// Whether `apply_compaction_result` keeps the state it is applied to in `rollback_state`.
+    retain_rollback_state: bool,
+    /// The state before the last `apply_compaction_result`, for crash-consistency tests.
+    rollback_state: Mutex<Option<Box<LsmStorageState>>>,
+    /// Whether `poll_compaction_task` skips snapshots with the same fingerprint as the last one.
+    memoize_tasks: bool,
+    /// Fingerprint of the snapshot of the last `poll_compaction_task`.
+    last_fingerprint: Mutex<Option<SnapshotFingerprint>>,
+    /// Whether `preview_shadowed_keys` is allowed to read the task inputs.
+    shadow_preview: bool,
+    /// Whether `poll_compaction_task` keeps its `CompactionStats` in `last_stats` and logs how the
+    /// task is picked.
+    collect_stats: bool,
+    last_stats: Mutex<Option<CompactionStats>>,
+    /// Whether `generate_compaction_task_excluding` picks a level compaction while the L0 flush
+    /// conflicts with a running compaction.
+    defer_l0_flush: bool,
+    /// Metadata of the SSTs missing from the snapshot, see `with_sst_meta`.
+    sst_metas: HashMap<usize, Arc<dyn SstMeta + Send + Sync>>,
 }
 
 impl LeveledCompactionController {
     pub fn new(options: LeveledCompactionOptions) -> Self {
-        Self { options }
+        if let Some(block_sizes) = &options.per_level_block_size {
+            assert_eq!(
+                block_sizes.len(),
+                options.max_levels,
+                "per_level_block_size should have one entry per level"
+            );
+        }
+        Self {
+            options,
+            on_task_begin: None,
+            on_task_end: None,
+            key_range_cache: Mutex::new(HashMap::new()),
+            entry_count_cache: Mutex::new(HashMap::new()),
+            target_output_sst_size: 2 << 20,
+            retain_rollback_state: false,
+            rollback_state: Mutex::new(None),
+            memoize_tasks: false,
+            last_fingerprint: Mutex::new(None),
+            shadow_preview: false,
+            collect_stats: false,
+            last_stats: Mutex::new(None),
+            defer_l0_flush: false,
+            sst_metas: HashMap::new(),
+        }
+    }
+
+    /// Set a callback invoked with each generated task, e.g. to trace compaction latency.
+    pub fn with_on_task_begin(
+        mut self,
+        f: impl Fn(&LeveledCompactionTask) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_task_begin = Some(Box::new(f));
+        self
+    }
+
+    /// Set a callback invoked with each applied task, its output SSTs and statistics.
+    pub fn with_on_task_end(
+        mut self,
+        f: impl Fn(&LeveledCompactionTask, &[usize], &CompactionTaskStats) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_task_end = Some(Box::new(f));
+        self
+    }
+
+    /// Set the size of the SSTs written by compaction, 2MB by default. The storage sets it to its
+    /// `target_sst_size`.
+    pub fn with_target_output_sst_size(mut self, target_output_sst_size: usize) -> Self {
+        self.target_output_sst_size = target_output_sst_size;
+        self
+    }
+
+    /// The size of the SSTs written by compaction.
+    pub fn target_output_sst_size(&self) -> usize {
+        self.target_output_sst_size
+    }
+
+    /// The expected number of SSTs of L1..=L_max once the tree is fully grown, when each level is
+    /// `level_size_multiplier` times larger than the one above, starting from `base_level_size_mb`
+    /// at L1, and is filled with SSTs of `target_output_sst_size`. Dashboards compare it against
+    /// the actual counts to spot imbalanced levels.
+    pub fn expected_ssts_per_level(&self) -> Vec<usize> {
+        let mut target_level_size = self.options.base_level_size_mb * 1024 * 1024;
+        let mut expected = Vec::with_capacity(self.options.max_levels);
+        for _ in 0..self.options.max_levels {
+            expected.push(target_level_size.div_ceil(self.target_output_sst_size));
+            target_level_size =
+                target_level_size.saturating_mul(self.options.level_size_multiplier);
+        }
+        expected
+    }
+
+    /// Remember the level counts and sizes of the last polled snapshot, and skip recomputing the
+    /// task if they have not changed, for callers that poll in a tight loop. A task that is not
+    /// applied is only generated once, so callers must retry a failed task themselves.
+    pub fn with_task_memoization(mut self) -> Self {
+        self.memoize_tasks = true;
+        self
     }
 
-    fn find_overlapping_ssts(
+    /// Allow `preview_shadowed_keys`, a debugging aid that reads every input SST of a task, so it
+    /// is off by default.
+    pub fn with_shadow_preview(mut self) -> Self {
+        self.shadow_preview = true;
+        self
+    }
+
+    /// Keep the `CompactionStats` of each polled snapshot for `last_stats`, e.g. to build a
+    /// dashboard, and log them along with the task picks at debug level.
+    pub fn with_stats_collection(mut self) -> Self {
+        self.collect_stats = true;
+        self
+    }
+
+    /// Let `generate_compaction_task_excluding` defer the L0 flush while it conflicts with a
+    /// running compaction, e.g. one targeting the base level, and pick a level compaction instead.
+    /// Without it the L0 flush keeps the top priority and no task is generated until it can run.
+    pub fn with_defer_l0_flush(mut self) -> Self {
+        self.defer_l0_flush = true;
+        self
+    }
+
+    /// Supply the metadata of the SST `sst_id` for planning when it is not in the snapshot, e.g. a
+    /// fake in tests or the metadata stored for an SST that is not loaded yet. The SSTs in the
+    /// snapshot take precedence.
+    pub fn with_sst_meta(
+        mut self,
+        sst_id: usize,
+        meta: impl SstMeta + Send + Sync + 'static,
+    ) -> Self {
+        self.sst_metas.insert(sst_id, Arc::new(meta));
+        self
+    }
+
+    /// The `CompactionStats` of the last snapshot polled by `generate_compaction_task`, `None` if
+    /// there is none or `with_stats_collection` is not set. A snapshot skipped by
+    /// `with_task_memoization` keeps the stats of the previous one.
+    pub fn last_stats(&self) -> Option<CompactionStats> {
+        self.last_stats.lock().clone()
+    }
+
+    /// Keep the state before each `apply_compaction_result`, so that a test harness can simulate a
+    /// crash in the middle of applying a compaction with `take_rollback_state`. Only the last
+    /// state is kept, but that is still a copy of the whole state, so this is off by default.
+    pub fn with_rollback_state(mut self) -> Self {
+        self.retain_rollback_state = true;
+        self
+    }
+
+    /// Take the state before the last `apply_compaction_result`, `None` if there is none or
+    /// `with_rollback_state` is not set.
+    pub fn take_rollback_state(&self) -> Option<LsmStorageState> {
+        self.rollback_state.lock().take().map(|state| *state)
+    }
+
+    /// Replace the options at runtime, e.g. to retune a running storage without restarting it.
+    /// `snapshot` is the current state, the levels beyond the new `max_levels` must be empty so
+    /// that no SST is orphaned. The options are unchanged if the new ones are rejected.
+    pub fn update_options(
+        &mut self,
+        snapshot: &LsmStorageState,
+        new: LeveledCompactionOptions,
+    ) -> Result<(), OptionsError> {
+        if new.level_size_multiplier <= 1 {
+            return Err(OptionsError::InvalidMultiplier {
+                multiplier: new.level_size_multiplier,
+            });
+        }
+        if new.max_levels == 0 || new.max_levels > snapshot.levels.len() {
+            return Err(OptionsError::TooManyLevels {
+                max_levels: new.max_levels,
+                state_levels: snapshot.levels.len(),
+            });
+        }
+        if let Some((level, _)) = snapshot.levels[new.max_levels..]
+            .iter()
+            .find(|(_, ssts)| !ssts.is_empty())
+        {
+            return Err(OptionsError::OrphanedLevel {
+                level: *level,
+                max_levels: new.max_levels,
+            });
+        }
+        if let Some(block_sizes) = &new.per_level_block_size {
+            if block_sizes.len() != new.max_levels {
+                return Err(OptionsError::BlockSizeCountMismatch {
+                    expected: new.max_levels,
+                    actual: block_sizes.len(),
+                });
+            }
+        }
+        self.options = new;
+        // with different options the same snapshot may yield a different task
+        *self.last_fingerprint.get_mut() = None;
+        Ok(())
+    }
+
+    /// The cached key range of `sst_id`, if it has been looked up.
+    pub fn cached_key_range(&self, sst_id: usize) -> Option<(KeyBytes, KeyBytes)> {
+        self.key_range_cache.lock().get(&sst_id).cloned()
+    }
+
+    /// Returns the key range of `sst_id`, filling the cache on a miss.
+    fn key_range(&self, snapshot: &LsmStorageState, sst_id: usize) -> (KeyBytes, KeyBytes) {
+        self.key_range_cache
+            .lock()
+            .entry(sst_id)
+            .or_insert_with(|| {
+                let sst = self.sst_meta(snapshot, sst_id);
+                (sst.first_key().clone(), sst.last_key().clone())
+            })
+            .clone()
+    }
+
+    /// The metadata of `sst_id` in the snapshot, or else supplied by `with_sst_meta`.
+    fn get_sst_meta<'a>(
+        &'a self,
+        snapshot: &'a LsmStorageState,
+        sst_id: usize,
+    ) -> Option<&'a dyn SstMeta> {
+        match snapshot.sstables.get(&sst_id) {
+            Some(sst) => Some(sst.as_ref() as &dyn SstMeta),
+            None => self
+                .sst_metas
+                .get(&sst_id)
+                .map(|meta| meta.as_ref() as &dyn SstMeta),
+        }
+    }
+
+    /// `get_sst_meta` of an SST that must exist.
+    fn sst_meta<'a>(&'a self, snapshot: &'a LsmStorageState, sst_id: usize) -> &'a dyn SstMeta {
+        self.get_sst_meta(snapshot, sst_id)
+            .unwrap_or_else(|| panic!("SST {} not found", sst_id))
+    }
+
+    /// Total entry count of the SSTs in `level`, filling the cache on a miss. An SST whose blocks
+    /// cannot be read, or that is not in the snapshot (e.g. only supplied by `with_sst_meta`),
+    /// counts as empty, so that it does not block compaction.
+    fn level_entry_count(&self, snapshot: &LsmStorageState, level: usize) -> usize {
+        let mut entry_count_cache = self.entry_count_cache.lock();
+        snapshot.levels[level - 1]
+            .1
+            .iter()
+            .map(|id| match snapshot.sstables.get(id) {
+                Some(sst) => *entry_count_cache
+                    .entry(*id)
+                    .or_insert_with(|| sst.num_entries().unwrap_or_default()),
+                // do not cache missing SSTs, read the entry count once they are loaded
+                None => 0,
+            })
+            .sum()
+    }
+
+    /// The block size to use for the compaction output written to `level`, `None` if it is not
+    /// configured per level.
+    pub fn output_block_size(&self, level: usize) -> Option<usize> {
+        self.options
+            .per_level_block_size
+            .as_ref()
+            .map(|block_sizes| block_sizes[level - 1])
+    }
+
+    /// The SSTs in `in_level` overlapping the key range of `sst_ids`, none if `sst_ids` is empty.
+    pub(crate) fn find_overlapping_ssts(
         &self,
         snapshot: &LsmStorageState,
         sst_ids: &[usize],
         in_level: usize,
     ) -> Vec<usize> {
-        let begin_key = sst_ids
-            .iter()
-            .map(|id| snapshot.sstables[id].first_key())
-            .min()
-            .cloned()
-            .unwrap();
-        let end_key = sst_ids
+        let ranges = sst_ids
             .iter()
-            .map(|id| snapshot.sstables[id].last_key())
-            .max()
-            .cloned()
-            .unwrap();
+            .map(|id| self.key_range(snapshot, *id))
+            .collect::<Vec<_>>();
+        let (Some(begin_key), Some(end_key)) = (
+            ranges.iter().map(|(first, _)| first).min().cloned(),
+            ranges.iter().map(|(_, last)| last).max().cloned(),
+        ) else {
+            return Vec::new();
+        };
         let mut overlap_ssts = Vec::new();
         for sst_id in &snapshot.levels[in_level - 1].1 {
-            let sst = &snapshot.sstables[sst_id];
-            let first_key = sst.first_key();
-            let last_key = sst.last_key();
-            if !(last_key < &begin_key || first_key > &end_key) {
+            let (first_key, last_key) = self.key_range(snapshot, *sst_id);
+            if !(last_key < begin_key || first_key > end_key) {
                 overlap_ssts.push(*sst_id);
             }
         }
         overlap_ssts
     }
 
-    pub fn generate_compaction_task(
+    /// The total size and the number of the SSTs in the base level that the combined key range
+    /// of L0 overlaps, i.e. what flushing L0 would rewrite besides L0 itself.
+    pub fn l0_to_base_overlap(&self, snapshot: &LsmStorageState) -> (u64, usize) {
+        let (_, _, base_level) = self.compute_level_size(snapshot);
+        let overlap_ssts = self.find_overlapping_ssts(snapshot, &snapshot.l0_sstables, base_level);
+        let overlap_bytes = overlap_ssts
+            .iter()
+            .map(|id| self.sst_meta(snapshot, *id).table_size())
+            .sum();
+        (overlap_bytes, overlap_ssts.len())
+    }
+
+    /// Total size of the SSTs a point get on `user_key` opens: every L0 SST whose key range
+    /// contains it and at most one SST per level, regardless of the bloom filters. This is the
+    /// read cost of a single key, e.g. to find hot keys that are expensive to read.
+    pub fn estimated_point_read_bytes(&self, snapshot: &LsmStorageState, user_key: &[u8]) -> u64 {
+        let contains = |sst_id: &&usize| {
+            let (first_key, last_key) = self.key_range(snapshot, **sst_id);
+            first_key.key_ref() <= user_key && user_key <= last_key.key_ref()
+        };
+        let l0_ssts = snapshot.l0_sstables.iter().filter(contains);
+        let level_ssts = snapshot
+            .levels
+            .iter()
+            .filter_map(|(_, level_ssts)| level_ssts.iter().find(contains));
+        l0_ssts
+            .chain(level_ssts)
+            .map(|id| self.sst_meta(snapshot, *id).table_size())
+            .sum()
+    }
+
+    /// The SSTs in `lower_level` that overlap `sst_id`, `None` if their size plus the size of
+    /// `sst_id` is over `max_bytes`. A subset is never returned, see `max_compaction_bytes`.
+    fn find_overlapping_ssts_bounded(
         &self,
         snapshot: &LsmStorageState,
+        sst_id: usize,
+        lower_level: usize,
+        max_bytes: u64,
+    ) -> Option<Vec<usize>> {
+        let overlap_ssts = self.find_overlapping_ssts(snapshot, &[sst_id], lower_level);
+        let input_bytes = std::iter::once(&sst_id)
+            .chain(&overlap_ssts)
+            .map(|id| self.sst_meta(snapshot, *id).table_size())
+            .sum::<u64>();
+        (input_bytes <= max_bytes).then_some(overlap_ssts)
+    }
+
+    /// Total `table_size()` of the SSTs in `lower_level` that overlap `sst_id`. This estimates the
+    /// cost of compacting `sst_id` into `lower_level` better than the number of overlapping SSTs.
+    pub fn overlapping_bytes(
+        &self,
+        snapshot: &LsmStorageState,
+        sst_id: usize,
+        lower_level: usize,
+    ) -> u64 {
+        self.find_overlapping_ssts(snapshot, &[sst_id], lower_level)
+            .iter()
+            .map(|id| self.sst_meta(snapshot, *id).table_size())
+            .sum()
+    }
+
+    /// Select the SST in `level` with the least overlapping bytes in the next level, preferring
+    /// the older one on ties. Returns `None` if the level is empty or is the last level.
+    pub fn select_min_overlapping_bytes(
+        &self,
+        snapshot: &LsmStorageState,
+        level: usize,
+    ) -> Option<usize> {
+        if level >= self.options.max_levels {
+            return None;
+        }
+        snapshot.levels[level - 1]
+            .1
+            .iter()
+            .copied()
+            .min_by_key(|id| (self.overlapping_bytes(snapshot, *id, level + 1), *id))
+    }
+
+    /// Total bytes that compaction needs to move down: the size above target of every level over
+    /// its target size, plus the size of the L0 SSTs beyond `level0_file_num_compaction_trigger`
+    /// (the oldest ones).
+    pub fn compaction_debt_bytes(&self, snapshot: &LsmStorageState) -> u64 {
+        let (target_level_size, real_level_size, _) = self.compute_level_size(snapshot);
+        let l0_debt = snapshot
+            .l0_sstables
+            .iter()
+            .skip(self.options.level0_file_num_compaction_trigger)
+            .filter_map(|id| snapshot.sstables.get(id))
+            .map(|sst| sst.table_size())
+            .sum::<u64>();
+        let level_debt = real_level_size
+            .iter()
+            .zip(target_level_size.iter())
+            .map(|(real, target)| real.saturating_sub(*target) as u64)
+            .sum::<u64>();
+        l0_debt + level_debt
+    }
+
+    /// Estimate the compaction churn of changing `base_level_size_mb` to `new_base_mb`: the bytes
+    /// that would need to move down because their level is over the target size recomputed with
+    /// the new base size. The base level may shift, in which case the levels above it have a
+    /// target of 0 and must be emptied.
+    pub fn estimate_churn_for_base_size(
+        &self,
+        snapshot: &LsmStorageState,
+        new_base_mb: usize,
+    ) -> u64 {
+        let (target_level_size, real_level_size, _) =
+            self.compute_level_size_with_base(snapshot, new_base_mb);
+        real_level_size
+            .iter()
+            .zip(target_level_size.iter())
+            .map(|(real, target)| real.saturating_sub(*target) as u64)
+            .sum()
+    }
+
+    /// List every SST of the snapshot with its value of `by`, the largest first (ties by id).
+    /// SSTs that cannot be read count as having no tombstones.
+    pub fn rank_ssts(&self, snapshot: &LsmStorageState, by: SstRankMetric) -> Vec<(usize, u64)> {
+        let newest_sst_id = self.newest_sst_id(snapshot);
+        let mut ranks = snapshot
+            .sstables
+            .iter()
+            .map(|(id, sst)| {
+                let value = match by {
+                    SstRankMetric::Size => sst.table_size(),
+                    SstRankMetric::TombstoneCount => sst.num_tombstones().unwrap_or(0),
+                    SstRankMetric::Age => newest_sst_id.saturating_sub(*id) as u64,
+                };
+                (*id, value)
+            })
+            .collect::<Vec<_>>();
+        ranks.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranks
+    }
+
+    /// Merge the input SSTs of `task` and list every version shadowed by a newer version of the same
+    /// key as `(user_key, dropped_ts, kept_ts)`, where `kept_ts` is the newest version. This is
+    /// what the compaction may discard, to investigate data loss reports. Fails unless enabled
+    /// with `with_shadow_preview`.
+    pub fn preview_shadowed_keys(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &LeveledCompactionTask,
+    ) -> Result<Vec<(Vec<u8>, u64, u64)>> {
+        if !self.shadow_preview {
+            bail!("shadow preview is not enabled");
+        }
+        let mut iters = Vec::new();
+        for id in task
+            .upper_level_sst_ids
+            .iter()
+            .chain(task.lower_level_sst_ids.iter())
+        {
+            let Some(sst) = snapshot.sstables.get(id) else {
+                bail!("SST {} is not in the snapshot", id);
+            };
+            iters.push(Box::new(SsTableIterator::create_and_seek_to_first(
+                sst.clone(),
+            )?));
+        }
+        let mut iter = MergeIterator::create(iters);
+        let mut shadowed = Vec::new();
+        // latest version of the current key
+        let mut newest: Option<(Vec<u8>, u64)> = None;
+        while iter.is_valid() {
+            let key = iter.key();
+            match &newest {
+                Some((user_key, kept_ts)) if user_key.as_slice() == key.key_ref() => {
+                    shadowed.push((user_key.clone(), key.ts(), *kept_ts));
+                }
+                _ => newest = Some((key.key_ref().to_vec(), key.ts())),
+            }
+            iter.next()?;
+        }
+        Ok(shadowed)
+    }
+
+    /// Generate compaction tasks that can run concurrently, on a best-effort basis: the L0 task if
+    /// triggered, then one task for each level over its target size in priority order, whose SST
+    /// is selected as by `generate_compaction_task`. Candidates touching an SST in `in_progress`
+    /// or in an earlier task of the batch, or referring to a missing SST, are skipped and
+    /// reported.
+    pub fn generate_compaction_batch(
+        &self,
+        snapshot: &LsmStorageState,
+        in_progress: &HashSet<usize>,
+    ) -> (Vec<LeveledCompactionTask>, Vec<SkippedReason>) {
+        let (target_level_size, real_level_size, base_level) = self.compute_level_size(snapshot);
+        // find_overlapping_ssts needs the upper SSTs and the whole lower level to be present
+        let find_missing = |level: usize, upper_level_sst_ids: &[usize], lower_level: usize| {
+            upper_level_sst_ids
+                .iter()
+                .chain(snapshot.levels[lower_level - 1].1.iter())
+                .find(|id| !snapshot.sstables.contains_key(id))
+                .map(|sst_id| SkippedReason::MissingSst {
+                    level,
+                    sst_id: *sst_id,
+                })
+        };
+        let mut candidates = Vec::new();
+        let l0_read_amp = match &snapshot.l0_sorted_runs {
+            Some(runs) => runs.len(),
+            None => snapshot.l0_sstables.len(),
+        };
+        if l0_read_amp >= self.options.level0_file_num_compaction_trigger
+            && !snapshot.l0_sstables.is_empty()
+        {
+            candidates.push(match find_missing(0, &snapshot.l0_sstables, base_level) {
+                Some(reason) => Err(reason),
+                None => Ok((
+                    None,
+                    snapshot.l0_sstables.clone(),
+                    base_level,
+                    self.find_overlapping_ssts(snapshot, &snapshot.l0_sstables, base_level),
+                )),
+            });
+        }
+        let mut priorities = Vec::with_capacity(self.options.max_levels);
+        for level in 0..self.options.max_levels - 1 {
+            let prio = real_level_size[level] as f64 / target_level_size[level] as f64;
+            if prio > 1.0 {
+                priorities.push((prio, level + 1));
+            }
+        }
+        priorities.sort_by(|a, b| a.partial_cmp(b).unwrap().reverse());
+        let newest_sst_id = self.newest_sst_id(snapshot);
+        for (_, level) in priorities {
+            // the selection reads the key range and size of every SST in the level
+            if let Some(reason) = find_missing(level, &snapshot.levels[level - 1].1, level + 1) {
+                candidates.push(Err(reason));
+                continue;
+            }
+            if let Some((selected_sst, lower_level_sst_ids)) =
+                self.select_upper_level_sst(snapshot, level, newest_sst_id)
+            {
+                candidates.push(Ok((
+                    Some(level),
+                    vec![selected_sst],
+                    level + 1,
+                    lower_level_sst_ids,
+                )));
+            }
+        }
+
+        let mut claimed = in_progress.clone();
+        let mut tasks = Vec::new();
+        let mut skipped = Vec::new();
+        for candidate in candidates {
+            let (upper_level, upper_level_sst_ids, lower_level, lower_level_sst_ids) =
+                match candidate {
+                    Ok(candidate) => candidate,
+                    Err(reason) => {
+                        skipped.push(reason);
+                        continue;
+                    }
+                };
+            let level = upper_level.unwrap_or(0);
+            let conflicts = upper_level_sst_ids
+                .iter()
+                .chain(lower_level_sst_ids.iter())
+                .filter(|id| claimed.contains(id))
+                .copied()
+                .collect::<Vec<_>>();
+            let task = LeveledCompactionTask {
+                upper_level,
+                upper_level_sst_ids,
+                lower_level,
+                lower_level_sst_ids,
+                is_lower_level_bottom_level: lower_level == self.options.max_levels,
+            };
+            if !conflicts.is_empty()
+                || tasks
+                    .iter()
+                    .any(|other| tasks_conflict(snapshot, &task, other))
+            {
+                skipped.push(SkippedReason::Conflict {
+                    level,
+                    sst_ids: conflicts,
+                });
+                continue;
+            }
+            claimed.extend(
+                task.upper_level_sst_ids
+                    .iter()
+                    .chain(task.lower_level_sst_ids.iter()),
+            );
+            tasks.push(task);
+        }
+        (tasks, skipped)
+    }
+
+    /// Generate the task with the highest priority that does not touch an SST in `in_progress`,
+    /// see `generate_compaction_batch`. If the triggered L0 flush conflicts, the L0 flush is
+    /// deferred when `with_defer_l0_flush` is set, otherwise `None` is returned.
+    pub fn generate_compaction_task_excluding(
+        &self,
+        snapshot: &LsmStorageState,
+        in_progress: &HashSet<usize>,
     ) -> Option<LeveledCompactionTask> {
-        // step 1: compute target level size
+        let (tasks, skipped) = self.generate_compaction_batch(snapshot, in_progress);
+        let l0_flush_blocked = skipped
+            .iter()
+            .any(|reason| matches!(reason, SkippedReason::Conflict { level: 0, .. }));
+        if l0_flush_blocked {
+            if !self.defer_l0_flush {
+                return None;
+            }
+            log::debug!("L0 flush conflicts with a running compaction, deferred");
+        }
+        tasks.into_iter().next()
+    }
+
+    /// Returns the target size and the real size of L1..=L_max, and the base level that L0 is
+    /// compacted into. The base level is never below the topmost non-empty level.
+    fn compute_level_size(&self, snapshot: &LsmStorageState) -> (Vec<usize>, Vec<usize>, usize) {
+        self.compute_level_size_with_base(snapshot, self.options.base_level_size_mb)
+    }
+
+    /// `compute_level_size` as if `base_level_size_mb` were set to `base_level_size_mb`.
+    fn compute_level_size_with_base(
+        &self,
+        snapshot: &LsmStorageState,
+        base_level_size_mb: usize,
+    ) -> (Vec<usize>, Vec<usize>, usize) {
         let mut target_level_size = (0..self.options.max_levels).map(|_| 0).collect::<Vec<_>>(); // exclude level 0
         let mut real_level_size = Vec::with_capacity(self.options.max_levels);
         // base_level是第一次写入的level
@@ -74,11 +907,12 @@ impl LeveledCompactionController {
                 snapshot.levels[i]
                     .1
                     .iter()
-                    .map(|x| snapshot.sstables.get(x).unwrap().table_size())
+                    .filter_map(|x| self.get_sst_meta(snapshot, *x))
+                    .map(|sst| sst.table_size())
                     .sum::<u64>() as usize,
             );
         }
-        let base_level_size_bytes = self.options.base_level_size_mb as usize * 1024 * 1024;
+        let base_level_size_bytes = base_level_size_mb * 1024 * 1024;
 
         // select base level and compute target level size
         target_level_size[self.options.max_levels - 1] =
@@ -93,10 +927,338 @@ impl LeveledCompactionController {
                 base_level = i + 1;
             }
         }
+        // with tiny target sizes the base level may sit below levels that still hold data, L0 must
+        // not skip past them, or newer versions would end up below older ones. The levels above
+        // have a target of 0 and get compacted down over time
+        if let Some(top_level) = snapshot.levels[..self.options.max_levels]
+            .iter()
+            .position(|(_, ssts)| !ssts.is_empty())
+        {
+            base_level = base_level.min(top_level + 1);
+        }
+        (target_level_size, real_level_size, base_level)
+    }
+
+    pub fn generate_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+    ) -> Option<LeveledCompactionTask> {
+        match self.poll_compaction_task(snapshot) {
+            TaskPoll::Task(task) => Some(task),
+            TaskPoll::NoTask | TaskPoll::NoChange => None,
+        }
+    }
+
+    /// Returns `snapshot` after flushing the SST `flushed_sst_id` of `size` bytes to L0, and the
+    /// task the controller would generate for it. An SST missing from the snapshot is added as a
+    /// meta-only SST with an empty key range. The task is picked by a `simulator`, so this neither
+    /// fires `on_task_begin`, updates the state of `with_task_memoization` nor caches the simulated
+    /// SST, to test the trigger logic deterministically.
+    pub fn simulate_flush(
+        &self,
+        snapshot: &LsmStorageState,
+        flushed_sst_id: usize,
+        size: u64,
+    ) -> (LsmStorageState, Option<LeveledCompactionTask>) {
+        let snapshot = Self::with_flushed_sst(snapshot, flushed_sst_id, size);
+        let task = self
+            .simulator()
+            .pick_compaction_task(&snapshot, &mut CompactionStats::default());
+        (snapshot, task)
+    }
+
+    /// A copy of `snapshot` with the SST `flushed_sst_id` of `size` bytes flushed to L0, see
+    /// `simulate_flush`.
+    fn with_flushed_sst(
+        snapshot: &LsmStorageState,
+        flushed_sst_id: usize,
+        size: u64,
+    ) -> LsmStorageState {
+        let mut snapshot = snapshot.clone();
+        snapshot.sstables.entry(flushed_sst_id).or_insert_with(|| {
+            Arc::new(SsTable::create_meta_only(
+                flushed_sst_id,
+                size,
+                KeyBytes::new(),
+                KeyBytes::new(),
+            ))
+        });
+        snapshot.insert_l0_sstable(flushed_sst_id);
+        snapshot
+    }
+
+    /// A controller with the same configuration but without callbacks and with empty caches, to
+    /// plan on simulated SSTs whose ids may later be reused by real ones.
+    fn simulator(&self) -> Self {
+        let mut simulator = Self::new(self.options.clone())
+            .with_target_output_sst_size(self.target_output_sst_size);
+        simulator.shadow_preview = self.shadow_preview;
+        simulator.defer_l0_flush = self.defer_l0_flush;
+        simulator.sst_metas = self.sst_metas.clone();
+        simulator
+    }
+
+    /// Returns the chain of tasks triggered by flushing an SST of `flushed_size` bytes to L0, each
+    /// applied before generating the next, until the tree stabilizes or `MAX_CASCADE_TASKS` tasks
+    /// are generated. The output of a task is modeled as a single meta-only SST with the total
+    /// size and the key range of its inputs. Like `simulate_flush`, the controller is untouched.
+    pub fn simulate_flush_cascade(
+        &self,
+        snapshot: &LsmStorageState,
+        flushed_size: u64,
+    ) -> Vec<LeveledCompactionTask> {
+        // applying a task updates the caches and simulated SST ids may clash with real ones later,
+        // so simulate on a fresh controller
+        let simulator = self.simulator();
+        let mut next_sst_id = snapshot
+            .sstables
+            .keys()
+            .chain(self.sst_metas.keys())
+            .max()
+            .map_or(1, |id| id + 1);
+        let mut snapshot = Self::with_flushed_sst(snapshot, next_sst_id, flushed_size);
+        // put the SSTs from `with_sst_meta` into the simulated snapshot so applying a task checks
+        // outputs against the input ranges
+        for (id, meta) in &self.sst_metas {
+            snapshot.sstables.entry(*id).or_insert_with(|| {
+                Arc::new(SsTable::create_meta_only(
+                    *id,
+                    meta.table_size(),
+                    meta.first_key().clone(),
+                    meta.last_key().clone(),
+                ))
+            });
+        }
+        let mut task = simulator.pick_compaction_task(&snapshot, &mut CompactionStats::default());
+        next_sst_id += 1;
+        let mut cascade = Vec::new();
+        while let Some(next_task) = task {
+            if cascade.len() >= MAX_CASCADE_TASKS {
+                log::warn!(
+                    "compaction cascade is cut at {} tasks, the tree does not stabilize",
+                    MAX_CASCADE_TASKS
+                );
+                break;
+            }
+            let inputs = next_task
+                .upper_level_sst_ids
+                .iter()
+                .chain(next_task.lower_level_sst_ids.iter());
+            let (first_key, last_key) = inputs
+                .clone()
+                .map(|id| simulator.key_range(&snapshot, *id))
+                .reduce(|(first, last), (x_first, x_last)| (first.min(x_first), last.max(x_last)))
+                .unwrap_or_default();
+            let size = inputs.map(|id| snapshot.sstables[id].table_size()).sum();
+            snapshot.sstables.insert(
+                next_sst_id,
+                Arc::new(SsTable::create_meta_only(
+                    next_sst_id,
+                    size,
+                    first_key,
+                    last_key,
+                )),
+            );
+            let (next_snapshot, removed) =
+                simulator.apply_compaction_result(&snapshot, &next_task, &[next_sst_id], false);
+            snapshot = next_snapshot;
+            for id in removed {
+                snapshot.sstables.remove(&id);
+            }
+            next_sst_id += 1;
+            cascade.push(next_task);
+            task = simulator.pick_compaction_task(&snapshot, &mut CompactionStats::default());
+        }
+        cascade
+    }
+
+    /// `generate_compaction_task` telling apart a snapshot with nothing to compact from one that
+    /// is skipped by `with_task_memoization`.
+    pub fn poll_compaction_task(&self, snapshot: &LsmStorageState) -> TaskPoll {
+        if self.memoize_tasks {
+            let fingerprint = std::iter::once(&snapshot.l0_sstables)
+                .chain(snapshot.levels.iter().map(|(_, ssts)| ssts))
+                .map(|ssts| {
+                    let size = ssts
+                        .iter()
+                        .filter_map(|id| snapshot.sstables.get(id))
+                        .map(|sst| sst.table_size())
+                        .sum();
+                    (ssts.len(), size)
+                })
+                .collect::<Vec<_>>();
+            let mut last_fingerprint = self.last_fingerprint.lock();
+            if last_fingerprint.as_ref() == Some(&fingerprint) {
+                return TaskPoll::NoChange;
+            }
+            *last_fingerprint = Some(fingerprint);
+        }
+        let mut stats = CompactionStats::default();
+        let task = self.pick_compaction_task(snapshot, &mut stats);
+        if self.collect_stats {
+            log::debug!("compaction stats: {:?}", stats);
+            *self.last_stats.lock() = Some(stats);
+        }
+        let Some(task) = task else {
+            return TaskPoll::NoTask;
+        };
+        if let Some(on_task_begin) = &self.on_task_begin {
+            on_task_begin(&task);
+        }
+        TaskPoll::Task(task)
+    }
+
+    /// Generate a manual compaction task that pushes the user key range `[lower, upper)` down one
+    /// level, e.g. to garbage collect a range with heavy churn. The task compacts the topmost level
+    /// with SSTs overlapping the range into the next level, calling it repeatedly moves the range
+    /// down level by level. Overlapping L0 SSTs compact the whole L0 into the base level, as a
+    /// subset of L0 could hold versions older than the L0 SSTs left behind. Returns `None` if only
+    /// the bottom level (or no level) overlaps the range.
+    pub fn generate_range_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+        lower: &[u8],
+        upper: &[u8],
+    ) -> Option<LeveledCompactionTask> {
+        let overlaps_range = |sst_id: &usize| {
+            let (first_key, last_key) = self.key_range(snapshot, *sst_id);
+            first_key.key_ref() < upper && last_key.key_ref() >= lower
+        };
+        if snapshot.l0_sstables.iter().any(overlaps_range) {
+            let (_, _, base_level) = self.compute_level_size(snapshot);
+            return Some(LeveledCompactionTask {
+                upper_level: None,
+                upper_level_sst_ids: snapshot.l0_sstables.clone(),
+                lower_level: base_level,
+                lower_level_sst_ids: self.find_overlapping_ssts(
+                    snapshot,
+                    &snapshot.l0_sstables,
+                    base_level,
+                ),
+                is_lower_level_bottom_level: base_level == self.options.max_levels,
+            });
+        }
+        for level in 1..self.options.max_levels {
+            let upper_level_sst_ids = snapshot.levels[level - 1]
+                .1
+                .iter()
+                .copied()
+                .filter(overlaps_range)
+                .collect::<Vec<_>>();
+            if upper_level_sst_ids.is_empty() {
+                continue;
+            }
+            // pick the lower SSTs by the full key range of the chosen SST, not just [lower, upper)
+            let lower_level_sst_ids =
+                self.find_overlapping_ssts(snapshot, &upper_level_sst_ids, level + 1);
+            return Some(LeveledCompactionTask {
+                upper_level: Some(level),
+                upper_level_sst_ids,
+                lower_level: level + 1,
+                lower_level_sst_ids,
+                is_lower_level_bottom_level: level + 1 == self.options.max_levels,
+            });
+        }
+        None
+    }
+
+    /// Generate a manual compaction task that moves every SST of `from_level` directly into
+    /// `to_level`, merging with the SSTs it overlaps there, e.g. to reorganize the tree when the
+    /// levels in between are empty. The levels in between must not overlap the moved SSTs, or the
+    /// moved (newer) versions would end up below older ones. Returns `None` if `from_level` is
+    /// empty.
+    pub fn generate_cross_level_task(
+        &self,
+        snapshot: &LsmStorageState,
+        from_level: usize,
+        to_level: usize,
+    ) -> Result<Option<LeveledCompactionTask>> {
+        if from_level == 0 || to_level <= from_level || to_level > self.options.max_levels {
+            bail!(
+                "cannot compact from level {} to level {}, expected 1 <= from_level < to_level <= {}",
+                from_level,
+                to_level,
+                self.options.max_levels
+            );
+        }
+        let upper_level_sst_ids = snapshot.levels[from_level - 1].1.clone();
+        if upper_level_sst_ids.is_empty() {
+            return Ok(None);
+        }
+        for level in from_level + 1..to_level {
+            let overlap_ssts = self.find_overlapping_ssts(snapshot, &upper_level_sst_ids, level);
+            if !overlap_ssts.is_empty() {
+                bail!(
+                    "level {} overlaps the SSTs of level {}: {:?}",
+                    level,
+                    from_level,
+                    overlap_ssts
+                );
+            }
+        }
+        let lower_level_sst_ids =
+            self.find_overlapping_ssts(snapshot, &upper_level_sst_ids, to_level);
+        Ok(Some(LeveledCompactionTask {
+            upper_level: Some(from_level),
+            upper_level_sst_ids,
+            lower_level: to_level,
+            lower_level_sst_ids,
+            is_lower_level_bottom_level: to_level == self.options.max_levels,
+        }))
+    }
+
+    /// Generate a task merging L0 and every level into a single sorted run at the bottom level,
+    /// dropping all deleted and overwritten keys. It is never picked by the triggers and is meant
+    /// to be called on demand. The L0 SSTs and the levels above the bottom are all taken as the
+    /// upper level of an L0 task, newest first. Returns `None` if there is no SST at all.
+    pub fn generate_full_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+    ) -> Option<LeveledCompactionTask> {
+        let bottom_level = self.options.max_levels;
+        let mut upper_level_sst_ids = snapshot.l0_sstables.clone();
+        for (_, level_ssts) in &snapshot.levels[..bottom_level - 1] {
+            upper_level_sst_ids.extend(level_ssts);
+        }
+        let lower_level_sst_ids = snapshot.levels[bottom_level - 1].1.clone();
+        if upper_level_sst_ids.is_empty() && lower_level_sst_ids.is_empty() {
+            return None;
+        }
+        Some(LeveledCompactionTask {
+            upper_level: None,
+            upper_level_sst_ids,
+            lower_level: bottom_level,
+            lower_level_sst_ids,
+            is_lower_level_bottom_level: true,
+        })
+    }
+
+    /// Pick the next task for `snapshot`, filling `stats` with the level sizes and priorities it
+    /// is picked from.
+    fn pick_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+        stats: &mut CompactionStats,
+    ) -> Option<LeveledCompactionTask> {
+        // step 1: compute target level size
+        let (target_level_size, real_level_size, base_level) = self.compute_level_size(snapshot);
+        stats.target_level_sizes = target_level_size.clone();
+        stats.real_level_sizes = real_level_size.clone();
+        stats.base_level = base_level;
 
-        // Flush L0 SST is the top priority
-        if snapshot.l0_sstables.len() >= self.options.level0_file_num_compaction_trigger {
-            println!("flush L0 SST to base level {}", base_level);
+        // Flush L0 SST is the top priority. When L0 is grouped into sorted runs, each run only costs
+        // one read, so the trigger counts runs; all runs are compacted, so a run is never split.
+        let l0_read_amp = match &snapshot.l0_sorted_runs {
+            Some(runs) => runs.len(),
+            None => snapshot.l0_sstables.len(),
+        };
+        // a trigger of 0 is met by an empty L0 too, but there is nothing to compact
+        if l0_read_amp >= self.options.level0_file_num_compaction_trigger
+            && !snapshot.l0_sstables.is_empty()
+        {
+            if self.collect_stats {
+                log::debug!("flush L0 SST to base level {}", base_level);
+            }
             return Some(LeveledCompactionTask {
                 upper_level: None,
                 upper_level_sst_ids: snapshot.l0_sstables.clone(),
@@ -110,51 +1272,490 @@ impl LeveledCompactionController {
             });
         }
 
+        let newest_sst_id = self.newest_sst_id(snapshot);
         // 计算优先级，寻找优先级最大的层
         let mut priorities = Vec::with_capacity(self.options.max_levels);
-        for level in 0..self.options.max_levels {
-            let prio = real_level_size[level] as f64 / target_level_size[level] as f64;
-            if prio > 1.0 {
-                priorities.push((prio, level + 1));
+        if self.options.prioritize_by_age {
+            // only the levels over their target size, the last level has no lower level to merge
+            // into. A level is as old as the smallest `max_ts` of its SSTs
+            let newest_ts = self.newest_max_ts(snapshot);
+            for level in 1..self.options.max_levels {
+                if real_level_size[level - 1] <= target_level_size[level - 1] {
+                    continue;
+                }
+                let oldest_ts = snapshot.levels[level - 1]
+                    .1
+                    .iter()
+                    .filter_map(|id| self.get_sst_meta(snapshot, *id))
+                    .map(|sst| sst.max_ts())
+                    .min();
+                if let Some(oldest_ts) = oldest_ts {
+                    let age = newest_ts.saturating_sub(oldest_ts);
+                    priorities.push((age as f64, level));
+                }
+            }
+        } else {
+            for level in 0..self.options.max_levels {
+                let mut prio = real_level_size[level] as f64 / target_level_size[level] as f64;
+                // the last level has no lower level to merge into, never trigger by entry count
+                if let Some(trigger) = self.options.level_entry_count_trigger {
+                    if level + 1 < self.options.max_levels {
+                        let entries = self.level_entry_count(snapshot, level + 1);
+                        prio = prio.max(entries as f64 / trigger as f64);
+                    }
+                }
+                if prio > 1.0 {
+                    priorities.push((prio, level + 1));
+                }
             }
         }
         priorities.sort_by(|a, b| a.partial_cmp(b).unwrap().reverse());
-        let priority = priorities.first();
-        if let Some((_, level)) = priority {
-            println!(
-                "target level sizes: {:?}, real level sizes: {:?}, base_level: {}",
-                target_level_size
-                    .iter()
-                    .map(|x| format!("{}MB", x / 1024 / 1024))
-                    .collect::<Vec<_>>(),
-                real_level_size
-                    .iter()
-                    .map(|x| format!("{}MB", x / 1024 / 1024))
-                    .collect::<Vec<_>>(),
-                base_level,
-            );
-            let level = *level;
-            // .copied()：这个方法将引用转换为值类型，通常用于从 Option<&T> 转换为 Option<T>
-            let selected_sst = snapshot.levels[level - 1].1.iter().min().copied().unwrap();
-            println!(
-                "compaction triggered by priority: {level} out of {:?}, select {selected_sst} for compaction",
-                priorities
-            );
+        if priorities.is_empty() {
+            return None;
+        }
+        for &(prio, level) in &priorities {
+            let Some((selected_sst, lower_level_sst_ids)) =
+                self.select_upper_level_sst(snapshot, level, newest_sst_id)
+            else {
+                continue;
+            };
+            if self.collect_stats {
+                log::debug!(
+                    "compaction triggered by priority: {level} out of {:?}, select {selected_sst} for compaction",
+                    priorities
+                );
+            }
+            stats.winning_priority = Some((prio, level));
             return Some(LeveledCompactionTask {
                 upper_level: Some(level),
                 upper_level_sst_ids: vec![selected_sst],
                 lower_level: level + 1,
-                lower_level_sst_ids: self.find_overlapping_ssts(
-                    snapshot,
-                    &[selected_sst],
-                    level + 1,
-                ),
+                lower_level_sst_ids,
                 is_lower_level_bottom_level: level + 1 == self.options.max_levels,
             });
         }
         None
     }
 
+    /// Select the SST of `level` to compact into the next level and the SSTs it overlaps there,
+    /// following `pick_strategy`, `min_sst_age_before_compaction`, `abort_overlap_ratio` and
+    /// `max_compaction_bytes`. Returns `None` if every SST is too young or overlaps too much.
+    fn select_upper_level_sst(
+        &self,
+        snapshot: &LsmStorageState,
+        level: usize,
+        newest_sst_id: usize,
+    ) -> Option<(usize, Vec<usize>)> {
+        // pick in pick_strategy order, skipping SSTs that are too new or overlap the lower level
+        // too much
+        let mut candidates = snapshot.levels[level - 1].1.clone();
+        match self.options.pick_strategy {
+            CompactionPickStrategy::LowestId => candidates.sort(),
+            CompactionPickStrategy::OldestByFirstKey => {
+                candidates.sort_by_key(|id| (self.key_range(snapshot, *id).0, *id))
+            }
+            CompactionPickStrategy::FewestOverlaps => candidates.sort_by_cached_key(|id| {
+                let overlap_ssts = self.find_overlapping_ssts(snapshot, &[*id], level + 1);
+                (overlap_ssts.len(), *id)
+            }),
+        }
+        candidates.retain(|id| {
+            !self.is_too_young(newest_sst_id, *id)
+                && !self.exceeds_abort_overlap_ratio(snapshot, *id, level + 1)
+        });
+        if candidates.is_empty() {
+            if self.collect_stats {
+                log::debug!(
+                    "compaction of level {level} deferred: every SST is too young or overlaps too much"
+                );
+            }
+            return None;
+        }
+        // with an input size limit pick the first SST within the limit, or the smallest input if
+        // none fits
+        let selected = match self.options.max_compaction_bytes {
+            Some(max_bytes) => candidates
+                .iter()
+                .find_map(|id| {
+                    self.find_overlapping_ssts_bounded(snapshot, *id, level + 1, max_bytes)
+                        .map(|overlap_ssts| (*id, overlap_ssts))
+                })
+                .unwrap_or_else(|| {
+                    let id = candidates
+                        .iter()
+                        .copied()
+                        .min_by_key(|id| {
+                            self.sst_meta(snapshot, *id).table_size()
+                                + self.overlapping_bytes(snapshot, *id, level + 1)
+                        })
+                        .unwrap();
+                    (id, self.find_overlapping_ssts(snapshot, &[id], level + 1))
+                }),
+            None => (
+                candidates[0],
+                self.find_overlapping_ssts(snapshot, &[candidates[0]], level + 1),
+            ),
+        };
+        Some(selected)
+    }
+
+    /// Whether fewer than `min_sst_age_before_compaction` SSTs have been written after `sst_id`.
+    fn is_too_young(&self, newest_sst_id: usize, sst_id: usize) -> bool {
+        self.options
+            .min_sst_age_before_compaction
+            .is_some_and(|min_age| (newest_sst_id.saturating_sub(sst_id) as u64) < min_age)
+    }
+
+    /// The largest SST id of the snapshot and of `with_sst_meta`, the reference for SST ages.
+    fn newest_sst_id(&self, snapshot: &LsmStorageState) -> usize {
+        snapshot
+            .sstables
+            .keys()
+            .chain(self.sst_metas.keys())
+            .max()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The largest `max_ts` of the snapshot and of `with_sst_meta`, the reference for the age of
+    /// the data in `prioritize_by_age`.
+    fn newest_max_ts(&self, snapshot: &LsmStorageState) -> u64 {
+        snapshot
+            .sstables
+            .values()
+            .map(|sst| sst.max_ts())
+            .chain(self.sst_metas.values().map(|meta| meta.max_ts()))
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Whether compacting `sst_id` into `lower_level` rewrites more than `abort_overlap_ratio`
+    /// times its own size in the lower level.
+    fn exceeds_abort_overlap_ratio(
+        &self,
+        snapshot: &LsmStorageState,
+        sst_id: usize,
+        lower_level: usize,
+    ) -> bool {
+        let Some(ratio) = self.options.abort_overlap_ratio else {
+            return false;
+        };
+        let upper_bytes = self.sst_meta(snapshot, sst_id).table_size().max(1);
+        self.overlapping_bytes(snapshot, sst_id, lower_level) as f64 / upper_bytes as f64 > ratio
+    }
+
+    /// Partition the key space covered by `level` (0 for L0) at the first and last keys of its SSTs,
+    /// and report how many SSTs cover each range as `(begin, end, depth)`. Adjacent ranges share
+    /// their boundary key, and neighbouring ranges with the same depth are merged. Gaps not covered
+    /// by any SST are left out. This is mostly useful to find the hot overlap zones of L0.
+    pub fn overlap_depth_map(
+        &self,
+        snapshot: &LsmStorageState,
+        level: usize,
+    ) -> Vec<(KeyVec, KeyVec, usize)> {
+        let sst_ids = if level == 0 {
+            &snapshot.l0_sstables
+        } else {
+            &snapshot.levels[level - 1].1
+        };
+        let ssts = sst_ids
+            .iter()
+            .map(|id| &snapshot.sstables[id])
+            .collect::<Vec<_>>();
+        let mut boundaries = ssts
+            .iter()
+            .flat_map(|sst| [sst.first_key(), sst.last_key()])
+            .collect::<Vec<_>>();
+        boundaries.sort();
+        boundaries.dedup();
+        let mut ranges: Vec<(KeyVec, KeyVec, usize)> = Vec::new();
+        for pair in boundaries.windows(2) {
+            let (begin, end) = (pair[0], pair[1]);
+            let depth = ssts
+                .iter()
+                .filter(|sst| sst.first_key() <= begin && sst.last_key() >= end)
+                .count();
+            if depth == 0 {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(last) if last.2 == depth && last.1.as_key_slice() == begin.as_key_slice() => {
+                    last.1 = end.as_key_slice().to_key_vec();
+                }
+                _ => ranges.push((
+                    begin.as_key_slice().to_key_vec(),
+                    end.as_key_slice().to_key_vec(),
+                    depth,
+                )),
+            }
+        }
+        // a single-point SST has no adjacent boundary
+        if boundaries.len() == 1 {
+            let key = boundaries[0].as_key_slice().to_key_vec();
+            ranges.push((key.clone(), key, ssts.len()));
+        }
+        ranges
+    }
+
+    /// Whether the SSTs of `level` (0 for L0) together cover every user key in `[lower, upper)`.
+    /// An SST covers `[first_key, last_key]`, so two adjacent SSTs leave a gap unless the next one
+    /// starts at the immediate successor of the previous one's last key (`last_key` + `\0`).
+    /// An empty range is always covered.
+    pub fn is_level_contiguous(
+        &self,
+        snapshot: &LsmStorageState,
+        level: usize,
+        lower: &[u8],
+        upper: &[u8],
+    ) -> bool {
+        if lower >= upper {
+            return true;
+        }
+        let sst_ids = if level == 0 {
+            &snapshot.l0_sstables
+        } else {
+            &snapshot.levels[level - 1].1
+        };
+        let mut ranges = sst_ids
+            .iter()
+            .map(|id| self.key_range(snapshot, *id))
+            .collect::<Vec<_>>();
+        ranges.sort();
+        // the covered range is [lower, covered_end)
+        let mut covered_end = lower.to_vec();
+        for (first_key, last_key) in ranges {
+            if first_key.key_ref() > covered_end.as_slice() {
+                return false;
+            }
+            let mut end = last_key.key_ref().to_vec();
+            end.push(0);
+            if end > covered_end {
+                covered_end = end;
+            }
+            if covered_end.as_slice() >= upper {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Split the SSTs of `level` (1 for L1, L0 SSTs overlap and cannot be split by key range) into
+    /// at most `num_partitions` groups of adjacent SSTs for a parallel scan, balanced by bytes so
+    /// that the largest group is as small as possible. Each group is returned as a half-open range
+    /// `[begin, end)` of positions in the level's SST id list, in key order.
+    pub fn partition_level_for_parallel_scan(
+        &self,
+        snapshot: &LsmStorageState,
+        level: usize,
+        num_partitions: usize,
+    ) -> Vec<(usize, usize)> {
+        assert!(level > 0, "L0 cannot be partitioned by key range");
+        let sizes = snapshot.levels[level - 1]
+            .1
+            .iter()
+            .map(|id| snapshot.sstables[id].table_size())
+            .collect::<Vec<_>>();
+        if sizes.is_empty() || num_partitions == 0 {
+            return Vec::new();
+        }
+        // greedily pack adjacent SSTs into groups of at most max_bytes
+        let partition = |max_bytes: u64| {
+            let mut partitions = Vec::new();
+            let mut begin = 0;
+            let mut bytes = 0;
+            for (idx, size) in sizes.iter().enumerate() {
+                if idx > begin && bytes + size > max_bytes {
+                    partitions.push((begin, idx));
+                    begin = idx;
+                    bytes = 0;
+                }
+                bytes += size;
+            }
+            partitions.push((begin, sizes.len()));
+            partitions
+        };
+        // binary search the smallest group size that needs at most `num_partitions` groups
+        let mut low = *sizes.iter().max().unwrap();
+        let mut high = sizes.iter().sum::<u64>();
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if partition(mid).len() <= num_partitions {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+        partition(low)
+    }
+
+    /// Split a global compaction write budget (bytes/sec) across L1..=Lmax, returned in level order.
+    /// Each level gets a share proportional to its target size when the tree is fully grown, which
+    /// grows by `level_size_multiplier` per level, so deeper levels get a larger budget. The
+    /// budgets always sum up to `total_budget_bytes_per_sec`.
+    pub fn level_write_budget(&self, total_budget_bytes_per_sec: u64) -> Vec<u64> {
+        let max_levels = self.options.max_levels;
+        if max_levels == 0 {
+            return Vec::new();
+        }
+        let multiplier = self.options.level_size_multiplier as f64;
+        let weights = (0..max_levels)
+            .map(|level| multiplier.powi(level as i32))
+            .collect::<Vec<_>>();
+        let total_weight = weights.iter().sum::<f64>();
+        let mut budgets = weights[..max_levels - 1]
+            .iter()
+            .map(|weight| (total_budget_bytes_per_sec as f64 * weight / total_weight) as u64)
+            .collect::<Vec<_>>();
+        // the bottom level gets the rest, including the rounding error
+        let assigned = budgets.iter().sum::<u64>();
+        budgets.push(total_budget_bytes_per_sec.saturating_sub(assigned));
+        budgets
+    }
+
+    /// Recommend `max_levels` for a dataset of `dataset_bytes`: the number of levels needed for the
+    /// bottom level to hold the whole dataset, starting from `base_level_size_mb` and growing by
+    /// `level_size_multiplier` per level. With too few levels, the bottom level grows beyond its
+    /// target and compaction falls behind the ingest.
+    pub fn recommend_max_levels(&self, dataset_bytes: usize) -> usize {
+        assert!(
+            self.options.level_size_multiplier > 1,
+            "level size multiplier must be larger than 1"
+        );
+        let mut level_size = self.options.base_level_size_mb * 1024 * 1024;
+        let mut levels = 1;
+        while level_size < dataset_bytes {
+            level_size = level_size.saturating_mul(self.options.level_size_multiplier);
+            levels += 1;
+        }
+        levels
+    }
+
+    /// The inverse of `recommend_max_levels`: the multiplier with which `levels` levels starting
+    /// from `base_mb` grow to exactly `dataset_bytes` at the bottom level. Never below 1, which is
+    /// returned when the dataset fits in the base level or there is a single level.
+    /// `level_size_multiplier` is an integer, round the result up to configure it.
+    pub fn optimal_multiplier(dataset_bytes: usize, base_mb: usize, levels: usize) -> f64 {
+        let base_bytes = (base_mb * 1024 * 1024) as f64;
+        if levels <= 1 || dataset_bytes as f64 <= base_bytes {
+            return 1.0;
+        }
+        (dataset_bytes as f64 / base_bytes).powf(1.0 / (levels - 1) as f64)
+    }
+
+    /// Estimate the number of distinct user keys in `level` (0 for L0) by merging the per-SST
+    /// key sketches, so that multiple versions of a key are only counted once. SSTs without a
+    /// sketch (e.g. meta-only SSTs) are ignored.
+    pub fn estimate_unique_keys(&self, snapshot: &LsmStorageState, level: usize) -> usize {
+        let sst_ids = if level == 0 {
+            &snapshot.l0_sstables
+        } else {
+            &snapshot.levels[level - 1].1
+        };
+        let mut sketch = HyperLogLog::new();
+        for id in sst_ids {
+            if let Some(sst_sketch) = &snapshot.sstables[id].sketch {
+                sketch.merge(sst_sketch);
+            }
+        }
+        sketch.estimate().round() as usize
+    }
+
+    /// Scan `level` (0 for L0) and map each number of versions to the number of user keys with
+    /// that many versions, to find keys piling up versions that compaction should collect.
+    pub fn version_count_histogram(
+        &self,
+        snapshot: &LsmStorageState,
+        level: usize,
+    ) -> Result<BTreeMap<usize, usize>> {
+        let sst_ids = if level == 0 {
+            &snapshot.l0_sstables
+        } else {
+            &snapshot.levels[level - 1].1
+        };
+        let mut iters = Vec::with_capacity(sst_ids.len());
+        for id in sst_ids {
+            let Some(sst) = snapshot.sstables.get(id) else {
+                bail!("SST {} is not in the snapshot", id);
+            };
+            iters.push(Box::new(SsTableIterator::create_and_seek_to_first(
+                sst.clone(),
+            )?));
+        }
+        let mut iter = MergeIterator::create(iters);
+        let mut histogram = BTreeMap::new();
+        // current user key and its number of versions
+        let mut current: Option<(Vec<u8>, usize)> = None;
+        while iter.is_valid() {
+            let user_key = iter.key().key_ref();
+            match &mut current {
+                Some((key, versions)) if key.as_slice() == user_key => *versions += 1,
+                _ => {
+                    if let Some((_, versions)) = current.replace((user_key.to_vec(), 1)) {
+                        *histogram.entry(versions).or_insert(0) += 1;
+                    }
+                }
+            }
+            iter.next()?;
+        }
+        if let Some((_, versions)) = current {
+            *histogram.entry(versions).or_insert(0) += 1;
+        }
+        Ok(histogram)
+    }
+
+    /// Compute what `apply_compaction_result` would change for `task` without cloning `snapshot`,
+    /// e.g. to explain a task. A task that is already applied moves and removes nothing.
+    pub fn preview(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &LeveledCompactionTask,
+    ) -> CompactionPreview {
+        let lower_level_ssts = &snapshot.levels[task.lower_level - 1].1;
+        if is_task_applied(snapshot, task) {
+            return CompactionPreview {
+                kept_lower_level_ssts: lower_level_ssts.clone(),
+                ..Default::default()
+            };
+        }
+        let moved_out = vec![
+            (
+                task.upper_level.unwrap_or(0),
+                task.upper_level_sst_ids.clone(),
+            ),
+            (task.lower_level, task.lower_level_sst_ids.clone()),
+        ];
+        let removed = task
+            .upper_level_sst_ids
+            .iter()
+            .chain(&task.lower_level_sst_ids)
+            .copied()
+            .collect();
+        let kept_lower_level_ssts = lower_level_ssts
+            .iter()
+            .copied()
+            .filter(|id| !task.lower_level_sst_ids.contains(id))
+            .collect::<Vec<_>>();
+        // the output covers the key range of all inputs, place it after the SSTs with a smaller
+        // first key
+        let output_position = match task_key_range(snapshot, task) {
+            Some((begin, _)) => kept_lower_level_ssts
+                .iter()
+                .take_while(|id| self.key_range(snapshot, **id).0.key_ref() < begin)
+                .count(),
+            None => kept_lower_level_ssts.len(),
+        };
+        CompactionPreview {
+            moved_out,
+            removed,
+            kept_lower_level_ssts,
+            output_position,
+        }
+    }
+
+    /// Apply `task` with its `output` SSTs to `snapshot`, returning the new state and the SSTs to
+    /// remove. A task whose inputs are all gone from their levels has already been applied, e.g.
+    /// when it is retried after a crash, and is a no-op that removes nothing. Inputs that are only
+    /// partly gone still panic, as that cannot come from applying the task twice.
     pub fn apply_compaction_result(
         &self,
         snapshot: &LsmStorageState,
@@ -162,6 +1763,37 @@ impl LeveledCompactionController {
         output: &[usize],
         in_recovery: bool,
     ) -> (LsmStorageState, Vec<usize>) {
+        if is_task_applied(snapshot, task) {
+            log::debug!("compaction task already applied, skipping: {:?}", task);
+            return (snapshot.clone(), Vec::new());
+        }
+        if !in_recovery {
+            debug_assert_output_within_input_range(snapshot, task, output);
+        }
+        if self.retain_rollback_state {
+            *self.rollback_state.lock() = Some(Box::new(snapshot.clone()));
+        }
+        // SSTs are not loaded during recovery, so skip the stats and the callback
+        let stats = match &self.on_task_end {
+            Some(_) if !in_recovery => {
+                let size_of = |ids: &mut dyn Iterator<Item = &usize>| {
+                    ids.map(|id| snapshot.sstables[id].table_size()).sum()
+                };
+                Some(CompactionTaskStats {
+                    input_sst_count: task.upper_level_sst_ids.len()
+                        + task.lower_level_sst_ids.len(),
+                    output_sst_count: output.len(),
+                    input_bytes: size_of(
+                        &mut task
+                            .upper_level_sst_ids
+                            .iter()
+                            .chain(task.lower_level_sst_ids.iter()),
+                    ),
+                    output_bytes: size_of(&mut output.iter()),
+                })
+            }
+            _ => None,
+        };
         let mut snapshot = snapshot.clone();
         let mut files_to_remove = Vec::new();
         let mut upper_level_sst_ids_set = task
@@ -201,8 +1833,16 @@ impl LeveledCompactionController {
                     Some(*x)
                 })
                 .collect::<Vec<_>>();
-            assert!(upper_level_sst_ids_set.is_empty());
             snapshot.l0_sstables = new_l0_ssts;
+            snapshot.sync_l0_sorted_runs();
+            // a full compaction also takes every level between L0 and the bottom level
+            for (_, level_ssts) in &mut snapshot.levels[..task.lower_level - 1] {
+                if upper_level_sst_ids_set.is_empty() {
+                    break;
+                }
+                level_ssts.retain(|x| !upper_level_sst_ids_set.remove(x));
+            }
+            assert!(upper_level_sst_ids_set.is_empty());
         }
         // 添加需要移除的元素
         files_to_remove.extend(&task.upper_level_sst_ids);
@@ -221,20 +1861,141 @@ impl LeveledCompactionController {
             .collect::<Vec<_>>();
         assert!(lower_level_sst_ids_set.is_empty());
         new_lower_level_ssts.extend(output);
-        println!("new_lower_level_ssts: {:?}", new_lower_level_ssts);
+        log::debug!("new_lower_level_ssts: {:?}", new_lower_level_ssts);
         // 在清单恢复阶段，SST 文件并未加载到内存中。换句话说，snapshot.sstables 可能并没有实际的 SST 文件内容。
         // 因此，排序操作依赖于一个假设：这些 SST 文件已经加载并且你可以访问它们的 first_key，但实际上这个假设在清单恢复时并不成立。
         if !in_recovery {
             new_lower_level_ssts.sort_by(|x, y| {
-                snapshot
-                    .sstables
-                    .get(x)
-                    .unwrap()
+                self.sst_meta(&snapshot, *x)
                     .first_key()
-                    .cmp(snapshot.sstables.get(y).unwrap().first_key())
+                    .cmp(self.sst_meta(&snapshot, *y).first_key())
             });
         }
         snapshot.levels[task.lower_level - 1].1 = new_lower_level_ssts;
+        let mut key_range_cache = self.key_range_cache.lock();
+        for id in &files_to_remove {
+            key_range_cache.remove(id);
+        }
+        if !in_recovery {
+            for id in output {
+                let sst = self.sst_meta(&snapshot, *id);
+                key_range_cache.insert(*id, (sst.first_key().clone(), sst.last_key().clone()));
+            }
+        }
+        drop(key_range_cache);
+        let mut entry_count_cache = self.entry_count_cache.lock();
+        for id in &files_to_remove {
+            entry_count_cache.remove(id);
+        }
+        drop(entry_count_cache);
+        if let (Some(stats), Some(on_task_end)) = (stats, &self.on_task_end) {
+            on_task_end(task, output, &stats);
+        }
         (snapshot, files_to_remove)
     }
 }
+
+/// Whether none of the input SSTs of `task` is in its level any more. A task without inputs is
+/// never considered applied.
+fn is_task_applied(snapshot: &LsmStorageState, task: &LeveledCompactionTask) -> bool {
+    let in_upper_level = |id: &usize| match task.upper_level {
+        Some(upper_level) => snapshot.levels[upper_level - 1].1.contains(id),
+        // a full compaction also takes the levels above the lower level as its upper level
+        None => {
+            snapshot.l0_sstables.contains(id)
+                || snapshot.levels[..task.lower_level - 1]
+                    .iter()
+                    .any(|(_, level_ssts)| level_ssts.contains(id))
+        }
+    };
+    let lower_level_ssts = &snapshot.levels[task.lower_level - 1].1;
+    (!task.upper_level_sst_ids.is_empty() || !task.lower_level_sst_ids.is_empty())
+        && task
+            .upper_level_sst_ids
+            .iter()
+            .all(|id| !in_upper_level(id))
+        && task
+            .lower_level_sst_ids
+            .iter()
+            .all(|id| !lower_level_ssts.contains(id))
+}
+
+/// Check that the user keys of the compaction output fall within the user key range of the inputs,
+/// a key outside of it can only come from a bug in the merge. Both the input and output SSTs must
+/// be in `snapshot.sstables`, so this is skipped during recovery.
+fn debug_assert_output_within_input_range(
+    snapshot: &LsmStorageState,
+    task: &LeveledCompactionTask,
+    output: &[usize],
+) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let Some((input_min, input_max)) = task_key_range(snapshot, task) else {
+        return;
+    };
+    if let Some((output_min, output_max)) = user_key_range(snapshot, output.iter()) {
+        debug_assert!(
+            input_min <= output_min && output_max <= input_max,
+            "compaction output range {:?}..={:?} is not within input range {:?}..={:?}",
+            Bytes::copy_from_slice(output_min),
+            Bytes::copy_from_slice(output_max),
+            Bytes::copy_from_slice(input_min),
+            Bytes::copy_from_slice(input_max),
+        );
+    }
+}
+
+/// The user key range covered by the SSTs `ids`, SSTs missing from the snapshot are ignored.
+fn user_key_range<'a, 'b>(
+    snapshot: &'a LsmStorageState,
+    ids: impl Iterator<Item = &'b usize>,
+) -> Option<(&'a [u8], &'a [u8])> {
+    ids.filter_map(|id| snapshot.sstables.get(id))
+        .map(|sst| (sst.first_key().key_ref(), sst.last_key().key_ref()))
+        .reduce(|(min, max), (first, last)| (min.min(first), max.max(last)))
+}
+
+/// The user key range of all input SSTs of `task`.
+fn task_key_range<'a>(
+    snapshot: &'a LsmStorageState,
+    task: &LeveledCompactionTask,
+) -> Option<(&'a [u8], &'a [u8])> {
+    user_key_range(
+        snapshot,
+        task.upper_level_sst_ids
+            .iter()
+            .chain(task.lower_level_sst_ids.iter()),
+    )
+}
+
+/// Whether two compaction tasks cannot run at the same time: they share an input SST, or they
+/// write to the same lower level with overlapping key ranges, so that their outputs would overlap
+/// in that level.
+pub fn tasks_conflict(
+    snapshot: &LsmStorageState,
+    a: &LeveledCompactionTask,
+    b: &LeveledCompactionTask,
+) -> bool {
+    let a_ssts = a
+        .upper_level_sst_ids
+        .iter()
+        .chain(a.lower_level_sst_ids.iter())
+        .collect::<HashSet<_>>();
+    if b.upper_level_sst_ids
+        .iter()
+        .chain(b.lower_level_sst_ids.iter())
+        .any(|id| a_ssts.contains(id))
+    {
+        return true;
+    }
+    if a.lower_level != b.lower_level {
+        return false;
+    }
+    match (task_key_range(snapshot, a), task_key_range(snapshot, b)) {
+        (Some((a_first, a_last)), Some((b_first, b_last))) => {
+            !(a_last < b_first || b_last < a_first)
+        }
+        _ => false,
+    }
+}
@@ -1,8 +1,10 @@
+use std::collections::HashSet;
+
 use serde::{Deserialize, Serialize};
 
 use crate::lsm_storage::LsmStorageState;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SimpleLeveledCompactionOptions {
     // lower level number of files / upper level number of files. When the ratio is too low (upper level has too many files), we should trigger a compaction.
     pub size_ratio_percent: usize,
@@ -79,12 +81,30 @@ impl SimpleLeveledCompactionController {
     /// and `sstables` hash map. Though there should only be one thread running compaction jobs, you should think about the case
     /// where an L0 SST gets flushed while the compactor generates new SSTs, and with that in mind, you should do some sanity checks
     /// in your implementation.
+    ///
+    /// L0 SSTs flushed after the task was generated are kept. `in_recovery` is set when replaying the
+    /// manifest, where the SSTs are not loaded.
     pub fn apply_compaction_result(
         &self,
         _snapshot: &LsmStorageState,
         _task: &SimpleLeveledCompactionTask,
         _output: &[usize],
+        in_recovery: bool,
     ) -> (LsmStorageState, Vec<usize>) {
+        // the output replaces the lower level as a whole and is sorted, SSTs are not loaded during
+        // recovery so only check outside of it
+        if !in_recovery {
+            debug_assert!(
+                _output
+                    .windows(2)
+                    .filter_map(|pair| _snapshot
+                        .sstables
+                        .get(&pair[0])
+                        .zip(_snapshot.sstables.get(&pair[1])))
+                    .all(|(prev, next)| prev.last_key() < next.first_key()),
+                "compaction output is not sorted by key"
+            );
+        }
         let mut snapshot = _snapshot.clone();
         let mut files_to_remove = Vec::new();
         // 如果 upper_level 存在
@@ -98,12 +118,18 @@ impl SimpleLeveledCompactionController {
             files_to_remove.extend(&snapshot.levels[upper_level - 1].1);
             snapshot.levels[upper_level - 1].1.clear();
         } else {
-            assert_eq!(
-                _task.upper_level_sst_ids, snapshot.l0_sstables,
-                "sst mismatched"
-            );
-            files_to_remove.extend(&snapshot.l0_sstables);
-            snapshot.l0_sstables.clear();
+            // new L0 SSTs may have been flushed during compaction, only remove the ones in the task
+            let mut l0_ssts_compacted = _task
+                .upper_level_sst_ids
+                .iter()
+                .copied()
+                .collect::<HashSet<_>>();
+            snapshot
+                .l0_sstables
+                .retain(|x| !l0_ssts_compacted.remove(x));
+            assert!(l0_ssts_compacted.is_empty(), "sst mismatched");
+            files_to_remove.extend(&_task.upper_level_sst_ids);
+            snapshot.sync_l0_sorted_runs();
         }
         assert_eq!(
             _task.lower_level_sst_ids,
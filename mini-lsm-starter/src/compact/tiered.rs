@@ -3,20 +3,29 @@ use std::collections::HashMap;
 
 use crate::lsm_storage::LsmStorageState;
 
+/// Merges the newest tiers (sorted runs) of `LsmStorageState::levels` into one tier.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TieredCompactionTask {
     pub tiers: Vec<(usize, Vec<usize>)>,
     pub bottom_tier_included: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TieredCompactionOptions {
+    /// Compaction starts once there are this many tiers, and reduces the tiers below it.
     pub num_tiers: usize,
+    /// Compact all tiers once the tiers above the bottom tier are this large, in percent of the
+    /// bottom tier.
     pub max_size_amplification_percent: usize,
+    /// Compact the tiers above a tier that is smaller than them by this percent.
     pub size_ratio: usize,
+    /// The minimum number of tiers merged by the size ratio trigger.
     pub min_merge_width: usize,
 }
 
+/// Size-tiered (universal) compaction: each flush creates a new tier at the top, and compaction
+/// merges tiers, triggered in order by space amplification, by size ratio, and by the number of
+/// tiers. Sizes are counted in SSTs.
 pub struct TieredCompactionController {
     options: TieredCompactionOptions,
 }
@@ -80,27 +89,44 @@ impl TieredCompactionController {
         // trying to reduce sorted runs without respecting size ratio
         let num_tiers_to_take = snapshot.levels.len() - self.options.num_tiers + 2;
         println!("compaction triggered by reducing sorted runs");
-        return Some(TieredCompactionTask {
+        Some(TieredCompactionTask {
             tiers: snapshot
                 .levels
                 .iter()
                 .take(num_tiers_to_take)
                 .cloned()
                 .collect::<Vec<_>>(),
-            bottom_tier_included: snapshot.levels.len() >= num_tiers_to_take,
-        });
+            bottom_tier_included: num_tiers_to_take >= snapshot.levels.len(),
+        })
     }
 
+    /// Replace the tiers of `task` with a tier of `output`, placed where the merged tiers were.
+    /// Returns the new state and the SSTs to remove. The output is written in key order, so
+    /// nothing is sorted; it is only checked when not `in_recovery`, as the SSTs are not loaded
+    /// during recovery. An empty output, e.g. when every key is deleted, removes the tiers.
     pub fn apply_compaction_result(
         &self,
         snapshot: &LsmStorageState,
         task: &TieredCompactionTask,
         output: &[usize],
+        in_recovery: bool,
     ) -> (LsmStorageState, Vec<usize>) {
         assert!(
             snapshot.l0_sstables.is_empty(),
             "should not add l0 ssts in tiered compaction"
         );
+        if !in_recovery {
+            debug_assert!(
+                output
+                    .windows(2)
+                    .filter_map(|pair| snapshot
+                        .sstables
+                        .get(&pair[0])
+                        .zip(snapshot.sstables.get(&pair[1])))
+                    .all(|(prev, next)| prev.last_key() < next.first_key()),
+                "compaction output is not sorted by key"
+            );
+        }
         let mut snapshot = snapshot.clone();
         // 在本次压缩任务中需要被移除的 SST 文件所属的层级和文件列表
         let mut tier_to_remove = task
@@ -123,7 +149,9 @@ impl TieredCompactionController {
             if tier_to_remove.is_empty() && !new_tier_added {
                 // add the compacted tier to the LSM tree
                 new_tier_added = true;
-                levels.push((output[0], output.to_vec()));
+                if let Some(tier_id) = output.first() {
+                    levels.push((*tier_id, output.to_vec()));
+                }
             }
         }
         if !tier_to_remove.is_empty() {
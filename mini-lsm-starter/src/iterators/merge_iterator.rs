@@ -42,6 +42,10 @@ impl<I: StorageIterator> Ord for HeapWrapper<I> {
 pub struct MergeIterator<I: StorageIterator> {
     iters: BinaryHeap<HeapWrapper<I>>,
     current: Option<HeapWrapper<I>>,
+    /// Key and value bytes of the entries that have been stepped over in all inputs.
+    consumed_bytes: u64,
+    /// Total key and value bytes of all inputs, used by `progress`.
+    total_bytes: u64,
 }
 
 impl<I: StorageIterator> MergeIterator<I> {
@@ -50,6 +54,8 @@ impl<I: StorageIterator> MergeIterator<I> {
             return Self {
                 iters: BinaryHeap::new(),
                 current: None,
+                consumed_bytes: 0,
+                total_bytes: 0,
             };
         }
 
@@ -61,6 +67,8 @@ impl<I: StorageIterator> MergeIterator<I> {
             return Self {
                 iters: heap,
                 current: Some(HeapWrapper(0, iters.pop().unwrap())),
+                consumed_bytes: 0,
+                total_bytes: 0,
             };
         }
 
@@ -74,8 +82,33 @@ impl<I: StorageIterator> MergeIterator<I> {
         Self {
             iters: heap,
             current: Some(current),
+            consumed_bytes: 0,
+            total_bytes: 0,
         }
     }
+
+    /// Set the total key and value bytes of all inputs so that `progress` can be reported.
+    pub fn with_total_bytes(mut self, total_bytes: u64) -> Self {
+        self.total_bytes = total_bytes;
+        self
+    }
+
+    /// Fraction of the input bytes consumed so far, in `[0.0, 1.0]`. It is 1.0 once the iterator
+    /// is exhausted, and 0.0 before that if the total is unknown.
+    pub fn progress(&self) -> f64 {
+        let exhausted = self
+            .current
+            .as_ref()
+            .map(|x| !x.1.is_valid())
+            .unwrap_or(true);
+        if exhausted {
+            return 1.0;
+        }
+        if self.total_bytes == 0 {
+            return 0.0;
+        }
+        (self.consumed_bytes as f64 / self.total_bytes as f64).min(1.0)
+    }
 }
 
 impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>> StorageIterator
@@ -107,6 +140,8 @@ impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>> StorageIt
                 "heap invariant violated"
             );
             if inner_iter.1.key() == current.1.key() {
+                self.consumed_bytes +=
+                    (inner_iter.1.key().raw_len() + inner_iter.1.value().len()) as u64;
                 // Case 1: an error occurred when calling `next`.
                 //只需要返回一个key，如果堆中多个迭代器当前指向相同的键（key），这段逻辑会推进所有这些迭代器，直到它们的键值对指向不同的键为止
                 if let e @ Err(_) = inner_iter.1.next() {
@@ -123,6 +158,7 @@ impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>> StorageIt
             }
         }
 
+        self.consumed_bytes += (current.1.key().raw_len() + current.1.value().len()) as u64;
         current.1.next()?;
 
         // If the current iterator is invalid, pop it out of the heap and select the next one.
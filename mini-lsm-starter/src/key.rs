@@ -1,4 +1,4 @@
-use std::{cmp::Reverse, fmt::Debug};
+use std::{cmp::Reverse, fmt::Debug, ops::Bound};
 
 use bytes::Bytes;
 
@@ -19,6 +19,48 @@ pub const TS_MIN: u64 = u64::MIN;
 pub const TS_RANGE_BEGIN: u64 = u64::MAX;
 pub const TS_RANGE_END: u64 = u64::MIN;
 
+/// Convert a user key range bound into a key that iterators can seek on. Keys are ordered by user
+/// key ascending and then by ts descending, so the versions of a user key sit between
+/// `(key, TS_RANGE_BEGIN)` and `(key, TS_RANGE_END)`:
+///
+/// * a lower bound uses the max ts so that the seek lands on the newest version, and an excluded
+///   lower bound seeks to `key + [0]`, the smallest user key that sorts after `key`;
+/// * an upper bound uses the min ts so that it covers the oldest version, and an excluded upper
+///   bound uses the max ts, which sorts before every written version as `TS_RANGE_BEGIN` is never
+///   assigned to a write.
+///
+/// Entries in range satisfy `lower <= key <= upper`. Returns `None` for `Unbounded`.
+pub fn bound_to_seek_key(bound: Bound<&[u8]>, for_lower: bool) -> Option<KeyVec> {
+    match (bound, for_lower) {
+        (Bound::Included(key), true) => {
+            Some(KeyVec::from_vec_with_ts(key.to_vec(), TS_RANGE_BEGIN))
+        }
+        (Bound::Excluded(key), true) => {
+            let mut key = key.to_vec();
+            key.push(0);
+            Some(KeyVec::from_vec_with_ts(key, TS_RANGE_BEGIN))
+        }
+        (Bound::Included(key), false) => Some(KeyVec::from_vec_with_ts(key.to_vec(), TS_RANGE_END)),
+        (Bound::Excluded(key), false) => {
+            Some(KeyVec::from_vec_with_ts(key.to_vec(), TS_RANGE_BEGIN))
+        }
+        (Bound::Unbounded, _) => None,
+    }
+}
+
+/// Returns the shortest user key `s` with `lower < s <= upper`, which is `upper` cut right after
+/// its first byte that differs from `lower`. Used to route keys between two adjacent ranges with
+/// a shorter key than the first key of the upper range. `lower` must be smaller than `upper`.
+pub fn shorten_separator(lower: &[u8], upper: &[u8]) -> Vec<u8> {
+    debug_assert!(lower < upper, "lower must be smaller than upper");
+    let common_prefix_len = lower
+        .iter()
+        .zip(upper.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    upper[..common_prefix_len + 1].to_vec()
+}
+
 impl<T: AsRef<[u8]>> Key<T> {
     pub fn into_inner(self) -> T {
         self.0
@@ -4,11 +4,12 @@
 use anyhow::{bail, Result};
 use bytes::Bytes;
 use std::ops::Bound;
+use std::sync::Arc;
 
 use crate::iterators::concat_iterator::SstConcatIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
-use crate::key::Key;
-use crate::table::SsTableIterator;
+use crate::key::KeySlice;
+use crate::mem_table::RangeTombstone;
 use crate::{
     iterators::{merge_iterator::MergeIterator, StorageIterator},
     mem_table::MemTableIterator,
@@ -16,27 +17,40 @@ use crate::{
 
 /// Represents the internal type for an LSM iterator. This type will be changed across the tutorial for multiple times.
 type LsmIteratorInner = TwoMergeIterator<
-    TwoMergeIterator<MergeIterator<MemTableIterator>, MergeIterator<SsTableIterator>>,
+    TwoMergeIterator<MergeIterator<MemTableIterator>, MergeIterator<SstConcatIterator>>,
     MergeIterator<SstConcatIterator>,
 >;
 
+/// The iterator returned by `LsmStorageState::scan`.
+pub type LsmRangeIterator = FusedIterator<LsmIterator>;
+
 pub struct LsmIterator {
     inner: LsmIteratorInner,
     end_bound: Bound<Bytes>,
     is_valid: bool,
     read_ts: u64,
     prev_key: Vec<u8>,
+    /// Range tombstones visible at `read_ts` that overlap the iterated range.
+    range_tombstones: Vec<Arc<RangeTombstone>>,
 }
 
 impl LsmIterator {
-    pub(crate) fn new(iter: LsmIteratorInner, end_bound: Bound<Bytes>, read_ts: u64) -> Result<Self> {
+    pub(crate) fn new(
+        iter: LsmIteratorInner,
+        end_bound: Bound<Bytes>,
+        read_ts: u64,
+        range_tombstones: Vec<Arc<RangeTombstone>>,
+    ) -> Result<Self> {
         let mut iter = Self {
             is_valid: iter.is_valid(),
             inner: iter,
             end_bound,
             read_ts,
             prev_key: Vec::new(),
+            range_tombstones,
         };
+        // the first key after the seek may already be past the upper bound
+        iter.is_valid = iter.is_valid && iter.within_end_bound();
         // iter.move_to_non_delete()?;
         iter.move_to_key()?;
         Ok(iter)
@@ -48,13 +62,17 @@ impl LsmIterator {
             self.is_valid = false;
             return Ok(());
         }
+        self.is_valid = self.within_end_bound();
+        Ok(())
+    }
+
+    fn within_end_bound(&self) -> bool {
         //获取 end_bound 的引用，并匹配其类型
         match self.end_bound.as_ref() {
-            Bound::Unbounded => {}
-            Bound::Included(key) => self.is_valid = self.inner.key().key_ref() <= key.as_ref(),
-            Bound::Excluded(key) => self.is_valid = self.inner.key().key_ref() < key.as_ref(),
+            Bound::Unbounded => true,
+            Bound::Included(key) => self.inner.key().key_ref() <= key.as_ref(),
+            Bound::Excluded(key) => self.inner.key().key_ref() < key.as_ref(),
         }
-        Ok(())
     }
 
     // fn move_to_non_delete(&mut self) -> Result<()> {
@@ -65,34 +83,40 @@ impl LsmIterator {
     // }
 
     fn move_to_key(&mut self) -> Result<()> {
+        // inner may still be valid past the upper bound, check self.is_valid to avoid walking over
+        // deleted keys
         loop {
-            while self.inner.is_valid() && self.inner.key().key_ref() == self.prev_key {
+            while self.is_valid && self.inner.key().key_ref() == self.prev_key {
                 self.next_inner()?;
             }
-            if !self.inner.is_valid() {
+            if !self.is_valid {
                 break;
             }
             self.prev_key.clear();
             self.prev_key.extend(self.inner.key().key_ref());
-            while self.inner.is_valid()
+            while self.is_valid
                 && self.inner.key().key_ref() == self.prev_key
                 && self.inner.key().ts() > self.read_ts
             {
                 self.next_inner()?;
             }
-            if !self.inner.is_valid() {
+            if !self.is_valid {
                 break;
             }
             if self.inner.key().key_ref() != self.prev_key {
                 continue;
             }
-            if !self.inner.value().is_empty() {
+            if !self.inner.value().is_empty() && !self.is_range_deleted() {
                 break;
             }
         }
         Ok(())
     }
 
+    /// Whether the current version is deleted by a range tombstone not older than it.
+    fn is_range_deleted(&self) -> bool {
+        RangeTombstone::any_covers(&self.range_tombstones, self.inner.key())
+    }
 }
 
 impl StorageIterator for LsmIterator {
@@ -121,6 +145,156 @@ impl StorageIterator for LsmIterator {
     }
 }
 
+/// Consume an iterator over versioned keys (sorted by user key, then by ts descending) and return
+/// the canonical read output: for each user key, the value of the newest version with
+/// `ts <= read_ts`, skipping keys whose newest visible version is a tombstone.
+pub fn materialize_scan(
+    mut iter: impl for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>,
+    read_ts: u64,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut entries = Vec::new();
+    let mut prev_key: Option<Vec<u8>> = None;
+    while iter.is_valid() {
+        let key = iter.key();
+        // skip versions invisible at read_ts and older versions of keys already seen
+        if key.ts() <= read_ts && prev_key.as_deref() != Some(key.key_ref()) {
+            prev_key = Some(key.key_ref().to_vec());
+            if !iter.value().is_empty() {
+                entries.push((key.key_ref().to_vec(), iter.value().to_vec()));
+            }
+        }
+        iter.next()?;
+    }
+    Ok(entries)
+}
+
+/// Yields, in key order, the newest version of each user key visible at `read_ts` if it was written
+/// after `since_ts`, i.e., what changed between a backup at `since_ts` and one at `read_ts`.
+/// Tombstones are kept so that the backup can apply the deletes. Wrap the iterator over all
+/// levels, which is sorted by user key and then by ts descending.
+pub struct SinceTsIterator<I> {
+    iter: I,
+    since_ts: u64,
+    read_ts: u64,
+    prev_key: Vec<u8>,
+}
+
+impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>> SinceTsIterator<I> {
+    pub fn new(iter: I, since_ts: u64, read_ts: u64) -> Result<Self> {
+        let mut iter = Self {
+            iter,
+            since_ts,
+            read_ts,
+            prev_key: Vec::new(),
+        };
+        iter.move_to_delta()?;
+        Ok(iter)
+    }
+
+    fn move_to_delta(&mut self) -> Result<()> {
+        loop {
+            // skip versions invisible at read_ts and older versions of keys already seen
+            while self.iter.is_valid()
+                && (self.iter.key().ts() > self.read_ts
+                    || self.iter.key().key_ref() == self.prev_key)
+            {
+                self.iter.next()?;
+            }
+            if !self.iter.is_valid() {
+                return Ok(());
+            }
+            self.prev_key.clear();
+            self.prev_key.extend(self.iter.key().key_ref());
+            if self.iter.key().ts() > self.since_ts {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>> StorageIterator
+    for SinceTsIterator<I>
+{
+    type KeyType<'a>
+        = KeySlice<'a>
+    where
+        Self: 'a;
+
+    fn is_valid(&self) -> bool {
+        self.iter.is_valid()
+    }
+
+    fn key(&self) -> KeySlice<'_> {
+        self.iter.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.iter.next()?;
+        self.move_to_delta()
+    }
+
+    fn num_active_iterators(&self) -> usize {
+        self.iter.num_active_iterators()
+    }
+}
+
+/// Hides the versions written after `read_ts`, so that a reader at `read_ts` never sees a future
+/// version. Every visible version is yielded, deciding which version of a key to read is left to
+/// the caller. The iterator becomes invalid if only future versions are left.
+pub struct SnapshotIterator<I> {
+    iter: I,
+    read_ts: u64,
+}
+
+impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>> SnapshotIterator<I> {
+    pub fn new(iter: I, read_ts: u64) -> Result<Self> {
+        let mut iter = Self { iter, read_ts };
+        iter.skip_future_versions()?;
+        Ok(iter)
+    }
+
+    fn skip_future_versions(&mut self) -> Result<()> {
+        while self.iter.is_valid() && self.iter.key().ts() > self.read_ts {
+            self.iter.next()?;
+        }
+        Ok(())
+    }
+}
+
+impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>> StorageIterator
+    for SnapshotIterator<I>
+{
+    type KeyType<'a>
+        = KeySlice<'a>
+    where
+        Self: 'a;
+
+    fn is_valid(&self) -> bool {
+        self.iter.is_valid()
+    }
+
+    fn key(&self) -> KeySlice<'_> {
+        self.iter.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.iter.next()?;
+        self.skip_future_versions()
+    }
+
+    fn num_active_iterators(&self) -> usize {
+        self.iter.num_active_iterators()
+    }
+}
+
 /// A wrapper around existing iterator, will prevent users from calling `next` when the iterator is
 /// invalid. If an iterator is already invalid, `next` does not do anything. If `next` returns an error,
 /// `is_valid` should return false, and `next` should always return an error.
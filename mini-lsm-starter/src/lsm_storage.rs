@@ -1,15 +1,16 @@
 #![allow(dead_code)] // REMOVE THIS LINE after fully implementing this functionality
 
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs::File;
 use std::ops::Bound;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Context, Result};
 use bytes::Bytes;
-use parking_lot::{Mutex, MutexGuard, RwLock};
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock};
 
 use crate::block::Block;
 use crate::compact::{
@@ -21,11 +22,11 @@ use crate::iterators::merge_iterator::MergeIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
 use crate::iterators::StorageIterator;
 use crate::key::{Key, KeySlice, TS_RANGE_BEGIN, TS_RANGE_END};
-use crate::lsm_iterator::{FusedIterator, LsmIterator};
+use crate::lsm_iterator::{FusedIterator, LsmIterator, LsmRangeIterator};
 use crate::manifest::{Manifest, ManifestRecord};
-use crate::mem_table::{map_bound, map_key_bound_plus_ts, MemTable};
+use crate::mem_table::{map_bound, map_key_bound_plus_ts, MemTable, RangeTombstone};
 use crate::mvcc::LsmMvccInner;
-use crate::table::{FileObject, SsTable, SsTableBuilder, SsTableIterator};
+use crate::table::{FileObject, SsTable, SsTableBuilder};
 
 pub type BlockCache = moka::sync::Cache<(usize, usize), Arc<Block>>;
 
@@ -43,6 +44,13 @@ pub struct LsmStorageState {
     pub levels: Vec<(usize, Vec<usize>)>,
     /// SST objects.  Arc 使得多个部分可以共享对该 SST 文件的引用，避免了所有权管理的问题
     pub sstables: HashMap<usize, Arc<SsTable>>,
+    /// L0 SSTs grouped into sorted runs, from latest to earliest. SSTs inside a run do not
+    /// overlap and are sorted by first key, so a run is read like a level. `None` when
+    /// `group_l0_sorted_runs` is disabled.
+    pub l0_sorted_runs: Option<Vec<Vec<usize>>>,
+    /// Range tombstones of the flushed memtables. They are never dropped, as the covered keys
+    /// may still be in any SST.
+    pub range_tombstones: Vec<Arc<RangeTombstone>>,
 }
 
 pub enum WriteBatchRecord<T: AsRef<[u8]>> {
@@ -67,10 +75,229 @@ impl LsmStorageState {
             l0_sstables: Vec::new(),
             levels,
             sstables: Default::default(),
+            l0_sorted_runs: if options.group_l0_sorted_runs {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            range_tombstones: Vec::new(),
+        }
+    }
+
+    /// Add a newly flushed SST to the front of L0. When sorted runs are tracked, the SST joins the
+    /// latest run if it does not overlap any SST in it, otherwise it starts a new run. The SST must
+    /// already be in `sstables`.
+    pub(crate) fn insert_l0_sstable(&mut self, sst_id: usize) {
+        self.l0_sstables.insert(0, sst_id);
+        let Some(runs) = self.l0_sorted_runs.as_mut() else {
+            return;
+        };
+        let sst = &self.sstables[&sst_id];
+        let fits_latest_run = runs.first().is_some_and(|run| {
+            run.iter().all(|id| {
+                let other = &self.sstables[id];
+                sst.last_key().key_ref() < other.first_key().key_ref()
+                    || sst.first_key().key_ref() > other.last_key().key_ref()
+            })
+        });
+        if fits_latest_run {
+            let run = &mut runs[0];
+            let idx = run.partition_point(|id| {
+                self.sstables[id].first_key().key_ref() < sst.first_key().key_ref()
+            });
+            run.insert(idx, sst_id);
+        } else {
+            runs.insert(0, vec![sst_id]);
+        }
+    }
+
+    /// Rebuild the sorted runs from `l0_sstables`, e.g. after recovering from the manifest.
+    pub(crate) fn rebuild_l0_sorted_runs(&mut self) {
+        if self.l0_sorted_runs.is_none() {
+            return;
+        }
+        let l0_sstables = std::mem::take(&mut self.l0_sstables);
+        self.l0_sorted_runs = Some(Vec::new());
+        for sst_id in l0_sstables.into_iter().rev() {
+            self.insert_l0_sstable(sst_id);
+        }
+    }
+
+    /// The L0 SSTs grouped into sorted runs from latest to earliest, each SST is a run of its own
+    /// when `group_l0_sorted_runs` is disabled. Reads go through one `SstConcatIterator` per run.
+    fn l0_runs(&self) -> Vec<Vec<usize>> {
+        match &self.l0_sorted_runs {
+            Some(runs) => runs.clone(),
+            None => self.l0_sstables.iter().map(|id| vec![*id]).collect(),
+        }
+    }
+
+    /// Drop the SSTs that are no longer in L0 from the sorted runs, removing empty runs.
+    pub(crate) fn sync_l0_sorted_runs(&mut self) {
+        let Some(runs) = self.l0_sorted_runs.as_mut() else {
+            return;
+        };
+        let l0_sstables = self.l0_sstables.iter().collect::<HashSet<_>>();
+        for run in runs.iter_mut() {
+            run.retain(|id| l0_sstables.contains(id));
         }
+        runs.retain(|run| !run.is_empty());
+    }
+
+    /// Estimate how much of the SSTs a scan of the user key range `[lower, upper)` reads, from the
+    /// key ranges of the SSTs and their blocks, without reading any block. The memtables are not
+    /// counted, as they are already in memory.
+    pub fn estimate_scan_cost(&self, lower: &[u8], upper: &[u8]) -> ScanCost {
+        let (lower, upper) = (Bound::Included(lower), Bound::Excluded(upper));
+        let mut cost = ScanCost::default();
+        let sst_ids = self
+            .l0_sstables
+            .iter()
+            .chain(self.levels.iter().flat_map(|(_, level)| level.iter()));
+        for sst_id in sst_ids {
+            let table = &self.sstables[sst_id];
+            if !range_overlap(
+                lower,
+                upper,
+                table.first_key().key_ref(),
+                table.last_key().key_ref(),
+            ) {
+                continue;
+            }
+            cost.ssts_touched += 1;
+            for (block_idx, meta) in table.block_meta.iter().enumerate() {
+                if range_overlap(
+                    lower,
+                    upper,
+                    meta.first_key.key_ref(),
+                    meta.last_key.key_ref(),
+                ) {
+                    let (offset, offset_end) = table.block_range(block_idx);
+                    cost.estimated_blocks += 1;
+                    cost.estimated_bytes += (offset_end - offset) as u64;
+                }
+            }
+        }
+        cost
+    }
+
+    /// Scan the user key range `(lower, upper)` of this state as of `read_ts`: the memtables, the
+    /// L0 SSTs and the levels overlapping the range are merged, and only the newest version of
+    /// each key visible at `read_ts` is returned, skipping the deleted keys.
+    pub fn scan(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        read_ts: u64,
+    ) -> Result<LsmRangeIterator> {
+        // an excluded lower bound skips every version of the key, an excluded upper one takes none
+        let key_lower = match lower {
+            Bound::Excluded(key) => Bound::Excluded(KeySlice::from_slice(key, TS_RANGE_END)),
+            _ => map_key_bound_plus_ts(lower, TS_RANGE_BEGIN),
+        };
+        let key_upper = match upper {
+            Bound::Excluded(key) => Bound::Excluded(KeySlice::from_slice(key, TS_RANGE_BEGIN)),
+            _ => map_key_bound_plus_ts(upper, TS_RANGE_END),
+        };
+        let mut memtable_iters = Vec::with_capacity(self.imm_memtables.len() + 1);
+        memtable_iters.push(Box::new(self.memtable.scan(key_lower, key_upper)));
+        for memtable in self.imm_memtables.iter() {
+            memtable_iters.push(Box::new(memtable.scan(key_lower, key_upper)));
+        }
+        let memtable_iter = MergeIterator::create(memtable_iters);
+
+        let overlapping_ssts = |sst_ids: &[usize]| {
+            sst_ids
+                .iter()
+                .map(|id| self.sstables[id].clone())
+                .filter(|table| {
+                    range_overlap(
+                        lower,
+                        upper,
+                        table.first_key().key_ref(),
+                        table.last_key().key_ref(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let create_iter = |ssts: Vec<Arc<SsTable>>| -> Result<SstConcatIterator> {
+            Ok(match lower {
+                Bound::Included(key) => SstConcatIterator::create_and_seek_to_key(
+                    ssts,
+                    Key::from_slice(key, TS_RANGE_BEGIN),
+                )?,
+                Bound::Excluded(key) => {
+                    let mut iter = SstConcatIterator::create_and_seek_to_key(
+                        ssts,
+                        Key::from_slice(key, TS_RANGE_END),
+                    )?;
+                    // seek straight to the oldest version of the key, only versions with ts 0 are
+                    // left to skip
+                    while iter.is_valid() && iter.key().key_ref() == key {
+                        iter.next()?;
+                    }
+                    iter
+                }
+                Bound::Unbounded => SstConcatIterator::create_and_seek_to_first(ssts)?,
+            })
+        };
+
+        let mut l0_iters = Vec::with_capacity(self.l0_sstables.len());
+        for run in self.l0_runs() {
+            let run_ssts = overlapping_ssts(&run);
+            if !run_ssts.is_empty() {
+                l0_iters.push(Box::new(create_iter(run_ssts)?));
+            }
+        }
+        let l0_iter = MergeIterator::create(l0_iters);
+
+        let mut level_iters = Vec::with_capacity(self.levels.len());
+        for (_, level_sst_ids) in &self.levels {
+            level_iters.push(Box::new(create_iter(overlapping_ssts(level_sst_ids))?));
+        }
+
+        let iter = TwoMergeIterator::create(memtable_iter, l0_iter)?;
+        let iter = TwoMergeIterator::create(iter, MergeIterator::create(level_iters))?;
+
+        Ok(FusedIterator::new(LsmIterator::new(
+            iter,
+            map_bound(upper),
+            read_ts,
+            self.visible_range_tombstones(read_ts, lower, upper),
+        )?))
+    }
+
+    /// The range tombstones with a ts not greater than `read_ts` that overlap the user keys
+    /// `(lower, upper)`, from the flushed memtables and the memtables still in memory.
+    pub fn visible_range_tombstones(
+        &self,
+        read_ts: u64,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Vec<Arc<RangeTombstone>> {
+        let memtable_range_tombstones = std::iter::once(&self.memtable)
+            .chain(self.imm_memtables.iter())
+            .flat_map(|memtable| memtable.range_tombstones());
+        self.range_tombstones
+            .iter()
+            .cloned()
+            .chain(memtable_range_tombstones)
+            .filter(|tombstone| tombstone.ts <= read_ts && tombstone.overlaps(lower, upper))
+            .collect()
     }
 }
 
+/// The read cost of a range scan, see `LsmStorageState::estimate_scan_cost`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanCost {
+    /// Number of SSTs whose key range overlaps the scan.
+    pub ssts_touched: usize,
+    /// Number of blocks whose key range overlaps the scan.
+    pub estimated_blocks: usize,
+    /// Total size of these blocks on disk.
+    pub estimated_bytes: u64,
+}
+
 #[derive(Debug, Clone)]
 pub struct LsmStorageOptions {
     // Block size in bytes
@@ -82,6 +309,11 @@ pub struct LsmStorageOptions {
     pub compaction_options: CompactionOptions,
     pub enable_wal: bool,
     pub serializable: bool,
+    // Group non-overlapping L0 SSTs into sorted runs, so that compaction is triggered by the
+    // number of runs instead of the number of files, and reads open one iterator per run
+    pub group_l0_sorted_runs: bool,
+    // Write the blocks of compaction output SSTs in reverse order, for descending-scan workloads
+    pub reverse_block_order_compaction_output: bool,
 }
 
 impl LsmStorageOptions {
@@ -93,6 +325,8 @@ impl LsmStorageOptions {
             enable_wal: false,
             num_memtable_limit: 50,
             serializable: false,
+            group_l0_sorted_runs: false,
+            reverse_block_order_compaction_output: false,
         }
     }
 
@@ -104,6 +338,8 @@ impl LsmStorageOptions {
             enable_wal: false,
             num_memtable_limit: 2,
             serializable: false,
+            group_l0_sorted_runs: false,
+            reverse_block_order_compaction_output: false,
         }
     }
 
@@ -115,6 +351,8 @@ impl LsmStorageOptions {
             enable_wal: false,
             num_memtable_limit: 2,
             serializable: false,
+            group_l0_sorted_runs: false,
+            reverse_block_order_compaction_output: false,
         }
     }
 }
@@ -168,6 +406,21 @@ pub(crate) struct LsmStorageInner {
     pub(crate) manifest: Option<Manifest>,
     pub(crate) mvcc: Option<LsmMvccInner>,
     pub(crate) compaction_filters: Arc<Mutex<Vec<CompactionFilter>>>,
+    /// The compaction options recorded in the manifest when they differ from the ones the storage
+    /// is opened with, i.e. the SSTs were laid out by other options.
+    pub(crate) persisted_compaction_options: Option<CompactionOptions>,
+    /// Wakes the flush thread before its next tick when a writer waits for the immutable
+    /// memtables to be flushed.
+    pub(crate) flush_wakeup: (
+        crossbeam_channel::Sender<()>,
+        crossbeam_channel::Receiver<()>,
+    ),
+    /// Signalled by the flush thread after each flush, see `wait_for_flush`.
+    pub(crate) flush_lock: Mutex<()>,
+    pub(crate) flush_cvar: Condvar,
+    /// Whether a flush thread is running. Without one nothing drains the immutable memtables,
+    /// so writers do not wait for it.
+    pub(crate) flush_thread_running: AtomicBool,
 }
 
 /// A thin wrapper for `LsmStorageInner` and the user interface for MiniLSM.
@@ -276,6 +529,10 @@ impl MiniLsm {
         self.inner.delete(key)
     }
 
+    pub fn delete_range(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<()> {
+        self.inner.delete_range(lower, upper)
+    }
+
     pub fn sync(&self) -> Result<()> {
         self.inner.sync()
     }
@@ -326,11 +583,14 @@ impl LsmStorageInner {
         let mut next_sst_id = 1;
         let block_cache = Arc::new(BlockCache::new(1 << 20)); // 4GB block cache,
         let manifest;
+        let mut persisted_compaction_options = None;
+        let mut last_commit_ts = 0;
 
         let compaction_controller = match &options.compaction_options {
-            CompactionOptions::Leveled(options) => {
-                CompactionController::Leveled(LeveledCompactionController::new(options.clone()))
-            }
+            CompactionOptions::Leveled(leveled_options) => CompactionController::Leveled(Box::new(
+                LeveledCompactionController::new(leveled_options.clone())
+                    .with_target_output_sst_size(options.target_sst_size),
+            )),
             CompactionOptions::Tiered(options) => {
                 CompactionController::Tiered(TieredCompactionController::new(options.clone()))
             }
@@ -352,11 +612,16 @@ impl LsmStorageInner {
                 )?);
             }
             manifest = Manifest::create(&manifest_path).context("failed to create manifest")?;
+            manifest.add_record_when_init(ManifestRecord::CompactionConfig(
+                options.compaction_options.clone(),
+            ))?;
             manifest.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
         } else {
             let (m, records) = Manifest::recover(&manifest_path)?;
             // 保存所有内存中的mem,
             let mut memtables = BTreeSet::new();
+            // memtables whose range tombstones are already in the manifest
+            let mut range_tombstones_flushed = HashSet::new();
             for record in records {
                 match record {
                     ManifestRecord::Flush(sst_id) => {
@@ -381,8 +646,33 @@ impl LsmStorageInner {
                         next_sst_id =
                             next_sst_id.max(output.iter().max().copied().unwrap_or_default());
                     }
+                    ManifestRecord::CompactionConfig(compaction_options) => {
+                        persisted_compaction_options = Some(compaction_options);
+                    }
+                    ManifestRecord::RangeTombstones(memtable_id, range_tombstones) => {
+                        state
+                            .range_tombstones
+                            .extend(range_tombstones.into_iter().map(Arc::new));
+                        range_tombstones_flushed.insert(memtable_id);
+                    }
                 }
             }
+            // changing the compaction options changes the target sizes, which may trigger a lot of
+            // recompaction
+            if persisted_compaction_options.as_ref() != Some(&options.compaction_options) {
+                if let Some(persisted) = &persisted_compaction_options {
+                    log::warn!(
+                        "compaction options changed from {:?} to {:?}, the SSTs may be recompacted",
+                        persisted,
+                        options.compaction_options
+                    );
+                }
+                m.add_record_when_init(ManifestRecord::CompactionConfig(
+                    options.compaction_options.clone(),
+                ))?;
+            } else {
+                persisted_compaction_options = None;
+            }
 
             let mut sst_cnt = 0;
             // recover SSTs
@@ -398,10 +688,15 @@ impl LsmStorageInner {
                     FileObject::open(&Self::path_of_sst_static(path, table_id))
                         .context("failed to open SST")?,
                 )?;
+                last_commit_ts = last_commit_ts.max(sst.max_ts());
                 state.sstables.insert(table_id, Arc::new(sst));
                 sst_cnt += 1;
             }
+            for range_tombstone in &state.range_tombstones {
+                last_commit_ts = last_commit_ts.max(range_tombstone.ts);
+            }
             println!("{} SSTs opened", sst_cnt);
+            state.rebuild_l0_sorted_runs();
 
             next_sst_id += 1;
 
@@ -409,9 +704,20 @@ impl LsmStorageInner {
             if options.enable_wal {
                 let mut wal_cnt = 0;
                 for id in memtables.iter() {
-                    let memtable =
-                        MemTable::recover_from_wal(*id, Self::path_of_wal_static(path, *id))?;
+                    let wal_path = Self::path_of_wal_static(path, *id);
+                    // a memtable with only range tombstones has no Flush record after flushing, its
+                    // WAL is already deleted
+                    if range_tombstones_flushed.contains(id) && !wal_path.exists() {
+                        continue;
+                    }
+                    let memtable = MemTable::recover_from_wal(*id, wal_path)?;
+                    if range_tombstones_flushed.contains(id) {
+                        // a crash after the range tombstones were recorded in the manifest leaves
+                        // them in the WAL as well, they are already in the state
+                        memtable.clear_range_tombstones();
+                    }
                     if !memtable.is_empty() {
+                        last_commit_ts = last_commit_ts.max(memtable.max_ts());
                         state.imm_memtables.insert(0, Arc::new(memtable));
                         wal_cnt += 1;
                     }
@@ -438,8 +744,13 @@ impl LsmStorageInner {
             compaction_controller,
             manifest: Some(manifest),
             options: options.into(),
-            mvcc: Some(LsmMvccInner::new(0)),
+            mvcc: Some(LsmMvccInner::new(last_commit_ts)),
             compaction_filters: Arc::new(Mutex::new(Vec::new())),
+            persisted_compaction_options,
+            flush_wakeup: crossbeam_channel::bounded(1),
+            flush_lock: Mutex::new(()),
+            flush_cvar: Condvar::new(),
+            flush_thread_running: AtomicBool::new(false),
         };
         storage.sync_dir()?;
 
@@ -460,6 +771,13 @@ impl LsmStorageInner {
         let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
         txn.get(key)
     }
+
+    /// Get the value of `key` as of `read_ts`, i.e. the newest version with a timestamp not
+    /// greater than `read_ts`, `None` if there is none or it is a delete.
+    pub fn get_as_of(&self, key: &[u8], read_ts: u64) -> Result<Option<Bytes>> {
+        self.get_with_ts(key, read_ts)
+    }
+
     pub(crate) fn get_with_ts(&self, key: &[u8], read_ts: u64) -> Result<Option<Bytes>> {
         let snapshot = {
             let guard = self.state.read();
@@ -487,13 +805,14 @@ impl LsmStorageInner {
         // }
         // 1-2、 search in memtable
         let mut memtable_iters = Vec::with_capacity(snapshot.imm_memtables.len() + 1);
+        // versions are sorted by descending ts, start reading at read_ts to skip newer versions
         memtable_iters.push(Box::new(snapshot.memtable.scan(
-            Bound::Included(KeySlice::from_slice(key, TS_RANGE_BEGIN)),
+            Bound::Included(KeySlice::from_slice(key, read_ts)),
             Bound::Included(KeySlice::from_slice(key, TS_RANGE_END)),
         )));
         for memtable in snapshot.imm_memtables.iter() {
             memtable_iters.push(Box::new(memtable.scan(
-                Bound::Included(KeySlice::from_slice(key, TS_RANGE_BEGIN)),
+                Bound::Included(KeySlice::from_slice(key, read_ts)),
                 Bound::Included(KeySlice::from_slice(key, TS_RANGE_END)),
             )));
         }
@@ -514,12 +833,17 @@ impl LsmStorageInner {
             false
         };
 
-        for table in snapshot.l0_sstables.iter() {
-            let table = snapshot.sstables[table].clone();
-            if keep_table(key, &table) {
-                l0_iters.push(Box::new(SsTableIterator::create_and_seek_to_key(
-                    table,
-                    KeySlice::from_slice(key, TS_RANGE_BEGIN),
+        // the SSTs of a sorted run do not overlap, so a run is read like a level
+        for run in snapshot.l0_runs() {
+            let run_ssts = run
+                .iter()
+                .map(|id| snapshot.sstables[id].clone())
+                .filter(|table| keep_table(key, table))
+                .collect::<Vec<_>>();
+            if !run_ssts.is_empty() {
+                l0_iters.push(Box::new(SstConcatIterator::create_and_seek_to_key(
+                    run_ssts,
+                    KeySlice::from_slice(key, read_ts),
                 )?));
             }
         }
@@ -538,7 +862,7 @@ impl LsmStorageInner {
             // SstConcatIterator在search key时会检查sst_lists的有序性
             let level_iter = SstConcatIterator::create_and_seek_to_key(
                 level_ssts,
-                KeySlice::from_slice(key, TS_RANGE_BEGIN),
+                KeySlice::from_slice(key, read_ts),
             )?;
             level_iters.push(Box::new(level_iter));
         }
@@ -550,6 +874,8 @@ impl LsmStorageInner {
                 MergeIterator::create(level_iters),
             )?,
             Bound::Unbounded,
+            read_ts,
+            snapshot.visible_range_tombstones(read_ts, Bound::Included(key), Bound::Included(key)),
         )?;
 
         if iter.is_valid() && iter.key() == key && !iter.value().is_empty() {
@@ -559,38 +885,41 @@ impl LsmStorageInner {
     }
 
     /// Write a batch of data into the storage. Implement in week 2 day 7.
+    ///
+    /// All records share one timestamp and are written to the active memtable and its WAL at
+    /// once, so readers see either the whole batch or none of it.
     pub fn write_batch<T: AsRef<[u8]>>(&self, batch: &[WriteBatchRecord<T>]) -> Result<()> {
-        let _lck = self.mvcc().write_lock.lock();
+        let lck = self.mvcc().write_lock.lock();
         let ts = self.mvcc().latest_commit_ts() + 1;
-        for record in batch {
-            match record {
+        let data = batch
+            .iter()
+            .map(|record| match record {
                 WriteBatchRecord::Del(key) => {
                     let key = key.as_ref();
                     assert!(!key.is_empty(), "key cannot be empty");
-                    let size;
-                    {
-                        let guard = self.state.read();
-                        guard.memtable.put(KeySlice::from_slice(key, ts), b"")?;
-                        size = guard.memtable.approximate_size();
-                    }
-                    self.try_freeze(size)?;
+                    (KeySlice::from_slice(key, ts), &[][..])
                 }
                 WriteBatchRecord::Put(key, value) => {
                     let key = key.as_ref();
                     let value = value.as_ref();
                     assert!(!key.is_empty(), "key cannot be empty");
                     assert!(!value.is_empty(), "value cannot be empty");
-                    let size;
-                    {
-                        let guard = self.state.read();
-                        guard.memtable.put(KeySlice::from_slice(key, ts), value)?;
-                        size = guard.memtable.approximate_size();
-                    }
-                    self.try_freeze(size)?;
+                    (KeySlice::from_slice(key, ts), value)
                 }
-            }
+            })
+            .collect::<Vec<_>>();
+        // the whole batch goes into one memtable and freezing is only checked afterwards, readers
+        // see it once the commit ts is published
+        let size;
+        {
+            let guard = self.state.read();
+            guard.memtable.put_batch(&data)?;
+            size = guard.memtable.approximate_size();
         }
+        self.try_freeze(size)?;
         self.mvcc().update_commit_ts(ts);
+        drop(lck);
+        self.wait_for_flush();
         Ok(())
     }
 
@@ -604,6 +933,27 @@ impl LsmStorageInner {
         self.write_batch(&[WriteBatchRecord::Del(key)])
     }
 
+    /// Remove all keys within `(lower, upper)` by writing a single range tombstone into the
+    /// current memtable and its WAL, instead of a delete for every key. Keys written after this
+    /// call are not affected.
+    pub fn delete_range(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<()> {
+        let lck = self.mvcc().write_lock.lock();
+        let ts = self.mvcc().latest_commit_ts() + 1;
+        let size;
+        {
+            let guard = self.state.read();
+            guard
+                .memtable
+                .delete_range(RangeTombstone::new(lower, upper, ts))?;
+            size = guard.memtable.approximate_size();
+        }
+        self.try_freeze(size)?;
+        self.mvcc().update_commit_ts(ts);
+        drop(lck);
+        self.wait_for_flush();
+        Ok(())
+    }
+
     fn try_freeze(&self, estimated_size: usize) -> Result<()> {
         if estimated_size >= self.options.target_sst_size {
             let state_lock = self.state_lock.lock();
@@ -617,6 +967,24 @@ impl LsmStorageInner {
         Ok(())
     }
 
+    /// Block the writer while `num_memtable_limit` or more immutable memtables are queued, so
+    /// bursty writes cannot freeze memtables faster than the flush thread drains them. Called
+    /// after the write lock is released, so other writers and the flush itself are not held up.
+    fn wait_for_flush(&self) {
+        let limit = self.options.num_memtable_limit.max(1);
+        let mut guard = self.flush_lock.lock();
+        while self
+            .flush_thread_running
+            .load(std::sync::atomic::Ordering::SeqCst)
+            && self.state.read().imm_memtables.len() >= limit
+        {
+            self.flush_wakeup.0.try_send(()).ok();
+            // the timeout covers the flush thread stopping while we wait
+            self.flush_cvar
+                .wait_for(&mut guard, Duration::from_millis(50));
+        }
+    }
+
     pub(crate) fn path_of_sst_static(path: impl AsRef<Path>, id: usize) -> PathBuf {
         path.as_ref().join(format!("{:05}.sst", id))
     }
@@ -695,11 +1063,29 @@ impl LsmStorageInner {
         let mut builder = SsTableBuilder::new(self.options.block_size);
         flush_memtable.flush(&mut builder)?;
         let sst_id = flush_memtable.id();
-        let sst = Arc::new(builder.build(
-            sst_id,
-            Some(self.block_cache.clone()),
-            self.path_of_sst(sst_id),
-        )?);
+        // a memtable with only range tombstones produces no SST
+        let sst = if builder.is_empty() {
+            None
+        } else {
+            Some(Arc::new(builder.build(
+                sst_id,
+                Some(self.block_cache.clone()),
+                self.path_of_sst(sst_id),
+            )?))
+        };
+        let range_tombstones = flush_memtable.range_tombstones();
+        if !range_tombstones.is_empty() {
+            self.manifest().add_record(
+                &_state_lock,
+                ManifestRecord::RangeTombstones(
+                    sst_id,
+                    range_tombstones
+                        .iter()
+                        .map(|tombstone| tombstone.as_ref().clone())
+                        .collect(),
+                ),
+            )?;
+        }
 
         // Add the flushed L0 table to the list.
         {
@@ -708,17 +1094,19 @@ impl LsmStorageInner {
             // Remove the memtable from the immutable memtables.
             let mem = snapshot.imm_memtables.pop().unwrap();
             assert_eq!(mem.id(), sst_id);
-            // Add L0 table
-            // Add L0 table
-            if self.compaction_controller.flush_to_l0() {
-                // In leveled compaction or no compaction, simply flush to L0
-                snapshot.l0_sstables.insert(0, sst_id);
-            } else {
-                // In tiered compaction, create a new tier
-                snapshot.levels.insert(0, (sst_id, vec![sst_id]));
+            snapshot.range_tombstones.extend(range_tombstones);
+            if let Some(sst) = sst.clone() {
+                println!("flushed {}.sst with size={}", sst_id, sst.table_size());
+                snapshot.sstables.insert(sst_id, sst);
+                // Add L0 table
+                if self.compaction_controller.flush_to_l0() {
+                    // In leveled compaction or no compaction, simply flush to L0
+                    snapshot.insert_l0_sstable(sst_id);
+                } else {
+                    // In tiered compaction, create a new tier
+                    snapshot.levels.insert(0, (sst_id, vec![sst_id]));
+                }
             }
-            println!("flushed {}.sst with size={}", sst_id, sst.table_size());
-            snapshot.sstables.insert(sst_id, sst);
             // Update the snapshot.
             *guard = Arc::new(snapshot);
         }
@@ -726,8 +1114,10 @@ impl LsmStorageInner {
         if self.options.enable_wal {
             std::fs::remove_file(self.path_of_wal(sst_id))?;
         }
-        self.manifest()
-            .add_record(&_state_lock, ManifestRecord::Flush(sst_id))?;
+        if sst.is_some() {
+            self.manifest()
+                .add_record(&_state_lock, ManifestRecord::Flush(sst_id))?;
+        }
         self.sync_dir()?;
         Ok(())
     }
@@ -748,94 +1138,6 @@ impl LsmStorageInner {
             Arc::clone(&guard)
         }; // drop global lock here
 
-        let mut memtable_iters = Vec::with_capacity(snapshot.imm_memtables.len() + 1);
-        // memtable_iters.push(Box::new(snapshot.memtable.scan(lower, upper)));
-        memtable_iters.push(Box::new(snapshot.memtable.scan(
-            map_key_bound_plus_ts(lower, TS_RANGE_BEGIN),
-            map_key_bound_plus_ts(upper, TS_RANGE_END),
-        )));
-        for memtable in snapshot.imm_memtables.iter() {
-            // memtable_iters.push(Box::new(memtable.scan(lower, upper)));
-            memtable_iters.push(Box::new(memtable.scan(
-                map_key_bound_plus_ts(lower, TS_RANGE_BEGIN),
-                map_key_bound_plus_ts(upper, TS_RANGE_END),
-            )));
-        }
-        let memtable_iter = MergeIterator::create(memtable_iters);
-
-        let mut table_iters = Vec::with_capacity(snapshot.l0_sstables.len());
-        for table_id in snapshot.l0_sstables.iter() {
-            let table = snapshot.sstables[table_id].clone();
-            if range_overlap(
-                lower,
-                upper,
-                table.first_key().key_ref(),
-                table.last_key().key_ref(),
-            ) {
-                let iter = match lower {
-                    Bound::Included(key) => SsTableIterator::create_and_seek_to_key(
-                        table,
-                        KeySlice::from_slice(key, TS_RANGE_BEGIN),
-                    )?,
-                    Bound::Excluded(key) => {
-                        let mut iter = SsTableIterator::create_and_seek_to_key(
-                            table,
-                            Key::from_slice(key, TS_RANGE_BEGIN),
-                        )?;
-                        if iter.is_valid() && iter.key().key_ref() == key {
-                            iter.next()?;
-                        }
-                        iter
-                    }
-                    Bound::Unbounded => SsTableIterator::create_and_seek_to_first(table)?,
-                };
-
-                table_iters.push(Box::new(iter));
-            }
-        }
-
-        let l0_iter = MergeIterator::create(table_iters);
-        let mut level_iters = Vec::with_capacity(snapshot.levels.len());
-        for (_, level_sst_ids) in &snapshot.levels {
-            let mut level_ssts = Vec::with_capacity(level_sst_ids.len());
-            for table in level_sst_ids {
-                let table = snapshot.sstables[table].clone();
-                if range_overlap(
-                    lower,
-                    upper,
-                    table.first_key().key_ref(),
-                    table.last_key().key_ref(),
-                ) {
-                    level_ssts.push(table);
-                }
-            }
-
-            let level_iter = match lower {
-                Bound::Included(key) => SstConcatIterator::create_and_seek_to_key(
-                    level_ssts,
-                    Key::from_slice(key, TS_RANGE_BEGIN),
-                )?,
-                Bound::Excluded(key) => {
-                    let mut iter = SstConcatIterator::create_and_seek_to_key(
-                        level_ssts,
-                        Key::from_slice(key, TS_RANGE_BEGIN),
-                    )?;
-                    if iter.is_valid() && iter.key().key_ref() == key {
-                        iter.next()?;
-                    }
-                    iter
-                }
-                Bound::Unbounded => SstConcatIterator::create_and_seek_to_first(level_ssts)?,
-            };
-            level_iters.push(Box::new(level_iter));
-        }
-
-        let iter = TwoMergeIterator::create(memtable_iter, l0_iter)?;
-        let iter = TwoMergeIterator::create(iter, MergeIterator::create(level_iters))?;
-
-        Ok(FusedIterator::new(LsmIterator::new(
-            iter,
-            map_bound(upper),
-        )?))
+        snapshot.scan(lower, upper, self.mvcc().latest_commit_ts())
     }
 }
@@ -8,7 +8,8 @@ use bytes::{Buf, BufMut};
 use parking_lot::{Mutex, MutexGuard};
 use serde::{Deserialize, Serialize};
 
-use crate::compact::CompactionTask;
+use crate::compact::{CompactionOptions, CompactionTask};
+use crate::mem_table::RangeTombstone;
 
 pub struct Manifest {
     file: Arc<Mutex<File>>,
@@ -19,6 +20,11 @@ pub enum ManifestRecord {
     Flush(usize),
     NewMemtable(usize),
     Compaction(CompactionTask, Vec<usize>),
+    /// The compaction options the storage is opened with, recorded when they change.
+    CompactionConfig(CompactionOptions),
+    /// The range tombstones of a memtable being flushed, which move to the storage state since
+    /// SSTs do not store them. Written before the `Flush` of the memtable.
+    RangeTombstones(usize, Vec<RangeTombstone>),
 }
 
 impl Manifest {
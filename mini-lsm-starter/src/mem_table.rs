@@ -10,6 +10,8 @@ use bytes::Bytes;
 use crossbeam_skiplist::map::Entry;
 use crossbeam_skiplist::SkipMap;
 use ouroboros::self_referencing;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
 
 use crate::iterators::StorageIterator;
 use crate::key;
@@ -26,6 +28,81 @@ pub struct MemTable {
     wal: Option<Wal>,
     id: usize,
     approximate_size: Arc<AtomicUsize>,
+    /// Range tombstones written by `delete_range`, kept out of the skiplist because they cover a
+    /// key range instead of a single key.
+    range_tombstones: RwLock<Vec<Arc<RangeTombstone>>>,
+}
+
+/// Deletes every user key within `(lower, upper)` whose version is not newer than `ts`. A later
+/// write of a covered key (with a larger ts) is not affected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RangeTombstone {
+    pub lower: Bound<Vec<u8>>,
+    pub upper: Bound<Vec<u8>>,
+    pub ts: u64,
+}
+
+impl RangeTombstone {
+    pub fn new(lower: Bound<&[u8]>, upper: Bound<&[u8]>, ts: u64) -> Self {
+        Self {
+            lower: lower.map(|x| x.to_vec()),
+            upper: upper.map(|x| x.to_vec()),
+            ts,
+        }
+    }
+
+    /// Whether the user key is within the range.
+    pub fn contains(&self, user_key: &[u8]) -> bool {
+        let above_lower = match &self.lower {
+            Bound::Included(lower) => user_key >= lower.as_slice(),
+            Bound::Excluded(lower) => user_key > lower.as_slice(),
+            Bound::Unbounded => true,
+        };
+        let below_upper = match &self.upper {
+            Bound::Included(upper) => user_key <= upper.as_slice(),
+            Bound::Excluded(upper) => user_key < upper.as_slice(),
+            Bound::Unbounded => true,
+        };
+        above_lower && below_upper
+    }
+
+    /// Whether the range overlaps the user keys `(lower, upper)`.
+    pub fn overlaps(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> bool {
+        // two ranges intersect iff each lower bound is not past the other upper bound
+        fn not_above(lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> bool {
+            match (lower, upper) {
+                (Bound::Unbounded, _) | (_, Bound::Unbounded) => true,
+                (Bound::Included(lower), Bound::Included(upper)) => lower <= upper,
+                (Bound::Included(lower), Bound::Excluded(upper))
+                | (Bound::Excluded(lower), Bound::Included(upper))
+                | (Bound::Excluded(lower), Bound::Excluded(upper)) => lower < upper,
+            }
+        }
+        let self_lower = self.lower.as_ref().map(|x| x.as_slice());
+        let self_upper = self.upper.as_ref().map(|x| x.as_slice());
+        not_above(self_lower, upper) && not_above(lower, self_upper)
+    }
+
+    /// Whether the version `key` is deleted by this tombstone, i.e. the key is within the range and
+    /// its ts is not newer than the tombstone.
+    pub fn covers(&self, key: KeySlice) -> bool {
+        key.ts() <= self.ts && self.contains(key.key_ref())
+    }
+
+    /// Whether the version `key` is deleted by any of the tombstones.
+    pub fn any_covers(range_tombstones: &[Arc<Self>], key: KeySlice) -> bool {
+        range_tombstones
+            .iter()
+            .any(|tombstone| tombstone.covers(key))
+    }
+
+    fn raw_len(&self) -> usize {
+        let bound_len = |bound: &Bound<Vec<u8>>| match bound {
+            Bound::Included(x) | Bound::Excluded(x) => x.len(),
+            Bound::Unbounded => 0,
+        };
+        bound_len(&self.lower) + bound_len(&self.upper) + std::mem::size_of::<u64>()
+    }
 }
 
 /// Create a bound of `Bytes` from a bound of `&[u8]`.
@@ -69,6 +146,7 @@ impl MemTable {
             map: Arc::new(SkipMap::new()),
             wal: None,
             approximate_size: Arc::new(AtomicUsize::new(0)),
+            range_tombstones: RwLock::new(Vec::new()),
         }
     }
 
@@ -79,17 +157,29 @@ impl MemTable {
             map: Arc::new(SkipMap::new()),
             wal: Some(Wal::create(path.as_ref())?),
             approximate_size: Arc::new(AtomicUsize::new(0)),
+            range_tombstones: RwLock::new(Vec::new()),
         })
     }
 
-    /// Create a memtable from WAL
+    /// Create a memtable from WAL, the approximate size is recomputed from the recovered entries.
     pub fn recover_from_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
         let map: Arc<SkipMap<KeyBytes, Bytes>> = Arc::new(SkipMap::new());
+        let mut range_tombstones = Vec::new();
+        let wal = Wal::recover(path.as_ref(), &map, &mut range_tombstones)?;
+        let approximate_size = map
+            .iter()
+            .map(|entry| entry.key().raw_len() + entry.value().len())
+            .sum::<usize>()
+            + range_tombstones
+                .iter()
+                .map(RangeTombstone::raw_len)
+                .sum::<usize>();
         Ok(Self {
             id,
-            wal: Some(Wal::recover(path.as_ref(), &map)?),
+            wal: Some(wal),
             map,
-            approximate_size: Arc::new(AtomicUsize::new(0)),
+            approximate_size: Arc::new(AtomicUsize::new(approximate_size)),
+            range_tombstones: RwLock::new(range_tombstones.into_iter().map(Arc::new).collect()),
         })
     }
 
@@ -130,23 +220,60 @@ impl MemTable {
     /// In week 2, day 6, also flush the data to WAL.
     /// In week 3, day 5, modify the function to use the batch API.
     pub fn put(&self, key: KeySlice, value: &[u8]) -> Result<()> {
-        let estimated_size = key.raw_len() + value.len();
-        self.map.insert(
-            key.to_key_vec().into_key_bytes(),
-            Bytes::copy_from_slice(value),
-        );
+        self.put_batch(&[(key, value)])
+    }
+
+    /// Put the key-value pairs into the mem-table in order, see `put`. The pairs are appended to
+    /// the WAL with a single write before any of them is inserted, so nothing is inserted if the
+    /// WAL cannot be written.
+    pub fn put_batch(&self, data: &[(KeySlice, &[u8])]) -> Result<()> {
+        if let Some(ref wal) = self.wal {
+            wal.put_batch(data)?;
+        }
+        let mut estimated_size = 0;
+        for (key, value) in data {
+            estimated_size += key.raw_len() + value.len();
+            self.map.insert(
+                key.to_key_vec().into_key_bytes(),
+                Bytes::copy_from_slice(value),
+            );
+        }
         //更新近似存储大小
         self.approximate_size
             .fetch_add(estimated_size, std::sync::atomic::Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Put a range tombstone into the mem-table, after appending it to the WAL.
+    pub fn delete_range(&self, range_tombstone: RangeTombstone) -> Result<()> {
         if let Some(ref wal) = self.wal {
-            wal.put(key, value)?;
+            wal.delete_range(&range_tombstone)?;
         }
+        self.approximate_size.fetch_add(
+            range_tombstone.raw_len(),
+            std::sync::atomic::Ordering::Relaxed,
+        );
+        self.range_tombstones
+            .write()
+            .push(Arc::new(range_tombstone));
         Ok(())
     }
 
-    /// Implement this in week 3, day 5.
-    pub fn put_batch(&self, _data: &[(KeySlice, &[u8])]) -> Result<()> {
-        unimplemented!()
+    /// Drop the range tombstones of the mem-table, keeping its point entries.
+    pub fn clear_range_tombstones(&self) {
+        let range_tombstones = std::mem::take(&mut *self.range_tombstones.write());
+        self.approximate_size.fetch_sub(
+            range_tombstones
+                .iter()
+                .map(|tombstone| tombstone.raw_len())
+                .sum::<usize>(),
+            std::sync::atomic::Ordering::Relaxed,
+        );
+    }
+
+    /// The range tombstones in the mem-table, in the order they are written.
+    pub fn range_tombstones(&self) -> Vec<Arc<RangeTombstone>> {
+        self.range_tombstones.read().clone()
     }
 
     pub fn sync_wal(&self) -> Result<()> {
@@ -189,14 +316,29 @@ impl MemTable {
         self.id
     }
 
+    /// Returns the bytes of the keys (with their ts) and values put into the mem-table, kept as a
+    /// running counter so that deciding when to freeze does not scan the skiplist. The counter
+    /// only grows: overwriting a key with the same ts counts both values.
     pub fn approximate_size(&self) -> usize {
         self.approximate_size
             .load(std::sync::atomic::Ordering::Relaxed)
     }
 
+    /// The largest timestamp of the keys in the memtable, 0 if it is empty. This scans the whole
+    /// memtable, so it is only used when recovering from the WAL.
+    pub fn max_ts(&self) -> u64 {
+        let range_tombstones = self.range_tombstones.read();
+        self.map
+            .iter()
+            .map(|entry| entry.key().ts())
+            .chain(range_tombstones.iter().map(|tombstone| tombstone.ts))
+            .max()
+            .unwrap_or(0)
+    }
+
     /// Only use this function when closing the database
     pub fn is_empty(&self) -> bool {
-        self.map.is_empty()
+        self.map.is_empty() && self.range_tombstones.read().is_empty()
     }
 }
 
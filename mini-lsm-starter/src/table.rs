@@ -3,22 +3,43 @@
 
 pub(crate) mod bloom;
 mod builder;
+pub(crate) mod hll;
 mod iterator;
+mod level_index;
+mod merkle;
 
 use std::fs::File;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use anyhow::{anyhow, bail, Result};
 pub use builder::SsTableBuilder;
-use bytes::{Buf, BufMut};
-pub use iterator::SsTableIterator;
+use bytes::{Buf, BufMut, Bytes};
+pub use iterator::{MultiGetIterator, SsTableIterator};
+pub use level_index::LevelIndex;
+pub use merkle::MerkleNode;
 
 use crate::block::Block;
-use crate::key::{KeyBytes, KeySlice};
+use crate::key::{KeyBytes, KeySlice, KeyVec};
 use crate::lsm_storage::BlockCache;
 
 use self::bloom::Bloom;
+use self::hll::HyperLogLog;
+
+/// Whether `SsTable::read_block` checks the crc32 of each block, on by default.
+static VERIFY_CHECKSUMS: AtomicBool = AtomicBool::new(true);
+
+/// Turns the crc32 check of `SsTable::read_block` on or off for the whole crate. Turning it off
+/// saves hashing every block read, for storage that already detects corruption.
+pub fn set_verify_checksums(enabled: bool) {
+    VERIFY_CHECKSUMS.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether `SsTable::read_block` checks the crc32 of each block.
+pub fn verify_checksums() -> bool {
+    VERIFY_CHECKSUMS.load(Ordering::Relaxed)
+}
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct BlockMeta {
@@ -135,6 +156,17 @@ impl FileObject {
     }
 }
 
+/// Summary of `SsTable::verify_all_blocks`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Number of blocks read.
+    pub blocks_checked: usize,
+    /// Number of blocks that failed the crc32 or the block checksum, or could not be read.
+    pub blocks_corrupt: usize,
+    /// Index of the first corrupted block.
+    pub first_corrupt_block: Option<usize>,
+}
+
 /// An SSTable.
 pub struct SsTable {
     /// The actual storage unit of SsTable, the format is as above.
@@ -148,6 +180,8 @@ pub struct SsTable {
     first_key: KeyBytes,
     last_key: KeyBytes,
     pub(crate) bloom: Option<Bloom>,
+    /// Sketch of the distinct user keys in this SST.
+    pub(crate) sketch: Option<HyperLogLog>,
     /// The maximum timestamp stored in this SST, implemented in week 3.
     max_ts: u64,
 }
@@ -160,10 +194,15 @@ impl SsTable {
 
     /// Open SSTable from a file.
     pub fn open(id: usize, block_cache: Option<Arc<BlockCache>>, file: FileObject) -> Result<Self> {
-        let len = file.size();
-        let raw_bloom_offset = file.read(len - 4, 4)?;
+        let len = file.size() - 8;
+        let max_ts = (&file.read(len, 8)?[..]).get_u64();
+        let raw_sketch_offset = file.read(len - 4, 4)?;
+        let sketch_offset = (&raw_sketch_offset[..]).get_u32() as u64;
+        let raw_sketch = file.read(sketch_offset, len - 4 - sketch_offset)?;
+        let sketch = HyperLogLog::decode(&raw_sketch)?;
+        let raw_bloom_offset = file.read(sketch_offset - 4, 4)?;
         let bloom_offset = (&raw_bloom_offset[..]).get_u32() as u64;
-        let raw_bloom = file.read(bloom_offset, len - 4 - bloom_offset)?;
+        let raw_bloom = file.read(bloom_offset, sketch_offset - 4 - bloom_offset)?;
         let bloom_filter = Bloom::decode(&raw_bloom)?;
         let raw_meta_offset = file.read(bloom_offset - 4, 4)?;
         let block_meta_offset = (&raw_meta_offset[..]).get_u32() as u64;
@@ -178,7 +217,8 @@ impl SsTable {
             id,
             block_cache,
             bloom: Some(bloom_filter),
-            max_ts: 0,
+            sketch: Some(sketch),
+            max_ts,
         })
     }
 
@@ -198,27 +238,45 @@ impl SsTable {
             first_key,
             last_key,
             bloom: None,
+            sketch: None,
             max_ts: 0,
         }
     }
 
-    /// Read a block from the disk.
-    pub fn read_block(&self, block_idx: usize) -> Result<Arc<Block>> {
+    /// Whether the data blocks are stored from the last to the first, see
+    /// `SsTableBuilder::set_reverse_block_order`.
+    pub fn is_reverse_block_order(&self) -> bool {
+        self.block_meta.len() > 1 && self.block_meta[0].offset > self.block_meta[1].offset
+    }
+
+    /// Returns the range of the `block_idx`-th block in the file, including its crc32.
+    pub(crate) fn block_range(&self, block_idx: usize) -> (usize, usize) {
         let offset = self.block_meta[block_idx].offset;
-        let offset_end = self
-            .block_meta
-            .get(block_idx + 1)
-            .map_or(self.block_meta_offset, |x| x.offset);
-        let block_len = offset_end - offset - 4;
-        let block_data_with_chksum: Vec<u8> = self
-            .file
-            .read(offset as u64, (offset_end - offset) as u64)?;
-        let block_data = &block_data_with_chksum[..block_len];
-        let checksum = (&block_data_with_chksum[block_len..]).get_u32();
-        if checksum != crc32fast::hash(block_data) {
-            bail!("block checksum mismatched");
+        // a block ends where the block stored after it begins
+        let next_block = if self.is_reverse_block_order() {
+            block_idx.checked_sub(1).map(|idx| &self.block_meta[idx])
+        } else {
+            self.block_meta.get(block_idx + 1)
+        };
+        (
+            offset,
+            next_block.map_or(self.block_meta_offset, |x| x.offset),
+        )
+    }
+
+    /// Read a block from the disk. If `verify_checksums` is on, its crc32 is checked and the
+    /// block is decoded with `Block::decode_checked`, so corruption is an error, not a panic.
+    pub fn read_block(&self, block_idx: usize) -> Result<Arc<Block>> {
+        let (offset, offset_end) = self.block_range(block_idx);
+        let block_data_with_chksum = Bytes::from(
+            self.file
+                .read(offset as u64, (offset_end - offset) as u64)?,
+        );
+        if verify_checksums() {
+            return Ok(Block::decode_shared_with_checksum(block_data_with_chksum)?);
         }
-        Ok(Arc::new(Block::decode(block_data)))
+        let block_data = block_data_with_chksum.slice(..block_data_with_chksum.len() - 4);
+        Ok(Block::decode_shared(block_data))
     }
 
     /// Read a block from disk, with block cache.
@@ -243,6 +301,86 @@ impl SsTable {
             .saturating_sub(1)
     }
 
+    /// The first key of each block in key order, i.e. the sparse index of the SST, for visualizing
+    /// how the entries are partitioned into blocks.
+    pub fn block_boundary_keys(&self) -> Vec<KeyVec> {
+        self.block_meta
+            .iter()
+            .map(|meta| meta.first_key.as_key_slice().to_key_vec())
+            .collect()
+    }
+
+    /// Count the entries (every version of every key) by reading the offsets of every block.
+    pub fn num_entries(&self) -> Result<usize> {
+        let mut entries = 0;
+        for block_idx in 0..self.num_of_blocks() {
+            entries += self.read_block_cached(block_idx)?.len();
+        }
+        Ok(entries)
+    }
+
+    /// Count the delete tombstones (entries with an empty value) by reading every block.
+    pub fn num_tombstones(&self) -> Result<u64> {
+        let mut tombstones = 0;
+        for block_idx in 0..self.num_of_blocks() {
+            self.read_block_cached(block_idx)?
+                .for_each_entry(|_, _, value| {
+                    if value.is_empty() {
+                        tombstones += 1;
+                    }
+                });
+        }
+        Ok(tombstones)
+    }
+
+    /// Returns the size of the entries (keys with their ts and values) divided by the bytes the
+    /// data blocks take on disk, by reading every block. Above 1.0, the block encoding (e.g. key
+    /// prefix compression) saves space, below it the per-entry overhead dominates. An SST without
+    /// blocks has a ratio of 1.0.
+    pub fn compression_ratio(&self) -> Result<f64> {
+        let mut uncompressed_size = 0;
+        for block_idx in 0..self.num_of_blocks() {
+            self.read_block_cached(block_idx)?
+                .for_each_entry(|_, key, value| {
+                    uncompressed_size += key.raw_len() + value.len();
+                });
+        }
+        // the data blocks, with their crc32, are stored before the block meta
+        if self.block_meta_offset == 0 {
+            return Ok(1.0);
+        }
+        Ok(uncompressed_size as f64 / self.block_meta_offset as f64)
+    }
+
+    /// Read every block from the disk, bypassing the block cache, and check its crc32 and its
+    /// content with `Block::decode_checked`. Unlike `read_block`, this does not stop at the first
+    /// corrupted block, so that fsck can report how much of the SST is damaged.
+    pub fn verify_all_blocks(&self) -> VerifyReport {
+        let mut report = VerifyReport {
+            blocks_checked: 0,
+            blocks_corrupt: 0,
+            first_corrupt_block: None,
+        };
+        for block_idx in 0..self.num_of_blocks() {
+            let (offset, offset_end) = self.block_range(block_idx);
+            let is_valid = offset_end >= offset + 4
+                && self
+                    .file
+                    .read(offset as u64, (offset_end - offset) as u64)
+                    .is_ok_and(|data| {
+                        let (block_data, checksum) = data.split_at(data.len() - 4);
+                        (&checksum[..]).get_u32() == crc32fast::hash(block_data)
+                            && Block::decode_checked(block_data).is_ok()
+                    });
+            report.blocks_checked += 1;
+            if !is_valid {
+                report.blocks_corrupt += 1;
+                report.first_corrupt_block.get_or_insert(block_idx);
+            }
+        }
+        report
+    }
+
     /// Get number of data blocks.
     pub fn num_of_blocks(&self) -> usize {
         self.block_meta.len()
@@ -264,7 +402,40 @@ impl SsTable {
         self.id
     }
 
+    /// The largest timestamp of the keys in the SST.
     pub fn max_ts(&self) -> u64 {
         self.max_ts
     }
 }
+
+/// The metadata of an SST used to plan compactions, so that the planning can be tested with fakes
+/// instead of SSTs written to disk.
+pub trait SstMeta {
+    fn first_key(&self) -> &KeyBytes;
+
+    fn last_key(&self) -> &KeyBytes;
+
+    fn table_size(&self) -> u64;
+
+    /// The largest timestamp of the keys in the SST. A compaction output keeps the timestamps of
+    /// its inputs while it gets a new id, so this is how old the data of an SST is.
+    fn max_ts(&self) -> u64;
+}
+
+impl SstMeta for SsTable {
+    fn first_key(&self) -> &KeyBytes {
+        &self.first_key
+    }
+
+    fn last_key(&self) -> &KeyBytes {
+        &self.last_key
+    }
+
+    fn table_size(&self) -> u64 {
+        self.file.1
+    }
+
+    fn max_ts(&self) -> u64 {
+        self.max_ts
+    }
+}
@@ -1,12 +1,15 @@
 #![allow(unused_variables)] // TODO(you): remove this lint after implementing this mod
 #![allow(dead_code)] // TODO(you): remove this lint after implementing this mod
 
+use std::fs::File;
+use std::io::Write;
 use std::path::Path;
 use std::sync::Arc;
 
 use super::{BlockMeta, FileObject, SsTable};
 use crate::key::{KeySlice, KeyVec};
 use crate::table::bloom::Bloom;
+use crate::table::hll::HyperLogLog;
 use crate::{block::BlockBuilder, lsm_storage::BlockCache};
 use anyhow::Result;
 use bytes::BufMut;
@@ -20,6 +23,13 @@ pub struct SsTableBuilder {
     pub(crate) meta: Vec<BlockMeta>,
     block_size: usize,
     key_hashes: Vec<u32>,
+    sketch: HyperLogLog,
+    /// Write the data blocks to the file from the last to the first.
+    reverse_block_order: bool,
+    /// Bytes of finished blocks already handed out by `take_data`.
+    taken_len: usize,
+    /// The largest timestamp of the added keys.
+    max_ts: u64,
 }
 
 impl SsTableBuilder {
@@ -33,19 +43,33 @@ impl SsTableBuilder {
             block_size,
             builder: BlockBuilder::new(block_size),
             key_hashes: Vec::new(),
+            sketch: HyperLogLog::new(),
+            reverse_block_order: false,
+            taken_len: 0,
+            max_ts: 0,
         }
     }
 
+    /// Write the data blocks in reverse order, so that a descending scan reads the file
+    /// sequentially. The keys and the block meta keep the logical order, so forward scans still
+    /// work but read the file backwards.
+    pub fn set_reverse_block_order(&mut self, reverse_block_order: bool) {
+        self.reverse_block_order = reverse_block_order;
+    }
+
     /// Adds a key-value pair to SSTable.
     ///
     /// Note: You should split a new block when the current block is full.(`std::mem::replace` may
     /// be helpful here)
     pub fn add(&mut self, key: KeySlice, value: &[u8]) {
+        assert!(!key.is_empty(), "key must not be empty");
         if self.first_key.is_empty() {
             self.first_key.set_from_slice(key);
         }
 
         self.key_hashes.push(farmhash::fingerprint32(key.key_ref()));
+        self.sketch.add_hash(farmhash::fingerprint64(key.key_ref()));
+        self.max_ts = self.max_ts.max(key.ts());
 
         if self.builder.add(key, value) {
             self.last_key.set_from_slice(key);
@@ -66,7 +90,7 @@ impl SsTableBuilder {
         let builder = std::mem::replace(&mut self.builder, BlockBuilder::new(self.block_size));
         let encoded_block = builder.build().encode();
         self.meta.push(BlockMeta {
-            offset: self.data.len(),
+            offset: self.taken_len + self.data.len(),
             first_key: std::mem::take(&mut self.first_key).into_key_bytes(),
             last_key: std::mem::take(&mut self.last_key).into_key_bytes(),
         });
@@ -75,45 +99,113 @@ impl SsTableBuilder {
         self.data.put_u32(checksum);
     }
 
+    /// Check if no key-value pair is added.
+    pub fn is_empty(&self) -> bool {
+        self.meta.is_empty() && self.builder.is_empty()
+    }
+
     /// Get the estimated size of the SSTable.
     ///
     /// Since the data blocks contain much more data than meta blocks, just return the size of data
     /// blocks here.
     pub fn estimated_size(&self) -> usize {
-        self.data.len()
+        self.taken_len + self.data.len()
     }
 
-    /// Builds the SSTable and writes it to the given path. Use the `FileObject` structure to manipulate the disk objects.
-    pub fn build(
-        mut self,
-        id: usize,
-        block_cache: Option<Arc<BlockCache>>,
-        path: impl AsRef<Path>,
-    ) -> Result<SsTable> {
+    /// Takes the blocks finished so far, so that the caller can write them to the SST file before
+    /// the whole SST is built and only keep one block in memory. Finish such an SST with
+    /// `build_streamed`. Not supported with reverse block order, which needs all the blocks.
+    pub(crate) fn take_data(&mut self) -> Vec<u8> {
+        assert!(
+            !self.reverse_block_order,
+            "cannot stream blocks in reverse order"
+        );
+        self.taken_len += self.data.len();
+        std::mem::take(&mut self.data)
+    }
+
+    /// Finish the last block and encode everything after the blocks taken by `take_data`.
+    /// Returns the encoded bytes, the offset of the block meta and the bloom filter.
+    fn encode_remaining(&mut self) -> (Vec<u8>, usize, Bloom) {
         self.finish_block();
-        let mut buf = self.data;
-        let meta_offset = buf.len();
+        let mut buf = std::mem::take(&mut self.data);
+        if self.reverse_block_order {
+            let mut reversed = Vec::with_capacity(buf.len());
+            let mut block_end = buf.len();
+            for meta in self.meta.iter_mut().rev() {
+                let block = &buf[meta.offset..block_end];
+                block_end = meta.offset;
+                meta.offset = reversed.len();
+                reversed.extend_from_slice(block);
+            }
+            buf = reversed;
+        }
+        let meta_offset = self.taken_len + buf.len();
         BlockMeta::encode_block_meta(&self.meta, &mut buf);
         buf.put_u32(meta_offset as u32);
         let bloom = Bloom::build_from_key_hashes(
             &self.key_hashes,
             Bloom::bloom_bits_per_key(self.key_hashes.len(), 0.01),
         );
-        let bloom_offset = buf.len();
+        let bloom_offset = self.taken_len + buf.len();
         bloom.encode(&mut buf);
         buf.put_u32(bloom_offset as u32);
-        let file = FileObject::create(path.as_ref(), buf)?;
-        Ok(SsTable {
+        let sketch_offset = self.taken_len + buf.len();
+        self.sketch.encode(&mut buf);
+        buf.put_u32(sketch_offset as u32);
+        buf.put_u64(self.max_ts);
+        (buf, meta_offset, bloom)
+    }
+
+    fn into_sstable(
+        self,
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        file: FileObject,
+        block_meta_offset: usize,
+        bloom: Bloom,
+    ) -> SsTable {
+        SsTable {
             id,
             file,
             first_key: self.meta.first().unwrap().first_key.clone(),
             last_key: self.meta.last().unwrap().last_key.clone(),
             block_meta: self.meta,
-            block_meta_offset: meta_offset,
+            block_meta_offset,
             block_cache,
             bloom: Some(bloom),
-            max_ts: 0, // will be changed to latest ts in week 2
-        })
+            sketch: Some(self.sketch),
+            max_ts: self.max_ts,
+        }
+    }
+
+    /// Builds the SSTable and writes it to the given path. Use the `FileObject` structure to manipulate the disk objects.
+    pub fn build(
+        mut self,
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        path: impl AsRef<Path>,
+    ) -> Result<SsTable> {
+        assert_eq!(self.taken_len, 0, "use build_streamed after take_data");
+        let (buf, meta_offset, bloom) = self.encode_remaining();
+        let file = FileObject::create(path.as_ref(), buf)?;
+        Ok(self.into_sstable(id, block_cache, file, meta_offset, bloom))
+    }
+
+    /// Builds an SSTable whose blocks from `take_data` are already written to `file`, which is
+    /// the file at `path`.
+    pub(crate) fn build_streamed(
+        mut self,
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        path: impl AsRef<Path>,
+        mut file: File,
+    ) -> Result<SsTable> {
+        let (buf, meta_offset, bloom) = self.encode_remaining();
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        let file = FileObject::open(path.as_ref())?;
+        Ok(self.into_sstable(id, block_cache, file, meta_offset, bloom))
     }
 
     #[cfg(test)]
@@ -0,0 +1,80 @@
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut};
+
+/// Number of bits of the hash used to select a register.
+const PRECISION: u32 = 10;
+const NUM_REGISTERS: usize = 1 << PRECISION;
+
+/// Implements a HyperLogLog sketch to estimate the number of distinct user keys
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HyperLogLog {
+    pub fn new() -> Self {
+        Self {
+            registers: vec![0; NUM_REGISTERS],
+        }
+    }
+
+    /// Add a 64-bit key hash to the sketch
+    pub fn add_hash(&mut self, hash: u64) {
+        let idx = (hash >> (64 - PRECISION)) as usize;
+        // position of the first set bit in the remaining bits, the sentinel bit bounds the rank
+        let rank = ((hash << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() as u8 + 1;
+        self.registers[idx] = self.registers[idx].max(rank);
+    }
+
+    /// Merge another sketch into this one, the result estimates the union of both key sets
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (reg, other) in self.registers.iter_mut().zip(other.registers.iter()) {
+            *reg = (*reg).max(*other);
+        }
+    }
+
+    /// Estimate the number of distinct hashes added to the sketch
+    pub fn estimate(&self) -> f64 {
+        let m = NUM_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum = self
+            .registers
+            .iter()
+            .map(|reg| 2f64.powi(-(*reg as i32)))
+            .sum::<f64>();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|reg| **reg == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            // small range correction with linear counting
+            m * (m / zeros as f64).ln()
+        } else {
+            estimate
+        }
+    }
+
+    /// Encode the sketch
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend(&self.registers);
+        buf.put_u32(crc32fast::hash(&self.registers));
+    }
+
+    /// Decode a sketch
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() != NUM_REGISTERS + 4 {
+            bail!("invalid sketch length {}", buf.len());
+        }
+        let registers = &buf[..NUM_REGISTERS];
+        if (&buf[NUM_REGISTERS..]).get_u32() != crc32fast::hash(registers) {
+            bail!("sketch checksum mismatched");
+        }
+        Ok(Self {
+            registers: registers.to_vec(),
+        })
+    }
+}
@@ -4,15 +4,24 @@
 use std::sync::Arc;
 
 use anyhow::Result;
+use bytes::Bytes;
 
 use super::SsTable;
-use crate::{block::BlockIterator, iterators::StorageIterator, key::KeySlice};
+use crate::{
+    block::{BlockIterator, SeekStrategy},
+    iterators::StorageIterator,
+    key::KeySlice,
+};
+
+/// Called with the index of the block `SsTableIterator` moves into, see `set_on_block_change`.
+type BlockChangeCallback = Box<dyn FnMut(usize)>;
 
 /// An iterator over the contents of an SSTable.
 pub struct SsTableIterator {
     table: Arc<SsTable>,
     blk_iter: BlockIterator,
     blk_idx: usize,
+    on_block_change: Option<BlockChangeCallback>,
 }
 
 impl SsTableIterator {
@@ -30,6 +39,7 @@ impl SsTableIterator {
             blk_iter,
             table,
             blk_idx,
+            on_block_change: None,
         };
         Ok(iter)
     }
@@ -42,6 +52,46 @@ impl SsTableIterator {
         Ok(())
     }
 
+    /// Create a new iterator and seek to the last key-value pair.
+    pub fn create_and_seek_to_last(table: Arc<SsTable>) -> Result<Self> {
+        let blk_idx = table.num_of_blocks() - 1;
+        let mut blk_iter =
+            BlockIterator::create_and_seek_to_first(table.read_block_cached(blk_idx)?);
+        blk_iter.seek_to_last();
+        Ok(Self {
+            blk_iter,
+            table,
+            blk_idx,
+            on_block_change: None,
+        })
+    }
+
+    /// Set a callback invoked with the index of the new block whenever `next` or `prev` crosses
+    /// into another block, e.g. to show the block boundaries during a scan. Seeks do not call it.
+    pub fn set_on_block_change(&mut self, on_block_change: impl FnMut(usize) + 'static) {
+        self.on_block_change = Some(Box::new(on_block_change));
+    }
+
+    fn notify_block_change(&mut self) {
+        if let Some(on_block_change) = &mut self.on_block_change {
+            on_block_change(self.blk_idx);
+        }
+    }
+
+    /// Move to the previous key-value pair, the iterator becomes invalid before the first one.
+    pub fn prev(&mut self) -> Result<()> {
+        self.blk_iter.prev();
+        if !self.blk_iter.is_valid() && self.blk_idx > 0 {
+            self.blk_idx -= 1;
+            self.blk_iter = BlockIterator::create_and_seek_to_first(
+                self.table.read_block_cached(self.blk_idx)?,
+            );
+            self.blk_iter.seek_to_last();
+            self.notify_block_change();
+        }
+        Ok(())
+    }
+
     // 找不到key为什么要往下一个block找？ :不一定能找到，这里是处理找不到的情况，返回第一个大于这个key的key
     fn seek_to_key_inner(table: &Arc<SsTable>, key: KeySlice) -> Result<(usize, BlockIterator)> {
         let mut blk_idx = table.find_block_idx(key);
@@ -64,6 +114,7 @@ impl SsTableIterator {
             blk_iter,
             table,
             blk_idx,
+            on_block_change: None,
         };
         Ok(iter)
     }
@@ -102,8 +153,121 @@ impl StorageIterator for SsTableIterator {
                 self.blk_iter = BlockIterator::create_and_seek_to_first(
                     self.table.read_block_cached(self.blk_idx)?,
                 );
+                self.notify_block_change();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Point-gets a batch of user keys in one pass over an SSTable. For each key, yields the newest
+/// version visible at `read_ts`, and skips the keys that have no such version in this SSTable.
+/// Tombstones are yielded with an empty value, so that the caller knows the key is deleted here
+/// and does not look it up in older SSTables.
+///
+/// The keys are sorted, so the blocks are visited in order and each block is loaded at most once.
+/// This beats independent seeks when there are many keys.
+pub struct MultiGetIterator {
+    table: Arc<SsTable>,
+    keys: Vec<Bytes>,
+    /// Index of the next key in `keys` to look up.
+    next_key: usize,
+    read_ts: u64,
+    blk_idx: usize,
+    /// Positioned at the current match, `None` once all keys are looked up.
+    blk_iter: Option<BlockIterator>,
+}
+
+impl MultiGetIterator {
+    /// Create a new iterator over the `keys` found in `table`, `keys` must be sorted.
+    pub fn create(table: Arc<SsTable>, mut keys: Vec<Bytes>, read_ts: u64) -> Result<Self> {
+        assert!(keys.is_sorted(), "keys must be sorted");
+        keys.dedup();
+        let mut iter = Self {
+            table,
+            keys,
+            next_key: 0,
+            read_ts,
+            blk_idx: 0,
+            blk_iter: None,
+        };
+        iter.find_next_match()?;
+        Ok(iter)
+    }
+
+    fn may_contain(&self, key: &[u8]) -> bool {
+        if key < self.table.first_key().key_ref() || key > self.table.last_key().key_ref() {
+            return false;
+        }
+        self.table
+            .bloom
+            .as_ref()
+            .is_none_or(|bloom| bloom.may_contain(farmhash::fingerprint32(key)))
+    }
+
+    /// Seek to the first entry >= `key` in the `blk_idx`-th block, reusing the loaded block if
+    /// possible.
+    fn seek_in_block(&mut self, blk_idx: usize, key: KeySlice) -> Result<()> {
+        match &mut self.blk_iter {
+            Some(blk_iter) if self.blk_idx == blk_idx => {
+                // target keys are increasing, search forward from the current position
+                blk_iter.seek_to_key_with(key, SeekStrategy::ExponentialFromCurrent);
+            }
+            _ => {
+                self.blk_idx = blk_idx;
+                self.blk_iter = Some(BlockIterator::create_and_seek_to_key(
+                    self.table.read_block_cached(blk_idx)?,
+                    key,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Look up the remaining keys until one of them has a version visible at `read_ts`.
+    fn find_next_match(&mut self) -> Result<()> {
+        while self.next_key < self.keys.len() {
+            let key = self.keys[self.next_key].clone();
+            self.next_key += 1;
+            if !self.may_contain(&key) {
+                continue;
+            }
+            let target = KeySlice::from_slice(&key, self.read_ts);
+            // blocks already passed by an earlier key cannot hold a larger key
+            let blk_idx = self.table.find_block_idx(target).max(self.blk_idx);
+            self.seek_in_block(blk_idx, target)?;
+            // versions may span a block boundary and land at the start of the next block
+            if !self.blk_iter.as_ref().unwrap().is_valid()
+                && blk_idx + 1 < self.table.num_of_blocks()
+            {
+                self.seek_in_block(blk_idx + 1, target)?;
+            }
+            let blk_iter = self.blk_iter.as_ref().unwrap();
+            if blk_iter.is_valid() && blk_iter.key().key_ref() == key {
+                return Ok(());
             }
         }
+        self.blk_iter = None;
         Ok(())
     }
 }
+
+impl StorageIterator for MultiGetIterator {
+    type KeyType<'a> = KeySlice<'a>;
+
+    fn value(&self) -> &[u8] {
+        self.blk_iter.as_ref().unwrap().value()
+    }
+
+    fn key(&self) -> KeySlice<'_> {
+        self.blk_iter.as_ref().unwrap().key()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.blk_iter.is_some()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.find_next_match()
+    }
+}
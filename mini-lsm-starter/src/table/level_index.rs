@@ -0,0 +1,63 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use super::SsTable;
+use crate::key::{shorten_separator, KeyBytes, KeySlice, TS_RANGE_BEGIN};
+
+/// Routes a key to the SST of a sorted, non-overlapping level that may contain it, with a
+/// separator key between each pair of adjacent SSTs.
+///
+/// With full keys, a separator is the first key of the SST after it. With compressed separators,
+/// it is the shortest key between the last key of the previous SST and that first key, see
+/// `shorten_separator`, which keeps the index small when keys are long. Both route every key
+/// stored in the level to the same SST. A key in the gap between two SSTs may go to either of
+/// them, neither contains it, and seeking from either one lands on the first key of the later SST.
+pub struct LevelIndex {
+    /// `separators[i]` is the smallest key routed to the SST at `i + 1`.
+    separators: Vec<KeyBytes>,
+    num_ssts: usize,
+}
+
+impl LevelIndex {
+    /// Builds the index of `ssts`, which are sorted by key and do not overlap.
+    pub fn new(ssts: &[Arc<SsTable>], compress_separators: bool) -> Self {
+        let separators = ssts
+            .windows(2)
+            .map(|pair| {
+                let (last_key, first_key) = (pair[0].last_key(), pair[1].first_key());
+                // when versions of one user key are split across two SSTs, only the full first key
+                // tells them apart
+                if !compress_separators || last_key.key_ref() >= first_key.key_ref() {
+                    return first_key.clone();
+                }
+                let separator = shorten_separator(last_key.key_ref(), first_key.key_ref());
+                // the newest possible version is the smallest key with this user key
+                KeyBytes::from_bytes_with_ts(Bytes::from(separator), TS_RANGE_BEGIN)
+            })
+            .collect();
+        Self {
+            separators,
+            num_ssts: ssts.len(),
+        }
+    }
+
+    /// Returns the index of the SST that `key` routes to, or `None` if the level is empty.
+    pub fn route(&self, key: KeySlice) -> Option<usize> {
+        if self.num_ssts == 0 {
+            return None;
+        }
+        Some(
+            self.separators
+                .partition_point(|separator| separator.as_key_slice() <= key),
+        )
+    }
+
+    /// Number of bytes of the user keys of the separators.
+    pub fn separators_len(&self) -> usize {
+        self.separators
+            .iter()
+            .map(|separator| separator.key_len())
+            .sum()
+    }
+}
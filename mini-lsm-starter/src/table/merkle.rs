@@ -0,0 +1,102 @@
+use anyhow::Result;
+
+use super::SsTable;
+use crate::block::XxHash64;
+
+/// A node of the merkle tree of an SST, see `SsTable::merkle_root`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleNode {
+    /// Hash of the block for a leaf, or of the hashes of the children.
+    pub hash: u64,
+    /// The blocks covered by this node, `[begin, end)`.
+    pub blocks: (usize, usize),
+    /// Empty for a leaf.
+    pub children: Vec<MerkleNode>,
+}
+
+impl MerkleNode {
+    fn from_children(children: Vec<MerkleNode>) -> Self {
+        let mut hasher = XxHash64::new();
+        for child in &children {
+            hasher.update(&child.hash.to_le_bytes());
+        }
+        Self {
+            hash: hasher.digest(),
+            blocks: (children[0].blocks.0, children.last().unwrap().blocks.1),
+            children,
+        }
+    }
+
+    /// Returns the block ranges where `self` and `other` differ, in order. Only the subtrees with
+    /// different hashes are visited, so replicas exchanging the nodes level by level find the
+    /// divergent blocks without transferring the whole SST. When the subtrees are not shaped the
+    /// same, e.g. the SSTs have different numbers of blocks, the range covering both is returned.
+    pub fn diff(&self, other: &MerkleNode) -> Vec<(usize, usize)> {
+        let mut ranges = Vec::new();
+        self.diff_into(other, &mut ranges);
+        ranges
+    }
+
+    fn diff_into(&self, other: &MerkleNode, ranges: &mut Vec<(usize, usize)>) {
+        if self.hash == other.hash && self.blocks == other.blocks {
+            return;
+        }
+        let same_shape = !self.children.is_empty()
+            && self.children.len() == other.children.len()
+            && self
+                .children
+                .iter()
+                .zip(other.children.iter())
+                .all(|(a, b)| a.blocks == b.blocks);
+        if same_shape {
+            for (a, b) in self.children.iter().zip(other.children.iter()) {
+                a.diff_into(b, ranges);
+            }
+        } else {
+            ranges.push((
+                self.blocks.0.min(other.blocks.0),
+                self.blocks.1.max(other.blocks.1),
+            ));
+        }
+    }
+}
+
+impl SsTable {
+    /// Build a merkle tree over the blocks of the SST, with the hash of each block (as stored on
+    /// disk) as a leaf and at most `fanout` children per inner node. Compare the trees of two
+    /// replicas with `MerkleNode::diff`.
+    pub fn merkle_root(&self, fanout: usize) -> Result<MerkleNode> {
+        assert!(fanout >= 2, "fanout must be at least 2");
+        let mut nodes = Vec::with_capacity(self.num_of_blocks());
+        for block_idx in 0..self.num_of_blocks() {
+            let (offset, offset_end) = self.block_range(block_idx);
+            let data = self
+                .file
+                .read(offset as u64, (offset_end - offset) as u64)?;
+            nodes.push(MerkleNode {
+                hash: XxHash64::hash(&data),
+                blocks: (block_idx, block_idx + 1),
+                children: Vec::new(),
+            });
+        }
+        if nodes.is_empty() {
+            return Ok(MerkleNode {
+                hash: XxHash64::hash(&[]),
+                blocks: (0, 0),
+                children: Vec::new(),
+            });
+        }
+        // bottom up, merge every fanout nodes into one parent
+        while nodes.len() > 1 {
+            let mut parents = Vec::with_capacity(nodes.len().div_ceil(fanout));
+            let mut nodes_iter = nodes.into_iter().peekable();
+            while nodes_iter.peek().is_some() {
+                parents.push(MerkleNode::from_children(
+                    nodes_iter.by_ref().take(fanout).collect(),
+                ));
+            }
+            nodes = parents;
+        }
+        Ok(nodes.pop().unwrap())
+    }
+}
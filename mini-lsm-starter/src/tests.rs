@@ -1,7 +1,13 @@
 //! DO NOT MODIFY -- Mini-LSM tests modules
 //! This file will be automatically rewritten by the copy-test command.
 
+mod block_ext;
+mod compact_ext;
 mod harness;
+mod iterator_ext;
+mod key_ext;
+mod mem_table_ext;
+mod table_ext;
 mod week1_day1;
 mod week1_day2;
 mod week1_day3;
@@ -0,0 +1,1313 @@
+use std::{
+    io::{Cursor, Read, Seek, SeekFrom},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use rand::Rng;
+
+use crate::{
+    block::{
+        AddResult, Block, BlockBufferAllocator, BlockBuilder, BlockCompression, BlockError,
+        BlockIterator, BlockLayout, BlockSizePolicy, SeekStrategy, StreamingBlockReader, XxHash64,
+    },
+    iterators::StorageIterator,
+    key::KeySlice,
+};
+
+#[test]
+fn test_block_non_utf8_key_indices() {
+    let mut builder = BlockBuilder::new(4096);
+    let keys: [&[u8]; 5] = [b"apple", b"apple\xff", b"banana", b"c\xc3\x28", b"cherry"];
+    for key in keys {
+        assert!(builder.add(KeySlice::for_testing_from_slice_no_ts(key), b"value"));
+    }
+    let block = builder.build();
+    assert_eq!(block.non_utf8_key_indices(), vec![1, 3]);
+    let block = Block::decode(&block.encode());
+    assert_eq!(block.non_utf8_key_indices(), vec![1, 3]);
+}
+
+#[test]
+fn test_block_seek_strategies_agree() {
+    let mut builder = BlockBuilder::new(65536);
+    for idx in 0..200 {
+        let key = format!("key_{:05}", idx * 5);
+        assert!(builder.add(KeySlice::for_testing_from_slice_no_ts(key.as_bytes()), b"v"));
+    }
+    let block = Arc::new(builder.build());
+    let mut binary = BlockIterator::create_and_seek_to_first(block.clone());
+    let mut exponential = BlockIterator::create_and_seek_to_first(block);
+    let mut rng = rand::thread_rng();
+    for _ in 0..1000 {
+        // the target key is sometimes missing and sometimes past the last key
+        let key = format!("key_{:05}", rng.gen_range(0..1010));
+        let key = KeySlice::for_testing_from_slice_no_ts(key.as_bytes());
+        binary.seek_to_key_with(key, SeekStrategy::Binary);
+        exponential.seek_to_key_with(key, SeekStrategy::ExponentialFromCurrent);
+        assert_eq!(binary.is_valid(), exponential.is_valid());
+        if binary.is_valid() {
+            assert_eq!(binary.key(), exponential.key());
+            assert!(binary.key() >= key);
+        }
+    }
+}
+
+#[test]
+fn test_block_filter_values() {
+    let mut builder = BlockBuilder::new(4096);
+    for idx in 0..20u64 {
+        let key = format!("key_{:03}", idx);
+        let value = (idx * 10).to_be_bytes();
+        assert!(builder.add(
+            KeySlice::for_testing_from_slice_no_ts(key.as_bytes()),
+            &value
+        ));
+    }
+    let block = builder.build();
+    let entries = block.filter_values(|value| u64::from_be_bytes(value.try_into().unwrap()) > 150);
+    assert_eq!(
+        entries
+            .iter()
+            .map(|(key, value)| (
+                String::from_utf8(key.key_ref().to_vec()).unwrap(),
+                u64::from_be_bytes(value.as_slice().try_into().unwrap())
+            ))
+            .collect::<Vec<_>>(),
+        vec![
+            ("key_016".to_string(), 160),
+            ("key_017".to_string(), 170),
+            ("key_018".to_string(), 180),
+            ("key_019".to_string(), 190),
+        ]
+    );
+    assert!(block.filter_values(|_| false).is_empty());
+}
+
+#[test]
+fn test_block_empty_key_rejected() {
+    let mut builder = BlockBuilder::new(4096);
+    assert_eq!(
+        builder.try_add(KeySlice::for_testing_from_slice_no_ts(b""), b"value"),
+        AddResult::EmptyKeyRejected
+    );
+    assert!(!builder.add(KeySlice::for_testing_from_slice_no_ts(b""), b"value"));
+    assert!(builder.is_empty());
+    assert_eq!(
+        builder.try_add(KeySlice::for_testing_from_slice_no_ts(b"key"), b"value"),
+        AddResult::Added
+    );
+    let block = builder.build();
+    assert_eq!(block.offsets.len(), 1);
+}
+
+#[test]
+fn test_block_add_raw_matches_add() {
+    let mut builder = BlockBuilder::new(256);
+    let mut raw_builder = BlockBuilder::new(256);
+    let mut first_key: Option<Vec<u8>> = None;
+    for idx in 0..100u64 {
+        let key = format!("key_{:03}", idx / 2);
+        let value = format!("value_{}", idx);
+        let ts = 100 - idx;
+        let added = builder.try_add(
+            KeySlice::for_testing_from_slice_with_ts(key.as_bytes(), ts),
+            value.as_bytes(),
+        );
+        // the first entry of a block is stored without overlap
+        let overlap = first_key.as_ref().map_or(0, |first| {
+            first
+                .iter()
+                .zip(key.as_bytes())
+                .take_while(|(a, b)| a == b)
+                .count()
+        });
+        first_key.get_or_insert_with(|| key.as_bytes().to_vec());
+        let raw_added = raw_builder.add_raw(
+            overlap as u16,
+            &key.as_bytes()[overlap..],
+            ts,
+            value.as_bytes(),
+        );
+        assert_eq!(added, raw_added);
+        if added != AddResult::Added {
+            break;
+        }
+    }
+    // an empty key is rejected in release builds too
+    assert_eq!(
+        raw_builder.add_raw(0, b"", 0, b"value"),
+        AddResult::EmptyKeyRejected
+    );
+    assert_eq!(builder.build().encode(), raw_builder.build().encode());
+}
+
+#[test]
+fn test_block_seek_to_nth() {
+    let mut builder = BlockBuilder::new(4096);
+    for idx in 0..50 {
+        let key = format!("key_{:03}", idx);
+        assert!(builder.add(KeySlice::for_testing_from_slice_no_ts(key.as_bytes()), b"v"));
+    }
+    let block = Arc::new(builder.build());
+    let mut sequential = BlockIterator::create_and_seek_to_first(block.clone());
+    let mut nth = BlockIterator::create_and_seek_to_first(block);
+    for k in 0..50 {
+        nth.seek_to_nth(k);
+        assert!(nth.is_valid());
+        assert_eq!(nth.key(), sequential.key());
+        assert_eq!(nth.value(), sequential.value());
+        sequential.next();
+    }
+    nth.seek_to_nth(50);
+    assert!(!nth.is_valid());
+}
+
+#[test]
+fn test_block_value_handle() {
+    let mut builder = BlockBuilder::new(4096);
+    for idx in 0..50 {
+        let key = format!("key_{:03}", idx);
+        let value = format!("value_{}", idx * idx);
+        assert!(builder.add(
+            KeySlice::for_testing_from_slice_no_ts(key.as_bytes()),
+            value.as_bytes()
+        ));
+    }
+    let block = Arc::new(builder.build());
+    let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+    let mut handles = Vec::new();
+    while iter.is_valid() {
+        let handle = iter.current_value_handle();
+        assert_eq!(block.value_at(handle), iter.value());
+        // keep the handles of even keys only and read the values later
+        if iter.key().for_testing_key_ref()[6] % 2 == 0 {
+            handles.push(handle);
+        }
+        iter.next();
+    }
+    assert_eq!(handles.len(), 25);
+    for (idx, handle) in handles.into_iter().enumerate() {
+        let expected = format!("value_{}", idx * 2 * idx * 2);
+        assert_eq!(block.value_at(handle), expected.as_bytes());
+    }
+}
+
+#[test]
+fn test_block_columnar_layout() {
+    let mut builder = BlockBuilder::new_with_layout(4096, BlockLayout::Columnar);
+    let mut entries = Vec::new();
+    for idx in 0..60u64 {
+        let key = format!("key_{:03}", idx);
+        let value = "v".repeat(idx as usize % 7);
+        if !builder.add(
+            KeySlice::for_testing_from_slice_with_ts(key.as_bytes(), idx),
+            value.as_bytes(),
+        ) {
+            break;
+        }
+        entries.push((key, idx, value));
+    }
+    let encoded = builder.build().encode();
+    let block = Arc::new(Block::decode(&encoded));
+    assert_eq!(block.layout, BlockLayout::Columnar);
+    let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+    for (key, ts, value) in &entries {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().for_testing_key_ref(), key.as_bytes());
+        assert_eq!(iter.key().ts(), *ts);
+        assert_eq!(iter.value(), value.as_bytes());
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+    iter.seek_to_key(KeySlice::for_testing_from_slice_with_ts(b"key_010", 10));
+    assert_eq!(iter.value(), "vvv".as_bytes());
+
+    // scribble over the value region: keys are still decoded correctly since key-only iteration
+    // never reads it
+    let values_begin = block.value_offsets[0] as usize;
+    let mut corrupted = encoded.to_vec();
+    corrupted[values_begin..block.data.len()].fill(0xff);
+    // the plain layout has no checksum that would catch the corruption
+    let corrupted = Arc::new(Block::decode(&corrupted));
+    assert!(corrupted.checksum.is_none());
+    let mut iter = BlockIterator::create_and_seek_to_first(corrupted.clone());
+    for (key, ts, _) in &entries {
+        assert_eq!(iter.key().for_testing_key_ref(), key.as_bytes());
+        assert_eq!(iter.key().ts(), *ts);
+        iter.next();
+    }
+    iter.seek_to_key(KeySlice::for_testing_from_slice_with_ts(b"key_020", 20));
+    assert_eq!(iter.key().for_testing_key_ref(), b"key_020");
+    assert!(iter.value().iter().all(|x| *x == 0xff));
+}
+
+#[test]
+fn test_block_key_length_stats() {
+    let mut builder = BlockBuilder::new(4096);
+    // overlaps with the first key "apple": 0, 5, 5, 0
+    let keys: [&[u8]; 4] = [b"apple", b"applesauce", b"applet", b"banana"];
+    for key in keys {
+        assert!(builder.add(KeySlice::for_testing_from_slice_no_ts(key), b"value"));
+    }
+    let block = Block::decode(&builder.build().encode());
+    let stats = block.key_length_stats();
+    assert_eq!(stats.min, 5);
+    assert_eq!(stats.max, 10);
+    assert_eq!(stats.mean, 27.0 / 4.0);
+    assert_eq!(stats.total_overlap_bytes, 10);
+}
+
+fn build_validate_block(layout: BlockLayout) -> Block {
+    let mut builder = BlockBuilder::new_with_layout(4096, layout);
+    for idx in 0..10 {
+        let key = format!("key_{:03}", idx);
+        let value = format!("value_{}", idx);
+        assert!(builder.add(
+            KeySlice::for_testing_from_slice_no_ts(key.as_bytes()),
+            value.as_bytes()
+        ));
+    }
+    builder.build()
+}
+
+#[test]
+fn test_block_validate() {
+    for layout in [BlockLayout::RowWise, BlockLayout::Columnar] {
+        let block = build_validate_block(layout);
+        assert_eq!(block.validate(), Ok(()));
+        assert!(Block::decode_checked(&block.encode()).is_ok());
+    }
+
+    assert_eq!(
+        Block::decode_checked(&[0]).err(),
+        Some(BlockError::TooShort { len: 1 })
+    );
+
+    // the header declares more entries than the block can hold
+    let mut encoded = build_validate_block(BlockLayout::RowWise).encode().to_vec();
+    let len = encoded.len();
+    encoded[len - 2..].copy_from_slice(&1000u16.to_be_bytes());
+    assert!(matches!(
+        Block::decode_checked(&encoded),
+        Err(BlockError::EntryCountMismatch { declared: 1000, .. })
+    ));
+
+    let mut block = build_validate_block(BlockLayout::RowWise);
+    block.offsets.swap(3, 4);
+    assert_eq!(
+        block.validate(),
+        Err(BlockError::OffsetsNotIncreasing { idx: 4 })
+    );
+
+    let mut block = build_validate_block(BlockLayout::RowWise);
+    let offset = block.data.len() + 5;
+    block.offsets[9] = offset as u16;
+    assert_eq!(
+        block.validate(),
+        Err(BlockError::OffsetOutOfBounds { idx: 9, offset })
+    );
+
+    // the value length of the third entry runs into the fourth entry, the entry only stores the
+    // last byte of the key as the rest overlaps with the first key
+    let mut block = build_validate_block(BlockLayout::RowWise);
+    let entry = block.offsets[2] as usize;
+    let value_len = entry + 2 + 1 + 1 + 8;
+    let mut data = block.data.to_vec();
+    data[value_len..value_len + 2].copy_from_slice(&100u16.to_be_bytes());
+    block.data = data.into();
+    assert_eq!(block.validate(), Err(BlockError::EntryOverrun { idx: 2 }));
+
+    let mut block = build_validate_block(BlockLayout::Columnar);
+    let entry = block.offsets[5] as usize;
+    let mut data = block.data.to_vec();
+    data[entry..entry + 2].copy_from_slice(&200u16.to_be_bytes());
+    block.data = data.into();
+    assert_eq!(
+        block.validate(),
+        Err(BlockError::InvalidOverlap {
+            idx: 5,
+            overlap: 200
+        })
+    );
+
+    let mut block = build_validate_block(BlockLayout::Columnar);
+    block.value_offsets.swap(1, 2);
+    assert_eq!(
+        block.validate(),
+        Err(BlockError::OffsetsNotIncreasing { idx: 2 })
+    );
+}
+
+#[test]
+fn test_block_key_longer_than_u16() {
+    let long_key = vec![b'k'; 70000];
+    let mut builder = BlockBuilder::new(4096);
+    assert!(builder.add(KeySlice::for_testing_from_slice_no_ts(&long_key), b"value"));
+    // the key is larger than the block, nothing else fits in the same block
+    assert!(!builder.add(KeySlice::for_testing_from_slice_no_ts(b"z"), b"value"));
+    let block = Arc::new(Block::decode(&builder.build().encode()));
+    assert_eq!(block.validate(), Ok(()));
+    let iter = BlockIterator::create_and_seek_to_first(block);
+    assert_eq!(iter.key().key_ref(), &long_key[..]);
+    assert_eq!(iter.value(), b"value");
+
+    // a long key after a short one starts a new block
+    let mut builder = BlockBuilder::new(4096);
+    assert!(builder.add(KeySlice::for_testing_from_slice_no_ts(b"a"), b"value"));
+    assert!(!builder.add(KeySlice::for_testing_from_slice_no_ts(&long_key), b"value"));
+}
+
+#[test]
+fn test_block_extension_trailer() {
+    for layout in [BlockLayout::RowWise, BlockLayout::Columnar] {
+        let mut builder = BlockBuilder::new_with_layout(4096, layout);
+        builder.set_extension(b"future fields".to_vec());
+        for idx in 0..20 {
+            let key = format!("key_{:03}", idx);
+            let value = format!("value_{}", idx);
+            assert!(builder.add(
+                KeySlice::for_testing_from_slice_no_ts(key.as_bytes()),
+                value.as_bytes()
+            ));
+        }
+        let block = Arc::new(Block::decode_checked(&builder.build().encode()).unwrap());
+        assert_eq!(block.extension(), b"future fields");
+        let mut iter = BlockIterator::create_and_seek_to_first(block);
+        for idx in 0..20 {
+            assert_eq!(iter.key().key_ref(), format!("key_{:03}", idx).as_bytes());
+            assert_eq!(iter.value(), format!("value_{}", idx).as_bytes());
+            iter.next();
+        }
+        assert!(!iter.is_valid());
+    }
+
+    // blocks written before the extension trailer existed have no `EXTENSION_FLAG`
+    let block = build_validate_block(BlockLayout::RowWise);
+    let mut encoded = block.data.to_vec();
+    for offset in &block.offsets {
+        encoded.extend_from_slice(&offset.to_be_bytes());
+    }
+    encoded.extend_from_slice(&(block.offsets.len() as u16).to_be_bytes());
+    // which is still the layout of a block without extension
+    assert_eq!(&block.encode()[..], &encoded[..]);
+    let decoded = Block::decode(&encoded);
+    assert!(decoded.extension().is_empty());
+    assert_eq!(decoded.offsets, block.offsets);
+    assert_eq!(decoded.data, block.data);
+}
+
+#[test]
+fn test_xxhash64() {
+    assert_eq!(XxHash64::hash(b""), 0xEF46DB3751D8E999);
+    assert_eq!(XxHash64::hash(b"a"), 0xD24EC4F1A98C6E5B);
+    assert_eq!(XxHash64::hash(b"abc"), 0x44BC2CF5AD770999);
+    assert_eq!(
+        XxHash64::hash(b"Nobody inspects the spammish repetition"),
+        0xFBCEA83C8A378BF1
+    );
+    // feeding the input in pieces of any size gives the same hash
+    let data = (0..1000u32).map(|x| (x * 7) as u8).collect::<Vec<_>>();
+    for piece in [1, 3, 8, 31, 32, 33, 100] {
+        let mut hasher = XxHash64::new();
+        for chunk in data.chunks(piece) {
+            hasher.update(chunk);
+        }
+        assert_eq!(hasher.digest(), XxHash64::hash(&data));
+    }
+}
+
+#[test]
+fn test_block_incremental_checksum() {
+    for layout in [BlockLayout::RowWise, BlockLayout::Columnar] {
+        let block = build_validate_block(layout);
+        assert_eq!(block.checksum, Some(XxHash64::hash(&block.data)));
+        let decoded = Block::decode_with_checksum(&block.encode_with_checksum()).unwrap();
+        assert_eq!(decoded.checksum, block.checksum);
+        assert!(decoded.verify_checksum());
+        // the plain layout does not store the checksum
+        assert_eq!(Block::decode(&block.encode()).checksum, None);
+
+        // flip a byte of a value, without the crc32 that would catch it first
+        let mut encoded = block.encode_with_checksum().to_vec();
+        encoded.truncate(encoded.len() - 4);
+        let (begin, end) =
+            BlockIterator::create_and_seek_to_first(Arc::new(block)).current_value_handle();
+        assert!(begin < end);
+        encoded[begin] ^= 0xff;
+        let corrupted = Block::decode(&encoded);
+        assert!(!corrupted.verify_checksum());
+        assert!(matches!(
+            corrupted.validate(),
+            Err(BlockError::ChecksumMismatch { .. })
+        ));
+        assert!(matches!(
+            Block::decode_checked(&encoded),
+            Err(BlockError::ChecksumMismatch { .. })
+        ));
+    }
+}
+
+#[derive(Default)]
+struct CountingAllocator {
+    data: AtomicUsize,
+    offsets: AtomicUsize,
+}
+
+impl BlockBufferAllocator for CountingAllocator {
+    fn alloc_data(&self, capacity: usize) -> Vec<u8> {
+        self.data.fetch_add(1, Ordering::SeqCst);
+        Vec::with_capacity(capacity)
+    }
+
+    fn alloc_offsets(&self, capacity: usize) -> Vec<u16> {
+        self.offsets.fetch_add(1, Ordering::SeqCst);
+        Vec::with_capacity(capacity)
+    }
+}
+
+#[test]
+fn test_block_builder_custom_allocator() {
+    let allocator = CountingAllocator::default();
+    for round in 1..=3 {
+        let mut builder = BlockBuilder::new_in(4096, &allocator);
+        for idx in 0..10 {
+            let key = format!("key_{:03}", idx);
+            assert!(builder.add(
+                KeySlice::for_testing_from_slice_no_ts(key.as_bytes()),
+                b"value"
+            ));
+        }
+        let block = Arc::new(builder.build());
+        assert_eq!(allocator.data.load(Ordering::SeqCst), round);
+        assert_eq!(allocator.offsets.load(Ordering::SeqCst), round);
+        let iter = BlockIterator::create_and_seek_to_first(block.clone());
+        assert_eq!(iter.key().for_testing_key_ref(), b"key_000");
+        drop(iter);
+        // the buffer from the allocator is large enough for the whole block
+        let block = Arc::into_inner(block).unwrap();
+        assert!(Vec::from(block.data).capacity() >= 4096);
+    }
+}
+
+#[test]
+fn test_block_wire_round_trip() {
+    let mut builder = BlockBuilder::new(4096);
+    for idx in 0..100 {
+        let key = format!("key_{:05}", idx);
+        let value = format!("value_{:05}_{}", idx, "x".repeat(idx % 20));
+        if !builder.add(
+            KeySlice::for_testing_from_slice_no_ts(key.as_bytes()),
+            value.as_bytes(),
+        ) {
+            break;
+        }
+    }
+    let block = builder.build();
+    let encoded = block.encode();
+    for codec in [BlockCompression::None, BlockCompression::Lz4] {
+        let wire = block.to_wire(codec);
+        let decoded = Block::from_wire(&wire).unwrap();
+        assert_eq!(decoded.encode(), encoded);
+
+        assert!(Block::from_wire(&wire[..wire.len() - 1]).is_err());
+        let mut corrupted = wire.clone();
+        corrupted[0] = 0xff;
+        assert!(Block::from_wire(&corrupted).is_err());
+    }
+    let compressed = block.to_wire(BlockCompression::Lz4);
+    assert!(compressed.len() < encoded.len() / 2);
+
+    // incompressible data survives the round trip as well
+    let mut rng = rand::thread_rng();
+    let mut builder = BlockBuilder::new(4096);
+    let value = (0..1000).map(|_| rng.gen::<u8>()).collect::<Vec<_>>();
+    assert!(builder.add(KeySlice::for_testing_from_slice_no_ts(b"key"), &value));
+    let block = builder.build();
+    let decoded = Block::from_wire(&block.to_wire(BlockCompression::Lz4)).unwrap();
+    assert_eq!(decoded.encode(), block.encode());
+}
+
+#[test]
+fn test_block_dictionary_layout() {
+    let values = ["pending", "active", "suspended", "deleted", "archived"]
+        .map(|status| format!("{}_{}", status, "x".repeat(32)));
+    let build = |layout| {
+        let mut builder = BlockBuilder::new_with_layout(65536, layout);
+        for idx in 0..1000 {
+            let key = format!("key_{:04}", idx);
+            assert!(builder.add(
+                KeySlice::for_testing_from_slice_no_ts(key.as_bytes()),
+                values[idx * 7 % 5].as_bytes()
+            ));
+        }
+        builder.build().encode()
+    };
+    let inline = build(BlockLayout::RowWise);
+    let encoded = build(BlockLayout::Dictionary);
+    assert!(encoded.len() * 2 < inline.len());
+    let block = Arc::new(Block::decode_checked(&encoded).unwrap());
+    assert_eq!(block.layout, BlockLayout::Dictionary);
+    assert_eq!(block.value_offsets.len(), 5);
+    let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+    for idx in 0..1000 {
+        assert!(iter.is_valid());
+        assert_eq!(
+            iter.key().for_testing_key_ref(),
+            format!("key_{:04}", idx).as_bytes()
+        );
+        assert_eq!(iter.value(), values[idx * 7 % 5].as_bytes());
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+
+    // an entry referring to a value outside of the dictionary
+    let mut corrupted = Block::decode(&encoded);
+    corrupted.value_offsets.truncate(2);
+    corrupted.checksum = None;
+    assert!(matches!(
+        corrupted.validate(),
+        Err(BlockError::InvalidValueIndex { .. })
+    ));
+}
+
+#[test]
+fn test_block_dictionary_fallback() {
+    // too many distinct values: the entries are inlined as in the row-wise layout
+    let mut builder = BlockBuilder::new_with_layout(65536, BlockLayout::Dictionary);
+    let entries = (0..400)
+        .map(|idx| {
+            let value = if idx < 100 {
+                format!("repeated_{}", idx % 3)
+            } else {
+                format!("distinct_{}", idx)
+            };
+            (format!("key_{:04}", idx), value)
+        })
+        .collect::<Vec<_>>();
+    for (key, value) in &entries {
+        assert!(builder.add(
+            KeySlice::for_testing_from_slice_no_ts(key.as_bytes()),
+            value.as_bytes()
+        ));
+    }
+    let block = Arc::new(Block::decode_checked(&builder.build().encode()).unwrap());
+    assert_eq!(block.layout, BlockLayout::RowWise);
+    let mut iter = BlockIterator::create_and_seek_to_first(block);
+    for (key, value) in &entries {
+        assert_eq!(iter.key().for_testing_key_ref(), key.as_bytes());
+        assert_eq!(iter.value(), value.as_bytes());
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+
+    // the inlined entries would not fit: the block is finished with its dictionary
+    let mut builder = BlockBuilder::new_with_layout(16384, BlockLayout::Dictionary);
+    let repeated = "r".repeat(200);
+    let mut num_entries = 0;
+    for idx in 0..1000 {
+        let key = format!("key_{:04}", idx);
+        let value = if idx < 300 {
+            repeated.clone()
+        } else {
+            format!("d{}", idx)
+        };
+        if !builder.add(
+            KeySlice::for_testing_from_slice_no_ts(key.as_bytes()),
+            value.as_bytes(),
+        ) {
+            break;
+        }
+        num_entries += 1;
+    }
+    // one repeated value and 255 distinct ones
+    assert_eq!(num_entries, 555);
+    let block = Block::decode_checked(&builder.build().encode()).unwrap();
+    assert_eq!(block.layout, BlockLayout::Dictionary);
+    assert!(block.encode().len() <= 16384);
+}
+
+#[test]
+fn test_block_value_prefix_compression() {
+    let mut rng = rand::thread_rng();
+    let entries = (0..300)
+        .map(|idx| {
+            let key = format!("key_{:04}", idx);
+            let value = if idx % 10 < 7 {
+                // JSON documents with a long shared prefix
+                format!(
+                    r#"{{"sensor":"thermometer","site":"north","reading":{}}}"#,
+                    idx
+                )
+                .into_bytes()
+            } else {
+                (0..rng.gen_range(0..40)).map(|_| rng.gen::<u8>()).collect()
+            };
+            (key, value)
+        })
+        .collect::<Vec<_>>();
+    let build = |mut builder: BlockBuilder| {
+        for (key, value) in &entries {
+            assert!(builder.add(
+                KeySlice::for_testing_from_slice_no_ts(key.as_bytes()),
+                value
+            ));
+        }
+        builder.build()
+    };
+    let plain = build(BlockBuilder::new(65536));
+    assert_eq!(plain.layout, BlockLayout::RowWise);
+    let compressed = build(BlockBuilder::new_with_value_prefix_compression(65536));
+    let encoded = compressed.encode();
+    assert!(encoded.len() * 3 < plain.encode().len() * 2);
+    // the values stay compressed in memory
+    assert_eq!(
+        compressed.data.len(),
+        plain.data.len() - (plain.encode().len() - encoded.len())
+    );
+
+    let block = Arc::new(Block::decode_checked(&encoded).unwrap());
+    assert_eq!(block.layout, BlockLayout::PrefixCompressedValues);
+    assert_eq!(block.encode(), encoded);
+    let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+    for (key, value) in &entries {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().for_testing_key_ref(), key.as_bytes());
+        assert_eq!(iter.value(), &value[..]);
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+    for _ in 0..100 {
+        let idx = rng.gen_range(0..entries.len());
+        let (key, value) = &entries[idx];
+        iter.seek_to_key(KeySlice::for_testing_from_slice_no_ts(key.as_bytes()));
+        assert_eq!(iter.value(), &value[..]);
+        // the values after a seek are rebuilt from the current one
+        for (_, value) in entries.iter().skip(idx + 1).take(3) {
+            iter.next();
+            assert_eq!(iter.value(), &value[..]);
+        }
+        if idx > 0 {
+            iter.seek_to_key(KeySlice::for_testing_from_slice_no_ts(key.as_bytes()));
+            iter.prev();
+            assert_eq!(iter.value(), &entries[idx - 1].1[..]);
+        }
+        let expected = (!value.is_empty()).then(|| value.clone());
+        assert_eq!(block.get(key.as_bytes(), 0), expected);
+    }
+    let values = block.filter_values(|_| true);
+    assert_eq!(values.len(), entries.len());
+    for ((_, value), (_, expected)) in values.iter().zip(&entries) {
+        assert_eq!(value, expected);
+    }
+
+    // a value overlapping more than the previous value is rejected
+    let mut corrupted = encoded.to_vec();
+    let first_key_len = entries[0].0.len();
+    // overlap, key length, key and ts of the first entry, then its value overlap
+    let value_overlap = 2 + 1 + first_key_len + 8;
+    corrupted[value_overlap..value_overlap + 2].copy_from_slice(&1u16.to_be_bytes());
+    assert_eq!(
+        Block::decode_checked(&corrupted).err(),
+        Some(BlockError::InvalidValuePrefix { idx: 0, overlap: 1 })
+    );
+
+    // the block fits more entries than the row-wise layout
+    let count = |mut builder: BlockBuilder| {
+        entries
+            .iter()
+            .take_while(|(key, value)| {
+                builder.add(
+                    KeySlice::for_testing_from_slice_no_ts(key.as_bytes()),
+                    value,
+                )
+            })
+            .count()
+    };
+    assert!(
+        count(BlockBuilder::new_with_value_prefix_compression(4096))
+            > count(BlockBuilder::new(4096))
+    );
+}
+
+#[test]
+fn test_block_get() {
+    let mut builder = BlockBuilder::new(4096);
+    let entries: [(&[u8], u64, &[u8]); 7] = [
+        (b"a", 5, b"a5"),
+        (b"b", 9, b""),
+        (b"b", 6, b"b6"),
+        (b"b", 2, b"b2"),
+        (b"c", 4, b"c4"),
+        (b"c", 3, b""),
+        (b"c", 1, b"c1"),
+    ];
+    for (key, ts, value) in entries {
+        assert!(builder.add(KeySlice::for_testing_from_slice_with_ts(key, ts), value));
+    }
+    let block = Block::decode(&builder.build().encode());
+    assert_eq!(block.get(b"a", 5), Some(b"a5".to_vec()));
+    assert_eq!(block.get(b"a", 100), Some(b"a5".to_vec()));
+    assert_eq!(block.get(b"a", 4), None);
+    assert_eq!(block.get(b"b", 1), None);
+    assert_eq!(block.get(b"b", 2), Some(b"b2".to_vec()));
+    assert_eq!(block.get(b"b", 5), Some(b"b2".to_vec()));
+    assert_eq!(block.get(b"b", 8), Some(b"b6".to_vec()));
+    // deleted at ts 9
+    assert_eq!(block.get(b"b", 9), None);
+    assert_eq!(block.get(b"b", 100), None);
+    assert_eq!(block.get(b"c", 2), Some(b"c1".to_vec()));
+    assert_eq!(block.get(b"c", 3), None);
+    assert_eq!(block.get(b"c", 4), Some(b"c4".to_vec()));
+    // absent keys
+    assert_eq!(block.get(b"", 100), None);
+    assert_eq!(block.get(b"bb", 100), None);
+    assert_eq!(block.get(b"d", 100), None);
+}
+
+#[test]
+fn test_block_len() {
+    let mut builder = BlockBuilder::new(4096);
+    for idx in 0..10 {
+        let key = format!("key_{:03}", idx);
+        assert!(builder.add(KeySlice::for_testing_from_slice_no_ts(key.as_bytes()), b"v"));
+    }
+    let block = builder.build();
+    assert!(!block.is_empty());
+    assert_eq!(block.len(), 10);
+    let block = Arc::new(Block::decode(&block.encode()));
+    assert_eq!(block.len(), 10);
+    let mut iter = BlockIterator::create_and_seek_to_first(block);
+    assert_eq!(iter.num_entries(), 10);
+    while iter.is_valid() {
+        iter.next();
+    }
+    assert_eq!(iter.num_entries(), 10);
+}
+
+#[test]
+fn test_block_reverse_iteration() {
+    let mut builder = BlockBuilder::new(4096);
+    for idx in 0..50 {
+        let key = format!("key_{:03}", idx * 2);
+        let value = format!("value_{}", idx);
+        assert!(builder.add(
+            KeySlice::for_testing_from_slice_no_ts(key.as_bytes()),
+            value.as_bytes()
+        ));
+    }
+    let block = Arc::new(Block::decode(&builder.build().encode()));
+
+    let mut iter = BlockIterator::create_and_seek_to_last(block.clone());
+    for idx in (0..50).rev() {
+        assert!(iter.is_valid());
+        assert_eq!(
+            iter.key().for_testing_key_ref(),
+            format!("key_{:03}", idx * 2).as_bytes()
+        );
+        assert_eq!(iter.value(), format!("value_{}", idx).as_bytes());
+        iter.prev();
+    }
+    assert!(!iter.is_valid());
+
+    // exact match, between two keys, after the last key
+    for (target, expected) in [
+        ("key_040", "key_040"),
+        ("key_041", "key_040"),
+        ("z", "key_098"),
+    ] {
+        iter.seek_to_key_le(KeySlice::for_testing_from_slice_no_ts(target.as_bytes()));
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().for_testing_key_ref(), expected.as_bytes());
+    }
+    // continue backwards from the middle of the block
+    iter.seek_to_key_le(KeySlice::for_testing_from_slice_no_ts(b"key_007"));
+    let mut keys = Vec::new();
+    while iter.is_valid() {
+        keys.push(iter.key().for_testing_key_ref().to_vec());
+        iter.prev();
+    }
+    assert_eq!(
+        keys,
+        vec![
+            b"key_006".to_vec(),
+            b"key_004".to_vec(),
+            b"key_002".to_vec(),
+            b"key_000".to_vec()
+        ]
+    );
+    // before the first key
+    iter.seek_to_key_le(KeySlice::for_testing_from_slice_no_ts(b"a"));
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_block_builder_would_fit() {
+    for layout in [
+        BlockLayout::RowWise,
+        BlockLayout::Columnar,
+        BlockLayout::Dictionary,
+    ] {
+        let mut builder = BlockBuilder::new_with_layout(256, layout);
+        let mut rng = rand::thread_rng();
+        let mut idx = 0;
+        loop {
+            let key = format!("key_{:05}", idx);
+            let key = KeySlice::for_testing_from_slice_with_ts(key.as_bytes(), idx);
+            let value = vec![b'a' + rng.gen_range(0..4); rng.gen_range(0..40)];
+            let remaining = builder.estimated_remaining();
+            let fits = builder.would_fit(key, &value);
+            assert_eq!(builder.add(key, &value), fits);
+            if !fits {
+                assert_eq!(builder.estimated_remaining(), remaining);
+                break;
+            }
+            assert!(builder.estimated_remaining() < remaining);
+            idx += 1;
+        }
+        assert!(idx > 1);
+    }
+    let builder = BlockBuilder::new(4096);
+    assert!(!builder.would_fit(KeySlice::for_testing_from_slice_no_ts(b""), b"value"));
+    // the first entry always fits
+    assert!(builder.would_fit(KeySlice::for_testing_from_slice_no_ts(b"key"), &[0; 8192]));
+}
+
+#[test]
+fn test_block_builder_would_fit_dictionary_fallback() {
+    let mut builder = BlockBuilder::new_with_layout(65536, BlockLayout::Dictionary);
+    let mut idx = 0;
+    loop {
+        let key = format!("key_{:05}", idx);
+        let key = KeySlice::for_testing_from_slice_no_ts(key.as_bytes());
+        let value = format!("value_{}", idx);
+        let fits = builder.would_fit(key, value.as_bytes());
+        assert_eq!(builder.add(key, value.as_bytes()), fits);
+        if !fits {
+            break;
+        }
+        idx += 1;
+    }
+    // the builder fell back to the row-wise layout after 256 distinct values
+    assert!(idx > 256);
+}
+
+#[test]
+fn test_block_size_policy() {
+    let large_value = [0; 8192];
+    // the soft policy always takes the first entry
+    let mut builder = BlockBuilder::new_with_policy(4096, BlockSizePolicy::Soft);
+    let key = KeySlice::for_testing_from_slice_no_ts(b"key");
+    assert!(builder.would_fit(key, &large_value));
+    assert_eq!(builder.try_add(key, &large_value), AddResult::Added);
+    assert_eq!(builder.build().len(), 1);
+
+    // the hard policy refuses the first entry when it exceeds the maximum
+    let mut builder = BlockBuilder::new_with_policy(4096, BlockSizePolicy::Hard { max: 4096 });
+    assert!(!builder.would_fit(key, &large_value));
+    assert_eq!(
+        builder.try_add(key, &large_value),
+        AddResult::ExceedsHardLimit
+    );
+    assert!(!builder.add(key, &large_value));
+    assert!(builder.is_empty());
+    // a first entry above the block size but within the maximum is still taken
+    let mut builder = BlockBuilder::new_with_policy(4096, BlockSizePolicy::Hard { max: 16384 });
+    assert_eq!(builder.try_add(key, &large_value), AddResult::Added);
+    assert_eq!(
+        builder.try_add(KeySlice::for_testing_from_slice_no_ts(b"key2"), b"value"),
+        AddResult::Full
+    );
+
+    // once the block has entries, both policies split at the block size
+    let mut soft = BlockBuilder::new_with_policy(256, BlockSizePolicy::Soft);
+    let mut hard = BlockBuilder::new_with_policy(256, BlockSizePolicy::Hard { max: 256 });
+    let mut idx = 0;
+    loop {
+        let key = format!("key_{:05}", idx);
+        let key = KeySlice::for_testing_from_slice_no_ts(key.as_bytes());
+        let added = soft.add(key, b"value");
+        assert_eq!(hard.try_add(key, b"value") == AddResult::Added, added);
+        if !added {
+            break;
+        }
+        idx += 1;
+    }
+    let block = hard.build();
+    assert_eq!(block.len(), idx);
+    assert!(block.encode().len() <= 256);
+}
+
+/// Returns at most 3 bytes per read.
+struct ChunkedReader(Cursor<Vec<u8>>);
+
+impl Read for ChunkedReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(3);
+        self.0.read(&mut buf[..len])
+    }
+}
+
+impl Seek for ChunkedReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+#[test]
+fn test_streaming_block_reader() {
+    for mut builder in [
+        BlockBuilder::new(4096),
+        BlockBuilder::new_with_value_prefix_compression(4096),
+    ] {
+        builder.set_extension(b"ext".to_vec());
+        for idx in 0..60 {
+            let key = format!("key_{:03}", idx);
+            let value = format!(
+                "{{\"id\":{},\"payload\":\"{}\"}}",
+                idx / 3,
+                "x".repeat(idx % 7)
+            );
+            assert!(builder.add(
+                KeySlice::for_testing_from_slice_with_ts(key.as_bytes(), idx as u64),
+                value.as_bytes()
+            ));
+        }
+        let block = Arc::new(builder.build());
+        // the checksum without the crc32 that follows it
+        let encoded = block.encode_with_checksum();
+        let encoded = encoded.slice(..encoded.len() - 4);
+        // the block is in the middle of a file
+        let mut file = b"header".to_vec();
+        file.extend_from_slice(&encoded);
+        file.extend_from_slice(b"footer");
+        let mut reader = ChunkedReader(Cursor::new(file.clone()));
+        reader.seek(SeekFrom::Start(6)).unwrap();
+        let mut stream =
+            StreamingBlockReader::create_and_seek_to_first(reader, encoded.len()).unwrap();
+        let mut iter = BlockIterator::create_and_seek_to_first(block);
+        while iter.is_valid() {
+            assert!(stream.is_valid());
+            assert_eq!(stream.key(), iter.key());
+            assert_eq!(stream.value(), iter.value());
+            stream.next().unwrap();
+            iter.next();
+        }
+        assert!(!stream.is_valid());
+
+        // a corrupted value fails the checksum at the end of the block
+        file[6 + 20] ^= 1;
+        let mut reader = ChunkedReader(Cursor::new(file));
+        reader.seek(SeekFrom::Start(6)).unwrap();
+        let result = StreamingBlockReader::create_and_seek_to_first(reader, encoded.len())
+            .and_then(|mut stream| {
+                while stream.is_valid() {
+                    stream.next()?;
+                }
+                Ok(())
+            });
+        assert!(result.is_err());
+    }
+
+    let mut builder = BlockBuilder::new_with_layout(4096, BlockLayout::Columnar);
+    assert!(builder.add(KeySlice::for_testing_from_slice_no_ts(b"key"), b"value"));
+    let encoded = builder.build().encode();
+    assert!(StreamingBlockReader::create_and_seek_to_first(
+        Cursor::new(encoded.to_vec()),
+        encoded.len()
+    )
+    .is_err());
+}
+
+#[test]
+fn test_block_try_decode_corrupted() {
+    let mut builder = BlockBuilder::new(4096);
+    for idx in 0..20 {
+        let key = format!("key_{:03}", idx);
+        assert!(builder.add(
+            KeySlice::for_testing_from_slice_no_ts(key.as_bytes()),
+            b"value"
+        ));
+    }
+    let block = builder.build();
+    let encoded = block.encode();
+    assert!(Block::try_decode(&encoded).is_ok());
+    // truncated blocks and garbage
+    for len in [0, 1, 2, 10, encoded.len() / 2, encoded.len() - 1] {
+        assert!(Block::try_decode(&encoded[..len]).is_err());
+    }
+    let mut rng = rand::thread_rng();
+    for _ in 0..100 {
+        let len = rng.gen_range(0..256);
+        let garbage = (0..len).map(|_| rng.gen()).collect::<Vec<u8>>();
+        let _ = Block::try_decode(&garbage);
+    }
+
+    // an entry whose overlap is longer than the first key invalidates the iterator
+    let mut block = Block::decode(&encoded);
+    block.checksum = None;
+    let offset = block.offsets[5] as usize;
+    let mut data = block.data.to_vec();
+    data[offset..offset + 2].copy_from_slice(&u16::MAX.to_be_bytes());
+    block.data = data.into();
+    let encoded = block.encode();
+    assert!(Block::try_decode(&encoded).is_err());
+    let block = Arc::new(block);
+    let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+    for _ in 0..5 {
+        assert!(iter.is_valid());
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+    // the binary search runs into the malformed entry
+    iter.seek_to_key(KeySlice::for_testing_from_slice_no_ts(b"key_005"));
+    assert!(!iter.is_valid());
+    iter.seek_to_nth(2);
+    assert!(iter.is_valid());
+    iter.seek_to_key_with(
+        KeySlice::for_testing_from_slice_no_ts(b"key_006"),
+        SeekStrategy::ExponentialFromCurrent,
+    );
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_block_decode_shared() {
+    let build = |layout| {
+        let mut builder = BlockBuilder::new_with_layout(4096, layout);
+        let mut idx = 0;
+        loop {
+            let key = format!("key_{:05}", idx);
+            let value = format!("value_{}", idx % 7);
+            if !builder.add(
+                KeySlice::for_testing_from_slice_with_ts(key.as_bytes(), idx),
+                value.as_bytes(),
+            ) {
+                break;
+            }
+            idx += 1;
+        }
+        builder.build().encode()
+    };
+    for layout in [
+        BlockLayout::RowWise,
+        BlockLayout::Columnar,
+        BlockLayout::Dictionary,
+        BlockLayout::PrefixCompressedValues,
+    ] {
+        let encoded = build(layout);
+        let copied = Arc::new(Block::decode(&encoded));
+        let shared = Block::decode_shared(encoded.clone());
+        assert_eq!(shared.data, copied.data);
+        assert_eq!(shared.offsets, copied.offsets);
+        assert_eq!(shared.value_offsets, copied.value_offsets);
+        // the entries are not copied out of the encoded block
+        assert_eq!(shared.data.as_ptr(), encoded.as_ptr());
+        let mut copied_iter = BlockIterator::create_and_seek_to_first(copied);
+        let mut shared_iter = BlockIterator::create_and_seek_to_first(shared);
+        while copied_iter.is_valid() {
+            assert!(shared_iter.is_valid());
+            assert_eq!(shared_iter.key(), copied_iter.key());
+            assert_eq!(shared_iter.value(), copied_iter.value());
+            copied_iter.next();
+            shared_iter.next();
+        }
+        assert!(!shared_iter.is_valid());
+    }
+
+    let encoded = build(BlockLayout::RowWise);
+    let rounds = 10000;
+    let begin = std::time::Instant::now();
+    for _ in 0..rounds {
+        std::hint::black_box(Block::decode(&encoded));
+    }
+    let copied_elapsed = begin.elapsed();
+    let begin = std::time::Instant::now();
+    for _ in 0..rounds {
+        std::hint::black_box(Block::decode_shared(encoded.clone()));
+    }
+    let shared_elapsed = begin.elapsed();
+    println!(
+        "decode: {:?}, decode_shared: {:?} for {} rounds",
+        copied_elapsed, shared_elapsed, rounds
+    );
+}
+
+#[test]
+fn test_block_seek_to_user_key() {
+    let mut builder = BlockBuilder::new(4096);
+    let entries: [(&[u8], u64); 8] = [
+        (b"a", 9),
+        (b"b", 8),
+        (b"b", 5),
+        (b"b", 1),
+        (b"c", 7),
+        (b"d", 6),
+        (b"d", 3),
+        (b"d", 2),
+    ];
+    for (key, ts) in entries {
+        assert!(builder.add(KeySlice::for_testing_from_slice_with_ts(key, ts), b"v"));
+    }
+    let block = Arc::new(builder.build());
+    let mut iter = BlockIterator::create_and_seek_to_first(block);
+    for (user_key, expected) in [
+        (&b""[..], Some((&b"a"[..], 9))),
+        (b"a", Some((b"a", 9))),
+        (b"b", Some((b"b", 8))),
+        (b"bb", Some((b"c", 7))),
+        (b"d", Some((b"d", 6))),
+        (b"e", None),
+    ] {
+        iter.seek_to_user_key(user_key);
+        match expected {
+            Some((key, ts)) => {
+                assert!(iter.is_valid());
+                assert_eq!(iter.key().key_ref(), key);
+                assert_eq!(iter.key().ts(), ts);
+            }
+            None => assert!(!iter.is_valid()),
+        }
+    }
+    // the ts-aware seek still lands on the first version <= the ts
+    iter.seek_to_key(KeySlice::for_testing_from_slice_with_ts(b"d", 4));
+    assert_eq!(iter.key().key_ref(), b"d");
+    assert_eq!(iter.key().ts(), 3);
+    iter.seek_to_key(KeySlice::for_testing_from_slice_with_ts(b"b", 0));
+    assert_eq!(iter.key().key_ref(), b"c");
+}
+
+#[test]
+fn test_block_crc32() {
+    let block = build_validate_block(BlockLayout::RowWise);
+    let encoded = block.encode_with_checksum();
+    // the xxHash64 of the data and the crc32
+    assert_eq!(encoded.len(), block.encode().len() + 8 + 4);
+    let decoded = Block::decode_with_checksum(&encoded).unwrap();
+    assert_eq!(decoded.data, block.data);
+    assert_eq!(decoded.offsets, block.offsets);
+    let decoded = Block::decode_shared_with_checksum(encoded.clone()).unwrap();
+    assert_eq!(decoded.data, block.data);
+
+    for idx in [0, encoded.len() / 2, encoded.len() - 1] {
+        let mut corrupted = encoded.to_vec();
+        corrupted[idx] ^= 0x01;
+        assert!(matches!(
+            Block::decode_with_checksum(&corrupted),
+            Err(BlockError::Crc32Mismatch { .. })
+        ));
+        assert!(matches!(
+            Block::decode_shared_with_checksum(corrupted.into()),
+            Err(BlockError::Crc32Mismatch { .. })
+        ));
+    }
+    assert_eq!(
+        Block::decode_with_checksum(&[0; 3]).err(),
+        Some(BlockError::TooShort { len: 3 })
+    );
+
+    // the plain layout followed by its crc32, as in an SST
+    let mut encoded = block.encode().to_vec();
+    encoded.extend_from_slice(&crc32fast::hash(&encoded).to_be_bytes());
+    let decoded = Block::decode_with_checksum(&encoded).unwrap();
+    assert_eq!(decoded.data, block.data);
+    assert_eq!(decoded.checksum, None);
+
+    // a block corrupted before its crc32 was computed is caught by the checksum of its data
+    let mut corrupted = block.encode_with_checksum().to_vec();
+    corrupted.truncate(corrupted.len() - 4);
+    corrupted[block.offsets[1] as usize - 1] ^= 0x01;
+    corrupted.extend_from_slice(&crc32fast::hash(&corrupted).to_be_bytes());
+    assert!(matches!(
+        Block::decode_with_checksum(&corrupted),
+        Err(BlockError::ChecksumMismatch { .. })
+    ));
+    assert!(matches!(
+        Block::decode_shared_with_checksum(corrupted.into()),
+        Err(BlockError::ChecksumMismatch { .. })
+    ));
+}
+
+#[test]
+fn test_block_builder_build_with() {
+    const MASK: u8 = 0x5a;
+    let mut builder = BlockBuilder::new(4096);
+    for idx in 0..20 {
+        let key = format!("key_{:03}", idx);
+        let value = format!("value_{}", idx);
+        assert!(builder.add(
+            KeySlice::for_testing_from_slice_no_ts(key.as_bytes()),
+            value.as_bytes()
+        ));
+    }
+    let obfuscated = builder.build_with(|data, offsets| {
+        Block::from_raw_parts(data.into_iter().map(|x| x ^ MASK).collect(), offsets)
+    });
+    let encoded = obfuscated.encode();
+    assert!(!encoded
+        .windows(b"value_".len())
+        .any(|window| window == b"value_"));
+
+    // the reader reverses the finalizer
+    let (data, offsets) = Block::decode(&encoded).into_raw_parts();
+    let block = Block::from_raw_parts(data.into_iter().map(|x| x ^ MASK).collect(), offsets);
+    let mut iter = BlockIterator::create_and_seek_to_first(Arc::new(block));
+    for idx in 0..20 {
+        assert!(iter.is_valid());
+        assert_eq!(
+            iter.key().for_testing_key_ref(),
+            format!("key_{:03}", idx).as_bytes()
+        );
+        assert_eq!(iter.value(), format!("value_{}", idx).as_bytes());
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_recommend_block_size() {
+    let small = BlockBuilder::recommend_block_size(&[(8, 8), (10, 6), (12, 20)]);
+    let large = BlockBuilder::recommend_block_size(&[(16, 400), (24, 600)]);
+    assert!(small < large, "{small} >= {large}");
+    assert!(small.is_power_of_two() && large.is_power_of_two());
+    assert_eq!(BlockBuilder::recommend_block_size(&[]), 4096);
+    assert_eq!(BlockBuilder::recommend_block_size(&[(1, 0)]), 1024);
+    assert_eq!(
+        BlockBuilder::recommend_block_size(&[(64, 60000)]),
+        32 * 1024
+    );
+
+    // a block of the recommended size holds 64 to 128 of the sampled entries
+    let block_size = BlockBuilder::recommend_block_size(&[(8, 8)]);
+    let mut builder = BlockBuilder::new(block_size);
+    let mut entries = 0;
+    while builder.add(
+        KeySlice::for_testing_from_slice_no_ts(format!("key{:05}", entries).as_bytes()),
+        b"value_00",
+    ) {
+        entries += 1;
+    }
+    assert!((64..=128).contains(&entries), "{entries} entries");
+}
+
+#[test]
+fn test_block_common_prefix() {
+    let build = |keys: &[&str]| {
+        let mut builder = BlockBuilder::new(4096);
+        for key in keys {
+            assert!(builder.add(KeySlice::for_testing_from_slice_no_ts(key.as_bytes()), b"v"));
+        }
+        builder.build()
+    };
+    let block = build(&["user0001", "user0002", "user0100", "user9"]);
+    assert_eq!(block.common_prefix(), b"user");
+    // the prefix is cut by the shortest key
+    let block = build(&["user", "user0001", "user0002"]);
+    assert_eq!(block.common_prefix(), b"user");
+    let block = build(&["apple", "banana", "cherry"]);
+    assert!(block.common_prefix().is_empty());
+    let block = build(&["single"]);
+    assert_eq!(block.common_prefix(), b"single");
+}
@@ -0,0 +1,2310 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    ops::Bound,
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{
+        tasks_conflict, CompactionController, CompactionOptions, CompactionPickStrategy,
+        CompactionPreview, CompactionStats, LeveledCompactionController, LeveledCompactionOptions,
+        LeveledCompactionTask, OptionsError, OutputWriter, SimpleLeveledCompactionController,
+        SimpleLeveledCompactionOptions, SkippedReason, SstRankMetric, TaskPoll,
+        TieredCompactionController, TieredCompactionOptions,
+    },
+    iterators::StorageIterator,
+    key::{KeyBytes, KeySlice},
+    lsm_storage::{LsmStorageOptions, LsmStorageState, MiniLsm, ScanCost},
+    mem_table::MemTable,
+    table::{FileObject, SsTable, SsTableBuilder, SstMeta},
+};
+
+fn empty_state(max_levels: usize) -> LsmStorageState {
+    LsmStorageState {
+        memtable: Arc::new(MemTable::create(0)),
+        imm_memtables: Vec::new(),
+        l0_sstables: Vec::new(),
+        levels: (1..=max_levels).map(|level| (level, Vec::new())).collect(),
+        sstables: Default::default(),
+        l0_sorted_runs: None,
+        range_tombstones: Vec::new(),
+    }
+}
+
+fn add_meta_sst(state: &mut LsmStorageState, id: usize, size: u64, first: &str, last: &str) {
+    let sst = SsTable::create_meta_only(
+        id,
+        size,
+        KeyBytes::for_testing_from_bytes_no_ts(Bytes::copy_from_slice(first.as_bytes())),
+        KeyBytes::for_testing_from_bytes_no_ts(Bytes::copy_from_slice(last.as_bytes())),
+    );
+    state.sstables.insert(id, Arc::new(sst));
+}
+
+fn leveled_options(level0_file_num_compaction_trigger: usize) -> LeveledCompactionOptions {
+    LeveledCompactionOptions {
+        level_size_multiplier: 10,
+        level0_file_num_compaction_trigger,
+        max_levels: 4,
+        base_level_size_mb: 1,
+        per_level_block_size: None,
+        abort_overlap_ratio: None,
+        min_sst_age_before_compaction: None,
+        prioritize_by_age: false,
+        max_compaction_bytes: None,
+        pick_strategy: Default::default(),
+        level_entry_count_trigger: None,
+    }
+}
+
+#[test]
+fn test_l0_sorted_run_compacted_as_one_unit() {
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let mut state = empty_state(4);
+    state.l0_sorted_runs = Some(Vec::new());
+    // three disjoint flushes form a single sorted run
+    add_meta_sst(&mut state, 1, 1024, "a", "c");
+    state.insert_l0_sstable(1);
+    add_meta_sst(&mut state, 2, 1024, "g", "i");
+    state.insert_l0_sstable(2);
+    add_meta_sst(&mut state, 3, 1024, "d", "f");
+    state.insert_l0_sstable(3);
+    assert_eq!(state.l0_sorted_runs, Some(vec![vec![1, 3, 2]]));
+    // three files but only one run: below the trigger
+    assert!(controller.generate_compaction_task(&state).is_none());
+
+    // an overlapping flush starts a new run
+    add_meta_sst(&mut state, 4, 1024, "b", "h");
+    state.insert_l0_sstable(4);
+    assert_eq!(state.l0_sorted_runs, Some(vec![vec![4], vec![1, 3, 2]]));
+
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, None);
+    let mut upper = task.upper_level_sst_ids.clone();
+    upper.sort();
+    assert_eq!(upper, vec![1, 2, 3, 4]);
+
+    add_meta_sst(&mut state, 5, 4096, "a", "i");
+    let (state, _) = controller.apply_compaction_result(&state, &task, &[5], false);
+    assert!(state.l0_sstables.is_empty());
+    assert_eq!(state.l0_sorted_runs, Some(vec![]));
+}
+
+#[test]
+fn test_l0_sorted_runs_rebuilt_after_recovery() {
+    let mut state = empty_state(4);
+    state.l0_sorted_runs = Some(Vec::new());
+    add_meta_sst(&mut state, 1, 1024, "a", "c");
+    add_meta_sst(&mut state, 2, 1024, "d", "f");
+    add_meta_sst(&mut state, 3, 1024, "a", "z");
+    // latest to earliest, as replayed from the manifest
+    state.l0_sstables = vec![3, 2, 1];
+    state.rebuild_l0_sorted_runs();
+    assert_eq!(state.l0_sstables, vec![3, 2, 1]);
+    assert_eq!(state.l0_sorted_runs, Some(vec![vec![3], vec![1, 2]]));
+}
+
+#[test]
+fn test_read_l0_sorted_runs() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.group_l0_sorted_runs = true;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    // three disjoint flushes form one run, the overlapping one starts a new run
+    for (prefix, value) in [("a", "v1"), ("b", "v1"), ("c", "v1"), ("b", "v2")] {
+        for i in 0..10 {
+            let key = format!("{}_{:02}", prefix, i);
+            storage.put(key.as_bytes(), value.as_bytes()).unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+    assert_eq!(
+        storage.inner.state.read().l0_sorted_runs,
+        Some(vec![vec![3], vec![0, 1, 2]])
+    );
+    storage.delete(b"a_09").unwrap();
+
+    assert_eq!(
+        storage.get(b"a_05").unwrap(),
+        Some(Bytes::from_static(b"v1"))
+    );
+    assert_eq!(storage.get(b"a_09").unwrap(), None);
+    assert_eq!(
+        storage.get(b"b_05").unwrap(),
+        Some(Bytes::from_static(b"v2"))
+    );
+    assert_eq!(
+        storage.get(b"c_09").unwrap(),
+        Some(Bytes::from_static(b"v1"))
+    );
+    assert_eq!(storage.get(b"d_00").unwrap(), None);
+
+    let mut iter = storage
+        .scan(Bound::Excluded(b"a_07"), Bound::Included(b"c_01"))
+        .unwrap();
+    let mut entries = Vec::new();
+    while iter.is_valid() {
+        entries.push((
+            String::from_utf8(iter.key().to_vec()).unwrap(),
+            String::from_utf8(iter.value().to_vec()).unwrap(),
+        ));
+        iter.next().unwrap();
+    }
+    let mut expected = vec![("a_08".to_string(), "v1".to_string())];
+    expected.extend((0..10).map(|i| (format!("b_{:02}", i), "v2".to_string())));
+    expected.push(("c_00".to_string(), "v1".to_string()));
+    expected.push(("c_01".to_string(), "v1".to_string()));
+    assert_eq!(entries, expected);
+    storage.close().unwrap();
+}
+
+#[test]
+fn test_estimate_unique_keys() {
+    let dir = tempdir().unwrap();
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let mut state = empty_state(4);
+    // overlapping key ranges with two versions per key, 6000 distinct keys in total
+    for (id, range) in [(1, 0..3000), (2, 2000..5000), (3, 4000..6000)] {
+        let mut builder = SsTableBuilder::new(4096);
+        for i in range {
+            let key = format!("key_{:05}", i);
+            for ts in [2, 1] {
+                builder.add(
+                    KeySlice::for_testing_from_slice_with_ts(key.as_bytes(), ts),
+                    b"v",
+                );
+            }
+        }
+        let sst = builder
+            .build(id, None, dir.path().join(format!("{id}.sst")))
+            .unwrap();
+        state.sstables.insert(id, Arc::new(sst));
+        state.levels[1].1.push(id);
+    }
+    let estimate = controller.estimate_unique_keys(&state, 2);
+    assert!(
+        (5400..=6600).contains(&estimate),
+        "estimate {estimate} too far from 6000"
+    );
+    assert_eq!(controller.estimate_unique_keys(&state, 1), 0);
+
+    // the sketch is persisted with the SST
+    let sst = SsTable::open(
+        1,
+        None,
+        FileObject::open(&dir.path().join("1.sst")).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(sst.sketch, state.sstables[&1].sketch);
+}
+
+fn compact_l1_into_l2(output_first: &str, output_last: &str) {
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 1, 1, "b", "f");
+    add_meta_sst(&mut state, 2, 1, "d", "k");
+    add_meta_sst(&mut state, 3, 1, output_first, output_last);
+    state.levels[0].1 = vec![1];
+    state.levels[1].1 = vec![2];
+    let task = LeveledCompactionTask {
+        upper_level: Some(1),
+        upper_level_sst_ids: vec![1],
+        lower_level: 2,
+        lower_level_sst_ids: vec![2],
+        is_lower_level_bottom_level: true,
+    };
+    let (state, _) = controller.apply_compaction_result(&state, &task, &[3], false);
+    assert_eq!(state.levels[1].1, vec![3]);
+}
+
+#[test]
+fn test_generate_range_compaction_task() {
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let mut state = empty_state(4);
+    for (id, first, last) in [
+        (11, "a", "c"),
+        (12, "d", "f"),
+        (13, "g", "i"),
+        (21, "a", "e"),
+        (22, "f", "h"),
+        (23, "m", "p"),
+        (31, "a", "z"),
+        (41, "a", "zz"),
+    ] {
+        add_meta_sst(&mut state, id, 1, first, last);
+    }
+    state.levels[0].1 = vec![11, 12, 13];
+    state.levels[1].1 = vec![21, 22, 23];
+    state.levels[2].1 = vec![31];
+    state.levels[3].1 = vec![41];
+
+    // 13 starts at the excluded upper bound, the lower level is selected by the range of 12
+    let task = controller
+        .generate_range_compaction_task(&state, b"e", b"g")
+        .unwrap();
+    assert_eq!(task.upper_level, Some(1));
+    assert_eq!(task.upper_level_sst_ids, vec![12]);
+    assert_eq!(task.lower_level, 2);
+    assert_eq!(task.lower_level_sst_ids, vec![21, 22]);
+    assert!(!task.is_lower_level_bottom_level);
+    let mut output_state = state.clone();
+    add_meta_sst(&mut output_state, 50, 1, "a", "h");
+    let (output_state, removed) =
+        controller.apply_compaction_result(&output_state, &task, &[50], false);
+    assert_eq!(removed, vec![12, 21, 22]);
+    assert_eq!(output_state.levels[0].1, vec![11, 13]);
+    assert_eq!(output_state.levels[1].1, vec![50, 23]);
+
+    // the topmost overlapping level is L2
+    let task = controller
+        .generate_range_compaction_task(&state, b"m", b"n")
+        .unwrap();
+    assert_eq!(task.upper_level, Some(2));
+    assert_eq!(task.upper_level_sst_ids, vec![23]);
+    assert_eq!(task.lower_level_sst_ids, vec![31]);
+
+    // only the bottom level overlaps
+    assert!(controller
+        .generate_range_compaction_task(&state, b"za", b"zb")
+        .is_none());
+
+    // an overlapping L0 SST compacts the whole L0
+    add_meta_sst(&mut state, 1, 1, "x", "y");
+    add_meta_sst(&mut state, 2, 1, "e", "e");
+    state.l0_sstables = vec![2, 1];
+    let task = controller
+        .generate_range_compaction_task(&state, b"e", b"g")
+        .unwrap();
+    assert_eq!(task.upper_level, None);
+    assert_eq!(task.upper_level_sst_ids, vec![2, 1]);
+}
+
+#[test]
+fn test_generate_cross_level_task() {
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 11, 1, "a", "c");
+    add_meta_sst(&mut state, 12, 1, "e", "g");
+    add_meta_sst(&mut state, 41, 1, "b", "d");
+    add_meta_sst(&mut state, 42, 1, "x", "z");
+    state.levels[0].1 = vec![11, 12];
+    state.levels[3].1 = vec![41, 42];
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    assert!(controller.generate_cross_level_task(&state, 2, 2).is_err());
+    assert!(controller.generate_cross_level_task(&state, 3, 1).is_err());
+    assert!(controller.generate_cross_level_task(&state, 1, 5).is_err());
+    assert!(controller
+        .generate_cross_level_task(&state, 2, 4)
+        .unwrap()
+        .is_none());
+
+    let task = controller
+        .generate_cross_level_task(&state, 1, 4)
+        .unwrap()
+        .unwrap();
+    assert_eq!(task.upper_level, Some(1));
+    assert_eq!(task.upper_level_sst_ids, vec![11, 12]);
+    assert_eq!(task.lower_level, 4);
+    assert_eq!(task.lower_level_sst_ids, vec![41]);
+    assert!(task.is_lower_level_bottom_level);
+    add_meta_sst(&mut state, 43, 1, "a", "g");
+    let (new_state, removed) = controller.apply_compaction_result(&state, &task, &[43], false);
+    assert_eq!(removed, vec![11, 12, 41]);
+    assert!(new_state.levels[0].1.is_empty());
+    assert_eq!(new_state.levels[3].1, vec![43, 42]);
+
+    // a level in between overlaps the moved SSTs
+    add_meta_sst(&mut state, 31, 1, "f", "h");
+    state.levels[2].1 = vec![31];
+    assert!(controller.generate_cross_level_task(&state, 1, 4).is_err());
+}
+
+#[test]
+fn test_is_level_contiguous() {
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 11, 1, "b", "d");
+    add_meta_sst(&mut state, 12, 1, "d\0", "h");
+    add_meta_sst(&mut state, 13, 1, "k", "p");
+    state.levels[0].1 = vec![11, 12, 13];
+    // 12 starts right after the last key of 11
+    assert!(controller.is_level_contiguous(&state, 1, b"b", b"h"));
+    assert!(controller.is_level_contiguous(&state, 1, b"c", b"h\0"));
+    assert!(controller.is_level_contiguous(&state, 1, b"m", b"n"));
+    // keys after "h" and before "k" are not covered
+    assert!(!controller.is_level_contiguous(&state, 1, b"c", b"m"));
+    assert!(!controller.is_level_contiguous(&state, 1, b"a", b"c"));
+    assert!(!controller.is_level_contiguous(&state, 1, b"c", b"ha"));
+    assert!(controller.is_level_contiguous(&state, 1, b"x", b"x"));
+    assert!(!controller.is_level_contiguous(&state, 2, b"b", b"c"));
+}
+
+#[test]
+fn test_partition_level_for_parallel_scan() {
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let mut state = empty_state(4);
+    let sizes = [10, 1, 1, 1, 10, 1, 1, 10, 5];
+    for (idx, size) in sizes.iter().enumerate() {
+        let key = format!("key_{}", idx);
+        add_meta_sst(&mut state, idx + 1, *size, &key, &key);
+        state.levels[0].1.push(idx + 1);
+    }
+    let partitions = controller.partition_level_for_parallel_scan(&state, 1, 4);
+    assert_eq!(partitions, vec![(0, 3), (3, 6), (6, 8), (8, 9)]);
+    let partition_bytes = partitions
+        .iter()
+        .map(|(begin, end)| sizes[*begin..*end].iter().sum::<u64>())
+        .collect::<Vec<_>>();
+    // no split into 4 groups has a largest group under 12 bytes
+    assert_eq!(partition_bytes, vec![12, 12, 11, 5]);
+
+    let mut state = empty_state(4);
+    for id in 1..=8 {
+        let key = format!("key_{}", id);
+        add_meta_sst(&mut state, id, 100, &key, &key);
+        state.levels[1].1.push(id);
+    }
+    assert_eq!(
+        controller.partition_level_for_parallel_scan(&state, 2, 4),
+        vec![(0, 2), (2, 4), (4, 6), (6, 8)]
+    );
+    assert_eq!(
+        controller
+            .partition_level_for_parallel_scan(&state, 2, 16)
+            .len(),
+        8
+    );
+    assert_eq!(
+        controller.partition_level_for_parallel_scan(&state, 2, 1),
+        vec![(0, 8)]
+    );
+    assert!(controller
+        .partition_level_for_parallel_scan(&state, 3, 4)
+        .is_empty());
+}
+
+#[test]
+fn test_rollback_state() {
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 1, 1, "b", "f");
+    add_meta_sst(&mut state, 2, 1, "d", "k");
+    add_meta_sst(&mut state, 3, 1, "b", "k");
+    state.levels[0].1 = vec![1];
+    state.levels[1].1 = vec![2];
+    let task = LeveledCompactionTask {
+        upper_level: Some(1),
+        upper_level_sst_ids: vec![1],
+        lower_level: 2,
+        lower_level_sst_ids: vec![2],
+        is_lower_level_bottom_level: false,
+    };
+
+    // off by default
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    controller.apply_compaction_result(&state, &task, &[3], false);
+    assert!(controller.take_rollback_state().is_none());
+
+    let controller = LeveledCompactionController::new(leveled_options(2)).with_rollback_state();
+    let (new_state, _) = controller.apply_compaction_result(&state, &task, &[3], false);
+    assert_eq!(new_state.levels[1].1, vec![3]);
+    let rollback = controller.take_rollback_state().unwrap();
+    assert_eq!(rollback.l0_sstables, state.l0_sstables);
+    assert_eq!(rollback.levels, state.levels);
+    let mut sst_ids = rollback.sstables.keys().copied().collect::<Vec<_>>();
+    sst_ids.sort();
+    assert_eq!(sst_ids, vec![1, 2, 3]);
+    // the state is only handed out once
+    assert!(controller.take_rollback_state().is_none());
+}
+
+#[test]
+fn test_apply_compaction_result_twice() {
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 1, 1, "b", "f");
+    add_meta_sst(&mut state, 2, 1, "d", "k");
+    add_meta_sst(&mut state, 3, 1, "b", "k");
+    add_meta_sst(&mut state, 4, 1, "x", "z");
+    state.levels[0].1 = vec![1];
+    state.levels[1].1 = vec![2, 4];
+    let task = LeveledCompactionTask {
+        upper_level: Some(1),
+        upper_level_sst_ids: vec![1],
+        lower_level: 2,
+        lower_level_sst_ids: vec![2],
+        is_lower_level_bottom_level: false,
+    };
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let (state, removed) = controller.apply_compaction_result(&state, &task, &[3], false);
+    assert_eq!(removed, vec![1, 2]);
+    assert_eq!(state.levels[1].1, vec![3, 4]);
+
+    // retried after a crash, e.g. while replaying the manifest
+    for in_recovery in [false, true] {
+        let (retried, removed) =
+            controller.apply_compaction_result(&state, &task, &[3], in_recovery);
+        assert!(removed.is_empty());
+        assert_eq!(retried.levels, state.levels);
+        assert_eq!(retried.l0_sstables, state.l0_sstables);
+    }
+}
+
+#[test]
+fn test_generate_full_compaction_task() {
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let mut state = empty_state(4);
+    assert!(controller.generate_full_compaction_task(&state).is_none());
+
+    add_meta_sst(&mut state, 1, 1, "a", "c");
+    add_meta_sst(&mut state, 2, 1, "b", "e");
+    add_meta_sst(&mut state, 3, 1, "a", "d");
+    add_meta_sst(&mut state, 4, 1, "f", "k");
+    add_meta_sst(&mut state, 5, 1, "c", "m");
+    add_meta_sst(&mut state, 6, 1, "a", "z");
+    state.l0_sstables = vec![2, 1];
+    state.levels[0].1 = vec![3, 4];
+    state.levels[2].1 = vec![5];
+    state.levels[3].1 = vec![6];
+    let task = controller.generate_full_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, None);
+    assert_eq!(task.upper_level_sst_ids, vec![2, 1, 3, 4, 5]);
+    assert_eq!(task.lower_level, 4);
+    assert_eq!(task.lower_level_sst_ids, vec![6]);
+    assert!(task.is_lower_level_bottom_level);
+
+    add_meta_sst(&mut state, 7, 1, "a", "z");
+    let (state, mut removed) = controller.apply_compaction_result(&state, &task, &[7], false);
+    removed.sort();
+    assert_eq!(removed, vec![1, 2, 3, 4, 5, 6]);
+    assert!(state.l0_sstables.is_empty());
+    for (level, level_ssts) in &state.levels[..3] {
+        assert!(level_ssts.is_empty(), "level {} is not empty", level);
+    }
+    assert_eq!(state.levels[3].1, vec![7]);
+
+    let (retried, removed) = controller.apply_compaction_result(&state, &task, &[7], true);
+    assert!(removed.is_empty());
+    assert_eq!(retried.levels, state.levels);
+}
+
+#[test]
+fn test_l0_to_base_overlap() {
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 1, 100, "a", "c");
+    add_meta_sst(&mut state, 2, 200, "d", "f");
+    add_meta_sst(&mut state, 3, 300, "g", "k");
+    add_meta_sst(&mut state, 4, 10, "b", "c");
+    add_meta_sst(&mut state, 5, 10, "e", "e");
+    state.levels[3].1 = vec![1, 2, 3];
+    assert_eq!(controller.l0_to_base_overlap(&state), (0, 0));
+
+    // the union of the L0 ranges is b..e, which overlaps SST 1 and 2 in the base level
+    state.l0_sstables = vec![5, 4];
+    assert_eq!(controller.l0_to_base_overlap(&state), (300, 2));
+}
+
+/// An `SstMeta` without an SST behind it.
+struct FakeSstMeta {
+    first_key: KeyBytes,
+    last_key: KeyBytes,
+    size: u64,
+    max_ts: u64,
+}
+
+impl FakeSstMeta {
+    fn new(first: &str, last: &str, size: u64) -> Self {
+        let key = |key: &str| {
+            KeyBytes::for_testing_from_bytes_no_ts(Bytes::copy_from_slice(key.as_bytes()))
+        };
+        Self {
+            first_key: key(first),
+            last_key: key(last),
+            size,
+            max_ts: 0,
+        }
+    }
+
+    fn with_max_ts(mut self, max_ts: u64) -> Self {
+        self.max_ts = max_ts;
+        self
+    }
+}
+
+impl SstMeta for FakeSstMeta {
+    fn first_key(&self) -> &KeyBytes {
+        &self.first_key
+    }
+
+    fn last_key(&self) -> &KeyBytes {
+        &self.last_key
+    }
+
+    fn table_size(&self) -> u64 {
+        self.size
+    }
+
+    fn max_ts(&self) -> u64 {
+        self.max_ts
+    }
+}
+
+#[test]
+fn test_plan_with_fake_sst_meta() {
+    const MB: u64 = 1024 * 1024;
+    let controller = LeveledCompactionController::new(leveled_options(2))
+        .with_sst_meta(1, FakeSstMeta::new("a", "f", MB))
+        .with_sst_meta(2, FakeSstMeta::new("e", "k", MB))
+        .with_sst_meta(10, FakeSstMeta::new("a", "d", MB / 4))
+        .with_sst_meta(11, FakeSstMeta::new("g", "m", MB / 4))
+        .with_sst_meta(12, FakeSstMeta::new("x", "z", MB / 4));
+    // none of the SSTs is in `state.sstables`, L4 is below the base level size so it is the base
+    let mut state = empty_state(4);
+    state.l0_sstables = vec![2, 1];
+    state.levels[3].1 = vec![10, 11, 12];
+
+    assert_eq!(
+        controller.find_overlapping_ssts(&state, &state.l0_sstables, 4),
+        vec![10, 11]
+    );
+    assert_eq!(controller.overlapping_bytes(&state, 1, 4), MB / 4);
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, None);
+    assert_eq!(task.upper_level_sst_ids, vec![2, 1]);
+    assert_eq!(task.lower_level, 4);
+    assert_eq!(task.lower_level_sst_ids, vec![10, 11]);
+
+    // the output is sorted by the supplied first keys
+    let controller = controller.with_sst_meta(20, FakeSstMeta::new("a", "m", 2 * MB));
+    let (state, _) = controller.apply_compaction_result(&state, &task, &[20], false);
+    assert_eq!(state.levels[3].1, vec![20, 12]);
+}
+
+#[test]
+fn test_sst_age_with_fake_sst_meta() {
+    const MB: u64 = 1024 * 1024;
+    let mut options = leveled_options(2);
+    options.min_sst_age_before_compaction = Some(2);
+    // the supplied SSTs are newer than every SST in the snapshot
+    let controller = LeveledCompactionController::new(options)
+        .with_sst_meta(20, FakeSstMeta::new("a", "f", 2 * MB))
+        .with_sst_meta(21, FakeSstMeta::new("g", "k", MB))
+        .with_sst_meta(22, FakeSstMeta::new("a", "k", MB));
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 1, 100 * MB, "a", "z");
+    state.l0_sstables = vec![22];
+    state.levels[0].1 = vec![20, 21];
+    state.levels[3].1 = vec![1];
+
+    // 21 is too young, 20 is just old enough
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, Some(1));
+    assert_eq!(task.upper_level_sst_ids, vec![20]);
+    assert_eq!(
+        controller.rank_ssts(&state, SstRankMetric::Age),
+        vec![(1, 21)]
+    );
+}
+
+#[test]
+fn test_estimated_point_read_bytes() {
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 1, 100, "a", "m");
+    add_meta_sst(&mut state, 2, 200, "n", "z");
+    add_meta_sst(&mut state, 3, 50, "j", "l");
+    add_meta_sst(&mut state, 4, 1000, "a", "f");
+    add_meta_sst(&mut state, 5, 2000, "g", "y");
+    state.l0_sstables = vec![3, 2, 1];
+    state.levels[1].1 = vec![4, 5];
+
+    // L0 SSTs 1 and 3, and SST 5 in L2
+    assert_eq!(controller.estimated_point_read_bytes(&state, b"k"), 2150);
+    // the last key of an SST is included
+    assert_eq!(controller.estimated_point_read_bytes(&state, b"f"), 1100);
+    assert_eq!(controller.estimated_point_read_bytes(&state, b"z"), 200);
+    assert_eq!(controller.estimated_point_read_bytes(&state, b"zz"), 0);
+}
+
+#[test]
+fn test_compaction_preview() {
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 1, 1, "d", "f");
+    add_meta_sst(&mut state, 2, 1, "e", "k");
+    add_meta_sst(&mut state, 3, 1, "d", "k");
+    add_meta_sst(&mut state, 4, 1, "a", "b");
+    add_meta_sst(&mut state, 5, 1, "x", "z");
+    add_meta_sst(&mut state, 6, 1, "m", "n");
+    state.levels[0].1 = vec![1, 6];
+    state.levels[1].1 = vec![4, 2, 5];
+    let task = LeveledCompactionTask {
+        upper_level: Some(1),
+        upper_level_sst_ids: vec![1],
+        lower_level: 2,
+        lower_level_sst_ids: vec![2],
+        is_lower_level_bottom_level: false,
+    };
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let preview = controller.preview(&state, &task);
+    assert_eq!(
+        preview,
+        CompactionPreview {
+            moved_out: vec![(1, vec![1]), (2, vec![2])],
+            removed: vec![1, 2],
+            kept_lower_level_ssts: vec![4, 5],
+            output_position: 1,
+        }
+    );
+
+    let (new_state, files_to_remove) =
+        controller.apply_compaction_result(&state, &task, &[3], false);
+    assert_eq!(preview.removed, files_to_remove);
+    let mut lower_level_ssts = preview.kept_lower_level_ssts.clone();
+    lower_level_ssts.insert(preview.output_position, 3);
+    assert_eq!(new_state.levels[1].1, lower_level_ssts);
+    assert_eq!(new_state.levels[0].1, vec![6]);
+
+    // already applied
+    let preview = controller.preview(&new_state, &task);
+    assert!(preview.removed.is_empty());
+    assert!(preview.moved_out.is_empty());
+}
+
+#[test]
+fn test_find_overlapping_ssts_empty() {
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 1, 1, "a", "z");
+    state.levels[0].1 = vec![1];
+    let controller = LeveledCompactionController::new(leveled_options(0));
+    assert!(controller.find_overlapping_ssts(&state, &[], 1).is_empty());
+    assert_eq!(controller.find_overlapping_ssts(&state, &[1], 1), vec![1]);
+
+    // an empty L0 is never compacted, even with a trigger of 0
+    assert!(controller
+        .generate_compaction_task(&empty_state(4))
+        .is_none());
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, Some(1));
+}
+
+#[test]
+fn test_compaction_output_within_input_range() {
+    compact_l1_into_l2("b", "k");
+    compact_l1_into_l2("c", "h");
+}
+
+#[test]
+#[should_panic(expected = "is not within input range")]
+fn test_compaction_output_beyond_input_range() {
+    compact_l1_into_l2("b", "z");
+}
+
+#[test]
+fn test_level_write_budget() {
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let budgets = controller.level_write_budget(100 * 1024 * 1024);
+    assert_eq!(budgets.len(), 4);
+    assert_eq!(budgets.iter().sum::<u64>(), 100 * 1024 * 1024);
+    for pair in budgets.windows(2) {
+        assert!(pair[0] < pair[1], "{:?}", budgets);
+    }
+    let budgets = controller.level_write_budget(7);
+    assert_eq!(budgets.iter().sum::<u64>(), 7);
+}
+
+#[test]
+fn test_recommend_max_levels() {
+    // 100GB is 102400MB
+    let dataset_bytes = 100 * 1024 * 1024 * 1024;
+    // 1MB, 10MB, 100MB, 1000MB, 10000MB, 100000MB, 1000000MB
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    assert_eq!(controller.recommend_max_levels(dataset_bytes), 7);
+    // 256MB, 2560MB, 25600MB, 256000MB
+    let controller = LeveledCompactionController::new(LeveledCompactionOptions {
+        base_level_size_mb: 256,
+        ..leveled_options(2)
+    });
+    assert_eq!(controller.recommend_max_levels(dataset_bytes), 4);
+    // the base level alone is enough for a small dataset
+    assert_eq!(controller.recommend_max_levels(256 * 1024 * 1024), 1);
+    assert_eq!(controller.recommend_max_levels(0), 1);
+}
+
+#[test]
+fn test_optimal_multiplier() {
+    let dataset_bytes = 100 * 1024 * 1024 * 1024;
+    let multiplier = LeveledCompactionController::optimal_multiplier(dataset_bytes, 1, 6);
+    // 1MB * multiplier^5 = 102400MB
+    let bottom_level_bytes = (1024.0 * 1024.0) * multiplier.powi(5);
+    assert!((bottom_level_bytes / dataset_bytes as f64 - 1.0).abs() < 1e-9);
+    // rounded up, the multiplier fits the dataset in 6 levels but not in 5
+    let controller = LeveledCompactionController::new(LeveledCompactionOptions {
+        level_size_multiplier: multiplier.ceil() as usize,
+        ..leveled_options(2)
+    });
+    assert_eq!(controller.recommend_max_levels(dataset_bytes), 6);
+    assert_eq!(
+        LeveledCompactionController::optimal_multiplier(dataset_bytes, 1, 1),
+        1.0
+    );
+    assert_eq!(
+        LeveledCompactionController::optimal_multiplier(1024, 1, 4),
+        1.0
+    );
+}
+
+#[test]
+fn test_expected_ssts_per_level() {
+    let controller =
+        LeveledCompactionController::new(leveled_options(2)).with_target_output_sst_size(1 << 20);
+    assert_eq!(controller.target_output_sst_size(), 1 << 20);
+    assert_eq!(controller.expected_ssts_per_level(), vec![1, 10, 100, 1000]);
+    // a partially filled SST still counts
+    let controller =
+        LeveledCompactionController::new(leveled_options(2)).with_target_output_sst_size(3 << 20);
+    assert_eq!(controller.expected_ssts_per_level(), vec![1, 4, 34, 334]);
+    // the storage passes its target SST size to the controller
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(CompactionOptions::Leveled(leveled_options(2))),
+    )
+    .unwrap();
+    let CompactionController::Leveled(controller) = &storage.inner.compaction_controller else {
+        panic!("expect leveled compaction");
+    };
+    assert_eq!(controller.target_output_sst_size(), 1 << 20);
+}
+
+#[test]
+fn test_overlap_depth_map() {
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 1, 1, "a", "f");
+    add_meta_sst(&mut state, 2, 1, "c", "h");
+    add_meta_sst(&mut state, 3, 1, "d", "e");
+    add_meta_sst(&mut state, 4, 1, "x", "z");
+    state.l0_sstables = vec![4, 3, 2, 1];
+    let depths = controller
+        .overlap_depth_map(&state, 0)
+        .into_iter()
+        .map(|(begin, end, depth)| {
+            (
+                String::from_utf8(begin.key_ref().to_vec()).unwrap(),
+                String::from_utf8(end.key_ref().to_vec()).unwrap(),
+                depth,
+            )
+        })
+        .collect::<Vec<_>>();
+    let expected = [
+        ("a", "c", 1),
+        ("c", "d", 2),
+        ("d", "e", 3),
+        ("e", "f", 2),
+        ("f", "h", 1),
+        ("x", "z", 1),
+    ];
+    assert_eq!(
+        depths,
+        expected
+            .iter()
+            .map(|(begin, end, depth)| (begin.to_string(), end.to_string(), *depth))
+            .collect::<Vec<_>>()
+    );
+    assert!(controller.overlap_depth_map(&state, 1).is_empty());
+}
+
+#[test]
+fn test_per_level_block_size() {
+    let dir = tempdir().unwrap();
+    let mut compaction_options = leveled_options(2);
+    compaction_options.per_level_block_size = Some(vec![4096, 4096, 4096, 65536]);
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(CompactionOptions::Leveled(compaction_options)),
+    )
+    .unwrap();
+    for round in 0..2 {
+        for i in 0..1000 {
+            let key = format!("key_{:05}", i * 2 + round);
+            storage.put(key.as_bytes(), b"value_value_value").unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+    // L0 is compacted into the bottom level by the compaction thread
+    for _ in 0..100 {
+        if storage.inner.state.read().l0_sstables.is_empty() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    {
+        let state = storage.inner.state.read();
+        assert!(state.l0_sstables.is_empty());
+        assert!(!state.levels[3].1.is_empty());
+        for id in &state.levels[3].1 {
+            let sst = &state.sstables[id];
+            assert!(sst.num_of_blocks() > 1);
+            // every block except the last one is filled up to the configured block size
+            let first_block_size = sst.block_meta[1].offset - sst.block_meta[0].offset;
+            assert!(first_block_size > 4096, "block size {}", first_block_size);
+        }
+    }
+    storage.close().unwrap();
+}
+
+#[test]
+fn test_select_min_overlapping_bytes() {
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 1, 1, "a", "c");
+    add_meta_sst(&mut state, 2, 1, "m", "p");
+    // sst 1 overlaps one large SST, sst 2 overlaps two small ones
+    add_meta_sst(&mut state, 3, 100, "a", "d");
+    add_meta_sst(&mut state, 4, 10, "m", "n");
+    add_meta_sst(&mut state, 5, 10, "o", "q");
+    state.levels[0].1 = vec![1, 2];
+    state.levels[1].1 = vec![3, 4, 5];
+    assert_eq!(controller.overlapping_bytes(&state, 1, 2), 100);
+    assert_eq!(controller.overlapping_bytes(&state, 2, 2), 20);
+    // selecting by the number of overlapping SSTs would pick sst 1
+    assert_eq!(controller.select_min_overlapping_bytes(&state, 1), Some(2));
+    assert_eq!(controller.select_min_overlapping_bytes(&state, 2), Some(3));
+    assert_eq!(controller.select_min_overlapping_bytes(&state, 3), None);
+    assert_eq!(controller.select_min_overlapping_bytes(&state, 4), None);
+}
+
+#[test]
+fn test_compaction_batch_reports_skipped() {
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let mut state = empty_state(4);
+    const MB: u64 = 1024 * 1024;
+    add_meta_sst(&mut state, 20, 3 * MB, "m", "p");
+    add_meta_sst(&mut state, 30, 20 * MB, "a", "c");
+    add_meta_sst(&mut state, 40, 100 * MB, "a", "z");
+    state.levels[1].1 = vec![20];
+    state.levels[2].1 = vec![30];
+    state.levels[3].1 = vec![40];
+
+    // L2 (3MB of 1MB) and L3 (20MB of 10MB) are both over target and do not conflict
+    let (tasks, skipped) = controller.generate_compaction_batch(&state, &HashSet::new());
+    assert!(skipped.is_empty());
+    assert_eq!(tasks.len(), 2);
+    assert_eq!(tasks[0].upper_level, Some(2));
+    assert_eq!(tasks[0].upper_level_sst_ids, vec![20]);
+    assert!(tasks[0].lower_level_sst_ids.is_empty());
+    assert_eq!(tasks[1].upper_level, Some(3));
+    assert_eq!(tasks[1].lower_level_sst_ids, vec![40]);
+
+    // sst 40 is being compacted, so the L3 candidate is deferred
+    let (tasks, skipped) = controller.generate_compaction_batch(&state, &HashSet::from([40]));
+    assert_eq!(tasks.len(), 1);
+    assert_eq!(tasks[0].upper_level, Some(2));
+    assert_eq!(
+        skipped,
+        vec![SkippedReason::Conflict {
+            level: 3,
+            sst_ids: vec![40]
+        }]
+    );
+
+    state.levels[1].1 = vec![9, 20];
+    let (tasks, skipped) = controller.generate_compaction_batch(&state, &HashSet::new());
+    assert_eq!(tasks.len(), 1);
+    assert_eq!(
+        skipped,
+        vec![SkippedReason::MissingSst {
+            level: 2,
+            sst_id: 9
+        }]
+    );
+}
+
+#[test]
+fn test_compaction_batch_selects_like_single_task() {
+    const MB: u64 = 1024 * 1024;
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 19, 2 * MB, "a", "b");
+    add_meta_sst(&mut state, 20, MB, "m", "p");
+    add_meta_sst(&mut state, 30, 20 * MB, "a", "c");
+    add_meta_sst(&mut state, 40, 100 * MB, "a", "z");
+    state.levels[1].1 = vec![19, 20];
+    state.levels[2].1 = vec![30];
+    state.levels[3].1 = vec![40];
+
+    // sst 19 has the lowest id but overlaps 20MB in L3, sst 20 overlaps nothing
+    let mut fewest_overlaps = leveled_options(2);
+    fewest_overlaps.pick_strategy = CompactionPickStrategy::FewestOverlaps;
+    let mut min_age = leveled_options(2);
+    min_age.min_sst_age_before_compaction = Some(21);
+    let mut abort_overlap = leveled_options(2);
+    abort_overlap.abort_overlap_ratio = Some(5.0);
+    let mut max_bytes = leveled_options(2);
+    max_bytes.max_compaction_bytes = Some(10 * MB);
+    for (options, expected) in [
+        (leveled_options(2), 19),
+        (fewest_overlaps, 20),
+        (min_age, 19),
+        (abort_overlap, 20),
+        (max_bytes, 20),
+    ] {
+        let controller = LeveledCompactionController::new(options);
+        let task = controller.generate_compaction_task(&state).unwrap();
+        assert_eq!(task.upper_level, Some(2));
+        assert_eq!(task.upper_level_sst_ids, vec![expected]);
+        let (tasks, _) = controller.generate_compaction_batch(&state, &HashSet::new());
+        assert_eq!(tasks[0].upper_level, task.upper_level);
+        assert_eq!(tasks[0].upper_level_sst_ids, task.upper_level_sst_ids);
+        assert_eq!(tasks[0].lower_level_sst_ids, task.lower_level_sst_ids);
+    }
+}
+
+#[test]
+fn test_defer_l0_flush() {
+    let mut state = empty_state(4);
+    const MB: u64 = 1024 * 1024;
+    add_meta_sst(&mut state, 1, MB, "n", "o");
+    add_meta_sst(&mut state, 2, MB, "m", "n");
+    add_meta_sst(&mut state, 20, 3 * MB, "m", "p");
+    add_meta_sst(&mut state, 30, 20 * MB, "a", "c");
+    add_meta_sst(&mut state, 40, 100 * MB, "a", "z");
+    state.l0_sstables = vec![2, 1];
+    state.levels[1].1 = vec![20];
+    state.levels[2].1 = vec![30];
+    state.levels[3].1 = vec![40];
+
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let task = controller
+        .generate_compaction_task_excluding(&state, &HashSet::new())
+        .unwrap();
+    assert_eq!(task.upper_level, None);
+    assert_eq!(task.lower_level, 2);
+    assert_eq!(task.lower_level_sst_ids, vec![20]);
+
+    // sst 20 in the base level is the target of a running compaction
+    let in_progress = HashSet::from([20]);
+    assert!(controller
+        .generate_compaction_task_excluding(&state, &in_progress)
+        .is_none());
+    let controller = controller.with_defer_l0_flush();
+    let task = controller
+        .generate_compaction_task_excluding(&state, &in_progress)
+        .unwrap();
+    assert_eq!(task.upper_level, Some(3));
+    assert_eq!(task.upper_level_sst_ids, vec![30]);
+    assert_eq!(task.lower_level_sst_ids, vec![40]);
+}
+
+#[test]
+fn test_compaction_debt_bytes() {
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let mut state = empty_state(4);
+    const MB: u64 = 1024 * 1024;
+    for id in 1..=4 {
+        add_meta_sst(&mut state, id, MB, "a", "z");
+    }
+    add_meta_sst(&mut state, 20, 3 * MB, "m", "p");
+    add_meta_sst(&mut state, 30, 20 * MB, "a", "c");
+    add_meta_sst(&mut state, 40, 100 * MB, "a", "z");
+    state.l0_sstables = vec![4, 3, 2, 1];
+    state.levels[1].1 = vec![20];
+    state.levels[2].1 = vec![30];
+    state.levels[3].1 = vec![40];
+    // L0: 2 SSTs beyond the trigger, L2: 3MB of 1MB, L3: 20MB of 10MB
+    assert_eq!(
+        controller.compaction_debt_bytes(&state),
+        2 * MB + 2 * MB + 10 * MB
+    );
+    assert_eq!(controller.compaction_debt_bytes(&empty_state(4)), 0);
+}
+
+#[test]
+fn test_estimate_churn_for_base_size() {
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let mut state = empty_state(4);
+    const MB: u64 = 1024 * 1024;
+    add_meta_sst(&mut state, 20, 5 * MB, "a", "z");
+    add_meta_sst(&mut state, 30, 20 * MB, "a", "z");
+    add_meta_sst(&mut state, 40, 100 * MB, "a", "z");
+    state.levels[1].1 = vec![20];
+    state.levels[2].1 = vec![30];
+    state.levels[3].1 = vec![40];
+    // targets 0/1MB/10MB/100MB: L2 has 4MB and L3 has 10MB over target
+    assert_eq!(controller.estimate_churn_for_base_size(&state, 1), 14 * MB);
+    assert_eq!(
+        controller.estimate_churn_for_base_size(&state, 1),
+        controller.compaction_debt_bytes(&state)
+    );
+    // targets 0/0/10MB/100MB: the base level shifts to L3, so the whole L2 must move
+    assert_eq!(controller.estimate_churn_for_base_size(&state, 64), 15 * MB);
+    // targets 0/0/0/128MB: everything above L4 must move
+    assert_eq!(
+        controller.estimate_churn_for_base_size(&state, 128),
+        25 * MB
+    );
+}
+
+#[test]
+fn test_abort_overlap_ratio() {
+    const MB: u64 = 1024 * 1024;
+    let mut state = empty_state(4);
+    // L2 is over target, sst 21 is older but overlaps the whole L3
+    add_meta_sst(&mut state, 21, MB, "a", "z");
+    add_meta_sst(&mut state, 22, 2 * MB, "m", "n");
+    add_meta_sst(&mut state, 31, 4 * MB, "a", "c");
+    add_meta_sst(&mut state, 32, 4 * MB, "x", "z");
+    add_meta_sst(&mut state, 40, 100 * MB, "a", "z");
+    state.levels[1].1 = vec![21, 22];
+    state.levels[2].1 = vec![31, 32];
+    state.levels[3].1 = vec![40];
+
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level_sst_ids, vec![21]);
+    assert_eq!(task.lower_level_sst_ids, vec![31, 32]);
+
+    let mut options = leveled_options(2);
+    options.abort_overlap_ratio = Some(4.0);
+    let controller = LeveledCompactionController::new(options);
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, Some(2));
+    assert_eq!(task.upper_level_sst_ids, vec![22]);
+    assert!(task.lower_level_sst_ids.is_empty());
+
+    // all candidates overlap too much, nothing to compact
+    state.levels[1].1 = vec![21];
+    add_meta_sst(&mut state, 21, 3 * MB, "a", "z");
+    let mut options = leveled_options(2);
+    options.abort_overlap_ratio = Some(2.0);
+    let controller = LeveledCompactionController::new(options);
+    assert!(controller.generate_compaction_task(&state).is_none());
+}
+
+#[test]
+fn test_max_compaction_bytes() {
+    const MB: u64 = 1024 * 1024;
+    let mut state = empty_state(4);
+    // L2 is over target, sst 21 is the oldest but overlaps every SST of L3
+    add_meta_sst(&mut state, 21, MB, "a", "z");
+    add_meta_sst(&mut state, 22, MB, "n", "p");
+    add_meta_sst(&mut state, 23, 9 * MB, "q", "r");
+    for (idx, (first, last)) in [("a", "c"), ("d", "f"), ("g", "i"), ("j", "m"), ("n", "o")]
+        .into_iter()
+        .enumerate()
+    {
+        add_meta_sst(&mut state, 31 + idx, 2 * MB, first, last);
+    }
+    add_meta_sst(&mut state, 40, 100 * MB, "a", "z");
+    state.levels[1].1 = vec![21, 22, 23];
+    state.levels[2].1 = vec![31, 32, 33, 34, 35];
+    state.levels[3].1 = vec![40];
+
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level_sst_ids, vec![21]);
+    assert_eq!(task.lower_level_sst_ids, vec![31, 32, 33, 34, 35]);
+
+    // the overlap of sst 21 is over the cap, and is never cut short
+    let mut options = leveled_options(2);
+    options.max_compaction_bytes = Some(4 * MB);
+    let controller = LeveledCompactionController::new(options);
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, Some(2));
+    assert_eq!(task.upper_level_sst_ids, vec![22]);
+    assert_eq!(task.lower_level_sst_ids, vec![35]);
+
+    // nothing fits, the smallest input is compacted anyway
+    let mut options = leveled_options(2);
+    options.max_compaction_bytes = Some(MB);
+    let controller = LeveledCompactionController::new(options);
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level_sst_ids, vec![22]);
+    assert_eq!(task.lower_level_sst_ids, vec![35]);
+
+    // the output may be split into several SSTs
+    let (state, _) = controller.apply_compaction_result(&state, &task, &[50, 51], true);
+    assert_eq!(state.levels[1].1, vec![21, 23]);
+    assert_eq!(state.levels[2].1, vec![31, 32, 33, 34, 50, 51]);
+}
+
+#[test]
+fn test_compaction_pick_strategy() {
+    const MB: u64 = 1024 * 1024;
+    let mut state = empty_state(4);
+    // L2 is over target, sst 21 overlaps all of L3, sst 22 overlaps nothing
+    add_meta_sst(&mut state, 21, 6 * MB, "m", "z");
+    add_meta_sst(&mut state, 22, 6 * MB, "e", "f");
+    add_meta_sst(&mut state, 23, 6 * MB, "a", "b");
+    add_meta_sst(&mut state, 31, 4 * MB, "a", "c");
+    add_meta_sst(&mut state, 32, 4 * MB, "n", "o");
+    add_meta_sst(&mut state, 33, 4 * MB, "x", "z");
+    add_meta_sst(&mut state, 40, 100 * MB, "a", "z");
+    state.levels[1].1 = vec![21, 22, 23];
+    state.levels[2].1 = vec![31, 32, 33];
+    state.levels[3].1 = vec![40];
+
+    let mut options = leveled_options(2);
+    let controller = LeveledCompactionController::new(options.clone());
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, Some(2));
+    assert_eq!(task.upper_level_sst_ids, vec![21]);
+    assert_eq!(task.lower_level_sst_ids, vec![32, 33]);
+
+    options.pick_strategy = CompactionPickStrategy::OldestByFirstKey;
+    let controller = LeveledCompactionController::new(options.clone());
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level_sst_ids, vec![23]);
+    assert_eq!(task.lower_level_sst_ids, vec![31]);
+
+    options.pick_strategy = CompactionPickStrategy::FewestOverlaps;
+    let controller = LeveledCompactionController::new(options);
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level_sst_ids, vec![22]);
+    assert!(task.lower_level_sst_ids.is_empty());
+}
+
+#[test]
+fn test_level_entry_count_trigger() {
+    const MB: u64 = 1024 * 1024;
+    let dir = tempdir().unwrap();
+    let mut state = empty_state(4);
+    // L2 holds many small entries, far under its 1MB target
+    let mut builder = SsTableBuilder::new(4096);
+    for i in 0..500 {
+        builder.add(
+            KeySlice::for_testing_from_slice_no_ts(format!("key_{:05}", i).as_bytes()),
+            b"v",
+        );
+    }
+    let sst = builder.build(21, None, dir.path().join("21.sst")).unwrap();
+    assert_eq!(sst.num_entries().unwrap(), 500);
+    assert!(sst.table_size() < MB);
+    state.sstables.insert(21, Arc::new(sst));
+    add_meta_sst(&mut state, 31, 5 * MB, "a", "z");
+    add_meta_sst(&mut state, 40, 100 * MB, "a", "z");
+    state.levels[1].1 = vec![21];
+    state.levels[2].1 = vec![31];
+    state.levels[3].1 = vec![40];
+
+    let mut options = leveled_options(2);
+    let controller = LeveledCompactionController::new(options.clone());
+    assert!(controller.generate_compaction_task(&state).is_none());
+
+    options.level_entry_count_trigger = Some(1000);
+    let controller = LeveledCompactionController::new(options.clone());
+    assert!(controller.generate_compaction_task(&state).is_none());
+
+    options.level_entry_count_trigger = Some(100);
+    let controller = LeveledCompactionController::new(options.clone());
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, Some(2));
+    assert_eq!(task.upper_level_sst_ids, vec![21]);
+    assert_eq!(task.lower_level_sst_ids, vec![31]);
+
+    // an SST only known through `with_sst_meta` counts as empty
+    let controller = LeveledCompactionController::new(options)
+        .with_sst_meta(22, FakeSstMeta::new("zz", "zz", 1024));
+    state.levels[1].1 = vec![21, 22];
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, Some(2));
+    assert_eq!(task.upper_level_sst_ids, vec![21]);
+}
+
+#[test]
+fn test_compaction_stats() {
+    const MB: usize = 1024 * 1024;
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 21, 3 * MB as u64, "a", "c");
+    add_meta_sst(&mut state, 31, 20 * MB as u64, "a", "z");
+    add_meta_sst(&mut state, 40, 100 * MB as u64, "a", "z");
+    state.levels[1].1 = vec![21];
+    state.levels[2].1 = vec![31];
+    state.levels[3].1 = vec![40];
+
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(controller.last_stats(), None);
+
+    // L4 is 100MB, so L3 targets 10MB, L2 targets 1MB, and L1 is below the base level
+    let controller = LeveledCompactionController::new(leveled_options(2)).with_stats_collection();
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, Some(2));
+    assert_eq!(
+        controller.last_stats(),
+        Some(CompactionStats {
+            target_level_sizes: vec![0, MB, 10 * MB, 100 * MB],
+            real_level_sizes: vec![0, 3 * MB, 20 * MB, 100 * MB],
+            base_level: 2,
+            winning_priority: Some((3.0, 2)),
+        })
+    );
+
+    // L0 is compacted before any level
+    add_meta_sst(&mut state, 51, MB as u64, "a", "b");
+    add_meta_sst(&mut state, 52, MB as u64, "b", "c");
+    state.l0_sstables = vec![52, 51];
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, None);
+    let stats = controller.last_stats().unwrap();
+    assert_eq!(stats.base_level, 2);
+    assert_eq!(stats.winning_priority, None);
+}
+
+#[test]
+fn test_base_level_above_non_empty_levels() {
+    const KB: u64 = 1024;
+    let mut state = empty_state(4);
+    // the whole tree is far under base_level_size_mb, but L2 and L3 still hold data
+    add_meta_sst(&mut state, 21, 100 * KB, "a", "c");
+    add_meta_sst(&mut state, 31, 200 * KB, "a", "z");
+    add_meta_sst(&mut state, 41, 500 * KB, "a", "z");
+    add_meta_sst(&mut state, 51, KB, "a", "b");
+    add_meta_sst(&mut state, 52, KB, "b", "c");
+    state.levels[1].1 = vec![21];
+    state.levels[2].1 = vec![31];
+    state.levels[3].1 = vec![41];
+    state.l0_sstables = vec![52, 51];
+
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, None);
+    assert_eq!(task.lower_level, 2);
+    assert_eq!(task.lower_level_sst_ids, vec![21]);
+    assert!(!task.is_lower_level_bottom_level);
+
+    // with the upper levels drained, L0 goes to the bottom level
+    state.levels[1].1.clear();
+    state.levels[2].1.clear();
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.lower_level, 4);
+}
+
+#[test]
+fn test_min_sst_age_before_compaction() {
+    const MB: u64 = 1024 * 1024;
+    let mut state = empty_state(4);
+    // L2 has the highest priority, but its only SST was just written
+    add_meta_sst(&mut state, 50, 5 * MB, "a", "c");
+    add_meta_sst(&mut state, 31, 12 * MB, "d", "f");
+    add_meta_sst(&mut state, 40, 100 * MB, "a", "z");
+    state.levels[1].1 = vec![50];
+    state.levels[2].1 = vec![31];
+    state.levels[3].1 = vec![40];
+
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, Some(2));
+    assert_eq!(task.upper_level_sst_ids, vec![50]);
+
+    let mut options = leveled_options(2);
+    options.min_sst_age_before_compaction = Some(5);
+    let controller = LeveledCompactionController::new(options);
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, Some(3));
+    assert_eq!(task.upper_level_sst_ids, vec![31]);
+    assert_eq!(task.lower_level_sst_ids, vec![40]);
+
+    // every candidate is too young
+    let mut options = leveled_options(2);
+    options.min_sst_age_before_compaction = Some(20);
+    let controller = LeveledCompactionController::new(options);
+    assert!(controller.generate_compaction_task(&state).is_none());
+
+    // L0 pressure is never deferred
+    add_meta_sst(&mut state, 51, MB, "a", "b");
+    add_meta_sst(&mut state, 52, MB, "b", "c");
+    state.l0_sstables = vec![52, 51];
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, None);
+    assert_eq!(task.upper_level_sst_ids, vec![52, 51]);
+}
+
+#[test]
+fn test_prioritize_by_age() {
+    const MB: u64 = 1024 * 1024;
+    // SSTs age with their id here, see `test_prioritize_by_age_uses_max_ts` otherwise
+    let controller = |options| {
+        LeveledCompactionController::new(options)
+            .with_sst_meta(3, FakeSstMeta::new("m", "p", 20 * MB).with_max_ts(3))
+            .with_sst_meta(12, FakeSstMeta::new("a", "c", 3 * MB).with_max_ts(12))
+            .with_sst_meta(5, FakeSstMeta::new("x", "z", MB).with_max_ts(5))
+            .with_sst_meta(1, FakeSstMeta::new("a", "z", 100 * MB).with_max_ts(1))
+    };
+    let mut state = empty_state(4);
+    // L1 is far over its target with a young SST, L2 is less over its target with an old one
+    state.levels[0].1 = vec![12];
+    state.levels[1].1 = vec![5, 3];
+    state.levels[3].1 = vec![1];
+
+    let task = controller(leveled_options(2))
+        .generate_compaction_task(&state)
+        .unwrap();
+    assert_eq!(task.upper_level, Some(1));
+    assert_eq!(task.upper_level_sst_ids, vec![12]);
+
+    let mut options = leveled_options(2);
+    options.prioritize_by_age = true;
+    let task = controller(options.clone())
+        .generate_compaction_task(&state)
+        .unwrap();
+    assert_eq!(task.upper_level, Some(2));
+    assert_eq!(task.upper_level_sst_ids, vec![3]);
+    assert!(task.lower_level_sst_ids.is_empty());
+
+    // a level under its target is not compacted however old its SSTs are
+    let mut under_target = state.clone();
+    under_target.levels[0].1.clear();
+    under_target.levels[1].1 = vec![5];
+    assert!(controller(options.clone())
+        .generate_compaction_task(&under_target)
+        .is_none());
+
+    // too young SSTs are still skipped
+    options.min_sst_age_before_compaction = Some(10);
+    assert!(controller(options)
+        .generate_compaction_task(&state)
+        .is_none());
+}
+
+#[test]
+fn test_prioritize_by_age_uses_max_ts() {
+    const MB: u64 = 1024 * 1024;
+    let mut options = leveled_options(2);
+    options.prioritize_by_age = true;
+    // sst 2 has the lowest id but holds the newest data, ssts 7 and 9 are compaction outputs of
+    // older data
+    let controller = LeveledCompactionController::new(options)
+        .with_sst_meta(2, FakeSstMeta::new("a", "c", 3 * MB).with_max_ts(40))
+        .with_sst_meta(7, FakeSstMeta::new("d", "f", 10 * MB).with_max_ts(30))
+        .with_sst_meta(9, FakeSstMeta::new("g", "k", 10 * MB).with_max_ts(31))
+        .with_sst_meta(1, FakeSstMeta::new("a", "z", 100 * MB).with_max_ts(1));
+    let mut state = empty_state(4);
+    state.levels[0].1 = vec![2];
+    state.levels[1].1 = vec![7, 9];
+    state.levels[3].1 = vec![1];
+
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, Some(2));
+    assert_eq!(task.upper_level_sst_ids, vec![7]);
+}
+
+#[test]
+fn test_rank_ssts() {
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 1, 300, "a", "b");
+    add_meta_sst(&mut state, 2, 100, "c", "d");
+    add_meta_sst(&mut state, 3, 500, "e", "f");
+    add_meta_sst(&mut state, 4, 100, "g", "h");
+    state.l0_sstables = vec![4, 3];
+    state.levels[0].1 = vec![1, 2];
+    assert_eq!(
+        controller.rank_ssts(&state, SstRankMetric::Size),
+        vec![(3, 500), (1, 300), (2, 100), (4, 100)]
+    );
+    assert_eq!(
+        controller.rank_ssts(&state, SstRankMetric::Age),
+        vec![(1, 3), (2, 2), (3, 1), (4, 0)]
+    );
+
+    let dir = tempdir().unwrap();
+    let mut builder = SsTableBuilder::new(128);
+    for i in 0..100 {
+        let key = format!("key_{:03}", i);
+        let value: &[u8] = if i % 3 == 0 { b"" } else { b"value" };
+        builder.add(
+            KeySlice::for_testing_from_slice_no_ts(key.as_bytes()),
+            value,
+        );
+    }
+    let sst = builder.build(5, None, dir.path().join("5.sst")).unwrap();
+    assert!(sst.num_of_blocks() > 1);
+    state.sstables.insert(5, Arc::new(sst));
+    assert_eq!(
+        controller.rank_ssts(&state, SstRankMetric::TombstoneCount)[0],
+        (5, 34)
+    );
+}
+
+#[test]
+fn test_tasks_conflict() {
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 1, 1, "a", "c");
+    add_meta_sst(&mut state, 2, 1, "b", "e");
+    add_meta_sst(&mut state, 3, 1, "x", "z");
+    add_meta_sst(&mut state, 4, 1, "d", "f");
+    add_meta_sst(&mut state, 5, 1, "m", "n");
+    let task = |upper_level: usize, upper: Vec<usize>, lower: Vec<usize>| LeveledCompactionTask {
+        upper_level: Some(upper_level),
+        upper_level_sst_ids: upper,
+        lower_level: upper_level + 1,
+        lower_level_sst_ids: lower,
+        is_lower_level_bottom_level: false,
+    };
+    // share sst 2
+    assert!(tasks_conflict(
+        &state,
+        &task(1, vec![1], vec![2]),
+        &task(2, vec![2], vec![])
+    ));
+    // both write b..c into L2
+    assert!(tasks_conflict(
+        &state,
+        &task(1, vec![1], vec![]),
+        &task(1, vec![2], vec![])
+    ));
+    // disjoint ranges in the same lower level
+    assert!(!tasks_conflict(
+        &state,
+        &task(1, vec![1], vec![2]),
+        &task(1, vec![3], vec![])
+    ));
+    // overlapping ranges in different levels
+    assert!(!tasks_conflict(
+        &state,
+        &task(1, vec![1], vec![]),
+        &task(2, vec![2], vec![])
+    ));
+    assert!(!tasks_conflict(
+        &state,
+        &task(2, vec![5], vec![]),
+        &task(3, vec![3], vec![])
+    ));
+}
+
+#[test]
+fn test_key_range_cache() {
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let mut state = empty_state(4);
+    let mut next_id = 1;
+    for round in 0..4 {
+        // two overlapping L0 SSTs trigger an L0 compaction into the bottom level
+        for _ in 0..2 {
+            let first = format!("{}", round);
+            let last = format!("{}z", round);
+            add_meta_sst(&mut state, next_id, 1, &first, &last);
+            state.l0_sstables.insert(0, next_id);
+            next_id += 1;
+        }
+        let task = controller.generate_compaction_task(&state).unwrap();
+        assert_eq!(task.upper_level, None);
+        let output = next_id;
+        next_id += 1;
+        let first = format!("{}", round);
+        let last = format!("{}z", round);
+        add_meta_sst(&mut state, output, 2, &first, &last);
+        let (new_state, removed) =
+            controller.apply_compaction_result(&state, &task, &[output], false);
+        state = new_state;
+        for id in &removed {
+            state.sstables.remove(id);
+            assert!(controller.cached_key_range(*id).is_none());
+        }
+        for (_, level) in &state.levels {
+            for id in level {
+                let sst = &state.sstables[id];
+                assert_eq!(
+                    controller.cached_key_range(*id),
+                    Some((sst.first_key().clone(), sst.last_key().clone()))
+                );
+            }
+        }
+    }
+    assert_eq!(state.levels[3].1.len(), 4);
+}
+
+#[test]
+fn test_task_callbacks() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let begin_events = events.clone();
+    let end_events = events.clone();
+    let controller = LeveledCompactionController::new(leveled_options(2))
+        .with_on_task_begin(move |task| {
+            begin_events
+                .lock()
+                .push(format!("begin {:?}", task.upper_level_sst_ids));
+        })
+        .with_on_task_end(move |task, output, stats| {
+            end_events.lock().push(format!(
+                "end {:?} -> {:?} {}/{} {}/{}",
+                task.upper_level_sst_ids,
+                output,
+                stats.input_sst_count,
+                stats.output_sst_count,
+                stats.input_bytes,
+                stats.output_bytes
+            ));
+        });
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 1, 10, "a", "c");
+    add_meta_sst(&mut state, 2, 20, "b", "d");
+    add_meta_sst(&mut state, 3, 25, "a", "d");
+    state.l0_sstables = vec![2, 1];
+    assert!(events.lock().is_empty());
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(*events.lock(), vec!["begin [2, 1]".to_string()]);
+    controller.apply_compaction_result(&state, &task, &[3], false);
+    // no callback when replaying the manifest
+    controller.apply_compaction_result(&state, &task, &[3], true);
+    assert_eq!(
+        *events.lock(),
+        vec![
+            "begin [2, 1]".to_string(),
+            "end [2, 1] -> [3] 2/1 30/25".to_string()
+        ]
+    );
+}
+
+#[test]
+fn test_streaming_output_writer_matches_buffered() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions {
+            target_sst_size: 16 << 10,
+            ..LsmStorageOptions::default_for_week1_test()
+        },
+    )
+    .unwrap();
+    let write_output = |streaming: bool| {
+        let mut writer = OutputWriter::new(&storage.inner, 4096).with_streaming(streaming);
+        for i in 0..1000 {
+            let key = format!("key_{:05}", i);
+            for ts in (1..=3).rev() {
+                let value = format!("value_{}_{}", i, ts);
+                writer
+                    .push_entry(KeySlice::from_slice(key.as_bytes(), ts), value.as_bytes())
+                    .unwrap();
+            }
+        }
+        writer.finish().unwrap()
+    };
+    let streamed = write_output(true);
+    let buffered = write_output(false);
+    assert!(streamed.len() > 1);
+    assert_eq!(streamed.len(), buffered.len());
+    for (streamed, buffered) in streamed.iter().zip(buffered.iter()) {
+        assert_ne!(streamed.sst_id(), buffered.sst_id());
+        assert_eq!(streamed.first_key(), buffered.first_key());
+        assert_eq!(streamed.last_key(), buffered.last_key());
+        assert_eq!(
+            std::fs::read(storage.inner.path_of_sst(streamed.sst_id())).unwrap(),
+            std::fs::read(storage.inner.path_of_sst(buffered.sst_id())).unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_output_writer_removes_unfinished_ssts() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions {
+            target_sst_size: 16 << 10,
+            ..LsmStorageOptions::default_for_week1_test()
+        },
+    )
+    .unwrap();
+    let num_ssts = || {
+        std::fs::read_dir(&dir)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("sst".as_ref()))
+            .count()
+    };
+    for streaming in [true, false] {
+        let mut writer = OutputWriter::new(&storage.inner, 4096).with_streaming(streaming);
+        for i in 0..1000 {
+            writer
+                .push_entry(
+                    KeySlice::from_slice(format!("key_{:05}", i).as_bytes(), 1),
+                    b"value",
+                )
+                .unwrap();
+        }
+        // some SSTs are finished and the last one is being written
+        assert!(num_ssts() > 0);
+        // a compaction failing at this point drops the writer
+        drop(writer);
+        assert_eq!(num_ssts(), 0);
+    }
+}
+
+#[test]
+fn test_estimate_scan_cost() {
+    let dir = tempdir().unwrap();
+    let mut state = empty_state(2);
+    for (id, range) in [
+        (10, 40..60),
+        (20, 0..50),
+        (21, 50..100),
+        (30, 0..100),
+        (31, 100..200),
+    ] {
+        let mut builder = SsTableBuilder::new(128);
+        for i in range {
+            let key = format!("key_{:03}", i);
+            builder.add(
+                KeySlice::for_testing_from_slice_no_ts(key.as_bytes()),
+                b"value",
+            );
+        }
+        let sst = builder
+            .build(id, None, dir.path().join(format!("{id}.sst")))
+            .unwrap();
+        state.sstables.insert(id, Arc::new(sst));
+    }
+    state.l0_sstables = vec![10];
+    state.levels[0].1 = vec![20, 21];
+    state.levels[1].1 = vec![30, 31];
+
+    // the blocks holding a key in the range
+    let (lower, upper) = (b"key_045".as_slice(), b"key_055".as_slice());
+    let mut expected_blocks = 0;
+    for id in [10, 20, 21, 30] {
+        let sst = &state.sstables[&id];
+        for block_idx in 0..sst.num_of_blocks() {
+            let mut in_range = false;
+            sst.read_block(block_idx)
+                .unwrap()
+                .for_each_entry(|_, key, _| {
+                    in_range |= lower <= key.key_ref() && key.key_ref() < upper;
+                });
+            expected_blocks += in_range as usize;
+        }
+    }
+    let cost = state.estimate_scan_cost(lower, upper);
+    assert_eq!(cost.ssts_touched, 4);
+    assert_eq!(cost.estimated_blocks, expected_blocks);
+
+    // the whole tree reads every block
+    let full = state.estimate_scan_cost(b"", b"z");
+    assert_eq!(full.ssts_touched, 5);
+    assert_eq!(
+        full.estimated_blocks,
+        state
+            .sstables
+            .values()
+            .map(|sst| sst.num_of_blocks())
+            .sum::<usize>()
+    );
+    assert_eq!(
+        full.estimated_bytes,
+        state
+            .sstables
+            .values()
+            .map(|sst| sst.block_meta_offset as u64)
+            .sum::<u64>()
+    );
+    assert!(0 < cost.estimated_bytes && cost.estimated_bytes < full.estimated_bytes / 2);
+
+    assert_eq!(state.estimate_scan_cost(b"x", b"z"), ScanCost::default());
+}
+
+#[test]
+fn test_state_scan() {
+    let dir = tempdir().unwrap();
+    let mut state = empty_state(2);
+    let mut add_sst = |id: usize, entries: &[(&str, u64, &str)]| {
+        let mut builder = SsTableBuilder::new(4096);
+        for (key, ts, value) in entries {
+            builder.add(
+                KeySlice::for_testing_from_slice_with_ts(key.as_bytes(), *ts),
+                value.as_bytes(),
+            );
+        }
+        let sst = builder
+            .build(id, None, dir.path().join(format!("{id}.sst")))
+            .unwrap();
+        state.sstables.insert(id, Arc::new(sst));
+    };
+    add_sst(10, &[("a", 4, "a4"), ("c", 5, "c5"), ("d", 6, "")]);
+    add_sst(20, &[("b", 3, ""), ("c", 3, "c3")]);
+    add_sst(
+        30,
+        &[
+            ("a", 1, "a1"),
+            ("b", 1, "b1"),
+            ("c", 1, "c1"),
+            ("d", 1, "d1"),
+            ("e", 1, "e1"),
+        ],
+    );
+    state.l0_sstables = vec![10];
+    state.levels[0].1 = vec![20];
+    state.levels[1].1 = vec![30];
+    for (key, ts, value) in [("a", 9, ""), ("e", 7, "e7"), ("f", 8, "f8")] {
+        state
+            .memtable
+            .put(
+                KeySlice::for_testing_from_slice_with_ts(key.as_bytes(), ts),
+                value.as_bytes(),
+            )
+            .unwrap();
+    }
+
+    let scan = |lower: Bound<&str>, upper: Bound<&str>, read_ts: u64| {
+        let mut iter = state
+            .scan(lower.map(str::as_bytes), upper.map(str::as_bytes), read_ts)
+            .unwrap();
+        let mut entries = Vec::new();
+        while iter.is_valid() {
+            entries.push((
+                String::from_utf8(iter.key().to_vec()).unwrap(),
+                String::from_utf8(iter.value().to_vec()).unwrap(),
+            ));
+            iter.next().unwrap();
+        }
+        entries
+    };
+    let pairs = |pairs: &[(&str, &str)]| {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        scan(Bound::Unbounded, Bound::Unbounded, 10),
+        pairs(&[("c", "c5"), ("e", "e7"), ("f", "f8")])
+    );
+    assert_eq!(
+        scan(Bound::Unbounded, Bound::Unbounded, 5),
+        pairs(&[("a", "a4"), ("c", "c5"), ("d", "d1"), ("e", "e1")])
+    );
+    assert_eq!(
+        scan(Bound::Included("b"), Bound::Included("d"), 2),
+        pairs(&[("b", "b1"), ("c", "c1"), ("d", "d1")])
+    );
+    // no version of an excluded bound is returned
+    assert_eq!(
+        scan(Bound::Excluded("a"), Bound::Excluded("e"), 5),
+        pairs(&[("c", "c5"), ("d", "d1")])
+    );
+    assert_eq!(
+        scan(Bound::Excluded("e"), Bound::Unbounded, 10),
+        pairs(&[("f", "f8")])
+    );
+    assert_eq!(
+        scan(Bound::Included("bb"), Bound::Excluded("c"), 10),
+        pairs(&[])
+    );
+}
+
+#[test]
+fn test_update_options() {
+    const MB: u64 = 1024 * 1024;
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 1, 5 * MB, "a", "c");
+    add_meta_sst(&mut state, 2, 60 * MB, "d", "f");
+    state.levels[2].1 = vec![1];
+    state.levels[3].1 = vec![2];
+    let mut controller = LeveledCompactionController::new(leveled_options(2));
+    assert!(controller.generate_compaction_task(&state).is_none());
+
+    // L4 would be orphaned
+    let err = controller
+        .update_options(
+            &state,
+            LeveledCompactionOptions {
+                max_levels: 3,
+                ..leveled_options(2)
+            },
+        )
+        .unwrap_err();
+    assert_eq!(
+        err,
+        OptionsError::OrphanedLevel {
+            level: 4,
+            max_levels: 3
+        }
+    );
+    assert_eq!(
+        err.to_string(),
+        "L4 still holds SSTs, cannot reduce max_levels to 3"
+    );
+    let err = controller
+        .update_options(
+            &state,
+            LeveledCompactionOptions {
+                level_size_multiplier: 1,
+                ..leveled_options(2)
+            },
+        )
+        .unwrap_err();
+    assert_eq!(err, OptionsError::InvalidMultiplier { multiplier: 1 });
+    assert!(controller.generate_compaction_task(&state).is_none());
+
+    // with a larger multiplier, L3 is over its target of 60MB / 20
+    controller
+        .update_options(
+            &state,
+            LeveledCompactionOptions {
+                level_size_multiplier: 20,
+                ..leveled_options(2)
+            },
+        )
+        .unwrap();
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, Some(3));
+    assert_eq!(task.upper_level_sst_ids, vec![1]);
+}
+
+#[test]
+fn test_task_memoization() {
+    const MB: u64 = 1024 * 1024;
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 1, 5 * MB, "a", "c");
+    add_meta_sst(&mut state, 2, 12 * MB, "d", "f");
+    state.levels[2].1 = vec![1];
+    state.levels[3].1 = vec![2];
+    let computed = Arc::new(Mutex::new(0));
+    let counter = computed.clone();
+    let controller = LeveledCompactionController::new(leveled_options(2))
+        .with_on_task_begin(move |_| *counter.lock() += 1)
+        .with_task_memoization();
+
+    assert!(matches!(
+        controller.poll_compaction_task(&state),
+        TaskPoll::Task(_)
+    ));
+    assert!(matches!(
+        controller.poll_compaction_task(&state),
+        TaskPoll::NoChange
+    ));
+    assert!(controller.generate_compaction_task(&state).is_none());
+    assert_eq!(*computed.lock(), 1);
+
+    // a changed level is recomputed
+    add_meta_sst(&mut state, 3, 100 * MB, "a", "z");
+    state.levels[3].1.push(3);
+    assert!(matches!(
+        controller.poll_compaction_task(&state),
+        TaskPoll::NoTask
+    ));
+    assert!(matches!(
+        controller.poll_compaction_task(&state),
+        TaskPoll::NoChange
+    ));
+
+    // without memoization, every poll recomputes the task
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    assert!(matches!(
+        controller.poll_compaction_task(&state),
+        TaskPoll::NoTask
+    ));
+    assert!(matches!(
+        controller.poll_compaction_task(&state),
+        TaskPoll::NoTask
+    ));
+}
+
+#[test]
+fn test_task_serialized_size() {
+    let tasks = [
+        LeveledCompactionTask {
+            upper_level: None,
+            upper_level_sst_ids: vec![7, 6, 5],
+            lower_level: 1,
+            lower_level_sst_ids: vec![],
+            is_lower_level_bottom_level: false,
+        },
+        LeveledCompactionTask {
+            upper_level: Some(2),
+            upper_level_sst_ids: vec![10],
+            lower_level: 3,
+            lower_level_sst_ids: vec![1, 2, 3, 4],
+            is_lower_level_bottom_level: true,
+        },
+        LeveledCompactionTask {
+            upper_level: Some(1),
+            upper_level_sst_ids: vec![],
+            lower_level: 2,
+            lower_level_sst_ids: (0..1000).collect(),
+            is_lower_level_bottom_level: false,
+        },
+    ];
+    for task in tasks {
+        let encoded = task.encode();
+        assert_eq!(task.serialized_size(), encoded.len());
+        let decoded = LeveledCompactionTask::decode(&encoded).unwrap();
+        assert_eq!(decoded.upper_level, task.upper_level);
+        assert_eq!(decoded.upper_level_sst_ids, task.upper_level_sst_ids);
+        assert_eq!(decoded.lower_level, task.lower_level);
+        assert_eq!(decoded.lower_level_sst_ids, task.lower_level_sst_ids);
+        assert_eq!(
+            decoded.is_lower_level_bottom_level,
+            task.is_lower_level_bottom_level
+        );
+        assert!(LeveledCompactionTask::decode(&encoded[..encoded.len() - 1]).is_err());
+    }
+}
+
+#[test]
+fn test_preview_shadowed_keys() {
+    let dir = tempdir().unwrap();
+    let mut state = empty_state(4);
+    let versions: [(usize, &[(&str, u64)]); 3] = [
+        (1, &[("a", 1), ("b", 2), ("d", 3)]),
+        (2, &[("b", 5), ("c", 4)]),
+        (3, &[("a", 7), ("b", 6), ("e", 8)]),
+    ];
+    for (id, entries) in versions {
+        let mut builder = SsTableBuilder::new(4096);
+        for (key, ts) in entries {
+            builder.add(
+                KeySlice::for_testing_from_slice_with_ts(key.as_bytes(), *ts),
+                format!("{}@{}", key, ts).as_bytes(),
+            );
+        }
+        let sst = builder
+            .build(id, None, dir.path().join(format!("{}.sst", id)))
+            .unwrap();
+        state.sstables.insert(id, Arc::new(sst));
+    }
+    let task = LeveledCompactionTask {
+        upper_level: None,
+        upper_level_sst_ids: vec![3, 2],
+        lower_level: 1,
+        lower_level_sst_ids: vec![1],
+        is_lower_level_bottom_level: false,
+    };
+
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    assert!(controller.preview_shadowed_keys(&state, &task).is_err());
+
+    let controller = LeveledCompactionController::new(leveled_options(2)).with_shadow_preview();
+    assert_eq!(
+        controller.preview_shadowed_keys(&state, &task).unwrap(),
+        vec![
+            (b"a".to_vec(), 1, 7),
+            (b"b".to_vec(), 5, 6),
+            (b"b".to_vec(), 2, 6),
+        ]
+    );
+    // nothing is shadowed within a single input
+    let task = LeveledCompactionTask {
+        upper_level: Some(1),
+        upper_level_sst_ids: vec![1],
+        lower_level: 2,
+        lower_level_sst_ids: vec![],
+        is_lower_level_bottom_level: false,
+    };
+    assert!(controller
+        .preview_shadowed_keys(&state, &task)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_version_count_histogram() {
+    let dir = tempdir().unwrap();
+    let mut state = empty_state(4);
+    let mut hot_key = (1..=10).rev().map(|ts| ("hot", ts)).collect::<Vec<_>>();
+    hot_key.insert(0, ("a", 3));
+    let ssts = [(1, hot_key), (2, vec![("x", 4), ("y", 2), ("z", 9)])];
+    for (id, entries) in ssts {
+        let mut builder = SsTableBuilder::new(128);
+        for (key, ts) in entries {
+            builder.add(
+                KeySlice::for_testing_from_slice_with_ts(key.as_bytes(), ts),
+                format!("{}@{}", key, ts).as_bytes(),
+            );
+        }
+        let sst = builder
+            .build(id, None, dir.path().join(format!("{}.sst", id)))
+            .unwrap();
+        state.sstables.insert(id, Arc::new(sst));
+        state.levels[0].1.push(id);
+    }
+
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    assert_eq!(
+        controller.version_count_histogram(&state, 1).unwrap(),
+        BTreeMap::from([(1, 4), (10, 1)])
+    );
+    assert!(controller
+        .version_count_histogram(&state, 2)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_simulate_flush() {
+    let controller = LeveledCompactionController::new(leveled_options(4));
+    let mut state = empty_state(4);
+    for id in 1..=4 {
+        let (next_state, task) = controller.simulate_flush(&state, id, 1024);
+        assert_eq!(next_state.l0_sstables.first(), Some(&id));
+        assert_eq!(next_state.l0_sstables.len(), id);
+        assert_eq!(next_state.sstables[&id].table_size(), 1024);
+        // the snapshot is left untouched
+        assert_eq!(state.l0_sstables.len(), id - 1);
+        if id < 4 {
+            assert!(task.is_none());
+        } else {
+            let task = task.unwrap();
+            assert_eq!(task.upper_level, None);
+            assert_eq!(task.upper_level_sst_ids, vec![4, 3, 2, 1]);
+        }
+        state = next_state;
+    }
+}
+
+#[test]
+fn test_simulate_flush_does_not_cache_simulated_sst() {
+    let controller = LeveledCompactionController::new(leveled_options(1));
+    let mut state = empty_state(4);
+    add_meta_sst(&mut state, 1, 1024, "a", "z");
+    state.levels[3].1 = vec![1];
+    let (_, task) = controller.simulate_flush(&state, 2, 1024);
+    assert_eq!(task.unwrap().lower_level_sst_ids, Vec::<usize>::new());
+    assert_eq!(controller.cached_key_range(2), None);
+
+    // the real SST 2 overlaps the bottom level, unlike the simulated one
+    add_meta_sst(&mut state, 2, 1024, "m", "n");
+    state.l0_sstables = vec![2];
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level_sst_ids, vec![2]);
+    assert_eq!(task.lower_level_sst_ids, vec![1]);
+}
+
+#[test]
+fn test_simulate_flush_cascade() {
+    let controller = LeveledCompactionController::new(leveled_options(2));
+    let mut state = empty_state(4);
+    const KB: u64 = 1024;
+    add_meta_sst(&mut state, 1, 200 * KB, "a", "z");
+    add_meta_sst(&mut state, 2, 900 * KB, "a", "z");
+    add_meta_sst(&mut state, 3, 8 * 1024 * KB, "a", "z");
+    add_meta_sst(&mut state, 4, 100 * 1024 * KB, "a", "z");
+    state.l0_sstables = vec![1];
+    state.levels[1].1 = vec![2];
+    state.levels[2].1 = vec![3];
+    state.levels[3].1 = vec![4];
+
+    // a small flush is not enough to fill L3 (8MB of 10MB)
+    let cascade = controller.simulate_flush_cascade(&state, 100 * KB);
+    let levels = |cascade: &[LeveledCompactionTask]| {
+        cascade
+            .iter()
+            .map(|task| (task.upper_level, task.lower_level))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(levels(&cascade), vec![(None, 2), (Some(2), 3)]);
+    assert_eq!(cascade[0].upper_level_sst_ids, vec![5, 1]);
+    assert_eq!(cascade[0].lower_level_sst_ids, vec![2]);
+
+    // the simulator plans with the configuration of the controller
+    let meta_controller = LeveledCompactionController::new(leveled_options(2))
+        .with_sst_meta(10, FakeSstMeta::new("a", "f", 100 * KB));
+    let mut meta_state = empty_state(4);
+    meta_state.l0_sstables = vec![10];
+    let cascade = meta_controller.simulate_flush_cascade(&meta_state, 100 * KB);
+    assert_eq!(levels(&cascade), vec![(None, 4)]);
+    assert_eq!(cascade[0].upper_level_sst_ids, vec![11, 10]);
+
+    // a larger one pushes L2 and then L3 over their targets
+    let cascade = controller.simulate_flush_cascade(&state, 1024 * KB);
+    assert_eq!(
+        levels(&cascade),
+        vec![(None, 2), (Some(2), 3), (Some(3), 4)]
+    );
+    assert_eq!(cascade[2].lower_level_sst_ids, vec![4]);
+    // the snapshot is left untouched
+    assert_eq!(state.l0_sstables, vec![1]);
+    assert_eq!(state.levels[1].1, vec![2]);
+
+    // nothing is triggered below the L0 trigger
+    state.l0_sstables.clear();
+    assert!(controller.simulate_flush_cascade(&state, KB).is_empty());
+}
+
+/// A state whose tiers hold `sizes[i]` SSTs each, from the newest tier to the bottom tier.
+fn tiered_state(sizes: &[usize]) -> LsmStorageState {
+    let mut state = empty_state(0);
+    let mut next_id = 1;
+    for size in sizes {
+        let ids = (next_id..next_id + size).collect::<Vec<_>>();
+        next_id += size;
+        state.levels.push((ids[0], ids));
+    }
+    state
+}
+
+#[test]
+fn test_tiered_compaction_triggers() {
+    let controller = TieredCompactionController::new(TieredCompactionOptions {
+        num_tiers: 4,
+        max_size_amplification_percent: 200,
+        size_ratio: 1,
+        min_merge_width: 2,
+    });
+    // not enough tiers
+    assert!(controller
+        .generate_compaction_task(&tiered_state(&[1, 1, 1]))
+        .is_none());
+
+    // space amplification: 3 SSTs above a bottom tier of 1 SST
+    let task = controller
+        .generate_compaction_task(&tiered_state(&[1, 1, 1, 1]))
+        .unwrap();
+    assert_eq!(task.tiers.len(), 4);
+    assert!(task.bottom_tier_included);
+
+    // size ratio: the first tier is larger than the second one
+    let task = controller
+        .generate_compaction_task(&tiered_state(&[2, 1, 10, 20]))
+        .unwrap();
+    assert_eq!(
+        task.tiers.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+        vec![1, 3]
+    );
+    assert!(!task.bottom_tier_included);
+
+    // tier count: every tier is much smaller than the next one
+    let task = controller
+        .generate_compaction_task(&tiered_state(&[1, 3, 10, 40, 200]))
+        .unwrap();
+    assert_eq!(task.tiers.len(), 3);
+    assert!(!task.bottom_tier_included);
+}
+
+#[test]
+fn test_tiered_apply_compaction_result() {
+    let controller = TieredCompactionController::new(TieredCompactionOptions {
+        num_tiers: 3,
+        max_size_amplification_percent: 200,
+        size_ratio: 1,
+        min_merge_width: 2,
+    });
+    let mut state = tiered_state(&[1, 2, 20]);
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.tiers.len(), 2);
+    for (id, first, last) in [(100, "a", "f"), (101, "g", "m")] {
+        add_meta_sst(&mut state, id, 1, first, last);
+    }
+    let (next_state, mut removed) =
+        controller.apply_compaction_result(&state, &task, &[100, 101], false);
+    removed.sort();
+    assert_eq!(removed, vec![1, 2, 3]);
+    assert_eq!(
+        next_state.levels,
+        vec![(100, vec![100, 101]), state.levels[2].clone()]
+    );
+    // the SSTs are not loaded during recovery
+    let state = tiered_state(&[1, 2, 20]);
+    let (recovered, _) = controller.apply_compaction_result(&state, &task, &[100, 101], true);
+    assert_eq!(recovered.levels, next_state.levels);
+    // every key is deleted, the merged tiers are dropped
+    let (next_state, _) = controller.apply_compaction_result(&state, &task, &[], false);
+    assert_eq!(next_state.levels, vec![state.levels[2].clone()]);
+}
+
+#[test]
+fn test_simple_leveled_compaction_ratio_trigger() {
+    let controller = SimpleLeveledCompactionController::new(SimpleLeveledCompactionOptions {
+        size_ratio_percent: 200,
+        level0_file_num_compaction_trigger: 2,
+        max_levels: 3,
+    });
+    let mut state = empty_state(3);
+    state.l0_sstables = vec![1];
+    assert!(controller.generate_compaction_task(&state).is_none());
+
+    // L0 reaches the trigger
+    state.l0_sstables = vec![2, 1];
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, None);
+    assert_eq!(task.upper_level_sst_ids, vec![2, 1]);
+    assert_eq!(task.lower_level, 1);
+    assert!(!task.is_lower_level_bottom_level);
+
+    // L2 / L1 = 3 / 2 < 200%
+    state.l0_sstables.clear();
+    state.levels[0].1 = vec![1, 2];
+    state.levels[1].1 = vec![3, 4, 5];
+    state.levels[2].1 = vec![6, 7, 8, 9, 10, 11];
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, Some(1));
+    assert_eq!(task.lower_level, 2);
+    assert_eq!(task.lower_level_sst_ids, vec![3, 4, 5]);
+
+    // L2 / L1 = 4 / 2 and L3 / L2 = 8 / 4 reach the ratio
+    state.levels[1].1 = vec![3, 4, 5, 12];
+    state.levels[2].1.extend([13, 14]);
+    assert!(controller.generate_compaction_task(&state).is_none());
+}
+
+#[test]
+fn test_simple_leveled_compaction_to_bottom_level() {
+    let controller = SimpleLeveledCompactionController::new(SimpleLeveledCompactionOptions {
+        size_ratio_percent: 200,
+        level0_file_num_compaction_trigger: 2,
+        max_levels: 2,
+    });
+    let mut state = empty_state(2);
+    state.l0_sstables = vec![2, 1];
+    let mut next_id = 3;
+    while let Some(task) = controller.generate_compaction_task(&state) {
+        let output = vec![next_id];
+        next_id += 1;
+        // an L0 SST flushed while compacting is kept
+        if task.upper_level.is_none() {
+            state.l0_sstables.insert(0, 100);
+        }
+        let (new_state, removed) = controller.apply_compaction_result(&state, &task, &output, true);
+        let mut expected_removed = task.upper_level_sst_ids.clone();
+        expected_removed.extend(&task.lower_level_sst_ids);
+        assert_eq!(removed, expected_removed);
+        state = new_state;
+        if task.upper_level.is_none() {
+            assert_eq!(state.l0_sstables, vec![100]);
+            state.l0_sstables.clear();
+        }
+    }
+    // everything ends up in the bottom level
+    assert!(state.l0_sstables.is_empty());
+    assert!(state.levels[0].1.is_empty());
+    assert_eq!(state.levels[1].1, vec![next_id - 1]);
+}
+
+#[test]
+fn test_compaction_options_persisted_in_manifest() {
+    let dir = tempdir().unwrap();
+    let open = |options: LeveledCompactionOptions| {
+        MiniLsm::open(
+            &dir,
+            LsmStorageOptions::default_for_week2_test(CompactionOptions::Leveled(options)),
+        )
+        .unwrap()
+    };
+    let storage = open(leveled_options(2));
+    assert_eq!(storage.inner.persisted_compaction_options, None);
+    storage.close().unwrap();
+    drop(storage);
+
+    // reopening with the same options is silent
+    let storage = open(leveled_options(2));
+    assert_eq!(storage.inner.persisted_compaction_options, None);
+    storage.close().unwrap();
+    drop(storage);
+
+    let changed = LeveledCompactionOptions {
+        level_size_multiplier: 4,
+        ..leveled_options(2)
+    };
+    let storage = open(changed.clone());
+    assert_eq!(
+        storage.inner.persisted_compaction_options,
+        Some(CompactionOptions::Leveled(leveled_options(2)))
+    );
+    storage.close().unwrap();
+    drop(storage);
+
+    // the new options were recorded on the previous open
+    let storage = open(changed);
+    assert_eq!(storage.inner.persisted_compaction_options, None);
+    storage.close().unwrap();
+}
@@ -0,0 +1,483 @@
+use std::ops::Bound;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    iterators::{merge_iterator::MergeIterator, StorageIterator},
+    key::KeySlice,
+    lsm_iterator::{materialize_scan, SinceTsIterator, SnapshotIterator},
+    lsm_storage::{LsmStorageInner, LsmStorageOptions, MiniLsm},
+    manifest::ManifestRecord,
+    mem_table::MemTable,
+};
+
+use super::harness::MockIterator;
+
+#[test]
+fn test_merge_iterator_progress() {
+    let data = |keys: &[&'static str]| {
+        keys.iter()
+            .map(|k| {
+                (
+                    Bytes::from_static(k.as_bytes()),
+                    Bytes::from_static(b"value"),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let inputs = [
+        data(&["a", "c", "e", "g"]),
+        data(&["b", "c", "f"]),
+        data(&["d", "h"]),
+    ];
+    // every entry is a 1-byte key, an 8-byte ts and a 5-byte value
+    let total_bytes = inputs.iter().map(|x| x.len() as u64 * 14).sum();
+    let mut iter = MergeIterator::create(
+        inputs
+            .into_iter()
+            .map(|x| Box::new(MockIterator::new(x)))
+            .collect(),
+    )
+    .with_total_bytes(total_bytes);
+    let mut last = iter.progress();
+    assert_eq!(last, 0.0);
+    while iter.is_valid() {
+        iter.next().unwrap();
+        let progress = iter.progress();
+        assert!(progress > last, "progress {} -> {}", last, progress);
+        last = progress;
+    }
+    assert_eq!(last, 1.0);
+}
+
+#[test]
+fn test_materialize_scan() {
+    let memtable = MemTable::create(0);
+    let versions: [(&[u8], u64, &[u8]); 9] = [
+        (b"a", 1, b"a1"),
+        (b"a", 3, b"a3"),
+        (b"a", 5, b""),
+        (b"b", 2, b""),
+        (b"b", 4, b"b4"),
+        (b"c", 6, b"c6"),
+        (b"d", 1, b"d1"),
+        (b"d", 2, b""),
+        (b"e", 4, b"e4"),
+    ];
+    for (key, ts, value) in versions {
+        memtable.put(KeySlice::from_slice(key, ts), value).unwrap();
+    }
+    let scan = |read_ts| {
+        let entries =
+            materialize_scan(memtable.scan(Bound::Unbounded, Bound::Unbounded), read_ts).unwrap();
+        entries
+            .into_iter()
+            .map(|(key, value)| {
+                (
+                    String::from_utf8(key).unwrap(),
+                    String::from_utf8(value).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let expected = |entries: &[(&str, &str)]| {
+        entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(scan(0), expected(&[]));
+    assert_eq!(scan(1), expected(&[("a", "a1"), ("d", "d1")]));
+    // b is deleted at ts 2, d is deleted at ts 2
+    assert_eq!(scan(2), expected(&[("a", "a1")]));
+    assert_eq!(scan(4), expected(&[("a", "a3"), ("b", "b4"), ("e", "e4")]));
+    // a is deleted at ts 5
+    assert_eq!(scan(6), expected(&[("b", "b4"), ("c", "c6"), ("e", "e4")]));
+}
+
+#[test]
+fn test_since_ts_iterator() {
+    let memtable = MemTable::create(0);
+    let versions: [(&[u8], u64, &[u8]); 9] = [
+        (b"a", 1, b"a1"),
+        (b"a", 4, b"a4"),
+        (b"b", 2, b"b2"),
+        (b"c", 1, b"c1"),
+        (b"c", 3, b""),
+        (b"d", 3, b"d3"),
+        (b"d", 6, b"d6"),
+        (b"e", 2, b"e2"),
+        (b"f", 6, b"f6"),
+    ];
+    for (key, ts, value) in versions {
+        memtable.put(KeySlice::from_slice(key, ts), value).unwrap();
+    }
+    let delta = |since_ts, read_ts| {
+        let mut iter = SinceTsIterator::new(
+            memtable.scan(Bound::Unbounded, Bound::Unbounded),
+            since_ts,
+            read_ts,
+        )
+        .unwrap();
+        let mut entries = Vec::new();
+        while iter.is_valid() {
+            entries.push((
+                String::from_utf8(iter.key().key_ref().to_vec()).unwrap(),
+                iter.key().ts(),
+                String::from_utf8(iter.value().to_vec()).unwrap(),
+            ));
+            iter.next().unwrap();
+        }
+        entries
+    };
+    let expected = |entries: &[(&str, u64, &str)]| {
+        entries
+            .iter()
+            .map(|(key, ts, value)| (key.to_string(), *ts, value.to_string()))
+            .collect::<Vec<_>>()
+    };
+    // the first backup at ts 2 is a full backup
+    assert_eq!(
+        delta(0, 2),
+        expected(&[
+            ("a", 1, "a1"),
+            ("b", 2, "b2"),
+            ("c", 1, "c1"),
+            ("e", 2, "e2")
+        ])
+    );
+    // between the backups at ts 2 and ts 5: a is updated, c is deleted and d is added, the
+    // versions written after ts 5 are left to the next backup
+    assert_eq!(
+        delta(2, 5),
+        expected(&[("a", 4, "a4"), ("c", 3, ""), ("d", 3, "d3")])
+    );
+    assert_eq!(delta(5, 6), expected(&[("d", 6, "d6"), ("f", 6, "f6")]));
+    assert_eq!(delta(6, 10), expected(&[]));
+}
+
+#[test]
+fn test_snapshot_iterator() {
+    let memtable = MemTable::create(0);
+    let versions: [(&[u8], u64, &[u8]); 7] = [
+        (b"a", 5, b"a5"),
+        (b"a", 2, b"a2"),
+        (b"b", 7, b"b7"),
+        (b"c", 3, b"c3"),
+        (b"c", 1, b""),
+        (b"d", 4, b"d4"),
+        (b"e", 9, b"e9"),
+    ];
+    for (key, ts, value) in versions {
+        memtable.put(KeySlice::from_slice(key, ts), value).unwrap();
+    }
+    let visible = |read_ts| {
+        let mut iter =
+            SnapshotIterator::new(memtable.scan(Bound::Unbounded, Bound::Unbounded), read_ts)
+                .unwrap();
+        let mut entries = Vec::new();
+        while iter.is_valid() {
+            entries.push((iter.key().key_ref().to_vec(), iter.key().ts()));
+            iter.next().unwrap();
+        }
+        entries
+    };
+    assert_eq!(
+        visible(3),
+        vec![(b"a".to_vec(), 2), (b"c".to_vec(), 3), (b"c".to_vec(), 1)]
+    );
+    assert_eq!(
+        visible(4),
+        vec![
+            (b"a".to_vec(), 2),
+            (b"c".to_vec(), 3),
+            (b"c".to_vec(), 1),
+            (b"d".to_vec(), 4)
+        ]
+    );
+    assert_eq!(visible(9).len(), versions.len());
+    // only future versions, the iterator starts invalid
+    assert!(visible(0).is_empty());
+}
+
+#[test]
+fn test_scan_bounds_across_levels() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction),
+    )
+    .unwrap();
+    for key in ["a", "b", "c", "d", "e", "f", "g", "h"] {
+        storage.put(key.as_bytes(), b"v1").unwrap();
+    }
+    storage.delete(b"c").unwrap();
+    storage.force_flush().unwrap();
+    storage.force_full_compaction().unwrap();
+    // L0
+    storage.put(b"b", b"v2").unwrap();
+    storage.delete(b"e").unwrap();
+    storage.force_flush().unwrap();
+    // memtable
+    storage.put(b"g", b"v3").unwrap();
+    storage.delete(b"a").unwrap();
+
+    let scan = |lower: Bound<&str>, upper: Bound<&str>| {
+        let mut iter = storage
+            .scan(lower.map(str::as_bytes), upper.map(str::as_bytes))
+            .unwrap();
+        let mut entries = Vec::new();
+        while iter.is_valid() {
+            entries.push(format!(
+                "{}={}",
+                String::from_utf8_lossy(iter.key()),
+                String::from_utf8_lossy(iter.value())
+            ));
+            iter.next().unwrap();
+        }
+        entries
+    };
+    use Bound::*;
+    assert_eq!(
+        scan(Unbounded, Unbounded),
+        ["b=v2", "d=v1", "f=v1", "g=v3", "h=v1"]
+    );
+    assert_eq!(
+        scan(Included("b"), Included("g")),
+        ["b=v2", "d=v1", "f=v1", "g=v3"]
+    );
+    assert_eq!(scan(Excluded("b"), Included("g")), ["d=v1", "f=v1", "g=v3"]);
+    assert_eq!(scan(Included("b"), Excluded("g")), ["b=v2", "d=v1", "f=v1"]);
+    assert_eq!(scan(Excluded("b"), Excluded("g")), ["d=v1", "f=v1"]);
+    assert_eq!(scan(Unbounded, Excluded("d")), ["b=v2"]);
+    assert_eq!(scan(Excluded("f"), Unbounded), ["g=v3", "h=v1"]);
+    // deleted keys on the bounds
+    assert_eq!(scan(Included("c"), Included("e")), ["d=v1"]);
+    assert_eq!(scan(Excluded("a"), Excluded("c")), ["b=v2"]);
+    assert!(scan(Excluded("d"), Excluded("f")).is_empty());
+}
+
+#[test]
+fn test_get_as_of() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction),
+    )
+    .unwrap();
+    let latest_ts = || storage.inner.mvcc().latest_commit_ts();
+    storage.put(b"key", b"v1").unwrap();
+    let put_ts = latest_ts();
+    storage.force_flush().unwrap();
+    storage.delete(b"key").unwrap();
+    let delete_ts = latest_ts();
+    storage.force_flush().unwrap();
+    storage.put(b"key", b"v3").unwrap();
+    let rewrite_ts = latest_ts();
+    assert!(put_ts < delete_ts && delete_ts < rewrite_ts);
+
+    let check = |storage: &MiniLsm| {
+        let get_as_of = |read_ts| storage.inner.get_as_of(b"key", read_ts).unwrap();
+        assert_eq!(get_as_of(put_ts - 1), None);
+        assert_eq!(get_as_of(put_ts), Some(Bytes::from_static(b"v1")));
+        assert_eq!(get_as_of(delete_ts), None);
+        assert_eq!(get_as_of(rewrite_ts), Some(Bytes::from_static(b"v3")));
+        assert_eq!(get_as_of(u64::MAX), Some(Bytes::from_static(b"v3")));
+        assert_eq!(storage.inner.get_as_of(b"other", u64::MAX).unwrap(), None);
+    };
+    // the versions are in the memtable and two L0 SSTs
+    check(&storage);
+
+    // the commit ts is recovered from the SSTs
+    storage.close().unwrap();
+    drop(storage);
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction),
+    )
+    .unwrap();
+    assert_eq!(storage.inner.mvcc().latest_commit_ts(), rewrite_ts);
+    assert_eq!(
+        storage.get(b"key").unwrap(),
+        Some(Bytes::from_static(b"v3"))
+    );
+    check(&storage);
+
+    // all versions in a single SST
+    storage.force_full_compaction().unwrap();
+    check(&storage);
+}
+
+fn scan_all(storage: &MiniLsm) -> Vec<(Bytes, Bytes)> {
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    let mut entries = Vec::new();
+    while iter.is_valid() {
+        entries.push((
+            Bytes::copy_from_slice(iter.key()),
+            Bytes::copy_from_slice(iter.value()),
+        ));
+        iter.next().unwrap();
+    }
+    entries
+}
+
+fn entries(pairs: &[(&'static str, &'static str)]) -> Vec<(Bytes, Bytes)> {
+    pairs
+        .iter()
+        .map(|(k, v)| {
+            (
+                Bytes::from_static(k.as_bytes()),
+                Bytes::from_static(v.as_bytes()),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn test_delete_range_with_point_writes() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction),
+    )
+    .unwrap();
+    let latest_ts = || storage.inner.mvcc().latest_commit_ts();
+    for key in ["a", "b", "c", "d", "e"] {
+        storage.put(key.as_bytes(), b"v1").unwrap();
+    }
+    let put_ts = latest_ts();
+    storage.force_flush().unwrap();
+    // [b, d) deletes b and c, then c is written again
+    storage
+        .delete_range(Bound::Included(b"b"), Bound::Excluded(b"d"))
+        .unwrap();
+    let first_range_ts = latest_ts();
+    storage.put(b"c", b"v2").unwrap();
+    // (c, e] overlaps the previous range, leaves the earlier c alone but deletes the d written
+    // afterwards
+    storage.put(b"d", b"v2").unwrap();
+    storage
+        .delete_range(Bound::Excluded(b"c"), Bound::Included(b"e"))
+        .unwrap();
+    let second_range_ts = latest_ts();
+    storage.put(b"e", b"v3").unwrap();
+
+    assert_eq!(
+        scan_all(&storage),
+        entries(&[("a", "v1"), ("c", "v2"), ("e", "v3")])
+    );
+    assert_eq!(storage.get(b"b").unwrap(), None);
+    assert_eq!(storage.get(b"d").unwrap(), None);
+    assert_eq!(storage.get(b"c").unwrap(), Some(Bytes::from_static(b"v2")));
+    let get_as_of = |key: &[u8], read_ts| storage.inner.get_as_of(key, read_ts).unwrap();
+    assert_eq!(get_as_of(b"b", put_ts), Some(Bytes::from_static(b"v1")));
+    assert_eq!(get_as_of(b"b", first_range_ts), None);
+    assert_eq!(get_as_of(b"c", first_range_ts), None);
+    assert_eq!(
+        get_as_of(b"d", first_range_ts),
+        Some(Bytes::from_static(b"v1"))
+    );
+    assert_eq!(
+        get_as_of(b"d", second_range_ts - 1),
+        Some(Bytes::from_static(b"v2"))
+    );
+    assert_eq!(get_as_of(b"e", second_range_ts), None);
+
+    // the scan starts inside the deleted range
+    let mut iter = storage
+        .scan(Bound::Excluded(b"a"), Bound::Included(b"d"))
+        .unwrap();
+    assert_eq!(iter.key(), b"c");
+    iter.next().unwrap();
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_delete_range_recovery() {
+    let dir = tempdir().unwrap();
+    let options = || LsmStorageOptions {
+        enable_wal: true,
+        ..LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction)
+    };
+    let storage = MiniLsm::open(&dir, options()).unwrap();
+    for key in ["a", "b", "c"] {
+        storage.put(key.as_bytes(), b"v1").unwrap();
+    }
+    storage.force_flush().unwrap();
+    storage
+        .delete_range(Bound::Unbounded, Bound::Excluded(b"c"))
+        .unwrap();
+    let expected = entries(&[("c", "v1")]);
+    assert_eq!(scan_all(&storage), expected);
+
+    // the range tombstone is recovered from the WAL
+    storage.close().unwrap();
+    drop(storage);
+    let storage = MiniLsm::open(&dir, options()).unwrap();
+    assert_eq!(scan_all(&storage), expected);
+
+    // flushing a memtable with only the range tombstone does not create an SST
+    let l0_sstables = storage.inner.state.read().l0_sstables.len();
+    storage.force_flush().unwrap();
+    let snapshot = storage.inner.state.read().clone();
+    assert!(snapshot.imm_memtables.is_empty());
+    assert_eq!(snapshot.l0_sstables.len(), l0_sstables);
+    assert_eq!(snapshot.range_tombstones.len(), 1);
+    storage.put(b"a", b"v2").unwrap();
+    storage.force_flush().unwrap();
+    let expected = entries(&[("a", "v2"), ("c", "v1")]);
+    assert_eq!(scan_all(&storage), expected);
+
+    // the flushed range tombstone is recovered from the manifest
+    storage.close().unwrap();
+    drop(storage);
+    let storage = MiniLsm::open(&dir, options()).unwrap();
+    assert_eq!(storage.inner.state.read().range_tombstones.len(), 1);
+    assert_eq!(scan_all(&storage), expected);
+    assert_eq!(storage.get(b"b").unwrap(), None);
+}
+
+#[test]
+fn test_delete_range_recovery_after_recorded_tombstones() {
+    let dir = tempdir().unwrap();
+    let options = || LsmStorageOptions {
+        enable_wal: true,
+        ..LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction)
+    };
+    let storage = LsmStorageInner::open(&dir, options()).unwrap();
+    storage.put(b"a", b"v1").unwrap();
+    storage
+        .delete_range(Bound::Included(b"b"), Bound::Included(b"c"))
+        .unwrap();
+    let state_lock = storage.state_lock.lock();
+    storage.force_freeze_memtable(&state_lock).unwrap();
+    let memtable = storage.state.read().imm_memtables[0].clone();
+    // crash after the flush recorded the range tombstones, before the WAL is removed
+    storage
+        .manifest()
+        .add_record(
+            &state_lock,
+            ManifestRecord::RangeTombstones(
+                memtable.id(),
+                memtable
+                    .range_tombstones()
+                    .iter()
+                    .map(|tombstone| tombstone.as_ref().clone())
+                    .collect(),
+            ),
+        )
+        .unwrap();
+    storage.sync().unwrap();
+    drop(state_lock);
+    drop(storage);
+
+    let storage = Arc::new(LsmStorageInner::open(&dir, options()).unwrap());
+    let snapshot = storage.state.read().clone();
+    assert_eq!(snapshot.range_tombstones.len(), 1);
+    assert_eq!(snapshot.imm_memtables.len(), 1);
+    assert!(snapshot.imm_memtables[0].range_tombstones().is_empty());
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from_static(b"v1")));
+}
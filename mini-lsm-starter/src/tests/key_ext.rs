@@ -0,0 +1,86 @@
+use std::{ops::Bound, sync::Arc};
+
+use crate::{
+    block::{BlockBuilder, BlockIterator},
+    key::{bound_to_seek_key, shorten_separator, KeySlice, KeyVec, TS_RANGE_BEGIN, TS_RANGE_END},
+};
+
+fn build_versioned_block() -> BlockIterator {
+    let mut builder = BlockBuilder::new(4096);
+    for (key, ts) in [(b"a", 3), (b"a", 1), (b"b", 5), (b"b", 2), (b"c", 4)] {
+        assert!(builder.add(KeySlice::for_testing_from_slice_with_ts(key, ts), b"v"));
+    }
+    BlockIterator::create_and_seek_to_first(Arc::new(builder.build()))
+}
+
+#[test]
+fn test_bound_to_seek_key_variants() {
+    assert_eq!(
+        bound_to_seek_key(Bound::Included(b"b"), true),
+        Some(KeyVec::from_vec_with_ts(b"b".to_vec(), TS_RANGE_BEGIN))
+    );
+    assert_eq!(
+        bound_to_seek_key(Bound::Excluded(b"b"), true),
+        Some(KeyVec::from_vec_with_ts(b"b\0".to_vec(), TS_RANGE_BEGIN))
+    );
+    assert_eq!(
+        bound_to_seek_key(Bound::Included(b"b"), false),
+        Some(KeyVec::from_vec_with_ts(b"b".to_vec(), TS_RANGE_END))
+    );
+    assert_eq!(
+        bound_to_seek_key(Bound::Excluded(b"b"), false),
+        Some(KeyVec::from_vec_with_ts(b"b".to_vec(), TS_RANGE_BEGIN))
+    );
+    assert_eq!(bound_to_seek_key(Bound::Unbounded, true), None);
+    assert_eq!(bound_to_seek_key(Bound::Unbounded, false), None);
+}
+
+#[test]
+fn test_bound_to_seek_key_captures_all_versions() {
+    let mut iter = build_versioned_block();
+    let collect = |iter: &mut BlockIterator, lower: Bound<&[u8]>, upper: Bound<&[u8]>| {
+        let lower = bound_to_seek_key(lower, true);
+        let upper = bound_to_seek_key(upper, false);
+        match &lower {
+            Some(key) => iter.seek_to_key(key.as_key_slice()),
+            None => iter.seek_to_first(),
+        }
+        let mut result = Vec::new();
+        while iter.is_valid()
+            && upper
+                .as_ref()
+                .is_none_or(|u| iter.key() <= u.as_key_slice())
+        {
+            result.push((iter.key().key_ref().to_vec(), iter.key().ts()));
+            iter.next();
+        }
+        result
+    };
+    assert_eq!(
+        collect(&mut iter, Bound::Included(b"b"), Bound::Included(b"b")),
+        vec![(b"b".to_vec(), 5), (b"b".to_vec(), 2)]
+    );
+    assert_eq!(
+        collect(&mut iter, Bound::Excluded(b"a"), Bound::Excluded(b"c")),
+        vec![(b"b".to_vec(), 5), (b"b".to_vec(), 2)]
+    );
+    assert_eq!(
+        collect(&mut iter, Bound::Unbounded, Bound::Excluded(b"b")),
+        vec![(b"a".to_vec(), 3), (b"a".to_vec(), 1)]
+    );
+    assert_eq!(
+        collect(&mut iter, Bound::Excluded(b"b"), Bound::Unbounded),
+        vec![(b"c".to_vec(), 4)]
+    );
+}
+
+#[test]
+fn test_shorten_separator() {
+    assert_eq!(shorten_separator(b"apple", b"banana"), b"b");
+    assert_eq!(shorten_separator(b"key_00099", b"key_00100"), b"key_001");
+    // `lower` is a prefix of `upper`
+    assert_eq!(shorten_separator(b"key", b"key_0"), b"key_");
+    assert_eq!(shorten_separator(b"", b"a"), b"a");
+    // nothing shorter than `upper` sorts after `lower`
+    assert_eq!(shorten_separator(b"key_0", b"key_1"), b"key_1");
+}
@@ -0,0 +1,151 @@
+use std::ops::Bound;
+
+use tempfile::tempdir;
+
+use crate::{
+    iterators::StorageIterator,
+    key::KeySlice,
+    lsm_storage::{LsmStorageInner, LsmStorageOptions, MiniLsm, WriteBatchRecord},
+    mem_table::MemTable,
+};
+
+#[test]
+fn test_memtable_approximate_size() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.wal");
+    let memtable = MemTable::create_with_wal(1, &path).unwrap();
+    assert_eq!(memtable.approximate_size(), 0);
+    // the ts takes 8 bytes of each key
+    memtable
+        .put(
+            KeySlice::for_testing_from_slice_with_ts(b"key1", 1),
+            b"value1",
+        )
+        .unwrap();
+    assert_eq!(memtable.approximate_size(), 4 + 8 + 6);
+    memtable
+        .put_batch(&[
+            (KeySlice::for_testing_from_slice_with_ts(b"k2", 2), b"v2"),
+            (KeySlice::for_testing_from_slice_with_ts(b"key3", 3), b""),
+        ])
+        .unwrap();
+    assert_eq!(memtable.approximate_size(), 18 + (2 + 8 + 2) + (4 + 8));
+    // an overwrite is counted again
+    memtable
+        .put(
+            KeySlice::for_testing_from_slice_with_ts(b"key1", 1),
+            b"value",
+        )
+        .unwrap();
+    assert_eq!(memtable.approximate_size(), 30 + 12 + 4 + 8 + 5);
+    memtable.sync_wal().unwrap();
+    drop(memtable);
+
+    // the recovered counter only counts the latest value of each key
+    let memtable = MemTable::recover_from_wal(1, &path).unwrap();
+    assert_eq!(
+        memtable.approximate_size(),
+        (4 + 8 + 5) + (2 + 8 + 2) + (4 + 8)
+    );
+}
+
+#[test]
+fn test_write_batch_is_atomic() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions {
+            // freeze the memtable every few batches
+            target_sst_size: 4096,
+            enable_wal: true,
+            ..LsmStorageOptions::default_for_week1_test()
+        },
+    )
+    .unwrap();
+    let keys = (0..16).map(|i| format!("key_{:02}", i)).collect::<Vec<_>>();
+    std::thread::scope(|s| {
+        let writer = s.spawn(|| {
+            for round in 1..=300 {
+                let value = format!("value_{:03}", round);
+                let batch = keys
+                    .iter()
+                    .map(|key| WriteBatchRecord::Put(key.as_bytes(), value.as_bytes()))
+                    .collect::<Vec<_>>();
+                storage.write_batch(&batch).unwrap();
+            }
+        });
+        while !writer.is_finished() {
+            let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+            let mut values = Vec::new();
+            while iter.is_valid() {
+                values.push(iter.value().to_vec());
+                iter.next().unwrap();
+            }
+            assert!(values.is_empty() || values.len() == keys.len());
+            assert!(
+                values.windows(2).all(|pair| pair[0] == pair[1]),
+                "a partially applied batch is visible"
+            );
+        }
+    });
+    {
+        let state = storage.inner.state.read();
+        assert!(!state.imm_memtables.is_empty() || !state.l0_sstables.is_empty());
+    }
+    for key in &keys {
+        assert_eq!(
+            &storage.get(key.as_bytes()).unwrap().unwrap()[..],
+            b"value_300"
+        );
+    }
+}
+
+#[test]
+fn test_imm_memtables_bounded_under_small_writes() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions {
+            // every write freezes the memtable
+            target_sst_size: 16,
+            num_memtable_limit: 3,
+            ..LsmStorageOptions::default_for_week1_test()
+        },
+    )
+    .unwrap();
+    for i in 0..100 {
+        let key = format!("key_{:03}", i);
+        storage.put(key.as_bytes(), b"value").unwrap();
+        let state = storage.inner.state.read();
+        assert!(
+            state.imm_memtables.len() < 3,
+            "{} immutable memtables after {} writes",
+            state.imm_memtables.len(),
+            i + 1
+        );
+    }
+    assert!(!storage.inner.state.read().l0_sstables.is_empty());
+    assert_eq!(&storage.get(b"key_000").unwrap().unwrap()[..], b"value");
+}
+
+#[test]
+fn test_writes_leave_flushing_to_flush_thread() {
+    let dir = tempdir().unwrap();
+    // no flush thread is spawned for the inner storage
+    let storage = LsmStorageInner::open(
+        &dir,
+        LsmStorageOptions {
+            target_sst_size: 16,
+            num_memtable_limit: 3,
+            ..LsmStorageOptions::default_for_week1_test()
+        },
+    )
+    .unwrap();
+    for i in 0..10 {
+        let key = format!("key_{:03}", i);
+        storage.put(key.as_bytes(), b"value").unwrap();
+    }
+    let state = storage.state.read();
+    assert_eq!(state.imm_memtables.len(), 10);
+    assert!(state.l0_sstables.is_empty());
+}
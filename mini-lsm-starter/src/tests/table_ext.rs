@@ -0,0 +1,352 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    block::{BlockError, BlockIterator},
+    iterators::StorageIterator,
+    key::{KeyBytes, KeySlice},
+    table::{
+        FileObject, LevelIndex, MerkleNode, MultiGetIterator, SsTable, SsTableBuilder,
+        SsTableIterator, VerifyReport,
+    },
+};
+
+fn key_of(idx: usize) -> Vec<u8> {
+    format!("key_{:05}", idx).into_bytes()
+}
+
+fn value_of(idx: usize) -> Vec<u8> {
+    format!("value_{:010}", idx).into_bytes()
+}
+
+#[test]
+fn test_reverse_block_order_sst() {
+    let dir = tempdir().unwrap();
+    let mut builder = SsTableBuilder::new(128);
+    builder.set_reverse_block_order(true);
+    for idx in 0..200 {
+        builder.add(
+            KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+            &value_of(idx),
+        );
+    }
+    let path = dir.path().join("1.sst");
+    builder.build(1, None, &path).unwrap();
+    let sst = Arc::new(SsTable::open(1, None, FileObject::open(&path).unwrap()).unwrap());
+    assert!(sst.num_of_blocks() > 2);
+    assert!(sst.is_reverse_block_order());
+    assert!(sst.block_meta[0].first_key < sst.block_meta[1].first_key);
+
+    // descending scan
+    let mut iter = SsTableIterator::create_and_seek_to_last(sst.clone()).unwrap();
+    for idx in (0..200).rev() {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().for_testing_key_ref(), key_of(idx));
+        assert_eq!(iter.value(), value_of(idx));
+        iter.prev().unwrap();
+    }
+    assert!(!iter.is_valid());
+
+    // forward scan and seek still work
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+    for idx in 0..200 {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().for_testing_key_ref(), key_of(idx));
+        assert_eq!(iter.value(), value_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    let iter = SsTableIterator::create_and_seek_to_key(
+        sst,
+        KeySlice::for_testing_from_slice_no_ts(&key_of(123)),
+    )
+    .unwrap();
+    assert_eq!(iter.value(), value_of(123));
+}
+
+#[test]
+fn test_multi_get_iterator() {
+    let dir = tempdir().unwrap();
+    let mut builder = SsTableBuilder::new(64);
+    for idx in 0..100 {
+        let key = key_of(idx);
+        match idx % 4 {
+            // two versions, the newer one is not visible at the read ts
+            0 => {
+                builder.add(KeySlice::from_slice(&key, 8), b"new");
+                builder.add(KeySlice::from_slice(&key, 4), &value_of(idx));
+            }
+            // deleted at the read ts
+            1 => {
+                builder.add(KeySlice::from_slice(&key, 5), b"");
+                builder.add(KeySlice::from_slice(&key, 2), &value_of(idx));
+            }
+            // only written after the read ts
+            2 => builder.add(KeySlice::from_slice(&key, 7), &value_of(idx)),
+            _ => builder.add(KeySlice::from_slice(&key, 3), &value_of(idx)),
+        }
+    }
+    let path = dir.path().join("1.sst");
+    let sst = Arc::new(builder.build(1, None, &path).unwrap());
+    assert!(sst.num_of_blocks() > 10);
+
+    // absent keys before, between and after the keys of the SST
+    let mut keys = vec![
+        Bytes::from_static(b"a"),
+        Bytes::from(format!("key_{:05}x", 42)),
+        Bytes::from_static(b"zzz"),
+    ];
+    keys.extend((0..100).step_by(3).map(|idx| Bytes::from(key_of(idx))));
+    keys.sort();
+
+    let mut iter = MultiGetIterator::create(sst, keys, 6).unwrap();
+    for idx in (0..100).step_by(3) {
+        let (ts, value) = match idx % 4 {
+            0 => (4, value_of(idx)),
+            1 => (5, Vec::new()),
+            2 => continue,
+            _ => (3, value_of(idx)),
+        };
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), KeySlice::from_slice(&key_of(idx), ts));
+        assert_eq!(iter.value(), value);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_verify_all_blocks() {
+    let dir = tempdir().unwrap();
+    let mut builder = SsTableBuilder::new(128);
+    for idx in 0..100 {
+        builder.add(
+            KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+            &value_of(idx),
+        );
+    }
+    let path = dir.path().join("1.sst");
+    let sst = builder.build(1, None, &path).unwrap();
+    let num_of_blocks = sst.num_of_blocks();
+    assert!(num_of_blocks > 5);
+    assert_eq!(
+        sst.verify_all_blocks(),
+        VerifyReport {
+            blocks_checked: num_of_blocks,
+            blocks_corrupt: 0,
+            first_corrupt_block: None,
+        }
+    );
+
+    // flip a byte in the middle of block 3
+    let offset = (sst.block_meta[3].offset + sst.block_meta[4].offset) / 2;
+    drop(sst);
+    let mut data = std::fs::read(&path).unwrap();
+    data[offset] ^= 0xff;
+    std::fs::write(&path, data).unwrap();
+    let sst = SsTable::open(1, None, FileObject::open(&path).unwrap()).unwrap();
+    assert_eq!(
+        sst.verify_all_blocks(),
+        VerifyReport {
+            blocks_checked: num_of_blocks,
+            blocks_corrupt: 1,
+            first_corrupt_block: Some(3),
+        }
+    );
+    let err = sst.read_block(3).err().unwrap();
+    assert!(matches!(
+        err.downcast_ref::<BlockError>(),
+        Some(BlockError::Crc32Mismatch { .. })
+    ));
+    assert!(sst.read_block(2).is_ok());
+}
+
+#[test]
+fn test_merkle_diff() {
+    let dir = tempdir().unwrap();
+    let build = |id: usize, changed: Option<usize>| {
+        let mut builder = SsTableBuilder::new(128);
+        for idx in 0..200 {
+            // the changed value has the same length, so that the block boundaries do not move
+            let value = if Some(idx) == changed {
+                format!("VALUE_{:010}", idx).into_bytes()
+            } else {
+                value_of(idx)
+            };
+            builder.add(KeySlice::for_testing_from_slice_no_ts(&key_of(idx)), &value);
+        }
+        builder
+            .build(id, None, dir.path().join(format!("{id}.sst")))
+            .unwrap()
+    };
+    let sst = build(1, None);
+    let replica = build(2, None);
+    let diverged = build(3, Some(123));
+    assert!(sst.num_of_blocks() > 16);
+
+    let root = sst.merkle_root(4).unwrap();
+    assert_eq!(root.blocks, (0, sst.num_of_blocks()));
+    assert!(root.children.len() <= 4);
+    assert_eq!(root, replica.merkle_root(4).unwrap());
+    assert!(root.diff(&replica.merkle_root(4).unwrap()).is_empty());
+
+    let block_idx = sst.find_block_idx(KeySlice::for_testing_from_slice_no_ts(&key_of(123)));
+    let diverged_root: MerkleNode = diverged.merkle_root(4).unwrap();
+    assert_ne!(root.hash, diverged_root.hash);
+    assert_eq!(root.diff(&diverged_root), vec![(block_idx, block_idx + 1)]);
+    assert_eq!(diverged_root.diff(&root), vec![(block_idx, block_idx + 1)]);
+}
+
+#[test]
+fn test_level_index_compressed_separators() {
+    let long_key = |idx: usize, ts: u64| {
+        KeyBytes::from_bytes_with_ts(Bytes::from(format!("{}_{:05}", "x".repeat(200), idx)), ts)
+    };
+    // SST 3 starts with an older version of the last key of SST 2
+    let ranges = [
+        (0, 5, 99, 5),
+        (100, 5, 120, 5),
+        (120, 4, 300, 5),
+        (500, 5, 999, 5),
+    ];
+    let ssts = ranges
+        .iter()
+        .enumerate()
+        .map(|(id, (first, first_ts, last, last_ts))| {
+            Arc::new(SsTable::create_meta_only(
+                id,
+                0,
+                long_key(*first, *first_ts),
+                long_key(*last, *last_ts),
+            ))
+        })
+        .collect::<Vec<_>>();
+    let full = LevelIndex::new(&ssts, false);
+    let compressed = LevelIndex::new(&ssts, true);
+    assert!(compressed.separators_len() < full.separators_len());
+    for idx in 0..1100 {
+        for ts in [6, 5, 4, 0] {
+            let key = long_key(idx, ts);
+            let key = key.as_key_slice();
+            let expected = ssts
+                .partition_point(|sst| sst.first_key().as_key_slice() <= key)
+                .saturating_sub(1);
+            assert_eq!(full.route(key), Some(expected));
+            let routed = compressed.route(key).unwrap();
+            if ssts[expected].last_key().as_key_slice() >= key {
+                // the key is within the range of the SST
+                assert_eq!(routed, expected);
+            } else {
+                // the key is in the gap after the SST
+                assert!(routed == expected || routed == expected + 1);
+            }
+        }
+    }
+    assert_eq!(
+        LevelIndex::new(&[], true).route(long_key(0, 0).as_key_slice()),
+        None
+    );
+}
+
+#[test]
+fn test_sst_compression_ratio() {
+    let dir = tempdir().unwrap();
+    let build = |id: usize, key_prefix: &str, value_len: usize| {
+        let mut builder = SsTableBuilder::new(4096);
+        for idx in 0..500 {
+            let key = format!("{}{:05}", key_prefix, idx);
+            builder.add(
+                KeySlice::for_testing_from_slice_no_ts(key.as_bytes()),
+                &vec![b'v'; value_len],
+            );
+        }
+        builder
+            .build(id, None, dir.path().join(format!("{}.sst", id)))
+            .unwrap()
+    };
+    // long keys sharing a prefix with the first key of their block are stored as short suffixes
+    let sst = build(1, &"prefix_".repeat(30), 4);
+    assert!(sst.compression_ratio().unwrap() > 5.0);
+    // short keys and large values leave nothing to save
+    let sst = build(2, "", 200);
+    let ratio = sst.compression_ratio().unwrap();
+    assert!(ratio > 0.9 && ratio <= 1.0, "ratio {}", ratio);
+
+    let sst = SsTable::create_meta_only(
+        3,
+        0,
+        KeyBytes::for_testing_from_bytes_no_ts(Bytes::from_static(b"a")),
+        KeyBytes::for_testing_from_bytes_no_ts(Bytes::from_static(b"b")),
+    );
+    assert_eq!(sst.compression_ratio().unwrap(), 1.0);
+}
+
+#[test]
+fn test_block_boundary_keys() {
+    let dir = tempdir().unwrap();
+    let mut builder = SsTableBuilder::new(128);
+    for idx in 0..200 {
+        builder.add(
+            KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+            &value_of(idx),
+        );
+    }
+    let path = dir.path().join("1.sst");
+    let sst = builder.build(1, None, &path).unwrap();
+    let boundary_keys = sst.block_boundary_keys();
+    assert!(boundary_keys.len() > 2);
+    assert_eq!(boundary_keys.len(), sst.num_of_blocks());
+    assert_eq!(
+        boundary_keys[0].as_key_slice(),
+        sst.first_key().as_key_slice()
+    );
+    for (block_idx, key) in boundary_keys.iter().enumerate() {
+        let iter = BlockIterator::create_and_seek_to_first(sst.read_block(block_idx).unwrap());
+        assert_eq!(key.as_key_slice(), iter.key());
+    }
+    assert!(boundary_keys.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[test]
+fn test_sst_iterator_block_change_callback() {
+    let dir = tempdir().unwrap();
+    let mut builder = SsTableBuilder::new(128);
+    for idx in 0..200 {
+        builder.add(
+            KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+            &value_of(idx),
+        );
+    }
+    let sst = Arc::new(builder.build(1, None, dir.path().join("1.sst")).unwrap());
+    assert!(sst.num_of_blocks() > 2);
+
+    let changes = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+    let recorded = changes.clone();
+    iter.set_on_block_change(move |blk_idx| recorded.lock().unwrap().push(blk_idx));
+    let mut entries = 0;
+    while iter.is_valid() {
+        entries += 1;
+        iter.next().unwrap();
+    }
+    assert_eq!(entries, 200);
+    assert_eq!(
+        *changes.lock().unwrap(),
+        (1..sst.num_of_blocks()).collect::<Vec<_>>()
+    );
+
+    // backwards, from the last block to the first
+    changes.lock().unwrap().clear();
+    let mut iter = SsTableIterator::create_and_seek_to_last(sst.clone()).unwrap();
+    let recorded = changes.clone();
+    iter.set_on_block_change(move |blk_idx| recorded.lock().unwrap().push(blk_idx));
+    while iter.is_valid() {
+        iter.prev().unwrap();
+    }
+    assert_eq!(
+        *changes.lock().unwrap(),
+        (0..sst.num_of_blocks() - 1).rev().collect::<Vec<_>>()
+    );
+}
@@ -17,6 +17,13 @@ fn test_integration() {
                 level0_file_num_compaction_trigger: 2,
                 level_size_multiplier: 2,
                 base_level_size_mb: 1,
+                per_level_block_size: None,
+                abort_overlap_ratio: None,
+                min_sst_age_before_compaction: None,
+                prioritize_by_age: false,
+                max_compaction_bytes: None,
+                pick_strategy: Default::default(),
+                level_entry_count_trigger: None,
                 max_levels: 4,
             },
         )),
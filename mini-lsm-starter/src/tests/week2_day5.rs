@@ -19,6 +19,13 @@ fn test_integration_leveled() {
         level0_file_num_compaction_trigger: 2,
         max_levels: 3,
         base_level_size_mb: 1,
+        per_level_block_size: None,
+        abort_overlap_ratio: None,
+        min_sst_age_before_compaction: None,
+        prioritize_by_age: false,
+        max_compaction_bytes: None,
+        pick_strategy: Default::default(),
+        level_entry_count_trigger: None,
     }))
 }
 
@@ -53,6 +60,13 @@ fn test_multiple_compacted_ssts_leveled() {
         level0_file_num_compaction_trigger: 2,
         max_levels: 2,
         base_level_size_mb: 2,
+        per_level_block_size: None,
+        abort_overlap_ratio: None,
+        min_sst_age_before_compaction: None,
+        prioritize_by_age: false,
+        max_compaction_bytes: None,
+        pick_strategy: Default::default(),
+        level_entry_count_trigger: None,
     });
 
     let lsm_storage_options = LsmStorageOptions::default_for_week2_test(compaction_options.clone());
@@ -1,10 +1,12 @@
 use std::fs::{File, OpenOptions};
 use std::hash::Hasher;
 use std::io::{Read, Write};
+use std::ops::Bound;
 use std::path::Path;
 use std::sync::Arc;
 
 use crate::key::{KeyBytes, KeySlice};
+use crate::mem_table::RangeTombstone;
 use anyhow::{bail, Context, Result};
 use bytes::{Buf, BufMut, Bytes};
 use crossbeam_skiplist::SkipMap;
@@ -28,7 +30,13 @@ impl Wal {
         })
     }
 
-    pub fn recover(path: impl AsRef<Path>, skiplist: &SkipMap<KeyBytes, Bytes>) -> Result<Self> {
+    /// Replay the WAL, putting the key-value pairs into `skiplist` and the range tombstones into
+    /// `range_tombstones`.
+    pub fn recover(
+        path: impl AsRef<Path>,
+        skiplist: &SkipMap<KeyBytes, Bytes>,
+        range_tombstones: &mut Vec<RangeTombstone>,
+    ) -> Result<Self> {
         let path = path.as_ref();
         let mut file = OpenOptions::new()
             .read(true)
@@ -42,6 +50,19 @@ impl Wal {
             let mut hasher = crc32fast::Hasher::new();
             let key_len = rbuf.get_u16() as usize;
             hasher.write_u16(key_len as u16);
+            // keys cannot be empty, a key_len of 0 marks a range tombstone
+            if key_len == 0 {
+                let lower = Self::decode_bound(&mut rbuf, &mut hasher);
+                let upper = Self::decode_bound(&mut rbuf, &mut hasher);
+                let ts = rbuf.get_u64();
+                hasher.write_u64(ts);
+                let checksum = rbuf.get_u32();
+                if hasher.finalize() != checksum {
+                    bail!("checksum mismatch");
+                }
+                range_tombstones.push(RangeTombstone { lower, upper, ts });
+                continue;
+            }
             let key = Bytes::copy_from_slice(&rbuf[..key_len]);
             hasher.write(&key);
             rbuf.advance(key_len);
@@ -64,9 +85,25 @@ impl Wal {
     }
 
     pub fn put(&self, key: KeySlice, value: &[u8]) -> Result<()> {
+        self.put_batch(&[(key, value)])
+    }
+
+    /// Append the records of `data` with a single write, in the same format as `put`.
+    pub fn put_batch(&self, data: &[(KeySlice, &[u8])]) -> Result<()> {
+        let mut buf: Vec<u8> = Vec::with_capacity(
+            data.iter()
+                .map(|(key, value)| key.raw_len() + value.len() + 8)
+                .sum(),
+        );
+        for (key, value) in data {
+            Self::encode_record(*key, value, &mut buf);
+        }
         let mut file = self.file.lock();
-        let mut buf: Vec<u8> =
-            Vec::with_capacity(key.raw_len() + value.len() + std::mem::size_of::<u16>());
+        file.write_all(&buf)?;
+        Ok(())
+    }
+
+    fn encode_record(key: KeySlice, value: &[u8], buf: &mut Vec<u8>) {
         let mut hasher = crc32fast::Hasher::new();
         hasher.write_u16(key.key_len() as u16);
         buf.put_u16(key.key_len() as u16);
@@ -80,10 +117,55 @@ impl Wal {
         hasher.write(value);
         // add checksum: week 2 day 7
         buf.put_u32(hasher.finalize());
+    }
+
+    /// Append a range tombstone, as a record with an empty key followed by the two bounds and
+    /// the ts: `| 0u16 | lower | upper | ts u64 | checksum u32 |`, where each bound is
+    /// `| kind u8 | len u16 | key |` (kind 0 is unbounded, 1 included and 2 excluded).
+    pub fn delete_range(&self, range_tombstone: &RangeTombstone) -> Result<()> {
+        let mut buf = Vec::new();
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.write_u16(0);
+        buf.put_u16(0);
+        Self::encode_bound(&range_tombstone.lower, &mut buf, &mut hasher);
+        Self::encode_bound(&range_tombstone.upper, &mut buf, &mut hasher);
+        hasher.write_u64(range_tombstone.ts);
+        buf.put_u64(range_tombstone.ts);
+        buf.put_u32(hasher.finalize());
+        let mut file = self.file.lock();
         file.write_all(&buf)?;
         Ok(())
     }
 
+    fn encode_bound(bound: &Bound<Vec<u8>>, buf: &mut Vec<u8>, hasher: &mut crc32fast::Hasher) {
+        let (kind, key): (u8, &[u8]) = match bound {
+            Bound::Unbounded => (0, &[]),
+            Bound::Included(key) => (1, key),
+            Bound::Excluded(key) => (2, key),
+        };
+        hasher.write_u8(kind);
+        buf.put_u8(kind);
+        hasher.write_u16(key.len() as u16);
+        buf.put_u16(key.len() as u16);
+        hasher.write(key);
+        buf.put_slice(key);
+    }
+
+    fn decode_bound(rbuf: &mut &[u8], hasher: &mut crc32fast::Hasher) -> Bound<Vec<u8>> {
+        let kind = rbuf.get_u8();
+        hasher.write_u8(kind);
+        let len = rbuf.get_u16() as usize;
+        hasher.write_u16(len as u16);
+        let key = rbuf[..len].to_vec();
+        hasher.write(&key);
+        rbuf.advance(len);
+        match kind {
+            1 => Bound::Included(key),
+            2 => Bound::Excluded(key),
+            _ => Bound::Unbounded,
+        }
+    }
+
     pub fn sync(&self) -> Result<()> {
         let file = self.file.lock();
         file.sync_all()?;